@@ -0,0 +1,183 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the policy lifecycle: compiling the module, instantiating
+//! it, loading the data and evaluating entrypoints, including some calling
+//! builtins.
+//!
+//! The policy is built from `benches/fixtures/bench.rego` with
+//! `make build-opa`.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use opa_wasm::{
+    read_bundle, DefaultContext, HttpMockLayer, MockResponse, Policy, RequestMatcher, Runtime,
+};
+use tokio::runtime::Runtime as TokioRuntime;
+use wasmtime::{Config, Engine, Module, Store};
+
+/// The bench policy bundle
+const BUNDLE: &str = "benches/fixtures/bench.rego.tar.gz";
+
+/// The number of entries in the `permissions` data document
+const PERMISSIONS: usize = 1000;
+
+/// The context used by the policies, answering `http.send` requests without
+/// hitting the network
+type BenchContext = HttpMockLayer<DefaultContext>;
+
+/// Build the evaluation context
+fn context() -> BenchContext {
+    HttpMockLayer::new(DefaultContext::default()).mock(
+        RequestMatcher::new("https://example.com/bench"),
+        MockResponse::json(http::StatusCode::OK, &serde_json::json!({ "ok": true })),
+    )
+}
+
+/// Build a data document with `n` permissions
+fn data(n: usize) -> serde_json::Value {
+    let permissions: Vec<_> = (0..n)
+        .map(|i| serde_json::json!({ "user": format!("user{i}"), "action": "read" }))
+        .collect();
+    serde_json::json!({ "permissions": permissions })
+}
+
+/// The input shared by all the evaluations
+fn input() -> serde_json::Value {
+    serde_json::json!({
+        "user": {
+            "name": format!("user{}", PERMISSIONS - 1),
+            "email": "someone@example.com",
+            "roles": ["user"],
+        },
+        "action": "read",
+        "size": "12MiB",
+    })
+}
+
+/// Read the bench policy
+fn wasm(rt: &TokioRuntime) -> Vec<u8> {
+    rt.block_on(read_bundle(BUNDLE))
+        .unwrap_or_else(|error| panic!("could not read {BUNDLE}, run `make build-opa`: {error}"))
+}
+
+/// Create an engine with async support, as required by the runtime
+fn engine() -> Engine {
+    let mut config = Config::new();
+    config.async_support(true);
+    Engine::new(&config).unwrap()
+}
+
+/// Instantiate the policy with the bench data
+async fn instantiate(engine: &Engine, module: &Module) -> (Store<()>, Policy<BenchContext>) {
+    let mut store = Store::new(engine, ());
+    let runtime = Runtime::new_with_evaluation_context(&mut store, module, context())
+        .await
+        .unwrap();
+    let policy = runtime
+        .with_data(&mut store, &data(PERMISSIONS))
+        .await
+        .unwrap();
+    (store, policy)
+}
+
+/// Compiling the WASM module
+fn compile(c: &mut Criterion) {
+    let rt = TokioRuntime::new().unwrap();
+    let wasm = wasm(&rt);
+    let engine = engine();
+
+    c.bench_function("compile", |b| {
+        b.iter(|| Module::new(&engine, &wasm).unwrap());
+    });
+}
+
+/// Instantiating the compiled module
+fn instantiate_module(c: &mut Criterion) {
+    let rt = TokioRuntime::new().unwrap();
+    let engine = engine();
+    let module = Module::new(&engine, wasm(&rt)).unwrap();
+
+    c.bench_function("instantiate", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut store = Store::new(&engine, ());
+            Runtime::new_with_evaluation_context(&mut store, &module, context())
+                .await
+                .unwrap()
+        });
+    });
+}
+
+/// Loading data documents of different sizes
+fn load_data(c: &mut Criterion) {
+    let rt = TokioRuntime::new().unwrap();
+    let engine = engine();
+    let module = Module::new(&engine, wasm(&rt)).unwrap();
+
+    let mut group = c.benchmark_group("load_data");
+    for n in [10, 1000, 100_000] {
+        let data = data(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &data, |b, data| {
+            // Only time the data loading, not the instantiation
+            let (engine, module) = (&engine, &module);
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut store = Store::new(engine, ());
+                    let runtime =
+                        Runtime::new_with_evaluation_context(&mut store, module, context())
+                            .await
+                            .unwrap();
+
+                    let start = Instant::now();
+                    let policy = runtime.with_data(&mut store, data).await.unwrap();
+                    total += start.elapsed();
+                    drop(policy);
+                }
+                total
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Evaluating entrypoints, with and without the `opa_eval` fast path
+fn evaluate(c: &mut Criterion) {
+    let rt = TokioRuntime::new().unwrap();
+    let engine = engine();
+    let module = Module::new(&engine, wasm(&rt)).unwrap();
+    let (mut store, mut policy) = rt.block_on(instantiate(&engine, &module));
+    let input = input();
+
+    for (name, fast_path) in [("fast_path", true), ("slow_path", false)] {
+        policy.set_fast_path(fast_path);
+
+        let mut group = c.benchmark_group(name);
+        for entrypoint in ["bench/allow", "bench/regex", "bench/units", "bench/http"] {
+            group.bench_function(entrypoint, |b| {
+                b.iter(|| {
+                    let result: serde_json::Value = rt
+                        .block_on(policy.evaluate(&mut store, entrypoint, &input))
+                        .unwrap();
+                    result
+                });
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compile, instantiate_module, load_data, evaluate);
+criterion_main!(benches);
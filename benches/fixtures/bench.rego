@@ -0,0 +1,36 @@
+#  Copyright 2024 The Matrix.org Foundation C.I.C.
+# 
+#  Licensed under the Apache License, Version 2.0 (the "License");
+#  you may not use this file except in compliance with the License.
+#  You may obtain a copy of the License at
+# 
+#      http://www.apache.org/licenses/LICENSE-2.0
+# 
+#  Unless required by applicable law or agreed to in writing, software
+#  distributed under the License is distributed on an "AS IS" BASIS,
+#  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+#  See the License for the specific language governing permissions and
+#  limitations under the License.
+
+package bench
+
+# Plain rule evaluation, looking up the data
+default allow := false
+
+allow {
+	input.user.roles[_] == "admin"
+}
+
+allow {
+	some i
+	data.permissions[i].user == input.user.name
+	data.permissions[i].action == input.action
+}
+
+# Builtins implemented inside the module
+regex := regex.match(`^[a-z.]+@example\.com$`, input.user.email)
+
+# Builtins implemented by the host
+units := units.parse_bytes(input.size)
+
+http := http.send({"method": "GET", "url": "https://example.com/bench"}).body
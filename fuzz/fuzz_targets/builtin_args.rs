@@ -0,0 +1,39 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Call the builtins of this build with arbitrary arguments, as a policy
+//! could, which should fail to deserialize them without panicking
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use opa_wasm::{block_on, BuiltinRegistry, DefaultContext};
+
+fuzz_target!(|input: (u16, Vec<&[u8]>)| {
+    let (index, args) = input;
+    let names = opa_wasm::__fuzz::builtin_names();
+    let Some(name) = names.get(usize::from(index) % names.len().max(1)) else {
+        return;
+    };
+
+    let registry = BuiltinRegistry::<DefaultContext>::new();
+    let Ok(builtin) = registry.resolve(name) else {
+        return;
+    };
+
+    // Keep the builtins off the network
+    let mut context = DefaultContext::builder().http(false).dns(false).build();
+    let mut out = Vec::new();
+    let _ = block_on(builtin.call(&mut context, &args, &mut out));
+});
@@ -0,0 +1,26 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Load arbitrary bytes as a compiled bundle, which should be rejected
+//! without panicking, whatever the gzip stream or the tar archive holds
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use opa_wasm::{block_on, load_bundle, Bundle};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = block_on(load_bundle(data));
+    let _ = block_on(Bundle::load(data));
+});
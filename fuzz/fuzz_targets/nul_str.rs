@@ -0,0 +1,31 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read null-terminated strings at arbitrary addresses of an arbitrary
+//! memory, which should fail without panicking when out of bounds
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (i32, &[u8])| {
+    let (addr, memory) = input;
+    if let Ok(string) = opa_wasm::__fuzz::read_nul_str(memory, addr) {
+        let start = usize::try_from(addr).unwrap();
+        assert_eq!(
+            &memory[start..start + string.to_bytes().len()],
+            string.to_bytes()
+        );
+    }
+});
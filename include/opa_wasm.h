@@ -0,0 +1,70 @@
+/*
+ * Copyright 2024 The Matrix.org Foundation C.I.C.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/*
+ * C API of opa-wasm, built with:
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * Functions which can fail take an `error` out-parameter, which, if not NULL,
+ * is set to a message to free with `opa_wasm_string_free` when they do.
+ * A policy handle must not be used from multiple threads at once.
+ */
+
+#ifndef OPA_WASM_H
+#define OPA_WASM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A policy instance */
+typedef struct OpaWasmPolicy OpaWasmPolicy;
+
+/*
+ * Compile and instantiate a policy module, with an empty data document.
+ * Returns NULL on failure.
+ */
+OpaWasmPolicy *opa_wasm_policy_new(const uint8_t *module, size_t len, char **error);
+
+/*
+ * Replace the data document of a policy with the given JSON document.
+ * Returns 0 on success, and -1 on failure, in which case the policy keeps its
+ * previous data.
+ */
+int opa_wasm_policy_set_data(OpaWasmPolicy *policy, const char *data, char **error);
+
+/*
+ * Evaluate an entrypoint with the given JSON input, returning the JSON-encoded
+ * result set, to free with `opa_wasm_string_free`. Returns NULL on failure.
+ */
+char *opa_wasm_policy_evaluate(OpaWasmPolicy *policy, const char *entrypoint, const char *input,
+                               char **error);
+
+/* Destroy a policy. Does nothing if `policy` is NULL. */
+void opa_wasm_policy_free(OpaWasmPolicy *policy);
+
+/* Free a string returned by this API. Does nothing if `string` is NULL. */
+void opa_wasm_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OPA_WASM_H */
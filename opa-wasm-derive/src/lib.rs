@@ -0,0 +1,344 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Derive macros for the `PolicyInput` and `PolicyOutput` traits of
+//! `opa-wasm`, re-exported by it with the `derive` feature.
+
+#![deny(
+    missing_docs,
+    clippy::all,
+    clippy::pedantic,
+    clippy::missing_docs_in_private_items
+)]
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    ext::IdentExt, parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitStr, Type,
+};
+
+/// How the fields are named in the JSON documents
+#[derive(Clone, Copy)]
+enum RenameAll {
+    /// As in Rust, which is also the usual convention for Rego documents
+    SnakeCase,
+
+    /// In camel case, as in documents coming from Kubernetes for example
+    CamelCase,
+}
+
+impl RenameAll {
+    /// Rename a field
+    fn apply(self, name: &str) -> String {
+        match self {
+            Self::SnakeCase => name.to_owned(),
+            Self::CamelCase => {
+                let mut parts = name.split('_').filter(|part| !part.is_empty());
+                let mut renamed = parts.next().unwrap_or_default().to_owned();
+                for part in parts {
+                    let mut chars = part.chars();
+                    if let Some(first) = chars.next() {
+                        renamed.extend(first.to_uppercase());
+                        renamed.push_str(chars.as_str());
+                    }
+                }
+                renamed
+            }
+        }
+    }
+}
+
+/// The attributes of the struct
+struct Container {
+    /// The entrypoint the input is evaluated with
+    entrypoint: Option<LitStr>,
+
+    /// The result of the entrypoint
+    output: Option<Type>,
+}
+
+/// A field of the struct
+struct Field {
+    /// The name of the field in Rust
+    ident: Ident,
+
+    /// The type of the field
+    ty: Type,
+
+    /// The name of the field in the JSON documents
+    name: String,
+
+    /// Whether the field is left out of the JSON documents
+    skip: bool,
+}
+
+impl Field {
+    /// Whether the field is an `Option`, which may be missing
+    fn is_option(&self) -> bool {
+        match &self.ty {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "Option"),
+            _ => false,
+        }
+    }
+}
+
+/// Parse the attributes of the struct and of its fields
+fn parse(input: &DeriveInput) -> syn::Result<(Container, Vec<Field>)> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "generic types are not supported",
+        ));
+    }
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "only structs are supported",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "only structs with named fields are supported",
+        ));
+    };
+
+    let mut container = Container {
+        entrypoint: None,
+        output: None,
+    };
+    let mut rename_all = RenameAll::SnakeCase;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("policy"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("entrypoint") {
+                container.entrypoint = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("output") {
+                container.output = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("rename_all") {
+                let value: LitStr = meta.value()?.parse()?;
+                rename_all = match value.value().as_str() {
+                    "snake_case" => RenameAll::SnakeCase,
+                    "camelCase" => RenameAll::CamelCase,
+                    _ => return Err(meta.error("expected \"snake_case\" or \"camelCase\"")),
+                };
+            } else {
+                return Err(meta.error("unknown attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut fields = Vec::with_capacity(named.named.len());
+    for field in &named.named {
+        let Some(ident) = field.ident.clone() else {
+            continue;
+        };
+
+        let mut name = rename_all.apply(&ident.unraw().to_string());
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("policy"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    let value: LitStr = meta.value()?.parse()?;
+                    name = value.value();
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else {
+                    return Err(meta.error("unknown attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        fields.push(Field {
+            ident,
+            ty: field.ty.clone(),
+            name,
+            skip,
+        });
+    }
+
+    Ok((container, fields))
+}
+
+/// Implement `PolicyInput` and `Serialize`
+fn expand_input(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let (container, fields) = parse(input)?;
+    let ident = &input.ident;
+    let entrypoint = container.entrypoint.ok_or_else(|| {
+        Error::new_spanned(ident, "missing #[policy(entrypoint = \"...\")] attribute")
+    })?;
+    let output = container.output.map_or_else(
+        || quote!(::opa_wasm::__private::serde_json::Value),
+        |output| quote!(#output),
+    );
+
+    let fields: Vec<_> = fields.iter().filter(|field| !field.skip).collect();
+    let len = fields.len();
+    let names = fields.iter().map(|field| &field.name);
+    let idents = fields.iter().map(|field| &field.ident);
+    let ident_str = ident.to_string();
+
+    Ok(quote! {
+        impl ::opa_wasm::PolicyInput for #ident {
+            type Output = #output;
+            const ENTRYPOINT: &'static str = #entrypoint;
+        }
+
+        impl ::opa_wasm::__private::serde::Serialize for #ident {
+            fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+            where
+                S: ::opa_wasm::__private::serde::Serializer,
+            {
+                use ::opa_wasm::__private::serde::ser::SerializeStruct as _;
+                let mut state = serializer.serialize_struct(#ident_str, #len)?;
+                #( state.serialize_field(#names, &self.#idents)?; )*
+                state.end()
+            }
+        }
+    })
+}
+
+/// Implement `PolicyOutput` and `Deserialize`
+fn expand_output(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let (container, fields) = parse(input)?;
+    let ident = &input.ident;
+    if let Some(entrypoint) = container.entrypoint {
+        return Err(Error::new_spanned(
+            entrypoint,
+            "the entrypoint is set on the input",
+        ));
+    }
+    if let Some(output) = container.output {
+        return Err(Error::new_spanned(output, "the output is set on the input"));
+    }
+
+    let expecting = format!("struct {ident}");
+    let read: Vec<_> = fields.iter().filter(|field| !field.skip).collect();
+    let vars: Vec<_> = (0..read.len())
+        .map(|index| format_ident!("__field{index}"))
+        .collect();
+    let types = read.iter().map(|field| &field.ty);
+    let names = read.iter().map(|field| &field.name);
+
+    let mut vars_iter = vars.iter();
+    let values = fields.iter().map(|field| {
+        let ident = &field.ident;
+        if field.skip {
+            return quote!(#ident: ::core::default::Default::default());
+        }
+
+        // The iterator follows the fields which are not skipped
+        let var = vars_iter.next();
+        let name = &field.name;
+        if field.is_option() {
+            quote!(#ident: #var.unwrap_or_default())
+        } else {
+            quote! {
+                #ident: #var.ok_or_else(|| {
+                    <A::Error as ::opa_wasm::__private::serde::de::Error>::missing_field(#name)
+                })?
+            }
+        }
+    });
+    let values: Vec<_> = values.collect();
+
+    Ok(quote! {
+        impl ::opa_wasm::PolicyOutput for #ident {}
+
+        impl<'de> ::opa_wasm::__private::serde::Deserialize<'de> for #ident {
+            fn deserialize<D>(deserializer: D) -> ::core::result::Result<Self, D::Error>
+            where
+                D: ::opa_wasm::__private::serde::Deserializer<'de>,
+            {
+                struct Visitor;
+
+                impl<'de> ::opa_wasm::__private::serde::de::Visitor<'de> for Visitor {
+                    type Value = #ident;
+
+                    fn expecting(
+                        &self,
+                        formatter: &mut ::core::fmt::Formatter<'_>,
+                    ) -> ::core::fmt::Result {
+                        formatter.write_str(#expecting)
+                    }
+
+                    fn visit_map<A>(self, mut map: A) -> ::core::result::Result<Self::Value, A::Error>
+                    where
+                        A: ::opa_wasm::__private::serde::de::MapAccess<'de>,
+                    {
+                        #( let mut #vars: ::core::option::Option<#types> = ::core::option::Option::None; )*
+                        while let ::core::option::Option::Some(key) =
+                            map.next_key::<::std::string::String>()?
+                        {
+                            match key.as_str() {
+                                #( #names => #vars = ::core::option::Option::Some(map.next_value()?), )*
+                                _ => {
+                                    map.next_value::<::opa_wasm::__private::serde::de::IgnoredAny>()?;
+                                }
+                            }
+                        }
+
+                        ::core::result::Result::Ok(#ident { #( #values, )* })
+                    }
+                }
+
+                deserializer.deserialize_map(Visitor)
+            }
+        }
+    })
+}
+
+/// Derive `opa_wasm::PolicyInput` and `serde::Serialize`, tying the struct to
+/// the entrypoint set with `#[policy(entrypoint = "...")]`, whose result is
+/// the type set with `#[policy(output = ...)]`, a JSON value by default.
+///
+/// Fields keep their Rust name, without the `r#` prefix of raw identifiers,
+/// unless renamed with `#[policy(rename_all = "camelCase")]` on the struct or
+/// `#[policy(rename = "...")]` on the field. Fields marked
+/// `#[policy(skip)]` are left out.
+#[proc_macro_derive(PolicyInput, attributes(policy))]
+pub fn derive_policy_input(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_input(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Derive `opa_wasm::PolicyOutput` and `serde::Deserialize`, with the same
+/// field naming rules as `PolicyInput`.
+///
+/// Missing `Option` fields are `None`, fields marked `#[policy(skip)]` take
+/// their default value, and unknown fields are ignored.
+#[proc_macro_derive(PolicyOutput, attributes(policy))]
+pub fn derive_policy_output(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_output(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
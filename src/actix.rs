@@ -0,0 +1,277 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An actix-web middleware authorizing requests with a policy, a guard
+//! routing on its decision, and an extractor exposing it to the handlers
+
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    guard::{Guard, GuardContext},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+
+use crate::{EvaluationContext, HttpInput, Identity, PolicyDecision, PolicyPool, PolicyService};
+
+impl HttpInput {
+    /// Build the input from an actix-web request
+    #[must_use]
+    pub fn from_actix(request: &HttpRequest) -> Self {
+        let headers = request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes()));
+
+        Self::from_request(
+            request.method().as_str(),
+            request.path(),
+            Some(request.query_string()).filter(|query| !query.is_empty()),
+            headers,
+            request.extensions().get::<Identity>(),
+        )
+    }
+}
+
+impl FromRequest for PolicyDecision {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let decision = request.extensions().get::<Self>().cloned();
+        ready(decision.ok_or_else(|| {
+            actix_web::error::ErrorInternalServerError(
+                "the route is not behind an ActixAuthorization middleware",
+            )
+        }))
+    }
+}
+
+/// A guard matching the requests the [`ActixAuthorization`] middleware
+/// allowed.
+///
+/// Guards are synchronous, so this one does not evaluate the policy: it reads
+/// the decision the middleware stored in the request. Use it with
+/// [`ActixAuthorization::reject_denied`] set to `false`, to route denied
+/// requests to another handler instead of rejecting them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PolicyGuard;
+
+impl Guard for PolicyGuard {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        ctx.req_data()
+            .get::<PolicyDecision>()
+            .is_some_and(PolicyDecision::is_allowed)
+    }
+}
+
+/// An actix-web middleware authorizing requests by evaluating an entrypoint
+/// with the request as [input](HttpInput), mirroring the axum
+/// [`AuthorizationLayer`](crate::AuthorizationLayer).
+///
+/// Denied requests get a `403 Forbidden` response, and requests failing to
+/// evaluate a `500 Internal Server Error` one. Allowed requests are passed to
+/// the inner service, with the [`PolicyDecision`] in their extensions.
+pub struct ActixAuthorization<C, T = ()> {
+    /// The service evaluating the policy
+    service: PolicyService<HttpInput, serde_json::Value, C, T>,
+
+    /// Whether denied requests are rejected
+    reject_denied: bool,
+}
+
+impl<C, T> Clone for ActixAuthorization<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            reject_denied: self.reject_denied,
+        }
+    }
+}
+
+impl<C, T> std::fmt::Debug for ActixAuthorization<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActixAuthorization")
+            .field("service", &self.service)
+            .field("reject_denied", &self.reject_denied)
+            .finish()
+    }
+}
+
+impl<C, T> ActixAuthorization<C, T> {
+    /// Authorize requests by evaluating the given entrypoint on the instances
+    /// of the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>, entrypoint: impl Into<Arc<str>>) -> Self {
+        Self {
+            service: PolicyService::new(pool, entrypoint),
+            reject_denied: true,
+        }
+    }
+
+    /// Whether denied requests get a `403 Forbidden` response. When `false`,
+    /// they are passed to the inner service with their decision, for a
+    /// [`PolicyGuard`] or the handlers to act on. Defaults to `true`.
+    #[must_use]
+    pub fn reject_denied(mut self, reject_denied: bool) -> Self {
+        self.reject_denied = reject_denied;
+        self
+    }
+}
+
+impl<S, B, C, T> Transform<S, ServiceRequest> for ActixAuthorization<C, T>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ActixAuthorizationMiddleware<S, C, T>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, inner: S) -> Self::Future {
+        ready(Ok(ActixAuthorizationMiddleware {
+            inner: Rc::new(inner),
+            service: self.service.clone(),
+            reject_denied: self.reject_denied,
+        }))
+    }
+}
+
+/// The service created by the [`ActixAuthorization`] middleware
+pub struct ActixAuthorizationMiddleware<S, C, T = ()> {
+    /// The service handling allowed requests
+    inner: Rc<S>,
+
+    /// The service evaluating the policy
+    service: PolicyService<HttpInput, serde_json::Value, C, T>,
+
+    /// Whether denied requests are rejected
+    reject_denied: bool,
+}
+
+impl<S, C, T> std::fmt::Debug for ActixAuthorizationMiddleware<S, C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActixAuthorizationMiddleware")
+            .field("service", &self.service)
+            .field("reject_denied", &self.reject_denied)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, B, C, T> Service<ServiceRequest> for ActixAuthorizationMiddleware<S, C, T>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(inner);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let inner = Rc::clone(&self.inner);
+        let mut service = self.service.clone();
+        let reject_denied = self.reject_denied;
+
+        Box::pin(async move {
+            let input = HttpInput::from_actix(request.request());
+
+            let decision = match tower_service::Service::call(&mut service, input).await {
+                Ok(decision) => {
+                    PolicyDecision::from_result_set(decision.entrypoint, &decision.result)
+                }
+                Err(error) => {
+                    tracing::error!(
+                        entrypoint = service.entrypoint(),
+                        "could not evaluate the policy: {error:#}"
+                    );
+                    let response = HttpResponse::InternalServerError().finish();
+                    return Ok(request.into_response(response).map_into_right_body());
+                }
+            };
+
+            if reject_denied && !decision.is_allowed() {
+                let response = HttpResponse::Forbidden().finish();
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+
+            request.extensions_mut().insert(decision);
+            let response = inner.call(request).await?;
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::Method, test::TestRequest};
+
+    use super::*;
+
+    #[test]
+    fn requests_are_mapped_to_inputs() {
+        let request = TestRequest::default()
+            .method(Method::POST)
+            .uri("https://example.com/v1/users/?limit=10")
+            .insert_header(("Accept", "application/json"))
+            .append_header(("x-forwarded-for", "10.0.0.1"))
+            .append_header(("x-forwarded-for", "10.0.0.2"))
+            .to_http_request();
+        request
+            .extensions_mut()
+            .insert(Identity(serde_json::json!({ "sub": "alice" })));
+
+        let input = serde_json::to_value(HttpInput::from_actix(&request)).unwrap();
+        assert_eq!(
+            input,
+            serde_json::json!({
+                "method": "POST",
+                "path": ["v1", "users"],
+                "query": "limit=10",
+                "headers": {
+                    "accept": "application/json",
+                    "x-forwarded-for": "10.0.0.1, 10.0.0.2",
+                },
+                "identity": { "sub": "alice" },
+            })
+        );
+    }
+
+    #[test]
+    fn guards_follow_the_decision() {
+        let request = TestRequest::default().to_srv_request();
+        assert!(!PolicyGuard.check(&request.guard_ctx()));
+
+        request
+            .extensions_mut()
+            .insert(PolicyDecision::from_result_set(
+                "app/allow".into(),
+                &serde_json::json!([{ "result": true }]),
+            ));
+        assert!(PolicyGuard.check(&request.guard_ctx()));
+    }
+}
@@ -0,0 +1,629 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A high-level facade, loading a policy, keeping it up to date and
+//! evaluating it on a pool of instances, with decision logs and metrics
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::Mutex;
+use wasmtime::{Engine, Module, Store};
+
+#[cfg(feature = "compilation-cache")]
+use crate::CompilationCache;
+#[cfg(feature = "decision-logs")]
+use crate::DecisionLogger;
+#[cfg(feature = "prometheus")]
+use crate::PrometheusMetrics;
+use crate::{DefaultContext, EngineConfig, EvaluationContext, PolicyPool};
+
+/// A policy module and the revision of the bundle it came from
+struct Loaded {
+    /// The WASM module
+    wasm: Vec<u8>,
+
+    /// The revision of the bundle, if any
+    revision: Option<String>,
+}
+
+/// Where the agent loads the policy from
+enum Source {
+    /// A WASM module, loaded once
+    Module(Option<Vec<u8>>),
+
+    /// A bundle on disk, read again on each reload
+    #[cfg(feature = "loader")]
+    File {
+        /// The path of the bundle
+        path: std::path::PathBuf,
+
+        /// The module loaded last, to only reload when it changes
+        last: Option<(Vec<u8>, Option<String>)>,
+    },
+
+    /// A bundle served over HTTP
+    #[cfg(all(feature = "loader", feature = "http-client"))]
+    Url(crate::BundleFetcher),
+}
+
+impl Source {
+    /// Get the policy, or `None` if it did not change since the last call
+    #[cfg_attr(not(feature = "loader"), allow(clippy::unused_async))]
+    async fn next(&mut self) -> Result<Option<Loaded>> {
+        match self {
+            Self::Module(wasm) => Ok(wasm.take().map(|wasm| Loaded {
+                wasm,
+                revision: None,
+            })),
+
+            #[cfg(feature = "loader")]
+            Self::File { path, last } => {
+                let bundle = crate::Bundle::read(&*path).await?;
+                let revision = bundle.manifest.and_then(|manifest| manifest.revision);
+                let current = Some((bundle.policy, revision));
+                if *last == current {
+                    return Ok(None);
+                }
+
+                last.clone_from(&current);
+                Ok(current.map(|(wasm, revision)| Loaded { wasm, revision }))
+            }
+
+            #[cfg(all(feature = "loader", feature = "http-client"))]
+            Self::Url(fetcher) => {
+                let bundle = fetcher.fetch().await?;
+                Ok(bundle.map(|bundle| Loaded {
+                    wasm: bundle.policy,
+                    revision: bundle.manifest.and_then(|manifest| manifest.revision),
+                }))
+            }
+        }
+    }
+}
+
+/// The instances of the policy currently evaluated
+struct Active<C> {
+    /// The pool of instances
+    pool: Arc<PolicyPool<C>>,
+
+    /// The revision of the bundle they were loaded from
+    revision: Option<String>,
+}
+
+impl<C> Clone for Active<C> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: Arc::clone(&self.pool),
+            revision: self.revision.clone(),
+        }
+    }
+}
+
+/// Builds a [`PolicyAgent`]
+#[must_use]
+pub struct PolicyAgentBuilder<C = DefaultContext> {
+    /// Where the policy is loaded from
+    source: Option<Source>,
+
+    /// The data document
+    data: serde_json::Value,
+
+    /// The number of instances evaluating the policy
+    instances: NonZeroUsize,
+
+    /// The configuration of the engine
+    engine: EngineConfig,
+
+    /// Creates the evaluation context of each instance
+    context: Arc<dyn Fn() -> C + Send + Sync>,
+
+    /// The cache of compiled modules
+    #[cfg(feature = "compilation-cache")]
+    cache: Option<CompilationCache>,
+
+    /// The logger the decisions go through
+    #[cfg(feature = "decision-logs")]
+    logger: Option<DecisionLogger>,
+
+    /// The metrics recording the bundle activations
+    #[cfg(feature = "prometheus")]
+    metrics: Option<PrometheusMetrics>,
+}
+
+impl<C> std::fmt::Debug for PolicyAgentBuilder<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyAgentBuilder")
+            .field("instances", &self.instances)
+            .field("engine", &self.engine)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PolicyAgentBuilder {
+    /// Create a builder with a single instance, an empty data document and
+    /// the default evaluation context
+    fn new() -> Self {
+        Self {
+            source: None,
+            data: serde_json::Value::Object(serde_json::Map::new()),
+            instances: NonZeroUsize::MIN,
+            engine: EngineConfig::new(),
+            context: Arc::new(DefaultContext::default),
+
+            #[cfg(feature = "compilation-cache")]
+            cache: None,
+
+            #[cfg(feature = "decision-logs")]
+            logger: None,
+
+            #[cfg(feature = "prometheus")]
+            metrics: None,
+        }
+    }
+}
+
+impl<C> PolicyAgentBuilder<C> {
+    /// Evaluate the given WASM module, which is never reloaded
+    pub fn module(mut self, wasm: impl Into<Vec<u8>>) -> Self {
+        self.source = Some(Source::Module(Some(wasm.into())));
+        self
+    }
+
+    /// Evaluate the policy of the bundle at the given path, which is read
+    /// again on [reloads](PolicyAgent::reload)
+    #[cfg(feature = "loader")]
+    pub fn bundle_file(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.source = Some(Source::File {
+            path: path.into(),
+            last: None,
+        });
+        self
+    }
+
+    /// Evaluate the policy of the bundle downloaded by the fetcher, which is
+    /// downloaded again on [reloads](PolicyAgent::reload)
+    #[cfg(all(feature = "loader", feature = "http-client"))]
+    pub fn bundle_fetcher(mut self, fetcher: crate::BundleFetcher) -> Self {
+        self.source = Some(Source::Url(fetcher));
+        self
+    }
+
+    /// Load the given data document in the policy. Defaults to an empty
+    /// object.
+    pub fn data(mut self, data: serde_json::Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    /// Evaluate the policy on the given number of instances, which bounds how
+    /// many evaluations run at the same time. Defaults to one.
+    pub fn instances(mut self, instances: NonZeroUsize) -> Self {
+        self.instances = instances;
+        self
+    }
+
+    /// Configure the engine compiling and running the policy
+    pub fn engine_config(mut self, config: EngineConfig) -> Self {
+        self.engine = config;
+        self
+    }
+
+    /// Cache the compiled modules, so that restarts and reloads of the same
+    /// module skip the compilation
+    #[cfg(feature = "compilation-cache")]
+    pub fn compilation_cache(mut self, cache: CompilationCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Log each decision through the given logger
+    #[cfg(feature = "decision-logs")]
+    pub fn decision_logger(mut self, logger: DecisionLogger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Record the bundle activations in the given metrics. The evaluations
+    /// are recorded by a [`PrometheusLayer`](crate::PrometheusLayer) context
+    /// sharing them.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(mut self, metrics: PrometheusMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Create the evaluation context of each instance with the given
+    /// function, for example to add layers to a [`DefaultContext`]
+    pub fn context<C2>(
+        self,
+        context: impl Fn() -> C2 + Send + Sync + 'static,
+    ) -> PolicyAgentBuilder<C2> {
+        PolicyAgentBuilder {
+            source: self.source,
+            data: self.data,
+            instances: self.instances,
+            engine: self.engine,
+            context: Arc::new(context),
+
+            #[cfg(feature = "compilation-cache")]
+            cache: self.cache,
+
+            #[cfg(feature = "decision-logs")]
+            logger: self.logger,
+
+            #[cfg(feature = "prometheus")]
+            metrics: self.metrics,
+        }
+    }
+
+    /// Load the policy and instantiate it
+    ///
+    /// # Errors
+    ///
+    /// If no policy was given, or if it could not be loaded, compiled or
+    /// instantiated
+    pub async fn build(self) -> Result<PolicyAgent<C>>
+    where
+        C: EvaluationContext,
+    {
+        let mut source = self.source.context("no policy to evaluate was given")?;
+        let loaded = source.next().await?.context("could not load the policy")?;
+
+        let mut agent = PolicyAgent {
+            engine: self.engine.build()?,
+            engine_config: self.engine,
+            source: Mutex::new(source),
+            active: RwLock::new(None),
+            data: self.data,
+            instances: self.instances,
+            context: self.context,
+
+            #[cfg(feature = "compilation-cache")]
+            cache: self.cache,
+
+            #[cfg(feature = "decision-logs")]
+            logger: self.logger,
+
+            #[cfg(feature = "prometheus")]
+            metrics: self.metrics,
+        };
+
+        let active = agent.instantiate(loaded).await?;
+        *agent
+            .active
+            .get_mut()
+            .map_err(|_| anyhow::anyhow!("policy agent lock poisoned"))? = Some(active);
+        Ok(agent)
+    }
+}
+
+/// Loads a policy, keeps it up to date and evaluates it on a pool of
+/// instances, so that applications only have to call
+/// [`decide`](Self::decide).
+///
+/// The policy comes from a WASM module, or from a bundle on disk or served
+/// over HTTP which is polled for new revisions. Depending on the features,
+/// the compiled modules go through a [`CompilationCache`], the decisions are
+/// logged by a [`DecisionLogger`], and the bundle activations are recorded in
+/// [`PrometheusMetrics`].
+///
+/// [`CompilationCache`]: crate::CompilationCache
+/// [`DecisionLogger`]: crate::DecisionLogger
+/// [`PrometheusMetrics`]: crate::PrometheusMetrics
+pub struct PolicyAgent<C = DefaultContext> {
+    /// The engine compiling and running the policy
+    engine: Engine,
+
+    /// The configuration the engine was built from, which the stores of the
+    /// instances are set up with
+    engine_config: EngineConfig,
+
+    /// Where the policy is loaded from
+    source: Mutex<Source>,
+
+    /// The instances currently evaluating the policy, only `None` while the
+    /// agent is being built
+    active: RwLock<Option<Active<C>>>,
+
+    /// The data document
+    data: serde_json::Value,
+
+    /// The number of instances evaluating the policy
+    instances: NonZeroUsize,
+
+    /// Creates the evaluation context of each instance
+    context: Arc<dyn Fn() -> C + Send + Sync>,
+
+    /// The cache of compiled modules
+    #[cfg(feature = "compilation-cache")]
+    cache: Option<CompilationCache>,
+
+    /// The logger the decisions go through
+    #[cfg(feature = "decision-logs")]
+    logger: Option<DecisionLogger>,
+
+    /// The metrics recording the bundle activations
+    #[cfg(feature = "prometheus")]
+    metrics: Option<PrometheusMetrics>,
+}
+
+impl<C> std::fmt::Debug for PolicyAgent<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyAgent")
+            .field("instances", &self.instances)
+            .field("revision", &self.revision())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PolicyAgent {
+    /// Create a [`PolicyAgentBuilder`] to configure the agent
+    pub fn builder() -> PolicyAgentBuilder {
+        PolicyAgentBuilder::new()
+    }
+}
+
+impl<C> PolicyAgent<C> {
+    /// Get the instances currently evaluating the policy
+    fn active(&self) -> Result<Active<C>> {
+        self.active
+            .read()
+            .map_err(|_| anyhow::anyhow!("policy agent lock poisoned"))?
+            .clone()
+            .context("the policy agent is not built")
+    }
+
+    /// The revision of the bundle currently evaluated, if any
+    #[must_use]
+    pub fn revision(&self) -> Option<String> {
+        self.active().ok().and_then(|active| active.revision)
+    }
+
+    /// Compile and instantiate a policy
+    async fn instantiate(&self, loaded: Loaded) -> Result<Active<C>>
+    where
+        C: EvaluationContext,
+    {
+        #[cfg(feature = "compilation-cache")]
+        let module = match &self.cache {
+            Some(cache) => cache.module(&self.engine, &loaded.wasm)?,
+            None => Module::new(&self.engine, &loaded.wasm)?,
+        };
+        #[cfg(not(feature = "compilation-cache"))]
+        let module = Module::new(&self.engine, &loaded.wasm)?;
+
+        let store = || {
+            let mut store = Store::new(&self.engine, ());
+            self.engine_config.configure_store(&mut store);
+            store
+        };
+        let pool = PolicyPool::instantiate_revision(
+            &module,
+            &self.data,
+            self.instances,
+            loaded.revision.as_deref(),
+            store,
+            &*self.context,
+        )
+        .await?;
+
+        #[cfg(feature = "decision-logs")]
+        let pool = match &self.logger {
+            Some(logger) => pool.decision_logger(logger.clone()),
+            None => pool,
+        };
+
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_bundle_activation(loaded.revision.as_deref());
+        }
+
+        Ok(Active {
+            pool: Arc::new(pool),
+            revision: loaded.revision,
+        })
+    }
+
+    /// Load the policy again, and swap the instances if it changed.
+    /// Evaluations running during the swap finish on the previous instances.
+    ///
+    /// Returns whether a new policy was activated.
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be loaded or instantiated, in which case the
+    /// previous one is still evaluated
+    pub async fn reload(&self) -> Result<bool>
+    where
+        C: EvaluationContext,
+    {
+        let mut source = self.source.lock().await;
+        let Some(loaded) = source.next().await? else {
+            return Ok(false);
+        };
+
+        let active = self.instantiate(loaded).await?;
+        tracing::info!(revision = active.revision, "activated a new policy");
+        *self
+            .active
+            .write()
+            .map_err(|_| anyhow::anyhow!("policy agent lock poisoned"))? = Some(active);
+        Ok(true)
+    }
+
+    /// Reload the policy at the given interval, logging the failures. This
+    /// never returns, and is meant to be spawned on the executor.
+    #[cfg(feature = "loader")]
+    pub async fn poll(&self, interval: std::time::Duration)
+    where
+        C: EvaluationContext,
+    {
+        loop {
+            crate::rt::sleep(interval).await;
+            if let Err(error) = self.reload().await {
+                tracing::warn!("could not reload the policy: {error:#}");
+            }
+        }
+    }
+
+    /// Evaluate the entrypoint with the given input, on the next available
+    /// instance, and log the decision
+    ///
+    /// # Errors
+    ///
+    /// If the evaluation failed
+    pub async fn decide<V, R>(&self, entrypoint: &str, input: &V) -> Result<R>
+    where
+        V: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de>,
+        C: EvaluationContext,
+    {
+        self.active()?.pool.evaluate(entrypoint, input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stub::stub_wasm;
+
+    #[tokio::test]
+    async fn policies_are_required() {
+        let error = PolicyAgent::builder().build().await.unwrap_err();
+        assert_eq!(error.to_string(), "no policy to evaluate was given");
+
+        // An empty module, which is not an OPA policy
+        let error = PolicyAgent::builder()
+            .module(b"\0asm\x01\0\0\0".to_vec())
+            .build()
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "missing global opa_wasm_abi_version");
+    }
+
+    #[tokio::test]
+    async fn yielding_policies_are_evaluated() {
+        let wasm = stub_wasm(
+            "{}",
+            r#"{"allow":0}"#,
+            r#"(data (i32.const 1024) "[{\"result\":true}]\00")"#,
+            "i32.const 1024
+             global.set $result",
+        );
+        let agent = PolicyAgent::builder()
+            .module(wasm)
+            .instances(NonZeroUsize::new(2).unwrap())
+            .engine_config(EngineConfig::new().yield_interval(Duration::from_millis(1)))
+            .build()
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let result: serde_json::Value = agent.decide("allow", &()).await.unwrap();
+            assert_eq!(result, serde_json::json!([{ "result": true }]));
+        }
+    }
+
+    #[cfg(all(feature = "loader", feature = "decision-logs"))]
+    #[tokio::test]
+    async fn polled_bundles_are_evaluated_and_logged() {
+        use std::sync::Mutex;
+
+        /// Build a bundle whose `allow` entrypoint returns `result`
+        async fn bundle(revision: &str, result: &str) -> Vec<u8> {
+            let wasm = stub_wasm(
+                "{}",
+                r#"{"allow":0}"#,
+                &format!(r#"(data (i32.const 1024) "[{{\"result\":{result}}}]\00")"#),
+                "i32.const 1024
+                 global.set $result",
+            );
+            crate::loader::tests::build_bundle(revision, &wasm).await
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("opa-wasm-agent-{}.tar.gz", std::process::id()));
+        std::fs::write(&path, bundle("1", "true").await).unwrap();
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let logger = DecisionLogger::new().with_sink({
+            let entries = Arc::clone(&entries);
+            move |entry: &crate::DecisionLogEntry| entries.lock().unwrap().push(entry.clone())
+        });
+        let agent = Arc::new(
+            PolicyAgent::builder()
+                .bundle_file(&path)
+                .decision_logger(logger)
+                .build()
+                .await
+                .unwrap(),
+        );
+        let result: serde_json::Value = agent.decide("allow", &()).await.unwrap();
+        assert_eq!(result, serde_json::json!([{ "result": true }]));
+
+        // The poller picks up the new revision of the bundle
+        let poller = tokio::spawn({
+            let agent = Arc::clone(&agent);
+            async move { agent.poll(Duration::from_millis(10)).await }
+        });
+        std::fs::write(&path, bundle("2", "false").await).unwrap();
+        while agent.revision().as_deref() != Some("2") {
+            crate::rt::sleep(Duration::from_millis(10)).await;
+        }
+        poller.abort();
+        std::fs::remove_file(&path).unwrap();
+
+        let result: serde_json::Value = agent.decide("allow", &()).await.unwrap();
+        assert_eq!(result, serde_json::json!([{ "result": false }]));
+
+        // Both decisions were logged, with the revision they were made with
+        let entries = entries.lock().unwrap();
+        let decisions: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.path.as_str(),
+                    entry.revision.as_deref(),
+                    &entry.result,
+                )
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                ("allow", Some("1"), &Some(serde_json::Value::Bool(true))),
+                ("allow", Some("2"), &Some(serde_json::Value::Bool(false))),
+            ]
+        );
+    }
+
+    #[test]
+    fn decisions_are_send() {
+        /// Check that a future can be spawned on a multi-threaded executor
+        fn assert_send<F: std::future::Future + Send>(_: F) {}
+
+        fn check(agent: &PolicyAgent) {
+            assert_send(agent.decide::<_, serde_json::Value>("allow", &()));
+            assert_send(agent.reload());
+        }
+
+        let _ = check;
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The plumbing shared by the engine backends other than the wasmtime one:
+//! the builtins, and the evaluation of policies on top of the few operations
+//! each backend implements with its engine
+
+// Components call builtins by name, and only need `call`
+#![cfg_attr(not(any(feature = "wasmi", feature = "wasmer")), allow(dead_code))]
+
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use anyhow::{Context, Result};
+use tracing::Instrument;
+
+use crate::{
+    builtins::{traits::Builtin, BuiltinRegistry, HaltError},
+    failure::EvaluationFailure,
+    AbiVersion, EvaluationContext, EvaluationId, EvaluationMetadata, EvaluationOutcome,
+    MemoryUsage,
+};
+
+/// The size of a WASM memory page
+pub(crate) const PAGE_SIZE: usize = 64 * 1024;
+
+/// The builtins used by a policy with their name, by ID
+pub(crate) type Builtins<C> = HashMap<i32, (String, Arc<dyn Builtin<C>>)>;
+
+/// Resolve the builtins a policy uses, as listed by its `builtins` export
+pub(crate) fn resolve_builtins<C: EvaluationContext>(
+    registry: &BuiltinRegistry<C>,
+    builtins: HashMap<String, i32>,
+) -> Result<Builtins<C>> {
+    builtins
+        .into_iter()
+        .map(|(name, id)| {
+            let builtin = registry
+                .resolve(&name)
+                .with_context(|| format!("could not resolve {name}"))?;
+            Ok((id, (name, builtin)))
+        })
+        .collect()
+}
+
+/// Call a builtin with the JSON value of its arguments, recording the call in
+/// the context, and return the JSON value of its result
+pub(crate) async fn call_builtin<C: EvaluationContext>(
+    builtins: &Builtins<C>,
+    context: &mut C,
+    id: i32,
+    args: &[&[u8]],
+) -> Result<Vec<u8>> {
+    let (name, builtin) = builtins
+        .get(&id)
+        .with_context(|| format!("unknown builtin id {id}"))?;
+    call(&**builtin, name, context, args).await
+}
+
+/// Call a builtin with the JSON value of its arguments, recording the call in
+/// the context under the given name, and return the JSON value of its result
+pub(crate) async fn call<C: EvaluationContext>(
+    builtin: &dyn Builtin<C>,
+    name: &str,
+    context: &mut C,
+    args: &[&[u8]],
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let start = Instant::now();
+    let ret = builtin.call_into(context, args, &mut buffer).await;
+    context.record_builtin_duration(name, start.elapsed());
+    context.record_builtin_call(name, args, ret.as_ref().map(|()| &buffer[..]));
+    ret?;
+
+    Ok(buffer)
+}
+
+/// The state the builtins of a policy need, owned by its instance
+pub(crate) struct Host<C> {
+    /// The builtins used by the policy, by ID
+    pub(crate) builtins: Builtins<C>,
+
+    /// The evaluation context passed to builtins
+    pub(crate) context: C,
+
+    /// Whether builtin errors fail the evaluation, instead of making the
+    /// expression undefined
+    pub(crate) strict_builtin_errors: bool,
+}
+
+impl<C: EvaluationContext> Host<C> {
+    /// Create the state with no builtins resolved yet
+    pub(crate) fn new(context: C) -> Self {
+        Self {
+            builtins: HashMap::new(),
+            context,
+            strict_builtin_errors: false,
+        }
+    }
+
+    /// Call a builtin like [`call_builtin`] does, returning `None` if the
+    /// expression calling it is undefined because it failed
+    async fn call_builtin(&mut self, id: i32, args: &[&[u8]]) -> Result<Option<Vec<u8>>> {
+        let ret = call_builtin(&self.builtins, &mut self.context, id, args).await;
+
+        // A builtin running out of time or halting still fails the evaluation
+        let timed_out = self
+            .context
+            .remaining_budget()
+            .is_some_and(|budget| budget.is_zero());
+
+        match ret {
+            Ok(result) => Ok(Some(result)),
+            Err(error) if self.strict_builtin_errors || timed_out || HaltError::is_halt(&error) => {
+                Err(error)
+            }
+            Err(error) => {
+                // Like OPA without `--strict-builtin-errors`, the expression
+                // calling the builtin is undefined, which the module knows
+                // from the null address
+                tracing::debug!(
+                    builtin = id,
+                    "builtin failed, the expression is undefined: {error:#}"
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// The functions exported by policy modules
+#[derive(Debug, Clone)]
+pub(crate) struct Exports<F> {
+    /// `eval`, evaluating an entrypoint with an evaluation context
+    pub(crate) eval: F,
+
+    /// `builtins`, returning the builtins used by the policy
+    pub(crate) builtins: F,
+
+    /// `entrypoints`, returning the entrypoints of the policy
+    pub(crate) entrypoints: F,
+
+    /// `opa_eval_ctx_new`, creating an evaluation context
+    pub(crate) ctx_new: F,
+
+    /// `opa_eval_ctx_set_input`, setting the input of an evaluation context
+    pub(crate) ctx_set_input: F,
+
+    /// `opa_eval_ctx_set_data`, setting the data of an evaluation context
+    pub(crate) ctx_set_data: F,
+
+    /// `opa_eval_ctx_set_entrypoint`, setting the entrypoint of an evaluation
+    /// context
+    pub(crate) ctx_set_entrypoint: F,
+
+    /// `opa_eval_ctx_get_result`, getting the result of an evaluation context
+    pub(crate) ctx_get_result: F,
+
+    /// `opa_malloc`, allocating memory on the heap
+    pub(crate) malloc: F,
+
+    /// `opa_free`, freeing memory on the heap
+    pub(crate) free: F,
+
+    /// `opa_json_parse`, parsing a JSON value into a value
+    pub(crate) json_parse: F,
+
+    /// `opa_json_dump`, dumping a value as JSON
+    pub(crate) json_dump: F,
+
+    /// `opa_heap_ptr_set`, setting the heap pointer
+    pub(crate) heap_ptr_set: F,
+
+    /// `opa_heap_ptr_get`, getting the heap pointer
+    pub(crate) heap_ptr_get: F,
+
+    /// `opa_eval`, the one-call evaluation available since ABI 1.2
+    pub(crate) opa_eval: Option<F>,
+}
+
+impl<F> Exports<F> {
+    /// Look the exports up, with `get` returning the function exported under
+    /// a name, after checking that it takes and returns the given numbers of
+    /// `i32`
+    pub(crate) fn lookup(
+        version: AbiVersion,
+        mut get: impl FnMut(&str, usize, usize) -> Result<F>,
+    ) -> Result<Self> {
+        Ok(Self {
+            eval: get("eval", 1, 1)?,
+            builtins: get("builtins", 0, 1)?,
+            entrypoints: get("entrypoints", 0, 1)?,
+            ctx_new: get("opa_eval_ctx_new", 0, 1)?,
+            ctx_set_input: get("opa_eval_ctx_set_input", 2, 0)?,
+            ctx_set_data: get("opa_eval_ctx_set_data", 2, 0)?,
+            ctx_set_entrypoint: get("opa_eval_ctx_set_entrypoint", 2, 0)?,
+            ctx_get_result: get("opa_eval_ctx_get_result", 1, 1)?,
+            malloc: get("opa_malloc", 1, 1)?,
+            free: get("opa_free", 1, 0)?,
+            json_parse: get("opa_json_parse", 2, 1)?,
+            json_dump: get("opa_json_dump", 1, 1)?,
+            heap_ptr_set: get("opa_heap_ptr_set", 1, 0)?,
+            heap_ptr_get: get("opa_heap_ptr_get", 0, 1)?,
+            opa_eval: if version.has_eval_fastpath() {
+                Some(get("opa_eval", 7, 1)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// An instance of a policy module in one of the backends, with the few
+/// operations the evaluation needs from the engine
+pub(crate) trait Backend {
+    /// The evaluation context passed to builtins
+    type Context: EvaluationContext;
+
+    /// A function exported by the module
+    type Func: Clone;
+
+    /// The functions exported by the module
+    fn exports(&self) -> Result<&Exports<Self::Func>>;
+
+    /// The state the builtins need
+    fn host(&mut self) -> &mut Host<Self::Context>;
+
+    /// Read the nul-terminated string at `addr` from the memory
+    fn read_c_str(&mut self, addr: i32) -> Result<Vec<u8>>;
+
+    /// Write bytes to the memory, at `offset`
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<()>;
+
+    /// Copy the whole memory
+    fn read_memory(&mut self) -> Result<Vec<u8>>;
+
+    /// The size of the memory, in bytes
+    fn memory_size(&mut self) -> usize;
+
+    /// Grow the memory by `pages` pages
+    fn grow_memory(&mut self, pages: u32) -> Result<()>;
+
+    /// Call a function which does not call into the host, returning its
+    /// result, or 0 if it has none
+    fn call(&mut self, func: &Self::Func, params: &[i32]) -> Result<i32>;
+
+    /// Call a function evaluating the policy, calling the builtins it needs,
+    /// and return its result
+    async fn eval(&mut self, func: &Self::Func, params: &[i32]) -> Result<i32>;
+
+    /// Call one of the exports
+    fn call_export(
+        &mut self,
+        export: impl FnOnce(&Exports<Self::Func>) -> &Self::Func,
+        params: &[i32],
+    ) -> Result<i32> {
+        let func = export(self.exports()?).clone();
+        self.call(&func, params)
+    }
+
+    /// Parse a JSON value in the module memory
+    fn load_json(&mut self, json: &[u8]) -> Result<i32> {
+        let len = i32::try_from(json.len()).context("value too large")?;
+        let addr = self.call_export(|exports| &exports.malloc, &[len])?;
+        self.write(usize::try_from(addr).context("invalid address")?, json)?;
+        let value = self.call_export(|exports| &exports.json_parse, &[addr, len])?;
+        self.call_export(|exports| &exports.free, &[addr])?;
+        anyhow::ensure!(value != 0, "could not parse the JSON value");
+        Ok(value)
+    }
+
+    /// Dump a value as JSON
+    fn dump_json(&mut self, value: i32) -> Result<Vec<u8>> {
+        let addr = self.call_export(|exports| &exports.json_dump, &[value])?;
+        self.read_c_str(addr)
+    }
+
+    /// Dump a value as JSON and deserialize it
+    fn dump<V: serde::de::DeserializeOwned>(&mut self, value: i32) -> Result<V> {
+        Ok(serde_json::from_slice(&self.dump_json(value)?)?)
+    }
+
+    /// Grow the memory so that it is at least `len` bytes long
+    fn reserve_memory(&mut self, len: usize) -> Result<()> {
+        let pages = u32::try_from(len.div_ceil(PAGE_SIZE)).context("memory too large")?;
+        let current = u32::try_from(self.memory_size() / PAGE_SIZE).context("memory too large")?;
+        if pages > current {
+            self.grow_memory(pages - current)?;
+        }
+        Ok(())
+    }
+
+    /// Call a builtin with the values at the given addresses, returning the
+    /// address of its result, which is null if it is undefined
+    async fn call_builtin(&mut self, id: i32, args: &[i32]) -> Result<i32> {
+        let mut args_json = Vec::with_capacity(args.len());
+        for &arg in args {
+            args_json.push(self.dump_json(arg)?);
+        }
+        let args: Vec<&[u8]> = args_json.iter().map(Vec::as_slice).collect();
+
+        match self.host().call_builtin(id, &args).await? {
+            Some(result) => self.load_json(&result),
+            None => Ok(0),
+        }
+    }
+}
+
+/// What the backends know about a policy module once it is instantiated
+#[derive(Debug, Clone)]
+pub(crate) struct Module {
+    /// The ABI version of the module
+    pub(crate) version: AbiVersion,
+
+    /// The entrypoints of the policy, with their ID
+    pub(crate) entrypoints: HashMap<String, i32>,
+
+    /// The revision of the bundle the module was loaded from
+    pub(crate) revision: Option<String>,
+
+    /// Whether to restore the memory before each evaluation
+    pub(crate) memory_snapshot: bool,
+}
+
+impl Module {
+    /// Read the builtins and the entrypoints of the module, resolving the
+    /// builtins from the registry
+    pub(crate) fn inspect<B: Backend>(
+        backend: &mut B,
+        version: AbiVersion,
+        registry: &BuiltinRegistry<B::Context>,
+    ) -> Result<Self> {
+        let builtins = backend.call_export(|exports| &exports.builtins, &[])?;
+        let builtins = backend.dump(builtins)?;
+        backend.host().builtins = resolve_builtins(registry, builtins)?;
+
+        let entrypoints = backend.call_export(|exports| &exports.entrypoints, &[])?;
+        let entrypoints = backend.dump(entrypoints)?;
+
+        Ok(Self {
+            version,
+            entrypoints,
+            revision: None,
+            memory_snapshot: false,
+        })
+    }
+
+    /// Load the data in the module memory
+    pub(crate) fn load_data<B: Backend, V: serde::Serialize>(
+        &self,
+        backend: &mut B,
+        data: &V,
+    ) -> Result<Data> {
+        let data = serde_json::to_vec(data)?;
+        let document = backend.load_json(&data)?;
+        let heap_ptr = backend.call_export(|exports| &exports.heap_ptr_get, &[])?;
+        let snapshot = if self.memory_snapshot {
+            Some(backend.read_memory()?)
+        } else {
+            None
+        };
+
+        Ok(Data {
+            document,
+            heap_ptr,
+            snapshot,
+        })
+    }
+
+    /// Evaluate an entrypoint, notifying the context of the start and the
+    /// outcome of the evaluation
+    pub(crate) async fn evaluate<B: Backend, V: serde::Serialize>(
+        &self,
+        backend: &mut B,
+        data: &Data,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        let metadata = EvaluationMetadata {
+            entrypoint,
+            revision: self.revision.as_deref(),
+            evaluation_id: EvaluationId::next(),
+        };
+        backend
+            .host()
+            .context
+            .evaluation_start_with_metadata(&metadata);
+
+        // The same span as the wasmtime backend, so that the events and the
+        // exported spans look the same whatever the backend
+        let span = metadata.span();
+
+        let start = Instant::now();
+        let result = self
+            .evaluate_entrypoint(backend, data, entrypoint, input)
+            .instrument(span.clone())
+            .await;
+        let duration = start.elapsed();
+
+        // Tell where the evaluation failed if it failed in the module
+        let result = result.map_err(|error| {
+            let failure = EvaluationFailure::describe(
+                &error,
+                entrypoint,
+                self.revision.as_deref(),
+                self.version,
+                None,
+            );
+            match failure {
+                Some(failure) => error.context(failure),
+                None => error,
+            }
+        });
+        let memory = memory_usage(backend, data).ok();
+        let outcome = EvaluationOutcome {
+            metadata,
+            duration,
+            result: result.as_ref(),
+            memory,
+        };
+
+        outcome.record(&span);
+
+        let context = &mut backend.host().context;
+        context.record_evaluation_duration(entrypoint, duration);
+        context.evaluation_end(&outcome);
+
+        result
+    }
+
+    /// Evaluate the given entrypoint, through the fast path if available
+    async fn evaluate_entrypoint<B: Backend, V: serde::Serialize>(
+        &self,
+        backend: &mut B,
+        data: &Data,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        let entrypoint = *self
+            .entrypoints
+            .get(entrypoint)
+            .with_context(|| format!("could not find entrypoint {entrypoint}"))?;
+        let input = serde_json::to_vec(input)?;
+
+        // Restore the memory as it was right after loading the data
+        if let Some(snapshot) = &data.snapshot {
+            backend.write(0, snapshot)?;
+        }
+
+        if let Some(opa_eval) = backend.exports()?.opa_eval.clone() {
+            // Write the input right after the data, where the heap starts
+            let offset = usize::try_from(data.heap_ptr).context("invalid heap pointer")?;
+            backend.reserve_memory(offset + input.len())?;
+            backend.write(offset, &input)?;
+
+            let len = i32::try_from(input.len()).context("input too long")?;
+            let params = [
+                0,
+                entrypoint,
+                data.document,
+                data.heap_ptr,
+                len,
+                data.heap_ptr + len,
+                0,
+            ];
+            let result = backend.eval(&opa_eval, &params).await?;
+
+            let result = backend.read_c_str(result)?;
+            Ok(serde_json::from_slice(&result)?)
+        } else {
+            backend.call_export(|exports| &exports.heap_ptr_set, &[data.heap_ptr])?;
+            let input = backend.load_json(&input)?;
+
+            let ctx = backend.call_export(|exports| &exports.ctx_new, &[])?;
+            backend.call_export(|exports| &exports.ctx_set_data, &[ctx, data.document])?;
+            backend.call_export(|exports| &exports.ctx_set_input, &[ctx, input])?;
+            backend.call_export(|exports| &exports.ctx_set_entrypoint, &[ctx, entrypoint])?;
+
+            let eval = backend.exports()?.eval.clone();
+            backend.eval(&eval, &[ctx]).await?;
+
+            let result = backend.call_export(|exports| &exports.ctx_get_result, &[ctx])?;
+            backend.dump(result)
+        }
+    }
+}
+
+/// Measure how much memory the last evaluation used
+fn memory_usage<B: Backend>(backend: &mut B, data: &Data) -> Result<MemoryUsage> {
+    let heap_ptr = backend.call_export(|exports| &exports.heap_ptr_get, &[])?;
+    let heap_ptr: usize = heap_ptr.try_into().context("invalid heap pointer")?;
+    let base: usize = data.heap_ptr.try_into().context("invalid heap pointer")?;
+    Ok(MemoryUsage {
+        memory_size: backend.memory_size(),
+        heap_ptr,
+        heap_used: heap_ptr.saturating_sub(base),
+    })
+}
+
+/// The data loaded in a policy module
+#[derive(Debug)]
+pub(crate) struct Data {
+    /// The address of the data document
+    pub(crate) document: i32,
+
+    /// The heap pointer right after loading the data
+    pub(crate) heap_ptr: i32,
+
+    /// The memory right after loading the data, if it is restored before
+    /// each evaluation
+    pub(crate) snapshot: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestContext;
+
+    #[tokio::test]
+    async fn builtin_calls_are_recorded() {
+        let registry = BuiltinRegistry::<TestContext>::new();
+        let builtins =
+            resolve_builtins(&registry, HashMap::from([("trace".to_owned(), 0)])).unwrap();
+        let mut context = TestContext::default();
+        context.record_builtin_calls();
+
+        let result = call_builtin(&builtins, &mut context, 0, &[b"\"hello\""])
+            .await
+            .unwrap();
+        assert_eq!(result, b"true");
+        assert_eq!(context.builtin_calls().len(), 1);
+
+        let error = call_builtin(&builtins, &mut context, 1, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "unknown builtin id 1");
+
+        let error = resolve_builtins(&registry, HashMap::from([("unknown".to_owned(), 0)]))
+            .map(drop)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "could not resolve unknown");
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Batch mode, evaluating newline-delimited JSON inputs
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use opa_wasm::{EvaluationContext, Policy};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, BufWriter};
+use wasmtime::Store;
+
+use crate::limits::Limits;
+
+/// Evaluate each line of the file, or of stdin if the path is `-`, and print
+/// one decision per line. Inputs which fail to evaluate produce an
+/// `{"error": ...}` line, so that the output stays aligned with the input.
+pub async fn run<C: EvaluationContext>(
+    store: &mut Store<Limits>,
+    policy: &Policy<C>,
+    entrypoint: &str,
+    path: &Utf8Path,
+) -> Result<()> {
+    let reader: Box<dyn AsyncRead + Unpin + Send> = if path == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("could not open {path}"))?;
+        Box::new(file)
+    };
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut output = BufWriter::new(tokio::io::stdout());
+    let mut total = 0_usize;
+    let mut failed = 0_usize;
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        total += 1;
+        let result = async {
+            let input: serde_json::Value =
+                serde_json::from_str(line).context("invalid JSON input")?;
+            Limits::arm(store);
+            let result: serde_json::Value =
+                policy.evaluate(&mut *store, entrypoint, &input).await?;
+            Ok::<_, anyhow::Error>(result)
+        }
+        .await;
+
+        let result = result.unwrap_or_else(|error| {
+            failed += 1;
+            tracing::warn!(line = total, "evaluation failed: {error:#}");
+            serde_json::json!({ "error": format!("{error:#}") })
+        });
+
+        let mut line = serde_json::to_vec(&result)?;
+        line.push(b'\n');
+        output.write_all(&line).await?;
+    }
+
+    output.flush().await?;
+
+    if failed > 0 {
+        anyhow::bail!("{failed} out of {total} inputs failed to evaluate");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark mode, evaluating an entrypoint repeatedly and reporting latency
+//! statistics, like `opa bench` does
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Args;
+use opa_wasm::{EvaluationContext, Policy};
+use tracing::Instrument;
+use wasmtime::Store;
+
+use crate::{
+    limits::{Limits, LimitsArgs},
+    ContextArgs, DataArgs, InputArgs, PolicyArgs,
+};
+
+/// Arguments of the `bench` subcommand
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    /// Entrypoint to use. Defaults to the default entrypoint of the module,
+    /// the one with ID 0.
+    #[arg(short, long)]
+    entrypoint: Option<String>,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    #[command(flatten)]
+    context: ContextArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Number of measured evaluations
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    iterations: u32,
+
+    /// Number of evaluations run before measuring, to warm up the caches
+    #[arg(long, default_value_t = 100)]
+    warmup: u32,
+}
+
+/// The latencies measured for one evaluation path
+struct Report {
+    /// The durations of each evaluation, sorted
+    samples: Vec<Duration>,
+
+    /// The total time spent evaluating
+    total: Duration,
+}
+
+impl Report {
+    /// Build a report from the measured durations
+    fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let total = samples.iter().sum();
+        Self { samples, total }
+    }
+
+    /// Get the given percentile, using the nearest-rank method
+    fn percentile(&self, percentile: usize) -> Duration {
+        let rank = (self.samples.len() * percentile).div_ceil(100);
+        self.samples
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Print the report for the given evaluation path
+    fn print(&self, path: &str, iterations: u32) {
+        let throughput = f64::from(iterations) / self.total.as_secs_f64();
+        println!("{path} ({iterations} iterations)");
+        println!("  throughput: {throughput:.1} evals/s");
+        println!(
+            "  min: {:?}  p50: {:?}  p95: {:?}  p99: {:?}  max: {:?}",
+            self.samples.first().copied().unwrap_or_default(),
+            self.percentile(50),
+            self.percentile(95),
+            self.percentile(99),
+            self.samples.last().copied().unwrap_or_default(),
+        );
+    }
+}
+
+/// Evaluate the entrypoint the given number of times, after warming up
+async fn measure<C: EvaluationContext>(
+    store: &mut Store<Limits>,
+    policy: &Policy<C>,
+    entrypoint: &str,
+    input: &serde_json::Value,
+    warmup: u32,
+    iterations: u32,
+) -> Result<Report> {
+    for _ in 0..warmup {
+        let _: serde_json::Value = policy.evaluate(&mut *store, entrypoint, input).await?;
+    }
+
+    let mut samples = Vec::with_capacity(iterations.try_into()?);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let _: serde_json::Value = policy.evaluate(&mut *store, entrypoint, input).await?;
+        samples.push(start.elapsed());
+    }
+
+    Ok(Report::new(samples))
+}
+
+/// Load the policy and benchmark the entrypoint, through the fast path if the
+/// module exports it, and through the slow path
+pub async fn run(args: BenchArgs) -> Result<()> {
+    let module = args.policy.load().await?;
+    let data = args.data.load().await?;
+    let input = args.input.load().await?;
+
+    let (engine, module) = (async move { crate::compile(&module, &LimitsArgs::default()) })
+        .instrument(tracing::info_span!("compile_module"))
+        .await?;
+
+    let (mut store, mut policy) = crate::instantiate(
+        &engine,
+        &module,
+        &data,
+        args.context.build().await?,
+        Limits::default(),
+    )
+    .await?;
+
+    let entrypoint = crate::resolve_entrypoint(&policy, args.entrypoint.as_deref())?;
+
+    if policy.has_fast_path() {
+        let report = measure(
+            &mut store,
+            &policy,
+            &entrypoint,
+            &input,
+            args.warmup,
+            args.iterations,
+        )
+        .instrument(tracing::info_span!("bench_fast_path"))
+        .await?;
+        report.print("fast path", args.iterations);
+    } else {
+        eprintln!("the module does not export `opa_eval`, only the slow path is measured");
+    }
+
+    policy.set_fast_path(false);
+    let report = measure(
+        &mut store,
+        &policy,
+        &entrypoint,
+        &input,
+        args.warmup,
+        args.iterations,
+    )
+    .instrument(tracing::info_span!("bench_slow_path"))
+    .await?;
+    report.print("slow path", args.iterations);
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conformance mode, running the WASM test cases published by OPA
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use clap::Args;
+use opa_wasm::conformance::{ConformanceRunner, Outcome};
+
+/// Arguments of the `conformance` subcommand
+#[derive(Args)]
+pub struct ConformanceArgs {
+    /// Path to the directory or the gzipped tarball of the test cases
+    cases: Utf8PathBuf,
+
+    /// Only run the cases of the features with this prefix
+    #[arg(long, value_name = "PREFIX")]
+    filter: Option<String>,
+}
+
+/// Run the test cases, print the failures and a summary per feature, and fail
+/// if any case failed
+pub async fn run(args: ConformanceArgs) -> Result<()> {
+    let runner = ConformanceRunner::new()?;
+    let mut report = runner.run(&args.cases).await?;
+    if let Some(filter) = &args.filter {
+        report
+            .cases
+            .retain(|case| case.feature.starts_with(filter.as_str()));
+    }
+
+    for case in report.failures() {
+        if let Outcome::Failed(reason) = &case.outcome {
+            println!("FAIL {}: {}: {reason}", case.file, case.note);
+        }
+    }
+    println!("{report}");
+
+    anyhow::ensure!(report.is_success(), "some test cases failed");
+    Ok(())
+}
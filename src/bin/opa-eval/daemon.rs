@@ -0,0 +1,270 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Daemon mode, answering JSON-RPC requests over a unix socket
+//!
+//! Each line sent on the socket is a JSON-RPC 2.0 request, and is answered
+//! with a JSON-RPC response on its own line. The only method is `evaluate`,
+//! taking an `entrypoint` and an optional `input`, and returning the decision
+//! formatted like the OPA REST API does.
+
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::Args;
+use opa_wasm::PooledPolicy;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tracing::Instrument;
+
+use crate::{
+    limits::{parse_duration, LimitsArgs},
+    serve::Pool,
+    ContextArgs, DataArgs, PolicyArgs,
+};
+
+/// Invalid JSON was received
+const PARSE_ERROR: i64 = -32700;
+
+/// The JSON sent is not a valid request object
+const INVALID_REQUEST: i64 = -32600;
+
+/// The method does not exist
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// The parameters of the method are invalid
+const INVALID_PARAMS: i64 = -32602;
+
+/// The evaluation failed
+const EVALUATION_ERROR: i64 = -32000;
+
+/// Arguments of the `daemon` subcommand
+#[derive(Args)]
+pub struct DaemonArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    #[command(flatten)]
+    context: ContextArgs,
+
+    #[command(flatten)]
+    limits: LimitsArgs,
+
+    /// Path of the unix socket to listen on
+    #[arg(short, long, value_name = "PATH")]
+    socket: Utf8PathBuf,
+
+    /// Number of policy instances evaluating requests concurrently. Defaults
+    /// to the number of CPUs.
+    #[arg(long)]
+    pool_size: Option<NonZeroUsize>,
+
+    /// Download the bundle again at this interval, e.g. `30s`, and serve its
+    /// new revisions. Requires the bundle to be a URL.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    bundle_poll: Option<Duration>,
+}
+
+/// A JSON-RPC request
+#[derive(Deserialize)]
+struct Request {
+    /// The identifier of the request, echoed in the response
+    #[serde(default)]
+    id: serde_json::Value,
+
+    /// The name of the method
+    method: String,
+
+    /// The parameters of the method
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+/// The parameters of the `evaluate` method
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EvaluateParams {
+    /// The entrypoint to evaluate
+    entrypoint: String,
+
+    /// The input document
+    #[serde(default)]
+    input: Option<serde_json::Value>,
+}
+
+/// A JSON-RPC error
+struct RpcError {
+    /// The error code
+    code: i64,
+
+    /// A human-readable message
+    message: String,
+}
+
+impl RpcError {
+    /// Create an error with the given code and message
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Evaluate an entrypoint, and format the result like the OPA REST API does
+async fn evaluate(pool: &Pool, params: EvaluateParams) -> Result<serde_json::Value, RpcError> {
+    let mut instance = pool.get().await;
+    let PooledPolicy { store, policy } = &mut *instance;
+
+    if !policy.entrypoints().contains(params.entrypoint.as_str()) {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("no entrypoint named {:?}", params.entrypoint),
+        ));
+    }
+
+    let input = params
+        .input
+        .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::default()));
+    let result: serde_json::Value = policy
+        .evaluate(store, &params.entrypoint, &input)
+        .instrument(tracing::info_span!(
+            "evaluate",
+            entrypoint = params.entrypoint
+        ))
+        .await
+        .map_err(|error| RpcError::new(EVALUATION_ERROR, format!("{error:#}")))?;
+
+    // The policy returns a result set, which is empty if the decision is undefined
+    Ok(match result.get(0).and_then(|r| r.get("result")) {
+        Some(result) => serde_json::json!({ "result": result }),
+        None => serde_json::json!({}),
+    })
+}
+
+/// Handle a single line, returning the JSON-RPC response to send back
+async fn handle(pool: &Pool, line: &str) -> serde_json::Value {
+    let request: serde_json::Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(error) => {
+            return response(
+                &serde_json::Value::Null,
+                Err(RpcError::new(PARSE_ERROR, error.to_string())),
+            )
+        }
+    };
+
+    let request = match Request::deserialize(&request) {
+        Ok(request) => request,
+        Err(error) => {
+            let id = request.get("id").unwrap_or(&serde_json::Value::Null);
+            return response(id, Err(RpcError::new(INVALID_REQUEST, error.to_string())));
+        }
+    };
+
+    let result = match request.method.as_str() {
+        "evaluate" => match EvaluateParams::deserialize(&request.params) {
+            Ok(params) => evaluate(pool, params).await,
+            Err(error) => Err(RpcError::new(INVALID_PARAMS, error.to_string())),
+        },
+        method => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {method:?}"),
+        )),
+    };
+
+    response(&request.id, result)
+}
+
+/// Build a JSON-RPC response
+fn response(
+    id: &serde_json::Value,
+    result: Result<serde_json::Value, RpcError>,
+) -> serde_json::Value {
+    match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": error.code, "message": error.message },
+        }),
+    }
+}
+
+/// Answer the requests sent on a connection, until it is closed
+async fn serve_connection(pool: Arc<Pool>, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut response = handle(&pool, &line).await.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Load the policy, instantiate the pool and answer requests on the socket
+/// until interrupted
+pub async fn run(args: DaemonArgs) -> Result<()> {
+    let pool = crate::serve::start(
+        &args.policy,
+        args.bundle_poll,
+        &args.data,
+        args.context,
+        args.limits,
+        args.pool_size,
+    )
+    .await?;
+    let pool_size = pool.size();
+
+    let listener = UnixListener::bind(&args.socket)
+        .with_context(|| format!("could not listen on {}", args.socket))?;
+    tracing::info!(socket = %args.socket, pool_size, "listening");
+
+    let result = async {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        if let Err(error) = serve_connection(pool, stream).await {
+                            tracing::warn!("connection failed: {error:#}");
+                        }
+                    });
+                }
+                _ = tokio::signal::ctrl_c() => return Ok::<_, anyhow::Error>(()),
+            }
+        }
+    }
+    .await;
+
+    // Don't leave the socket behind, so that the next run can bind it again
+    let _ = std::fs::remove_file(&args.socket);
+
+    result
+}
@@ -0,0 +1,132 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Canned `http.send` responses, loaded from a fixtures file
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use opa_wasm::{HttpMockLayer, MockResponse, RequestMatcher};
+use serde::Deserialize;
+
+/// A request and the response to answer it with
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    /// What the request must look like
+    request: FixtureRequest,
+
+    /// The response to answer it with
+    response: FixtureResponse,
+}
+
+/// What a request must look like to match a fixture
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureRequest {
+    /// The method, or any method if not set
+    method: Option<String>,
+
+    /// The exact URL
+    url: String,
+
+    /// The headers the request must have
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+
+    /// The exact body, or any body if not set
+    body: Option<String>,
+}
+
+/// A canned response
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureResponse {
+    /// The status code
+    #[serde(default = "default_status")]
+    status: u16,
+
+    /// The response headers
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+
+    /// The raw response body
+    body: Option<String>,
+
+    /// A JSON response body, which also sets the content type
+    json: Option<serde_json::Value>,
+}
+
+/// The status code of fixtures which don't set one
+fn default_status() -> u16 {
+    200
+}
+
+impl Fixture {
+    /// Convert the fixture to the matcher and response used by the layer
+    fn parse(self) -> Result<(RequestMatcher, MockResponse)> {
+        let mut matcher = RequestMatcher::new(self.request.url);
+        if let Some(method) = self.request.method {
+            let method = http::Method::from_bytes(method.to_uppercase().as_bytes())
+                .with_context(|| format!("invalid method {method:?}"))?;
+            matcher = matcher.method(method);
+        }
+        for (name, value) in self.request.headers {
+            matcher = matcher.header(header_name(&name)?, value);
+        }
+        if let Some(body) = self.request.body {
+            matcher = matcher.body(body);
+        }
+
+        let status = http::StatusCode::from_u16(self.response.status)
+            .with_context(|| format!("invalid status code {}", self.response.status))?;
+        let mut response = match (self.response.json, self.response.body) {
+            (Some(_), Some(_)) => anyhow::bail!("a response can't have both a body and json"),
+            (Some(json), None) => MockResponse::json(status, &json),
+            (None, Some(body)) => MockResponse::new(status).body(body),
+            (None, None) => MockResponse::new(status),
+        };
+        for (name, value) in self.response.headers {
+            response = response.header(header_name(&name)?, value);
+        }
+
+        Ok((matcher, response))
+    }
+}
+
+/// Parse a header name
+fn header_name(name: &str) -> Result<http::HeaderName> {
+    http::HeaderName::try_from(name).with_context(|| format!("invalid header name {name:?}"))
+}
+
+/// Load the fixtures file, a JSON or YAML list of `request` and `response`
+/// pairs, into the layer
+pub async fn load<C>(layer: HttpMockLayer<C>, path: &Utf8Path) -> Result<HttpMockLayer<C>> {
+    let document = crate::read_document(path)
+        .await
+        .with_context(|| format!("could not read the HTTP fixtures from {path}"))?;
+    let fixtures: Vec<Fixture> = serde_json::from_value(document)
+        .with_context(|| format!("invalid HTTP fixtures in {path}"))?;
+
+    let mut layer = layer;
+    for (index, fixture) in fixtures.into_iter().enumerate() {
+        let (matcher, response) = fixture
+            .parse()
+            .with_context(|| format!("invalid HTTP fixture #{index} in {path}"))?;
+        layer = layer.mock(matcher, response);
+    }
+
+    Ok(layer)
+}
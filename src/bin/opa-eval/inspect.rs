@@ -0,0 +1,75 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inspect mode, printing what the module exports and requires
+
+use anyhow::Result;
+use clap::Args;
+use opa_wasm::Runtime;
+use tracing::Instrument;
+use wasmtime::Store;
+
+use crate::{limits::LimitsArgs, PolicyArgs};
+
+/// Arguments of the `inspect` subcommand
+#[derive(Args)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+}
+
+/// Load the module and print its ABI version, entrypoints and builtins,
+/// flagging the builtins this build does not support
+pub async fn run(args: InspectArgs) -> Result<()> {
+    let module = args.policy.load().await?;
+
+    let (engine, module) = (async move { crate::compile(&module, &LimitsArgs::default()) })
+        .instrument(tracing::info_span!("compile_module"))
+        .await?;
+
+    let mut store = Store::new(&engine, ());
+    let runtime = Runtime::inspect(&mut store, &module)
+        .instrument(tracing::info_span!("instanciate_module"))
+        .await?;
+    let info = runtime.module_info()?;
+
+    println!("ABI version: {}", info.abi_version);
+    println!(
+        "Fast path: {}",
+        if runtime.has_fast_path() { "yes" } else { "no" }
+    );
+
+    println!("Entrypoints:");
+    for (name, id) in &info.entrypoints {
+        println!("  {id:>4}  {name}");
+    }
+
+    println!("Builtins:");
+    for (name, id) in &info.builtins {
+        if info.unsupported_builtins.contains(name) {
+            println!("  {id:>4}  {name} (unsupported)");
+        } else {
+            println!("  {id:>4}  {name}");
+        }
+    }
+
+    if !info.unsupported_builtins.is_empty() {
+        eprintln!(
+            "warning: {} builtin(s) required by the module are not supported by this build",
+            info.unsupported_builtins.len()
+        );
+    }
+
+    Ok(())
+}
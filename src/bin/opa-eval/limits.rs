@@ -0,0 +1,191 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounds on the time and memory an evaluation can use
+
+use std::{process::ExitCode, time::Duration};
+
+use anyhow::Result;
+use clap::Args;
+use wasmtime::{Config, Engine, ResourceLimiter, Store, Trap};
+
+/// The exit code used when an evaluation exceeds its time or memory budget
+const BUDGET_EXCEEDED_EXIT_CODE: u8 = 3;
+
+/// How often the epoch of the engine is incremented, which is the resolution
+/// of the timeout
+const TICK: Duration = Duration::from_millis(10);
+
+/// Bounds on the resources used by the policy
+#[derive(Args, Clone, Copy, Default)]
+pub struct LimitsArgs {
+    /// Abort each evaluation which takes longer than the given duration,
+    /// e.g. `500ms` or `2s`
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Limit the memory of the policy instance, in bytes, or with a `K`, `M`
+    /// or `G` suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+}
+
+/// Parse a duration like `500ms` or `2s`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    duration_str::parse_std(value)
+}
+
+/// Parse a size in bytes, with an optional binary `K`, `M` or `G` suffix
+fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("iB")
+        .or_else(|| value.strip_suffix('B'))
+        .unwrap_or(value);
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    number
+        .trim()
+        .parse::<usize>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(multiplier)
+        .ok_or_else(|| "size too large".to_owned())
+}
+
+impl LimitsArgs {
+    /// Configure the engine so that evaluations can be interrupted
+    pub fn configure(&self, config: &mut Config) {
+        if self.timeout.is_some() {
+            config.epoch_interruption(true);
+        }
+    }
+
+    /// Start incrementing the epoch of the engine in the background, if a
+    /// timeout is set
+    pub fn start_ticker(&self, engine: &Engine) {
+        if self.timeout.is_none() {
+            return;
+        }
+
+        let engine = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TICK);
+            engine.increment_epoch();
+        });
+    }
+
+    /// Get the limits to store alongside the policy instance
+    pub fn limits(&self) -> Limits {
+        let ticks = self.timeout.map(|timeout| {
+            let ticks = timeout.as_nanos().div_ceil(TICK.as_nanos()).max(1);
+            u64::try_from(ticks).unwrap_or(u64::MAX)
+        });
+
+        Limits {
+            timeout_ticks: ticks,
+            max_memory: self.max_memory,
+        }
+    }
+}
+
+/// The memory limit was reached
+#[derive(Debug)]
+struct MemoryLimitExceeded {
+    /// The size the memory would have grown to
+    desired: usize,
+
+    /// The limit
+    limit: usize,
+}
+
+impl std::fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "memory limit exceeded: growing to {} bytes, limit is {} bytes",
+            self.desired, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// The limits of a policy instance, held as the data of its store
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// The number of epoch ticks an evaluation is allowed to take
+    timeout_ticks: Option<u64>,
+
+    /// The maximum size of a linear memory, in bytes
+    max_memory: Option<usize>,
+}
+
+impl Limits {
+    /// Create a store holding the limits, and enforcing the memory limit
+    pub fn store(self, engine: &Engine) -> Store<Self> {
+        let mut store = Store::new(engine, self);
+        store.limiter(|limits| limits);
+        Self::arm(&mut store);
+        store
+    }
+
+    /// Reset the evaluation deadline, to be called before each evaluation
+    pub fn arm(store: &mut Store<Self>) {
+        if let Some(ticks) = store.data().timeout_ticks {
+            store.set_epoch_deadline(ticks);
+        }
+    }
+}
+
+impl ResourceLimiter for Limits {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        match self.max_memory {
+            Some(limit) if desired > limit => Err(MemoryLimitExceeded { desired, limit }.into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Get the exit code for the given error, which is distinct if the time or
+/// memory budget was exceeded
+pub fn exit_code(error: &anyhow::Error) -> ExitCode {
+    let exceeded = error.chain().any(|cause| {
+        cause.is::<MemoryLimitExceeded>() || cause.downcast_ref::<Trap>() == Some(&Trap::Interrupt)
+    });
+
+    if exceeded {
+        ExitCode::from(BUDGET_EXCEEDED_EXIT_CODE)
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of the diagnostics output
+
+use clap::{Args, ValueEnum};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
+
+/// How the logs are formatted
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable trees of spans and events
+    #[default]
+    Forest,
+
+    /// One JSON object per event, on stderr
+    Json,
+}
+
+/// Where and how the logs are written
+#[derive(Args)]
+pub struct LogArgs {
+    /// Format of the logs, whose verbosity is set by `RUST_LOG`
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t,
+        global = true
+    )]
+    log_format: LogFormat,
+
+    /// Don't write any logs. Errors are still reported.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+impl LogArgs {
+    /// Install the tracing subscriber
+    pub fn init(&self) {
+        let filter = if self.quiet {
+            EnvFilter::new("off")
+        } else {
+            EnvFilter::from_default_env()
+        };
+
+        let (forest, json) = match self.log_format {
+            LogFormat::Forest => (Some(tracing_forest::ForestLayer::default()), None),
+            LogFormat::Json => (
+                None,
+                Some(
+                    tracing_subscriber::fmt::layer()
+                        .json()
+                        .with_writer(std::io::stderr),
+                ),
+            ),
+        };
+
+        Registry::default()
+            .with(forest)
+            .with(json)
+            .with(filter)
+            .init();
+    }
+}
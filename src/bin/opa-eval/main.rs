@@ -0,0 +1,775 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![deny(clippy::pedantic)]
+
+mod batch;
+mod bench;
+#[cfg(feature = "conformance")]
+mod conformance;
+#[cfg(unix)]
+mod daemon;
+mod fixtures;
+mod inspect;
+mod limits;
+mod logging;
+mod precompile;
+mod profile;
+mod repl;
+mod serve;
+#[cfg(feature = "testing")]
+mod test;
+#[cfg(feature = "validate")]
+mod validate;
+mod watch;
+
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use opa_wasm::{
+    BundleFetcher, DefaultContext, EngineConfig, EvaluationContext, HttpMockLayer, MetricsLayer,
+    Policy, Runtime, TimeSource,
+};
+use serde::Deserialize;
+use tracing::Instrument;
+use wasmtime::{Config, Engine, Module, Store};
+
+use self::{
+    limits::{Limits, LimitsArgs},
+    profile::Profile,
+};
+
+/// The evaluation context used by the CLI
+type EvalContext = HttpMockLayer<DefaultContext>;
+
+/// Evaluates OPA policies compiled as WASM modules
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    log: logging::LogArgs,
+
+    #[command(flatten)]
+    eval: EvalArgs,
+}
+
+/// The subcommands of the CLI
+#[derive(Subcommand)]
+enum Command {
+    /// Evaluate an entrypoint repeatedly and report latency statistics
+    Bench(bench::BenchArgs),
+
+    /// Run the WASM test cases published by OPA, and report the outcomes per
+    /// feature
+    #[cfg(feature = "conformance")]
+    Conformance(conformance::ConformanceArgs),
+
+    /// Answer JSON-RPC `evaluate` requests over a unix socket
+    #[cfg(unix)]
+    Daemon(daemon::DaemonArgs),
+
+    /// Print the ABI version, entrypoints and builtins of the module
+    Inspect(inspect::InspectArgs),
+
+    /// Compile the module ahead of time, for use with `--precompiled`
+    Precompile(precompile::PrecompileArgs),
+
+    /// Serve the policy over HTTP, exposing a subset of the OPA REST API
+    Serve(serve::ServeArgs),
+
+    /// Run the Rego unit tests compiled as entrypoints of the policy
+    #[cfg(feature = "testing")]
+    Test(test::TestArgs),
+
+    /// Check the module without loading it, and fail if it can't be loaded,
+    /// to reject a bundle before rolling it out
+    #[cfg(feature = "validate")]
+    Validate(validate::ValidateArgs),
+}
+
+/// Where to load the policy from
+#[derive(Args)]
+#[group(id = "policy", required = true, multiple = false)]
+struct PolicyArgs {
+    /// Path to the WASM module
+    #[arg(short, long)]
+    module: Option<Utf8PathBuf>,
+
+    /// Path to the OPA bundle, or an `http://` or `https://` URL to download
+    /// it from
+    #[arg(short, long)]
+    bundle: Option<Utf8PathBuf>,
+
+    /// Path to a module compiled with the `precompile` subcommand, built
+    /// with the same version of this tool
+    #[arg(long, value_name = "PATH")]
+    precompiled: Option<Utf8PathBuf>,
+}
+
+/// The bytes of a policy module
+enum ModuleBytes {
+    /// A WASM module, which needs to be compiled
+    Wasm(Vec<u8>),
+
+    /// A module serialized by the `precompile` subcommand
+    Precompiled(Vec<u8>),
+}
+
+impl PolicyArgs {
+    /// Read the WASM module, either directly or from the bundle, or the
+    /// precompiled module
+    async fn load(&self) -> Result<ModuleBytes> {
+        let (module, _fetcher) = self.load_with_fetcher().await?;
+        Ok(module)
+    }
+
+    /// Same as [`PolicyArgs::load`], also returning the fetcher which
+    /// downloaded the bundle if it comes from a URL, so that it can be polled
+    /// for new revisions
+    async fn load_with_fetcher(&self) -> Result<(ModuleBytes, Option<BundleFetcher>)> {
+        if let Some(url) = self.bundle.as_ref().filter(|bundle| is_url(bundle)) {
+            let mut fetcher = BundleFetcher::new(url.as_str())?;
+            let bundle = fetcher
+                .fetch()
+                .await?
+                .context("the server answered a conditional response to the first request")?;
+            return Ok((ModuleBytes::Wasm(bundle.policy), Some(fetcher)));
+        }
+
+        let module = if let Some(path) = &self.module {
+            let module = tokio::fs::read(path)
+                .instrument(tracing::info_span!("read_module"))
+                .await?;
+            ModuleBytes::Wasm(module)
+        } else if let Some(path) = &self.bundle {
+            ModuleBytes::Wasm(opa_wasm::read_bundle(path).await?)
+        } else if let Some(path) = &self.precompiled {
+            let module = tokio::fs::read(path)
+                .instrument(tracing::info_span!("read_module"))
+                .await?;
+            ModuleBytes::Precompiled(module)
+        } else {
+            // This should be enforced by clap
+            unreachable!()
+        };
+
+        Ok((module, None))
+    }
+}
+
+/// Whether the bundle is a URL to download it from, instead of a path
+fn is_url(bundle: &Utf8Path) -> bool {
+    bundle.as_str().starts_with("http://") || bundle.as_str().starts_with("https://")
+}
+
+/// Where to load the data document from
+#[derive(Args)]
+#[group(id = "data", multiple = true)]
+#[allow(clippy::struct_field_names)]
+struct DataArgs {
+    /// JSON literal to use as data. Can be repeated, in which case the
+    /// documents are merged.
+    #[arg(short, long = "data", value_name = "JSON")]
+    data_value: Vec<serde_json::Value>,
+
+    /// Path to a JSON or YAML file to load as data. Can be repeated, in which
+    /// case the documents are merged.
+    #[arg(short = 'D', long, value_name = "PATH")]
+    data_path: Vec<Utf8PathBuf>,
+
+    /// Name of an environment variable holding JSON to use as data. Can be
+    /// repeated, in which case the documents are merged.
+    #[arg(long, value_name = "VAR")]
+    data_env: Vec<String>,
+}
+
+impl DataArgs {
+    /// Load and merge the data documents, defaulting to an empty object
+    async fn load(&self) -> Result<serde_json::Value> {
+        let mut documents =
+            Vec::with_capacity(self.data_value.len() + self.data_path.len() + self.data_env.len());
+        documents.extend(self.data_value.iter().cloned().map(|value| (value, None)));
+
+        for path in &self.data_path {
+            let value = read_document(path).await?;
+            documents.push((value, Some(path.to_string())));
+        }
+
+        for var in &self.data_env {
+            documents.push((json_from_env(var)?, Some(format!("${var}"))));
+        }
+
+        // A single document is used as is, even if it is not an object
+        if documents.len() == 1 {
+            if let Some((value, _)) = documents.pop() {
+                return Ok(value);
+            }
+        }
+
+        let mut data = serde_json::Value::Object(serde_json::Map::default());
+        for (value, source) in documents {
+            merge(&mut data, value, &mut Vec::new()).with_context(|| match source {
+                Some(source) => format!("could not merge the data from {source}"),
+                None => "could not merge the data".to_owned(),
+            })?;
+        }
+
+        Ok(data)
+    }
+}
+
+/// Read a JSON document, or a YAML one if the file has a `.yaml` or `.yml`
+/// extension
+async fn read_document(path: &Utf8Path) -> Result<serde_json::Value> {
+    let content = tokio::fs::read(path).await?;
+    if matches!(path.extension(), Some("yaml" | "yml")) {
+        parse_yaml(&content).with_context(|| format!("invalid YAML in {path}"))
+    } else {
+        serde_json::from_slice(&content).with_context(|| format!("invalid JSON in {path}"))
+    }
+}
+
+/// Parse a YAML document. A stream of several documents, like a list of
+/// Kubernetes manifests, is parsed as an array.
+fn parse_yaml(content: &[u8]) -> Result<serde_json::Value> {
+    let mut documents = serde_yaml::Deserializer::from_slice(content)
+        .map(serde_json::Value::deserialize)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if documents.len() == 1 {
+        Ok(documents.swap_remove(0))
+    } else {
+        Ok(serde_json::Value::Array(documents))
+    }
+}
+
+/// Read and parse the JSON held by an environment variable
+fn json_from_env(var: &str) -> Result<serde_json::Value> {
+    let value = std::env::var(var).with_context(|| format!("could not read ${var}"))?;
+    serde_json::from_str(&value).with_context(|| format!("invalid JSON in ${var}"))
+}
+
+/// Merge a data document into another. Objects are merged recursively, and
+/// any other value conflicts with a different value at the same path.
+fn merge(
+    target: &mut serde_json::Value,
+    source: serde_json::Value,
+    path: &mut Vec<String>,
+) -> Result<()> {
+    match (target, source) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(source)) => {
+            for (key, value) in source {
+                if let Some(existing) = target.get_mut(&key) {
+                    path.push(key);
+                    merge(existing, value, path)?;
+                    path.pop();
+                } else {
+                    target.insert(key, value);
+                }
+            }
+            Ok(())
+        }
+        (target, source) if *target == source => Ok(()),
+        _ if path.is_empty() => anyhow::bail!("conflicting values for data"),
+        _ => anyhow::bail!("conflicting values for data.{}", path.join(".")),
+    }
+}
+
+/// Where to load the input document from
+#[derive(Args)]
+#[group(id = "input", multiple = false)]
+#[allow(clippy::struct_field_names)]
+struct InputArgs {
+    /// JSON literal to use as input
+    #[arg(short, long = "input", value_name = "JSON")]
+    input_value: Option<serde_json::Value>,
+
+    /// Path to a JSON or YAML file to load as input
+    #[arg(short = 'I', long, value_name = "PATH")]
+    input_path: Option<Utf8PathBuf>,
+
+    /// YAML literal to use as input
+    #[arg(long, value_name = "YAML")]
+    input_yaml: Option<String>,
+
+    /// Name of an environment variable holding JSON to use as input
+    #[arg(long, value_name = "VAR")]
+    input_env: Option<String>,
+}
+
+impl InputArgs {
+    /// Load the input document, defaulting to an empty object
+    async fn load(&self) -> Result<serde_json::Value> {
+        let input = if let Some(path) = &self.input_path {
+            read_document(path).await?
+        } else if let Some(input) = &self.input_value {
+            input.clone()
+        } else if let Some(input) = &self.input_yaml {
+            parse_yaml(input.as_bytes()).context("invalid YAML input")?
+        } else if let Some(var) = &self.input_env {
+            json_from_env(var)?
+        } else {
+            serde_json::Value::Object(serde_json::Map::default())
+        };
+
+        Ok(input)
+    }
+}
+
+/// How the evaluation context is configured
+#[derive(Args)]
+struct ContextArgs {
+    /// Use the given RFC 3339 timestamp as the current time, instead of the
+    /// system clock
+    #[arg(long, value_name = "RFC3339")]
+    now: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Seed the random number generator, so that random builtins return the
+    /// same values on each run
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u64>,
+
+    /// Deny outgoing HTTP requests from `http.send`
+    #[arg(long)]
+    no_http: bool,
+
+    /// Deny all network access, both HTTP requests and DNS lookups
+    #[arg(long)]
+    no_net: bool,
+
+    /// Hide the environment variables from `opa.runtime`
+    #[arg(long)]
+    no_env: bool,
+
+    /// Answer `http.send` requests with the canned responses from the given
+    /// JSON or YAML file, a list of `request` and `response` pairs. Requests
+    /// matching none of them fail instead of being sent.
+    #[arg(long, value_name = "PATH")]
+    http_fixtures: Option<Utf8PathBuf>,
+}
+
+impl ContextArgs {
+    /// Build the evaluation context
+    async fn build(&self) -> Result<EvalContext> {
+        let mut builder = DefaultContext::builder()
+            .http(!(self.no_http || self.no_net))
+            .dns(!self.no_net)
+            .env(!self.no_env);
+
+        if let Some(now) = self.now {
+            builder = builder.time_source(TimeSource::Fixed(now));
+        }
+
+        if let Some(seed) = self.seed {
+            builder = builder.rng_seed(seed);
+        }
+
+        let context = HttpMockLayer::new(builder.build());
+        match &self.http_fixtures {
+            Some(path) => fixtures::load(context, path).await,
+            None => Ok(context.passthrough(true)),
+        }
+    }
+}
+
+/// Arguments used when evaluating a policy, without subcommand
+#[derive(Args)]
+#[allow(clippy::struct_excessive_bools)] // They are independent flags
+struct EvalArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    /// Entrypoint to use. Defaults to the default entrypoint of the module,
+    /// the one with ID 0.
+    #[arg(short, long)]
+    entrypoint: Option<String>,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    context: ContextArgs,
+
+    #[command(flatten)]
+    limits: LimitsArgs,
+
+    /// Read inputs from stdin line by line and print each decision, instead
+    /// of evaluating a single input. Each line is either a JSON input, or an
+    /// entrypoint name optionally followed by a JSON input.
+    #[arg(long, conflicts_with = "input")]
+    repl: bool,
+
+    /// Watch the module, bundle, data and input files, and evaluate the
+    /// policy again each time one of them changes
+    #[arg(long, conflicts_with = "repl")]
+    watch: bool,
+
+    /// Read newline-delimited JSON inputs from the given file, or from stdin
+    /// if no file or `-` is given, and print one decision per line
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        conflicts_with_all = ["input", "repl", "watch"],
+    )]
+    batch: Option<Utf8PathBuf>,
+
+    /// Print on stderr how long compiling, instantiating, loading the data,
+    /// evaluating and each builtin took
+    #[arg(long, conflicts_with = "watch")]
+    profile: bool,
+
+    /// Print an explanation of the decision on stderr, after it
+    #[arg(long, value_enum, value_name = "MODE", conflicts_with_all = ["repl", "batch", "watch"])]
+    explain: Option<Explain>,
+
+    /// Print the state of the instance on stderr after the evaluation: the
+    /// data document as the policy sees it, the heap pointer and the memory
+    /// size
+    #[arg(long, conflicts_with_all = ["repl", "batch", "watch"])]
+    dump: bool,
+
+    #[command(flatten)]
+    fail: FailArgs,
+}
+
+/// When to exit with a non-zero code, depending on the decision
+#[derive(Args, Clone, Copy)]
+struct FailArgs {
+    /// Exit with a non-zero code if the decision is undefined or `false`
+    #[arg(long, conflicts_with_all = ["repl", "batch", "watch"])]
+    fail: bool,
+
+    /// Exit with a non-zero code if the decision is defined and not `false`
+    #[arg(long, conflicts_with_all = ["fail", "repl", "batch", "watch"])]
+    fail_defined: bool,
+}
+
+impl FailArgs {
+    /// Check the result set of an evaluation
+    fn check(self, result: &serde_json::Value) -> Result<()> {
+        // The policy returns a result set, which is empty if the decision is undefined
+        let decision = result.get(0).and_then(|r| r.get("result"));
+        let passed = decision.is_some_and(|decision| decision != &serde_json::Value::Bool(false));
+
+        if self.fail && !passed {
+            match decision {
+                Some(_) => anyhow::bail!("the decision is false"),
+                None => anyhow::bail!("the decision is undefined"),
+            }
+        }
+
+        if self.fail_defined && passed {
+            anyhow::bail!("the decision is defined");
+        }
+
+        Ok(())
+    }
+}
+
+/// What to explain about the decision
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Explain {
+    /// The notes emitted by the policy through `trace`
+    Notes,
+}
+
+/// Configure the WASM runtime
+fn engine_config() -> Config {
+    EngineConfig::default().to_wasmtime()
+}
+
+/// Compile the WASM module, with an engine able to enforce the limits
+fn compile(module: &ModuleBytes, limits: &LimitsArgs) -> Result<(Engine, Module)> {
+    let mut config = engine_config();
+    limits.configure(&mut config);
+
+    let engine = Engine::new(&config)?;
+    limits.start_ticker(&engine);
+
+    let module = load_module(&engine, module)?;
+    Ok((engine, module))
+}
+
+/// Compile the WASM module, or deserialize the precompiled one, with an
+/// existing engine
+fn load_module(engine: &Engine, module: &ModuleBytes) -> Result<Module> {
+    let module = match module {
+        ModuleBytes::Wasm(module) => Module::new(engine, module)?,
+        ModuleBytes::Precompiled(module) => {
+            // SAFETY: the file is trusted to have been produced by the
+            // `precompile` subcommand, which is what this flag documents.
+            // wasmtime still checks that it was compiled with a compatible
+            // version and configuration.
+            unsafe { Module::deserialize(engine, module) }.context(
+                "could not load the precompiled module, which must be built with the same \
+                 version of this tool, and with --interruptible to use --timeout",
+            )?
+        }
+    };
+
+    Ok(module)
+}
+
+/// Instantiate the module in a new store with the given context and limits,
+/// and load the data in it
+async fn instantiate(
+    engine: &Engine,
+    module: &Module,
+    data: &serde_json::Value,
+    context: EvalContext,
+    limits: Limits,
+) -> Result<(Store<Limits>, Policy<EvalContext>)> {
+    // Create a store which will hold the module instance
+    let mut store = limits.store(engine);
+
+    // Instantiate the module
+    let runtime = Runtime::new_with_evaluation_context(&mut store, module, context)
+        .instrument(tracing::info_span!("instanciate_module"))
+        .await?;
+
+    let policy = runtime
+        .with_data(&mut store, data)
+        .instrument(tracing::info_span!("load_data"))
+        .await?;
+
+    Ok((store, policy))
+}
+
+/// Get the entrypoint to evaluate, defaulting to the default entrypoint of
+/// the module. The error lists the available entrypoints if the requested one
+/// does not exist.
+fn resolve_entrypoint<C>(policy: &Policy<C>, entrypoint: Option<&str>) -> Result<String> {
+    let entrypoints = policy.entrypoints();
+    let found = match entrypoint {
+        Some(entrypoint) => entrypoints.contains(entrypoint).then_some(entrypoint),
+        None => policy.default_entrypoint(),
+    };
+
+    if let Some(entrypoint) = found {
+        return Ok(entrypoint.to_owned());
+    }
+
+    let mut available: Vec<_> = entrypoints.into_iter().collect();
+    available.sort_unstable();
+    let available = if available.is_empty() {
+        "none".to_owned()
+    } else {
+        available.join(", ")
+    };
+
+    match entrypoint {
+        Some(entrypoint) => anyhow::bail!(
+            "could not find entrypoint {entrypoint:?}, available entrypoints: {available}"
+        ),
+        None => anyhow::bail!(
+            "the module has no default entrypoint, use --entrypoint to pick one of: {available}"
+        ),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    cli.log.init();
+
+    let result = match cli.command {
+        Some(Command::Bench(args)) => bench::run(args).await,
+        #[cfg(feature = "conformance")]
+        Some(Command::Conformance(args)) => conformance::run(args).await,
+        #[cfg(unix)]
+        Some(Command::Daemon(args)) => daemon::run(args).await,
+        Some(Command::Inspect(args)) => inspect::run(args).await,
+        Some(Command::Precompile(args)) => precompile::run(args).await,
+        Some(Command::Serve(args)) => serve::run(args).await,
+        #[cfg(feature = "testing")]
+        Some(Command::Test(args)) => test::run(args).await,
+        #[cfg(feature = "validate")]
+        Some(Command::Validate(args)) => validate::run(args).await,
+        None => eval(cli.eval).await,
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            limits::exit_code(&error)
+        }
+    }
+}
+
+/// Evaluate the policy once, in a REPL, or each time the files change
+async fn eval(args: EvalArgs) -> Result<()> {
+    if args.watch {
+        return watch::run(&args).await;
+    }
+
+    let context = args.context.build().await?;
+    let limits = args.limits;
+    let explain = args.explain;
+    let dump = args.dump;
+    let fail = args.fail;
+    let (data, input, module, entrypoint, repl, batch, profile) = (async move {
+        let data = args.data.load().await?;
+
+        let input = args.input.load().await?;
+
+        let module = args.policy.load().await?;
+
+        Ok::<_, anyhow::Error>((
+            data,
+            input,
+            module,
+            args.entrypoint,
+            args.repl,
+            args.batch,
+            args.profile,
+        ))
+    })
+    .instrument(tracing::info_span!("load_args"))
+    .await?;
+
+    // The metrics layer is used for the profiling output
+    let context = MetricsLayer::new(context);
+    let mut timings = Profile::default();
+
+    let (engine, module) = timings
+        .time(
+            "compile",
+            (async move { compile(&module, &limits) })
+                .instrument(tracing::info_span!("compile_module")),
+        )
+        .await?;
+
+    let mut store = limits.limits().store(&engine);
+
+    let runtime = timings
+        .time(
+            "instantiate",
+            Runtime::new_with_evaluation_context(&mut store, &module, context)
+                .instrument(tracing::info_span!("instanciate_module")),
+        )
+        .await?;
+
+    let policy = timings
+        .time(
+            "load data",
+            runtime
+                .with_data(&mut store, &data)
+                .instrument(tracing::info_span!("load_data")),
+        )
+        .await?;
+
+    let entrypoint = resolve_entrypoint(&policy, entrypoint.as_deref())?;
+
+    let result = async {
+        if repl {
+            return repl::run(&mut store, &policy, &entrypoint).await;
+        }
+
+        if let Some(path) = batch {
+            return batch::run(&mut store, &policy, &entrypoint, &path).await;
+        }
+
+        // Evaluate the policy
+        Limits::arm(&mut store);
+        let res = policy
+            .evaluate(&mut store, &entrypoint, &input)
+            .instrument(tracing::info_span!("evaluate"))
+            .await;
+
+        // Dump the state even if the evaluation failed, to help understand why
+        if dump {
+            eprintln!("{}", policy.dump(&mut store).await?);
+        }
+        let res: serde_json::Value = res?;
+
+        println!("{res}");
+
+        if explain == Some(Explain::Notes) {
+            let notes = policy.with_context(|ctx| ctx.notes().to_vec()).await?;
+            for note in notes {
+                eprintln!("Note {note:?}");
+            }
+        }
+
+        fail.check(&res)
+    }
+    .await;
+
+    if profile {
+        timings.print(&policy).await?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn data_documents_are_merged() {
+        let mut data = json!({"users": {"alice": {"admin": true}}, "version": 1});
+        merge(
+            &mut data,
+            json!({"users": {"bob": {"admin": false}}, "version": 1}),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            data,
+            json!({
+                "users": {"alice": {"admin": true}, "bob": {"admin": false}},
+                "version": 1,
+            })
+        );
+
+        let error = merge(
+            &mut data,
+            json!({"users": {"alice": {"admin": false}}}),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "conflicting values for data.users.alice.admin"
+        );
+
+        let error = merge(&mut data, json!([]), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "conflicting values for data");
+    }
+
+    #[test]
+    fn yaml_streams_are_arrays() {
+        let document = parse_yaml(b"users:\n  - alice\n  - bob\n").unwrap();
+        assert_eq!(document, json!({"users": ["alice", "bob"]}));
+
+        let documents = parse_yaml(b"kind: Pod\n---\nkind: Service\n").unwrap();
+        assert_eq!(documents, json!([{"kind": "Pod"}, {"kind": "Service"}]));
+
+        assert!(parse_yaml(b"users: [alice").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Precompile mode, serializing the compiled module so that it can be loaded
+//! without running the compiler
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::Args;
+use tracing::Instrument;
+use wasmtime::Engine;
+
+use crate::{ModuleBytes, PolicyArgs};
+
+/// Arguments of the `precompile` subcommand
+#[derive(Args)]
+pub struct PrecompileArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    /// Where to write the precompiled module
+    #[arg(short, long, value_name = "PATH")]
+    output: Utf8PathBuf,
+
+    /// Compile the module so that its evaluations can be interrupted, which
+    /// is required to use it with `--timeout`
+    #[arg(long)]
+    interruptible: bool,
+}
+
+/// Compile the module and write it to the output file
+pub async fn run(args: PrecompileArgs) -> Result<()> {
+    let ModuleBytes::Wasm(module) = args.policy.load().await? else {
+        anyhow::bail!("the module is already precompiled");
+    };
+
+    let mut config = crate::engine_config();
+    config.epoch_interruption(args.interruptible);
+    let engine = Engine::new(&config)?;
+
+    let compiled = (async move { engine.precompile_module(&module) })
+        .instrument(tracing::info_span!("compile_module"))
+        .await?;
+
+    tokio::fs::write(&args.output, compiled)
+        .await
+        .with_context(|| format!("could not write {}", args.output))?;
+
+    Ok(())
+}
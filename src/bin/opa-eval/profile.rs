@@ -0,0 +1,86 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Profiling output, breaking down where the time was spent
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use opa_wasm::{DurationStats, MetricsLayer, Policy};
+
+/// The durations of the phases of a run
+#[derive(Default)]
+pub struct Profile {
+    /// The name and duration of each phase, in order
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Profile {
+    /// Run the future, recording how long it took as the given phase
+    pub async fn time<F: Future>(&mut self, phase: &'static str, future: F) -> F::Output {
+        let start = Instant::now();
+        let output = future.await;
+        self.phases.push((phase, start.elapsed()));
+        output
+    }
+
+    /// Print the phases, then the evaluation and builtin durations gathered
+    /// by the metrics layer of the policy, on stderr
+    pub async fn print<C>(&self, policy: &Policy<MetricsLayer<C>>) -> Result<()> {
+        let (evaluations, builtins) = policy
+            .with_context(|ctx| {
+                let evaluations = sorted(ctx.evaluation_stats().iter());
+                let builtins = sorted(ctx.builtin_stats().iter());
+                (evaluations, builtins)
+            })
+            .await?;
+
+        eprintln!("Profile:");
+        for (phase, duration) in &self.phases {
+            eprintln!("  {phase:<40} {:>12}", format!("{duration:?}"));
+        }
+
+        for (entrypoint, stats) in &evaluations {
+            print_stats(&format!("evaluate {entrypoint}"), stats);
+        }
+
+        for (builtin, stats) in &builtins {
+            print_stats(&format!("builtin {builtin}"), stats);
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect the stats, sorted by decreasing total duration
+fn sorted<'a>(
+    stats: impl Iterator<Item = (&'a String, &'a DurationStats)>,
+) -> Vec<(String, DurationStats)> {
+    let mut stats: Vec<_> = stats.map(|(name, stats)| (name.clone(), *stats)).collect();
+    stats.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.total));
+    stats
+}
+
+/// Print the aggregated durations of an entrypoint or a builtin
+fn print_stats(name: &str, stats: &DurationStats) {
+    eprintln!(
+        "  {name:<40} {:>12}  ({} calls, max {:?})",
+        format!("{:?}", stats.total),
+        stats.count,
+        stats.max,
+    );
+}
@@ -0,0 +1,88 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interactive mode, evaluating inputs read line by line
+
+use std::io::{IsTerminal, Write};
+
+use anyhow::{Context, Result};
+use opa_wasm::{EvaluationContext, Policy};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use wasmtime::Store;
+
+use crate::limits::Limits;
+
+/// Parse a line, which is either a JSON input evaluated with the default
+/// entrypoint, or an entrypoint name optionally followed by a JSON input
+fn parse_line<'a>(
+    line: &'a str,
+    default_entrypoint: &'a str,
+) -> Result<(&'a str, serde_json::Value)> {
+    if let Ok(input) = serde_json::from_str(line) {
+        return Ok((default_entrypoint, input));
+    }
+
+    let (entrypoint, input) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let input = input.trim();
+    let input = if input.is_empty() {
+        serde_json::Value::Object(serde_json::Map::default())
+    } else {
+        serde_json::from_str(input).context("invalid JSON input")?
+    };
+
+    Ok((entrypoint, input))
+}
+
+/// Read inputs from stdin line by line, and print the decision for each of
+/// them, until stdin is closed
+pub async fn run<C: EvaluationContext>(
+    store: &mut Store<Limits>,
+    policy: &Policy<C>,
+    entrypoint: &str,
+) -> Result<()> {
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        if interactive {
+            eprint!("> ");
+            std::io::stderr().flush()?;
+        }
+
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let result = async {
+            let (entrypoint, input) = parse_line(line, entrypoint)?;
+            Limits::arm(store);
+            let result: serde_json::Value =
+                policy.evaluate(&mut *store, entrypoint, &input).await?;
+            Ok::<_, anyhow::Error>(result)
+        }
+        .await;
+
+        match result {
+            Ok(result) => println!("{result}"),
+            Err(error) => eprintln!("error: {error:#}"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serve mode, exposing the policy through a subset of the OPA REST API
+
+use std::{net::SocketAddr, num::NonZeroUsize, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use clap::Args;
+use opa_wasm::{BundleFetcher, PolicyPool, RestApi};
+use tracing::Instrument;
+use wasmtime::{Engine, Module};
+
+use crate::{
+    limits::{parse_duration, Limits, LimitsArgs},
+    ContextArgs, DataArgs, ModuleBytes, PolicyArgs,
+};
+
+/// Arguments of the `serve` subcommand
+#[derive(Args)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    data: DataArgs,
+
+    #[command(flatten)]
+    context: ContextArgs,
+
+    #[command(flatten)]
+    limits: LimitsArgs,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8181")]
+    addr: SocketAddr,
+
+    /// Number of policy instances evaluating requests concurrently. Defaults
+    /// to the number of CPUs.
+    #[arg(long)]
+    pool_size: Option<NonZeroUsize>,
+
+    /// Download the bundle again at this interval, e.g. `30s`, and serve its
+    /// new revisions. Requires the bundle to be a URL.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    bundle_poll: Option<Duration>,
+}
+
+/// The pool of policy instances evaluating the requests
+pub type Pool = PolicyPool<crate::EvalContext, Limits>;
+
+/// Compile the module, and instantiate `size` instances of it with the data,
+/// defaulting to the number of CPUs
+async fn load(
+    module: &ModuleBytes,
+    data: &serde_json::Value,
+    context: &ContextArgs,
+    limits: &LimitsArgs,
+    size: Option<NonZeroUsize>,
+) -> Result<(Engine, Pool)> {
+    let (engine, module) = (async move { crate::compile(module, limits) })
+        .instrument(tracing::info_span!("compile_module"))
+        .await?;
+
+    let size = size
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN);
+
+    let pool = instantiate(&engine, &module, data, context, limits, size).await?;
+    Ok((engine, pool))
+}
+
+/// Instantiate the module `size` times, with the data loaded, arming the
+/// limits before each evaluation
+async fn instantiate(
+    engine: &Engine,
+    module: &Module,
+    data: &serde_json::Value,
+    context: &ContextArgs,
+    limits: &LimitsArgs,
+    size: NonZeroUsize,
+) -> Result<Pool> {
+    // The contexts are built upfront, as building them is asynchronous
+    let mut contexts = Vec::with_capacity(size.get());
+    for _ in 0..size.get() {
+        contexts.push(context.build().await?);
+    }
+    let mut contexts = contexts.into_iter();
+
+    let pool = PolicyPool::instantiate_with_stores(
+        module,
+        data,
+        size,
+        || limits.limits().store(engine),
+        || {
+            contexts
+                .next()
+                .expect("a context was built for each instance")
+        },
+    )
+    .instrument(tracing::info_span!("instanciate_module"))
+    .await?;
+
+    Ok(pool.before_evaluation(Limits::arm))
+}
+
+/// Download the bundle every `interval`, and replace the instances of the
+/// pool each time it changed. Failures are logged, and the previous version
+/// keeps being served.
+pub async fn poll_bundle(
+    pool: Arc<Pool>,
+    engine: Engine,
+    mut fetcher: BundleFetcher,
+    interval: Duration,
+    data: serde_json::Value,
+    context: ContextArgs,
+    limits: LimitsArgs,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately, and the bundle was just loaded
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let bundle = match fetcher.fetch().await {
+            Ok(Some(bundle)) => bundle,
+            Ok(None) => continue,
+            Err(error) => {
+                tracing::warn!(
+                    url = fetcher.url(),
+                    "could not download the bundle: {error:#}"
+                );
+                continue;
+            }
+        };
+
+        let revision = bundle.manifest.and_then(|manifest| manifest.revision);
+        let module = ModuleBytes::Wasm(bundle.policy);
+        let reloaded = async {
+            let module = crate::load_module(&engine, &module)?;
+            let size = NonZeroUsize::new(pool.size()).unwrap_or(NonZeroUsize::MIN);
+            let instances = instantiate(&engine, &module, &data, &context, &limits, size).await?;
+            pool.replace(instances).await
+        };
+        match reloaded.await {
+            Ok(()) => tracing::info!(url = fetcher.url(), ?revision, "loaded a new bundle"),
+            Err(error) => {
+                tracing::warn!(url = fetcher.url(), "could not load the bundle: {error:#}");
+            }
+        }
+    }
+}
+
+/// Load the policy and data, and start polling the bundle for new versions
+/// if asked to
+pub async fn start(
+    policy: &PolicyArgs,
+    bundle_poll: Option<Duration>,
+    data: &DataArgs,
+    context: ContextArgs,
+    limits: LimitsArgs,
+    size: Option<NonZeroUsize>,
+) -> Result<Arc<Pool>> {
+    let (module, fetcher) = policy.load_with_fetcher().await?;
+    if bundle_poll.is_some() && fetcher.is_none() {
+        anyhow::bail!("--bundle-poll requires the bundle to be a URL");
+    }
+
+    let data = data.load().await?;
+    let (engine, pool) = load(&module, &data, &context, &limits, size).await?;
+    let pool = Arc::new(pool);
+
+    if let (Some(interval), Some(fetcher)) = (bundle_poll, fetcher) {
+        tokio::spawn(poll_bundle(
+            pool.clone(),
+            engine,
+            fetcher,
+            interval,
+            data,
+            context,
+            limits,
+        ));
+    }
+
+    Ok(pool)
+}
+
+/// Load the policy, instantiate the pool and serve requests until interrupted
+pub async fn run(args: ServeArgs) -> Result<()> {
+    let pool = start(
+        &args.policy,
+        args.bundle_poll,
+        &args.data,
+        args.context,
+        args.limits,
+        args.pool_size,
+    )
+    .await?;
+    let pool_size = pool.size();
+
+    let app = RestApi::new(pool).router();
+
+    let listener = tokio::net::TcpListener::bind(args.addr).await?;
+    tracing::info!(addr = %listener.local_addr()?, pool_size, "listening");
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Test mode, running the Rego unit tests compiled in the policy
+
+use anyhow::Result;
+use clap::Args;
+use opa_wasm::testing::TestBundle;
+
+use crate::{DataArgs, ModuleBytes, PolicyArgs};
+
+/// Arguments of the `test` subcommand
+#[derive(Args)]
+pub struct TestArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    data: DataArgs,
+}
+
+/// Run the tests, print their outcomes, and fail if any of them failed
+pub async fn run(args: TestArgs) -> Result<()> {
+    let ModuleBytes::Wasm(module) = args.policy.load().await? else {
+        anyhow::bail!("tests can't run on a precompiled module");
+    };
+    let bundle = TestBundle::from_module(&module)?.with_data(args.data.load().await?);
+
+    let report = bundle.run_tests().await?;
+    println!("{report}");
+
+    anyhow::ensure!(report.is_success(), "some tests failed");
+    Ok(())
+}
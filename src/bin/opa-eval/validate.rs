@@ -0,0 +1,40 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validate mode, checking the module without loading it
+
+use anyhow::Result;
+use clap::Args;
+
+use crate::{ModuleBytes, PolicyArgs};
+
+/// Arguments of the `validate` subcommand
+#[derive(Args)]
+pub struct ValidateArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+}
+
+/// Check the module, print the report, and fail if it found problems
+pub async fn run(args: ValidateArgs) -> Result<()> {
+    let ModuleBytes::Wasm(module) = args.policy.load().await? else {
+        anyhow::bail!("a precompiled module can't be validated");
+    };
+
+    let report = opa_wasm::validate(&module);
+    println!("{report}");
+
+    anyhow::ensure!(report.is_valid(), "the module is invalid");
+    Ok(())
+}
@@ -0,0 +1,102 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watch mode, evaluating the policy again each time one of the files it
+//! depends on changes
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use camino::Utf8Path;
+use tracing::Instrument;
+
+use crate::{limits::Limits, EvalArgs};
+
+/// How often the files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Get the modification time of each file, or `None` if it can't be read,
+/// for example while an editor is replacing it
+async fn snapshot(paths: &[&Utf8Path]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(paths.len());
+    for path in paths {
+        let modified = tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        times.push(modified);
+    }
+    times
+}
+
+/// Load everything from scratch and evaluate the policy
+async fn evaluate(args: &EvalArgs) -> Result<serde_json::Value> {
+    let module = args.policy.load().await?;
+    let data = args.data.load().await?;
+    let input = args.input.load().await?;
+
+    let (engine, module) = crate::compile(&module, &args.limits)?;
+    let (mut store, policy) = crate::instantiate(
+        &engine,
+        &module,
+        &data,
+        args.context.build().await?,
+        args.limits.limits(),
+    )
+    .await?;
+
+    let entrypoint = crate::resolve_entrypoint(&policy, args.entrypoint.as_deref())?;
+    Limits::arm(&mut store);
+    policy
+        .evaluate(&mut store, &entrypoint, &input)
+        .instrument(tracing::info_span!("evaluate"))
+        .await
+}
+
+/// Evaluate the policy, then again each time one of the files changes, until
+/// interrupted
+pub async fn run(args: &EvalArgs) -> Result<()> {
+    let paths: Vec<&Utf8Path> = [
+        args.policy.module.as_deref(),
+        args.policy.bundle.as_deref(),
+        args.policy.precompiled.as_deref(),
+        args.input.input_path.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(args.data.data_path.iter().map(AsRef::as_ref))
+    .collect();
+
+    let mut last = snapshot(&paths).await;
+    loop {
+        match evaluate(args).await {
+            Ok(result) => println!("{result}"),
+            Err(error) => eprintln!("error: {error:#}"),
+        }
+
+        // Wait for a change, and for the files to settle down after it, so
+        // that partially written files are not picked up
+        let mut changed = false;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let current = snapshot(&paths).await;
+            if current != last {
+                last = current;
+                changed = true;
+            } else if changed {
+                break;
+            }
+        }
+    }
+}
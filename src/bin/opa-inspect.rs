@@ -0,0 +1,64 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![deny(clippy::pedantic)]
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use clap::Parser;
+use opa_wasm::{Bundle, Runtime};
+use wasmtime::{Config, Engine, Module, Store};
+
+/// Dumps the manifest, files, ABI version, entrypoints and builtins of an OPA
+/// bundle as JSON, without evaluating anything
+#[derive(Parser)]
+struct Cli {
+    /// Path to the OPA bundle
+    bundle: Utf8PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let bundle = Bundle::read(&cli.bundle).await?;
+
+    // Configure the WASM runtime
+    let mut config = Config::new();
+    config.async_support(true);
+
+    let engine = Engine::new(&config)?;
+
+    // Load the policy WASM module
+    let module = Module::new(&engine, &bundle.policy)?;
+
+    // Create a store which will hold the module instance
+    let mut store = Store::new(&engine, ());
+
+    // Instantiate the module, without failing on unsupported builtins
+    let runtime = Runtime::inspect(&mut store, &module).await?;
+    let info = runtime.module_info()?;
+
+    let output = serde_json::json!({
+        "manifest": bundle.manifest,
+        "files": bundle.files,
+        "abi_version": info.abi_version.to_string(),
+        "entrypoints": info.entrypoints,
+        "builtins": info.builtins,
+        "unsupported_builtins": info.unsupported_builtins,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&output)?);
+
+    Ok(())
+}
@@ -0,0 +1,194 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bindings generated with `UniFFI`, to evaluate policies from Kotlin, Swift or
+//! Python.
+//!
+//! Build the crate as a `cdylib`, then generate the bindings out of the shared
+//! library:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features uniffi --crate-type cdylib
+//! cargo run --features uniffi-bindgen --bin uniffi-bindgen -- \
+//!     generate --library target/release/libopa_wasm.so --language kotlin --out-dir out
+//! ```
+//!
+//! The methods block the calling thread until the evaluation completes, so
+//! mobile apps should call them off their main thread.
+
+use std::{num::NonZeroUsize, sync::Arc};
+
+use anyhow::Context;
+
+use crate::PolicyAgent;
+
+/// An error raised by a [`PolicyEvaluator`]
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum PolicyError {
+    /// The policy could not be loaded or evaluated
+    #[error("{message}")]
+    Failed {
+        /// What went wrong, with its causes
+        message: String,
+    },
+}
+
+impl From<anyhow::Error> for PolicyError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Failed {
+            message: format!("{error:#}"),
+        }
+    }
+}
+
+/// A policy, evaluated with JSON-encoded inputs and results
+#[derive(uniffi::Object)]
+pub struct PolicyEvaluator {
+    /// The runtime driving the evaluations to completion
+    executor: tokio::runtime::Runtime,
+
+    /// The policy and its instances
+    agent: PolicyAgent,
+}
+
+impl std::fmt::Debug for PolicyEvaluator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyEvaluator")
+            .field("agent", &self.agent)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Create the runtime driving the evaluations
+fn executor() -> anyhow::Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Parse the JSON-encoded data document, defaulting to an empty object
+fn parse_data(data: Option<String>) -> anyhow::Result<serde_json::Value> {
+    data.map_or_else(
+        || Ok(serde_json::Value::Object(serde_json::Map::new())),
+        |data| serde_json::from_str(&data).context("invalid data"),
+    )
+}
+
+/// Get the number of instances, one if zero
+fn instances(instances: u32) -> NonZeroUsize {
+    usize::try_from(instances)
+        .ok()
+        .and_then(NonZeroUsize::new)
+        .unwrap_or(NonZeroUsize::MIN)
+}
+
+#[uniffi::export]
+impl PolicyEvaluator {
+    /// Compile a WASM module and instantiate it with the given JSON-encoded
+    /// data document, on the given number of instances
+    ///
+    /// # Errors
+    ///
+    /// If the data is not valid JSON, or if the module could not be compiled
+    /// or instantiated
+    #[uniffi::constructor]
+    pub fn from_module(
+        module: Vec<u8>,
+        data: Option<String>,
+        instances: u32,
+    ) -> Result<Arc<Self>, PolicyError> {
+        let executor = executor()?;
+        let builder = PolicyAgent::builder()
+            .module(module)
+            .data(parse_data(data)?)
+            .instances(self::instances(instances));
+        let agent = executor.block_on(builder.build())?;
+        Ok(Arc::new(Self { executor, agent }))
+    }
+
+    /// Evaluate an entrypoint with the JSON-encoded input, returning the
+    /// JSON-encoded result set
+    ///
+    /// # Errors
+    ///
+    /// If the input is not valid JSON, or if the evaluation failed
+    pub fn evaluate(&self, entrypoint: &str, input: &str) -> Result<String, PolicyError> {
+        let input: serde_json::Value = serde_json::from_str(input).context("invalid input")?;
+        let result: serde_json::Value = self
+            .executor
+            .block_on(self.agent.decide(entrypoint, &input))?;
+        Ok(result.to_string())
+    }
+
+    /// Load the policy again, returning whether a new one was activated
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be loaded or instantiated, in which case the
+    /// previous one is still evaluated
+    pub fn reload(&self) -> Result<bool, PolicyError> {
+        Ok(self.executor.block_on(self.agent.reload())?)
+    }
+
+    /// The revision of the bundle currently evaluated, if any
+    pub fn revision(&self) -> Option<String> {
+        self.agent.revision()
+    }
+}
+
+#[cfg(feature = "loader")]
+#[uniffi::export]
+impl PolicyEvaluator {
+    /// Load the policy of the bundle at the given path and instantiate it with
+    /// the given JSON-encoded data document, on the given number of
+    /// instances. [`reload`](Self::reload) reads the bundle again.
+    ///
+    /// # Errors
+    ///
+    /// If the data is not valid JSON, or if the bundle could not be read,
+    /// compiled or instantiated
+    #[uniffi::constructor]
+    pub fn from_bundle(
+        path: String,
+        data: Option<String>,
+        instances: u32,
+    ) -> Result<Arc<Self>, PolicyError> {
+        let executor = executor()?;
+        let builder = PolicyAgent::builder()
+            .bundle_file(path)
+            .data(parse_data(data)?)
+            .instances(self::instances(instances));
+        let agent = executor.block_on(builder.build())?;
+        Ok(Arc::new(Self { executor, agent }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_reported() {
+        let error = PolicyEvaluator::from_module(b"\0asm\x01\0\0\0".to_vec(), None, 1).unwrap_err();
+        assert_eq!(error.to_string(), "missing global opa_wasm_abi_version");
+
+        let error =
+            PolicyEvaluator::from_module(b"\0asm\x01\0\0\0".to_vec(), Some("{".to_owned()), 1)
+                .unwrap_err();
+        assert!(error.to_string().starts_with("invalid data: "));
+
+        assert_eq!(instances(0), NonZeroUsize::MIN);
+        assert_eq!(instances(4).get(), 4);
+    }
+}
@@ -17,7 +17,7 @@
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 
 /// Serializes the input string into base64url encoding without padding.
-#[tracing::instrument]
+#[cfg_attr(feature = "detailed-tracing", tracing::instrument)]
 pub fn encode_no_pad(x: String) -> String {
     URL_SAFE_NO_PAD.encode(&x)
 }
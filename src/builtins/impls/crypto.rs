@@ -30,7 +30,10 @@ pub mod hmac {
     #[cfg(feature = "crypto-md5-builtins")]
     /// Returns a string representing the MD5 HMAC of the input message using
     /// the input key.
-    #[tracing::instrument(name = "crypto.hmac.md5", err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.hmac.md5", err)
+    )]
     pub fn md5(x: String, key: String) -> Result<String> {
         let mut mac = Hmac::<md5::Md5>::new_from_slice(key.as_bytes())?;
         mac.update(x.as_bytes());
@@ -41,7 +44,10 @@ pub mod hmac {
     #[cfg(feature = "crypto-sha1-builtins")]
     /// Returns a string representing the SHA1 HMAC of the input message using
     /// the input key.
-    #[tracing::instrument(name = "crypto.hmac.sha1", err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.hmac.sha1", err)
+    )]
     pub fn sha1(x: String, key: String) -> Result<String> {
         let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key.as_bytes())?;
         mac.update(x.as_bytes());
@@ -52,7 +58,10 @@ pub mod hmac {
     #[cfg(feature = "crypto-sha2-builtins")]
     /// Returns a string representing the SHA256 HMAC of the input message using
     /// the input key.
-    #[tracing::instrument(name = "crypto.hmac.sha256", err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.hmac.sha256", err)
+    )]
     pub fn sha256(x: String, key: String) -> Result<String> {
         let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes())?;
         mac.update(x.as_bytes());
@@ -63,7 +72,10 @@ pub mod hmac {
     #[cfg(feature = "crypto-sha2-builtins")]
     /// Returns a string representing the SHA512 HMAC of the input message using
     /// the input key.
-    #[tracing::instrument(name = "crypto.hmac.sha512", err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.hmac.sha512", err)
+    )]
     pub fn sha512(x: String, key: String) -> Result<String> {
         let mut mac = Hmac::<sha2::Sha512>::new_from_slice(key.as_bytes())?;
         mac.update(x.as_bytes());
@@ -87,7 +99,7 @@ pub mod digest {
     #[cfg(feature = "crypto-md5-builtins")]
     /// Returns a string representing the input string hashed with the MD5
     /// function
-    #[tracing::instrument(name = "crypto.md5")]
+    #[cfg_attr(feature = "detailed-tracing", tracing::instrument(name = "crypto.md5"))]
     pub fn md5(x: String) -> String {
         let mut hasher = md5::Md5::new();
         hasher.update(x.as_bytes());
@@ -98,7 +110,10 @@ pub mod digest {
     #[cfg(all(feature = "crypto-digest-builtins", feature = "crypto-sha1-builtins"))]
     /// Returns a string representing the input string hashed with the SHA1
     /// function
-    #[tracing::instrument(name = "crypto.sha1")]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.sha1")
+    )]
     pub fn sha1(x: String) -> String {
         let mut hasher = sha1::Sha1::new();
         hasher.update(x.as_bytes());
@@ -109,7 +124,10 @@ pub mod digest {
     #[cfg(all(feature = "crypto-digest-builtins", feature = "crypto-sha2-builtins"))]
     /// Returns a string representing the input string hashed with the SHA256
     /// function
-    #[tracing::instrument(name = "crypto.sha256")]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "crypto.sha256")
+    )]
     pub fn sha256(x: String) -> String {
         let mut hasher = sha2::Sha256::new();
         hasher.update(x.as_bytes());
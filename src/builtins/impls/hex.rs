@@ -17,7 +17,10 @@
 use anyhow::Result;
 
 /// Deserializes the hex-encoded input string.
-#[tracing::instrument(name = "hex.decode", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "hex.decode", err)
+)]
 pub fn decode(x: String) -> Result<String> {
     let decoded = hex::decode(x)?;
     let str = String::from_utf8(decoded)?;
@@ -25,7 +28,7 @@ pub fn decode(x: String) -> Result<String> {
 }
 
 /// Serializes the input string using hex-encoding.
-#[tracing::instrument(name = "hex.encode")]
+#[cfg_attr(feature = "detailed-tracing", tracing::instrument(name = "hex.encode"))]
 pub fn encode(x: String) -> String {
     hex::encode(x.as_bytes())
 }
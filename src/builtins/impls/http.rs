@@ -1,4 +1,4 @@
-// Copyright 2022 The Matrix.org Foundation C.I.C.
+// Copyright 2022-2024 The Matrix.org Foundation C.I.C.
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
 // you may not use this file except in compliance with the License.
@@ -14,10 +14,449 @@
 
 //! Builtins used to make HTTP request
 
-use anyhow::{bail, Result};
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{Capability, EvaluationContext, HaltError, HttpSendOptions};
+
+/// The timeout used when the request does not specify one, matching OPA's
+/// default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The initial delay between two attempts, doubled on each retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// The maximum delay between two attempts
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A request timeout, either as a number of nanoseconds or as a duration
+/// string like `"5s"`
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Timeout {
+    /// A number of nanoseconds
+    Nanoseconds(u64),
+
+    /// A duration string like `"5s"`
+    Duration(String),
+}
+
+impl Timeout {
+    /// Convert the timeout to a [`Duration`]
+    fn to_duration(&self) -> Result<Duration> {
+        match self {
+            Self::Nanoseconds(ns) => Ok(Duration::from_nanos(*ns)),
+            Self::Duration(duration) => {
+                duration_str::parse(duration.as_str()).map_err(|e| anyhow!("{e}"))
+            }
+        }
+    }
+}
+
+/// Returns `true`, used as a default value for serde
+const fn default_true() -> bool {
+    true
+}
+
+/// The request object passed to `http.send`
+#[derive(Deserialize, Debug)]
+pub struct HttpRequest {
+    /// The HTTP method to use
+    method: String,
+
+    /// The URL to send the request to
+    url: String,
+
+    /// The headers to set on the request
+    #[serde(default)]
+    headers: HashMap<String, String>,
+
+    /// A JSON body to send, serialized before sending
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+
+    /// A raw body to send, used if no JSON body is set
+    #[serde(default)]
+    raw_body: Option<String>,
+
+    /// Whether redirects should be followed
+    #[serde(default)]
+    enable_redirect: bool,
+
+    /// Decode the response body as JSON, regardless of its content type
+    #[serde(default)]
+    force_json_decode: bool,
+
+    /// The timeout of each attempt
+    #[serde(default)]
+    timeout: Option<Timeout>,
+
+    /// The number of times the request is retried on network errors
+    #[serde(default)]
+    max_retry_attempts: u32,
+
+    /// Whether network errors should fail the evaluation, or be reported in
+    /// the response object
+    #[serde(default = "default_true")]
+    raise_error: bool,
+}
+
+/// Convert the OPA request object to a [`http::Request`]
+///
+/// # Errors
+///
+/// If the method, the URL or one of the headers is invalid, or if the JSON body
+/// failed to serialize
+pub fn convert_opa_req_to_http_req(request: &HttpRequest) -> Result<http::Request<String>> {
+    let method = http::Method::from_bytes(request.method.to_uppercase().as_bytes())
+        .context("invalid HTTP method")?;
+
+    let mut builder = http::Request::builder()
+        .method(method)
+        .uri(request.url.as_str());
+
+    let mut has_content_type = false;
+    for (name, value) in &request.headers {
+        has_content_type |= name.eq_ignore_ascii_case("content-type");
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+
+    let body = if let Some(body) = &request.body {
+        if !has_content_type {
+            builder = builder.header(http::header::CONTENT_TYPE, "application/json");
+        }
+        serde_json::to_string(body).context("failed to serialize request body")?
+    } else {
+        request.raw_body.clone().unwrap_or_default()
+    };
+
+    builder.body(body).context("invalid HTTP request")
+}
+
+/// Convert a [`http::Response`] to the OPA response object
+fn convert_http_resp_to_opa_resp(
+    response: http::Response<String>,
+    force_json_decode: bool,
+) -> serde_json::Value {
+    let status = response.status();
+    let status_text = match status.canonical_reason() {
+        Some(reason) => format!("{} {reason}", status.as_u16()),
+        None => status.as_u16().to_string(),
+    };
+
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in response.headers() {
+        headers
+            .entry(name.as_str().to_owned())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+
+    let is_json = force_json_decode
+        || response
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("json"));
+
+    let raw_body = response.into_body();
+    let body = if is_json {
+        serde_json::from_str(&raw_body).unwrap_or(serde_json::Value::Null)
+    } else {
+        serde_json::Value::Null
+    };
+
+    serde_json::json!({
+        "status": status_text,
+        "status_code": status.as_u16(),
+        "headers": headers,
+        "body": body,
+        "raw_body": raw_body,
+    })
+}
+
+/// Send the request, retrying on errors, and clamping each attempt to the
+/// remaining evaluation budget
+async fn send_with_retries<C: EvaluationContext>(
+    ctx: &mut C,
+    request: &HttpRequest,
+) -> Result<http::Response<String>> {
+    let timeout = match &request.timeout {
+        Some(timeout) => timeout.to_duration()?,
+        None => DEFAULT_TIMEOUT,
+    };
+
+    let mut attempt = 0;
+    loop {
+        let timeout = match ctx.remaining_budget() {
+            Some(budget) if budget.is_zero() => bail!("evaluation deadline exceeded"),
+            Some(budget) => timeout.min(budget),
+            None => timeout,
+        };
+
+        let options = HttpSendOptions {
+            timeout: Some(timeout),
+            enable_redirect: request.enable_redirect,
+        };
+
+        let mut http_request = convert_opa_req_to_http_req(request)?;
+        ctx.inject_http_headers(http_request.headers_mut());
+
+        let error = match ctx.send_http(http_request, options).await {
+            Ok(response) => return Ok(response),
+            Err(error) if attempt >= request.max_retry_attempts => return Err(error),
+            Err(error) => error,
+        };
+
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(RETRY_MAX_DELAY);
+        let delay = match ctx.remaining_budget() {
+            Some(budget) if budget <= delay => return Err(error),
+            _ => delay,
+        };
+
+        tracing::warn!(%error, attempt, "http.send failed, retrying");
+        ctx.sleep(delay).await;
+        attempt += 1;
+    }
+}
 
 /// Returns a HTTP response to the given HTTP request.
-#[tracing::instrument(name = "http.send", err)]
-pub fn send(request: serde_json::Value) -> Result<serde_json::Value> {
-    bail!("not implemented");
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "http.send", skip(ctx), err)
+)]
+pub async fn send<C: EvaluationContext>(
+    ctx: &mut C,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    if !ctx.capability_enabled(Capability::Http) {
+        bail!("http.send: outgoing HTTP requests are disabled");
+    }
+
+    // Identical requests return the same response during an evaluation
+    let cache_key = ("http.send", &request);
+    if let Some(response) = ctx.cache_get(&cache_key)? {
+        return Ok(response);
+    }
+
+    let parsed = HttpRequest::deserialize(&request).context("invalid http.send request")?;
+
+    let response = match send_with_retries(ctx, &parsed).await {
+        Ok(response) => convert_http_resp_to_opa_resp(response, parsed.force_json_decode),
+        // Like in OPA, this fails the evaluation even without strict builtin
+        // errors
+        Err(error) if parsed.raise_error => {
+            return Err(error.context(HaltError("http.send failed".to_owned())))
+        }
+        Err(error) => serde_json::json!({
+            "status_code": 0,
+            "error": {
+                "code": "eval_http_send_network_error",
+                "message": error.to_string(),
+            },
+        }),
+    };
+
+    ctx.cache_set(&cache_key, &response)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{DefaultContext, HttpFault, MockResponse, RequestMatcher, TestContext};
+
+    /// A context which records the requests it sends, injects a
+    /// `traceparent` header, and always responds with a static JSON body
+    struct RecordingContext<C> {
+        inner: C,
+        requests: Vec<(http::Request<String>, HttpSendOptions)>,
+    }
+
+    impl<C: EvaluationContext> EvaluationContext for RecordingContext<C> {
+        crate::layers::forward!(
+            rng,
+            now,
+            evaluation_start,
+            evaluation_end,
+            cache,
+            capability_enabled,
+            records,
+            deadline,
+            resolve_jwt_key,
+            runtime_info,
+            messages,
+            secrets_provider,
+        );
+
+        fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+            headers.insert(
+                "traceparent",
+                http::HeaderValue::from_static(
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ),
+            );
+        }
+
+        async fn send_http(
+            &mut self,
+            request: http::Request<String>,
+            options: HttpSendOptions,
+        ) -> Result<http::Response<String>> {
+            self.requests.push((request, options));
+            Ok(http::Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"hello": "world"}"#.to_owned())?)
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_is_clamped_to_the_evaluation_budget() {
+        let mut ctx = RecordingContext {
+            inner: DefaultContext::builder()
+                .evaluation_timeout(Duration::from_secs(1))
+                .build(),
+            requests: Vec::new(),
+        };
+        ctx.evaluation_start();
+
+        let request = serde_json::json!({
+            "method": "get",
+            "url": "https://example.com/",
+            "timeout": "10s",
+        });
+        let response = send(&mut ctx, request.clone()).await.unwrap();
+        assert_eq!(response["status_code"], 200);
+        assert_eq!(response["body"]["hello"], "world");
+
+        // The second call is served from the cache
+        send(&mut ctx, request).await.unwrap();
+        assert_eq!(ctx.requests.len(), 1);
+        let (request, options) = &ctx.requests[0];
+        assert!(options.timeout.unwrap() <= Duration::from_secs(1));
+
+        // The context injected its headers
+        assert!(request.headers().contains_key("traceparent"));
+    }
+
+    #[tokio::test]
+    async fn test_context_serves_mocked_responses() {
+        let mut ctx = TestContext::default();
+        ctx.mock_http(
+            RequestMatcher::new("https://example.com/users/1").method(http::Method::GET),
+            MockResponse::json(http::StatusCode::OK, &serde_json::json!({"name": "alice"})),
+        );
+        ctx.evaluation_start();
+
+        let request = serde_json::json!({"method": "get", "url": "https://example.com/users/1"});
+        let response = send(&mut ctx, request).await.unwrap();
+        assert_eq!(response["status_code"], 200);
+        assert_eq!(response["body"]["name"], "alice");
+
+        let request = serde_json::json!({"method": "post", "url": "https://example.com/users/1"});
+        assert!(send(&mut ctx, request).await.is_err());
+        assert_eq!(ctx.unmatched_requests().len(), 1);
+        assert_eq!(ctx.unmatched_requests()[0].method(), http::Method::POST);
+    }
+
+    #[tokio::test]
+    async fn test_context_injects_faults() {
+        let mut ctx = TestContext::default();
+        ctx.mock_http(
+            RequestMatcher::new("https://example.com/users/1"),
+            MockResponse::json(http::StatusCode::OK, &serde_json::json!({"name": "alice"})),
+        );
+        ctx.inject_http_faults(
+            "https://example.com/users/*",
+            [HttpFault::ConnectionRefused, HttpFault::Timeout],
+        );
+        ctx.inject_http_fault(
+            "https://*.invalid/*",
+            HttpFault::Status(http::StatusCode::SERVICE_UNAVAILABLE),
+        );
+
+        // Both faults are injected in the first attempts, then the request is
+        // answered by the mock
+        ctx.evaluation_start();
+        let request = serde_json::json!({
+            "method": "get",
+            "url": "https://example.com/users/1",
+            "max_retry_attempts": 2,
+        });
+        let response = send(&mut ctx, request).await.unwrap();
+        assert_eq!(response["status_code"], 200);
+        assert_eq!(ctx.slept(), Duration::from_millis(300));
+
+        // The faults were consumed, and other URLs never had any
+        ctx.evaluation_start();
+        let request = serde_json::json!({"method": "get", "url": "https://example.com/users/1"});
+        assert_eq!(send(&mut ctx, request).await.unwrap()["status_code"], 200);
+        let request = serde_json::json!({"method": "get", "url": "https://example.com/"});
+        let error = send(&mut ctx, request).await.unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "http.send failed: no mocked response for GET https://example.com/"
+        );
+
+        // Server errors are responses, which are not retried
+        ctx.evaluation_start();
+        let request = serde_json::json!({"method": "get", "url": "https://api.invalid/status"});
+        let response = send(&mut ctx, request).await.unwrap();
+        assert_eq!(response["status_code"], 503);
+
+        // Delays are recorded instead of slept
+        ctx.inject_http_faults(
+            "https://example.com/users/1",
+            [HttpFault::Delay(Duration::from_secs(60))],
+        );
+        ctx.evaluation_start();
+        let request = serde_json::json!({
+            "method": "get",
+            "url": "https://example.com/users/1",
+            "timeout": "120s",
+        });
+        let start = Instant::now();
+        assert_eq!(send(&mut ctx, request).await.unwrap()["status_code"], 200);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(ctx.slept(), Duration::from_millis(60_300));
+
+        // Slow responses time out
+        ctx.inject_http_faults(
+            "https://example.com/slow",
+            [HttpFault::Delay(Duration::from_secs(60))],
+        );
+        ctx.evaluation_start();
+        let request = serde_json::json!({
+            "method": "get",
+            "url": "https://example.com/slow",
+            "timeout": "1ms",
+            "raise_error": false,
+        });
+        let response = send(&mut ctx, request).await.unwrap();
+        assert_eq!(response["status_code"], 0);
+        assert_eq!(
+            response["error"]["message"],
+            "request to https://example.com/slow timed out"
+        );
+
+        // Retries after failures which were not injected are slept
+        ctx.evaluation_start();
+        let slept = ctx.slept();
+        let request = serde_json::json!({
+            "method": "get",
+            "url": "https://example.com/unmocked",
+            "max_retry_attempts": 1,
+        });
+        let start = Instant::now();
+        assert!(send(&mut ctx, request).await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(ctx.slept(), slept);
+    }
 }
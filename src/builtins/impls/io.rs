@@ -16,9 +16,19 @@
 
 /// Builtins related to JWT encode/decode and verification/signature
 pub mod jwt {
+    #[cfg(feature = "jwt-builtins")]
     use std::collections::HashMap;
 
+    #[cfg(feature = "jwt-builtins")]
+    use anyhow::Context;
     use anyhow::{bail, Result};
+    #[cfg(feature = "jwt-builtins")]
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    #[cfg(feature = "jwt-builtins")]
+    use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+
+    #[cfg(feature = "jwt-builtins")]
+    use crate::{EvaluationContext, JwtKey};
 
     /// The headers part of a JWT
     type Headers = serde_json::Value;
@@ -29,23 +39,199 @@ pub mod jwt {
     /// A JSON Web Key
     type Jwk = serde_json::Value;
 
+    /// Split a JWT in its three base64url-encoded parts
+    #[cfg(feature = "jwt-builtins")]
+    fn split(jwt: &str) -> Result<(&str, &str, &str)> {
+        let mut parts = jwt.split('.');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(headers), Some(payload), Some(signature), None) => {
+                Ok((headers, payload, signature))
+            }
+            _ => bail!("JWT must have exactly three parts"),
+        }
+    }
+
+    /// Decode a base64url-encoded JSON object
+    #[cfg(feature = "jwt-builtins")]
+    fn decode_part(part: &str) -> Result<serde_json::Value> {
+        let part = URL_SAFE_NO_PAD
+            .decode(part.trim_end_matches('='))
+            .context("invalid base64url encoding")?;
+        let part = serde_json::from_slice(&part).context("invalid JSON")?;
+        Ok(part)
+    }
+
+    /// Get the decoding key to use from the key material and the token
+    /// algorithm
+    #[cfg(feature = "jwt-builtins")]
+    fn decoding_key(key: &JwtKey, alg: Algorithm, kid: Option<&str>) -> Result<DecodingKey> {
+        let cert = match key {
+            JwtKey::Secret(secret) => return Ok(DecodingKey::from_secret(secret.as_bytes())),
+            JwtKey::Certificate(cert) => cert.trim(),
+        };
+
+        // The certificate is a JWK or a JWK set
+        if cert.starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(cert).context("invalid JWK")?;
+            let jwk = if value.get("keys").is_some() {
+                let set: JwkSet = serde_json::from_value(value).context("invalid JWK set")?;
+                let jwk = match kid {
+                    Some(kid) => set.find(kid).cloned(),
+                    None => set.keys.into_iter().next(),
+                };
+                jwk.context("no matching key in JWK set")?
+            } else {
+                serde_json::from_value(value).context("invalid JWK")?
+            };
+
+            return Ok(DecodingKey::from_jwk(&jwk)?);
+        }
+
+        let cert = cert.as_bytes();
+        let key = match alg {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(cert)?,
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(cert)?,
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(cert)?,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                bail!("HMAC algorithms require a secret")
+            }
+        };
+        Ok(key)
+    }
+
+    /// Check the time-based and issuer/audience claims against the
+    /// constraints
+    #[cfg(feature = "jwt-builtins")]
+    fn claims_valid(
+        claims: &serde_json::Value,
+        constraints: &HashMap<String, serde_json::Value>,
+        now_ns: i64,
+    ) -> bool {
+        // Claims are in seconds, the time constraint is in nanoseconds
+        #[allow(clippy::cast_precision_loss)]
+        let now = now_ns as f64 / 1_000_000_000.;
+
+        if let Some(exp) = claims.get("exp").and_then(serde_json::Value::as_f64) {
+            if now >= exp {
+                return false;
+            }
+        }
+
+        if let Some(nbf) = claims.get("nbf").and_then(serde_json::Value::as_f64) {
+            if now < nbf {
+                return false;
+            }
+        }
+
+        if let Some(iss) = constraints.get("iss") {
+            if claims.get("iss") != Some(iss) {
+                return false;
+            }
+        }
+
+        match (constraints.get("aud"), claims.get("aud")) {
+            (None, None) => true,
+            (Some(aud), Some(serde_json::Value::Array(auds))) => auds.contains(aud),
+            (Some(aud), Some(claim)) => aud == claim,
+            _ => false,
+        }
+    }
+
     /// Decodes a JSON Web Token and outputs it as an object.
-    #[tracing::instrument(name = "io.jwt.decode", err)]
+    #[cfg(feature = "jwt-builtins")]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "io.jwt.decode", err)
+    )]
     pub fn decode(jwt: String) -> Result<(Headers, Payload, String)> {
-        bail!("not implemented");
+        let (headers, payload, signature) = split(&jwt)?;
+        let headers = decode_part(headers).context("invalid JWT headers")?;
+        let payload = decode_part(payload).context("invalid JWT payload")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature.trim_end_matches('='))
+            .context("invalid JWT signature")?;
+        Ok((headers, payload, hex::encode(signature)))
     }
 
     /// Verifies a JWT signature under parameterized constraints and decodes the
     /// claims if it is valid.
     ///
     /// Supports the following algorithms: HS256, HS384, HS512, RS256, RS384,
-    /// RS512, ES256, ES384, ES512, PS256, PS384 and PS512.
-    #[tracing::instrument(name = "io.jwt.decode_verify", err)]
-    pub fn decode_verify(
+    /// RS512, ES256, ES384, PS256, PS384 and PS512.
+    ///
+    /// If the constraints provide neither a `cert` nor a `secret`, the key is
+    /// resolved through the evaluation context.
+    #[cfg(feature = "jwt-builtins")]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "io.jwt.decode_verify", skip(ctx), err)
+    )]
+    pub async fn decode_verify<C: EvaluationContext>(
+        ctx: &mut C,
         jwt: String,
         constraints: HashMap<String, serde_json::Value>,
     ) -> Result<(bool, Headers, Payload)> {
-        bail!("not implemented");
+        let invalid = (false, serde_json::json!({}), serde_json::json!({}));
+
+        let Ok(header) = jsonwebtoken::decode_header(&jwt) else {
+            return Ok(invalid);
+        };
+
+        if let Some(alg) = constraints.get("alg") {
+            if serde_json::to_value(header.alg)? != *alg {
+                return Ok(invalid);
+            }
+        }
+
+        let cert = constraints.get("cert").and_then(serde_json::Value::as_str);
+        let secret = constraints
+            .get("secret")
+            .and_then(serde_json::Value::as_str);
+        let key = match (cert, secret) {
+            (Some(cert), _) => JwtKey::Certificate(cert.to_owned()),
+            (None, Some(secret)) => JwtKey::Secret(secret.to_owned()),
+            (None, None) => {
+                let issuer = constraints.get("iss").and_then(serde_json::Value::as_str);
+                ctx.resolve_jwt_key(header.kid.as_deref(), issuer)
+                    .await?
+                    .context("no key found to verify the JWT")?
+            }
+        };
+
+        let key = decoding_key(&key, header.alg, header.kid.as_deref())?;
+
+        // Time and audience checks are done separately, using the evaluation
+        // time
+        let mut validation = Validation::new(header.alg);
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        validation.validate_aud = false;
+        validation.required_spec_claims.clear();
+
+        let Ok(token) = jsonwebtoken::decode::<Payload>(&jwt, &key, &validation) else {
+            return Ok(invalid);
+        };
+
+        let now_ns = match constraints.get("time").and_then(serde_json::Value::as_i64) {
+            Some(time) => time,
+            None => ctx
+                .now()
+                .timestamp_nanos_opt()
+                .context("timestamp out of range")?,
+        };
+
+        if !claims_valid(&token.claims, &constraints, now_ns) {
+            return Ok(invalid);
+        }
+
+        let (headers, _, _) = split(&jwt)?;
+        let headers = decode_part(headers)?;
+        Ok((true, headers, token.claims))
     }
 
     /// Encodes and optionally signs a JSON Web Token. Inputs are taken as
@@ -137,3 +323,81 @@ pub mod jwt {
         bail!("not implemented");
     }
 }
+
+#[cfg(all(test, feature = "jwt-builtins"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use super::jwt::decode_verify;
+    use crate::{EvaluationContext, JwtKey, TestContext};
+
+    /// A context which resolves the `test` key ID to a static secret
+    struct KeyResolverContext<C> {
+        inner: C,
+    }
+
+    impl<C: EvaluationContext> EvaluationContext for KeyResolverContext<C> {
+        crate::layers::forward!(
+            rng,
+            now,
+            evaluation_start,
+            evaluation_end,
+            cache,
+            capability_enabled,
+            records,
+            deadline,
+            http,
+            runtime_info,
+            messages,
+            secrets_provider,
+        );
+
+        async fn resolve_jwt_key(
+            &mut self,
+            kid: Option<&str>,
+            _issuer: Option<&str>,
+        ) -> Result<Option<JwtKey>> {
+            Ok((kid == Some("test")).then(|| JwtKey::Secret("secret".to_owned())))
+        }
+    }
+
+    fn token(kid: &str, secret: &str) -> Result<String> {
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
+        header.kid = Some(kid.to_owned());
+        let claims = serde_json::json!({ "sub": "alice" });
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        Ok(jsonwebtoken::encode(&header, &claims, &key)?)
+    }
+
+    #[tokio::test]
+    async fn decode_verify_uses_the_key_resolver() {
+        let mut ctx = KeyResolverContext {
+            inner: TestContext::default(),
+        };
+        ctx.evaluation_start();
+
+        let (valid, headers, payload) =
+            decode_verify(&mut ctx, token("test", "secret").unwrap(), HashMap::new())
+                .await
+                .unwrap();
+        assert!(valid);
+        assert_eq!(headers["kid"], "test");
+        assert_eq!(payload["sub"], "alice");
+
+        let (valid, _, _) =
+            decode_verify(&mut ctx, token("test", "other").unwrap(), HashMap::new())
+                .await
+                .unwrap();
+        assert!(!valid);
+
+        let res = decode_verify(
+            &mut ctx,
+            token("unknown", "secret").unwrap(),
+            HashMap::new(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
@@ -20,7 +20,7 @@ use json_patch::Patch;
 /// For example: `json.patch({"a": {"foo": 1}}, [{"op": "add", "path": "/a/bar",
 /// "value": 2}])` results in `{"a": {"foo": 1, "bar": 2}`. The patches are
 /// applied atomically: if any of them fails, the result will be undefined.
-#[tracing::instrument(name = "json.patch")]
+#[cfg_attr(feature = "detailed-tracing", tracing::instrument(name = "json.patch"))]
 pub fn patch(mut object: serde_json::Value, patch: Patch) -> serde_json::Value {
     if json_patch::patch(&mut object, &patch).is_err() {
         serde_json::Value::Object(serde_json::Map::default())
@@ -19,6 +19,8 @@
 
 use anyhow::{bail, Result};
 
+use crate::EvaluationContext;
+
 #[cfg(feature = "base64url-builtins")]
 pub mod base64url;
 pub mod crypto;
@@ -27,6 +29,7 @@ pub mod graph;
 pub mod graphql;
 #[cfg(feature = "hex-builtins")]
 pub mod hex;
+#[cfg(feature = "http-builtins")]
 pub mod http;
 pub mod io;
 #[cfg(feature = "json-builtins")]
@@ -58,7 +61,7 @@ pub fn indexof_n(string: String, search: String) -> Result<Vec<u32>> {
 
 #[cfg(feature = "sprintf-builtins")]
 /// Returns the given string, formatted.
-#[tracing::instrument(err)]
+#[cfg_attr(feature = "detailed-tracing", tracing::instrument(err))]
 pub fn sprintf(format: String, values: Vec<serde_json::Value>) -> Result<String> {
     use sprintf::{vsprintf, Printf};
 
@@ -96,7 +99,29 @@ pub fn sprintf(format: String, values: Vec<serde_json::Value>) -> Result<String>
 /// explanation. To include variables in the message, use `sprintf`. For
 /// example, `person := "Bob"; trace(sprintf("Hello There! %v", [person]))` will
 /// emit `Note "Hello There! Bob"` inside of the explanation.
-#[tracing::instrument(err)]
-pub fn trace(note: String) -> Result<bool> {
-    bail!("not implemented");
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "trace", skip(ctx))
+)]
+pub fn trace<C: EvaluationContext>(ctx: &mut C, note: String) -> bool {
+    ctx.note(&note);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DefaultContext;
+
+    #[test]
+    fn trace_collects_notes() {
+        let mut ctx = DefaultContext::default();
+        ctx.evaluation_start();
+        assert!(trace(&mut ctx, "hello".to_owned()));
+        assert_eq!(ctx.notes(), ["hello"]);
+
+        // The notes are cleared when the next evaluation starts
+        ctx.evaluation_start();
+        assert!(ctx.notes().is_empty());
+    }
 }
@@ -18,6 +18,8 @@ use std::collections::HashSet;
 
 use anyhow::{bail, Result};
 
+use crate::{Capability, EvaluationContext};
+
 /// Checks if collections of cidrs or ips are contained within another
 /// collection of cidrs and returns matches. This function is similar to
 /// `net.cidr_contains` except it allows callers to pass collections of CIDRs or
@@ -53,7 +55,14 @@ pub fn cidr_merge(addrs: serde_json::Value) -> Result<HashSet<String>> {
 
 /// Returns the set of IP addresses (both v4 and v6) that the passed-in `name`
 /// resolves to using the standard name resolution mechanisms available.
-#[tracing::instrument(name = "net.lookup_ip_addr", err)]
-pub async fn lookup_ip_addr(name: String) -> Result<HashSet<String>> {
+#[tracing::instrument(name = "net.lookup_ip_addr", skip(ctx), err)]
+pub async fn lookup_ip_addr<C: EvaluationContext>(
+    ctx: &mut C,
+    name: String,
+) -> Result<HashSet<String>> {
+    if !ctx.capability_enabled(Capability::Dns) {
+        bail!("net.lookup_ip_addr: DNS lookups are disabled");
+    }
+
     bail!("not implemented");
 }
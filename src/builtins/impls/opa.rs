@@ -14,29 +14,37 @@
 
 //! Builtins related to the current OPA environment
 
-use std::{collections::HashMap, env};
-
-use serde::Serialize;
-
-/// Metadata about the OPA runtime
-#[derive(Serialize)]
-pub struct Runtime {
-    /// A map of the current environment variables
-    env: HashMap<String, String>,
-    /// The version of OPA runtime. This is currently set to an empty string
-    version: String,
-    /// The commit hash of the OPA runtime. This is currently set to an empty
-    commit: String,
-}
+use crate::{EvaluationContext, RuntimeInfo};
 
 /// Returns an object that describes the runtime environment where OPA is
 /// deployed.
-#[tracing::instrument(name = "opa.runtime")]
-pub fn runtime() -> Runtime {
-    let env = env::vars().collect();
-    Runtime {
-        env,
-        version: String::new(),
-        commit: String::new(),
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "opa.runtime", skip(ctx))
+)]
+pub fn runtime<C: EvaluationContext>(ctx: &mut C) -> RuntimeInfo {
+    ctx.runtime_info()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::TestContext;
+
+    #[test]
+    fn test_context_runtime_is_deterministic() {
+        let mut ctx = TestContext::default();
+        let info = runtime(&mut ctx);
+        assert!(info.env.is_empty());
+        assert!(info.version.is_empty());
+
+        ctx.set_runtime_info(RuntimeInfo {
+            env: HashMap::from([("HOME".to_owned(), "/root".to_owned())]),
+            version: "0.70.0".to_owned(),
+            commit: String::new(),
+        });
+        assert_eq!(runtime(&mut ctx).version, "0.70.0");
     }
 }
@@ -22,7 +22,10 @@ use crate::EvaluationContext;
 /// Returns a random integer between `0` and `n` (`n` exlusive). If `n` is `0`,
 /// then `y` is always `0`. For any given argument pair (`str`, `n`), the output
 /// will be consistent throughout a query evaluation.
-#[tracing::instrument(name = "rand.intn", skip(ctx), err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "rand.intn", skip(ctx), err)
+)]
 pub fn intn<C: EvaluationContext>(ctx: &mut C, str: String, n: i64) -> Result<i64> {
     if n == 0 {
         return Ok(0);
@@ -35,10 +38,33 @@ pub fn intn<C: EvaluationContext>(ctx: &mut C, str: String, n: i64) -> Result<i6
     let cache_key = ("rand", str, n);
     if let Some(v) = ctx.cache_get(&cache_key)? {
         return Ok(v);
-    };
+    }
 
     let mut rng = ctx.get_rng();
     let val = rng.gen_range(0..n);
     ctx.cache_set(&cache_key, &val)?;
     Ok(val)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DefaultContext;
+
+    #[test]
+    fn seeded_values_are_independent_and_replayed() {
+        let mut ctx = DefaultContext::builder().rng_seed(42).build();
+        let n = 1 << 48;
+
+        ctx.evaluation_start();
+        let first = intn(&mut ctx, "first".to_owned(), n).unwrap();
+        let second = intn(&mut ctx, "second".to_owned(), n).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(intn(&mut ctx, "first".to_owned(), n).unwrap(), first);
+
+        // The next evaluation draws the same values again
+        ctx.evaluation_start();
+        assert_eq!(intn(&mut ctx, "first".to_owned(), n).unwrap(), first);
+        assert_eq!(intn(&mut ctx, "second".to_owned(), n).unwrap(), second);
+    }
+}
@@ -19,8 +19,11 @@ use std::cmp::Ordering;
 use anyhow::Result;
 use semver::Version;
 
-/// Compares valid SemVer formatted version strings.
-#[tracing::instrument(name = "semver.compare", err)]
+/// Compares valid `SemVer` formatted version strings.
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "semver.compare", err)
+)]
 pub fn compare(a: String, b: String) -> Result<i8> {
     let a = Version::parse(&a)?;
     let b = Version::parse(&b)?;
@@ -31,8 +34,11 @@ pub fn compare(a: String, b: String) -> Result<i8> {
     }
 }
 
-/// Validates that the input is a valid SemVer string.
-#[tracing::instrument(name = "semver.is_valid")]
+/// Validates that the input is a valid `SemVer` string.
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "semver.is_valid")
+)]
 pub fn is_valid(vsn: String) -> bool {
     Version::parse(&vsn).is_ok()
 }
@@ -42,7 +42,7 @@ impl TimestampWithOptionalTimezone {
             Self::TimestampAndTimezone(ts, tz) => (
                 ts,
                 tz.parse()
-                    .map_err(|e| anyhow!("Could not parse timezone: {}", e))?,
+                    .map_err(|e| anyhow!("Could not parse timezone: {e}"))?,
             ),
         };
 
@@ -53,7 +53,10 @@ impl TimestampWithOptionalTimezone {
 /// Returns the nanoseconds since epoch after adding years, months and days to
 /// nanoseconds. `undefined` if the result would be outside the valid time range
 /// that can fit within an `int64`.
-#[tracing::instrument(name = "time.add_date", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.add_date", err)
+)]
 pub fn add_date(ns: i64, years: i32, months: i32, days: i64) -> Result<i64> {
     let date_time = {
         Utc.timestamp_nanos(ns)
@@ -67,14 +70,20 @@ pub fn add_date(ns: i64, years: i32, months: i32, days: i64) -> Result<i64> {
 
 /// Returns the `[hour, minute, second]` of the day for the nanoseconds since
 /// epoch.
-#[tracing::instrument(name = "time.clock", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.clock", err)
+)]
 pub fn clock(x: TimestampWithOptionalTimezone) -> Result<(u32, u32, u32)> {
     let date_time = x.into_datetime()?;
     Ok((date_time.hour(), date_time.minute(), date_time.second()))
 }
 
 /// Returns the `[year, month, day]` for the nanoseconds since epoch.
-#[tracing::instrument(name = "time.date", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.date", err)
+)]
 pub fn date(x: TimestampWithOptionalTimezone) -> Result<(i32, u32, u32)> {
     let date_time = x.into_datetime()?;
     Ok((date_time.year(), date_time.month(), date_time.day()))
@@ -89,7 +98,10 @@ pub fn diff(ns1: serde_json::Value, ns2: serde_json::Value) -> Result<(u8, u8, u
 }
 
 /// Returns the current time since epoch in nanoseconds.
-#[tracing::instrument(name = "time.now_ns", skip(ctx))]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.now_ns", skip(ctx))
+)]
 pub fn now_ns<C: EvaluationContext>(ctx: &mut C) -> Result<i64> {
     ctx.now()
         .timestamp_nanos_opt()
@@ -97,10 +109,13 @@ pub fn now_ns<C: EvaluationContext>(ctx: &mut C) -> Result<i64> {
 }
 
 /// Returns the duration in nanoseconds represented by a string.
-#[tracing::instrument(name = "time.parse_duration_ns", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.parse_duration_ns", err)
+)]
 pub fn parse_duration_ns(duration: String) -> Result<u128> {
     Ok(duration_str::parse(duration.as_str())
-        .map_err(|e| anyhow!("{}", e))?
+        .map_err(|e| anyhow!("{e}"))?
         .as_nanos())
 }
 
@@ -115,7 +130,10 @@ pub fn parse_ns(layout: String, value: String) -> Result<i64> {
 /// Returns the time in nanoseconds parsed from the string in RFC3339 format.
 /// `undefined` if the result would be outside the valid time range that can fit
 /// within an `int64`.
-#[tracing::instrument(name = "time.parse_rfc3339_ns", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.parse_rfc3339_ns", err)
+)]
 pub fn parse_rfc3339_ns(value: String) -> Result<i64> {
     DateTime::parse_from_rfc3339(value.as_ref())?
         .timestamp_nanos_opt()
@@ -124,7 +142,10 @@ pub fn parse_rfc3339_ns(value: String) -> Result<i64> {
 
 /// Returns the day of the week (Monday, Tuesday, ...) for the nanoseconds since
 /// epoch.
-#[tracing::instrument(name = "time.weekday", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "time.weekday", err)
+)]
 pub fn weekday(x: TimestampWithOptionalTimezone) -> Result<&'static str> {
     let date_time = x.into_datetime()?;
     Ok(match date_time.weekday() {
@@ -137,3 +158,35 @@ pub fn weekday(x: TimestampWithOptionalTimezone) -> Result<&'static str> {
         Weekday::Sun => "Sunday",
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvaluationId, EvaluationMetadata, EvaluationOutcome, TestContext};
+
+    #[test]
+    fn test_context_clock_can_be_moved() {
+        let mut ctx = TestContext::default();
+        assert_eq!(now_ns(&mut ctx).unwrap(), 1_594_731_202_000_000_000);
+
+        ctx.set_time(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+        ctx.advance(std::time::Duration::from_secs(60));
+        assert_eq!(now_ns(&mut ctx).unwrap(), 1_700_000_060_000_000_000);
+
+        // The time only moves between evaluations
+        ctx.auto_advance(std::time::Duration::from_millis(1));
+        ctx.evaluation_start();
+        assert_eq!(now_ns(&mut ctx).unwrap(), 1_700_000_060_000_000_000);
+        ctx.evaluation_end(&EvaluationOutcome {
+            metadata: EvaluationMetadata {
+                entrypoint: "app/allow",
+                revision: None,
+                evaluation_id: EvaluationId::next(),
+            },
+            duration: std::time::Duration::ZERO,
+            result: Ok(&serde_json::Value::Null),
+            memory: None,
+        });
+        assert_eq!(now_ns(&mut ctx).unwrap(), 1_700_000_060_001_000_000);
+    }
+}
@@ -38,7 +38,10 @@ pub enum UsizeOrFloat {
 /// Note that 'm' and 'M' are case-sensitive, to allow distinguishing between
 /// "milli" and "mega" units respectively. Other units are case-insensitive.
 #[allow(clippy::cast_precision_loss)]
-#[tracing::instrument(name = "units.parse", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "units.parse", err)
+)]
 pub fn parse(x: String) -> Result<UsizeOrFloat> {
     let p = Config::new().with_decimal();
     // edge case here, when 'm' is lowercase that's mili
@@ -53,7 +56,10 @@ pub fn parse(x: String) -> Result<UsizeOrFloat> {
 /// treated as decimal units and KiB, MiB, GiB, and TiB are treated as binary
 /// units. The bytes symbol (b/B) in the unit is optional and omitting it wil
 /// give the same result (e.g. Mi and MiB).
-#[tracing::instrument(name = "units.parse_bytes", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "units.parse_bytes", err)
+)]
 pub fn parse_bytes(x: String) -> Result<u64> {
     Config::new()
         .with_decimal()
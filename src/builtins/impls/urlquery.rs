@@ -40,13 +40,19 @@ impl<T: std::fmt::Debug> std::fmt::Debug for OneOrMany<T> {
 }
 
 /// Decodes a URL-encoded input string.
-#[tracing::instrument(name = "urlquery.decode", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "urlquery.decode", err)
+)]
 pub fn decode(x: String) -> Result<String> {
     Ok(urlencoding::decode(&x)?.into_owned())
 }
 
 /// Decodes the given URL query string into an object.
-#[tracing::instrument(name = "urlquery.decode_object")]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "urlquery.decode_object")
+)]
 pub fn decode_object(x: String) -> BTreeMap<String, Vec<String>> {
     let parsed = form_urlencoded::parse(x.as_bytes()).into_owned();
     let mut decoded_object: BTreeMap<String, Vec<String>> = BTreeMap::new();
@@ -57,13 +63,19 @@ pub fn decode_object(x: String) -> BTreeMap<String, Vec<String>> {
 }
 
 /// Encodes the input string into a URL-encoded string.
-#[tracing::instrument(name = "urlquery.encode")]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "urlquery.encode")
+)]
 pub fn encode(x: String) -> String {
     form_urlencoded::byte_serialize(x.as_bytes()).collect()
 }
 
 /// Encodes the given object into a URL encoded query string.
-#[tracing::instrument(name = "urlquery.encode_object")]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "urlquery.encode_object")
+)]
 pub fn encode_object(x: BTreeMap<String, OneOrMany<String>>) -> String {
     let mut encoded = form_urlencoded::Serializer::new(String::new());
 
@@ -18,22 +18,80 @@ use anyhow::Result;
 use serde_yaml;
 
 /// Verifies the input string is a valid YAML document.
-#[tracing::instrument(name = "yaml.is_valid")]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "yaml.is_valid")
+)]
 pub fn is_valid(x: String) -> bool {
     let parse: Result<serde_yaml::Value, _> = serde_yaml::from_str(&x);
     parse.is_ok()
 }
 
+/// Convert a JSON value to a YAML one. It doesn't go through serde, which
+/// sees numbers as maps with the `arbitrary-precision` feature. Integers out
+/// of the 64-bit range become floats, as YAML numbers can't hold them.
+fn to_yaml(value: serde_json::Value) -> serde_yaml::Value {
+    match value {
+        serde_json::Value::Null => serde_yaml::Value::Null,
+        serde_json::Value::Bool(b) => serde_yaml::Value::Bool(b),
+        serde_json::Value::Number(n) => {
+            let n = if let Some(n) = n.as_u64() {
+                serde_yaml::Number::from(n)
+            } else if let Some(n) = n.as_i64() {
+                serde_yaml::Number::from(n)
+            } else {
+                serde_yaml::Number::from(n.as_f64().unwrap_or(f64::NAN))
+            };
+            serde_yaml::Value::Number(n)
+        }
+        serde_json::Value::String(s) => serde_yaml::Value::String(s),
+        serde_json::Value::Array(a) => {
+            serde_yaml::Value::Sequence(a.into_iter().map(to_yaml).collect())
+        }
+        serde_json::Value::Object(o) => serde_yaml::Value::Mapping(
+            o.into_iter()
+                .map(|(k, v)| (serde_yaml::Value::String(k), to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
 /// Serializes the input term to YAML.
-#[tracing::instrument(name = "yaml.marshal", err)]
-pub fn marshal(x: serde_yaml::Value) -> Result<String> {
-    let parse: String = serde_yaml::to_string(&x)?;
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "yaml.marshal", err)
+)]
+pub fn marshal(x: serde_json::Value) -> Result<String> {
+    let parse: String = serde_yaml::to_string(&to_yaml(x))?;
     Ok(parse)
 }
 
 /// Deserializes the input string.
-#[tracing::instrument(name = "yaml.unmarshal", err)]
+#[cfg_attr(
+    feature = "detailed-tracing",
+    tracing::instrument(name = "yaml.unmarshal", err)
+)]
 pub fn unmarshal(x: String) -> Result<serde_json::Value> {
     let parse: serde_json::Value = serde_yaml::from_str(&x)?;
     Ok(parse)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn numbers_are_marshalled() {
+        let document = json!({"id": 9_007_199_254_740_993_u64, "price": 1.5, "debt": -3});
+        assert_eq!(
+            marshal(document.clone()).unwrap(),
+            "debt: -3\nid: 9007199254740993\nprice: 1.5\n"
+        );
+        assert_eq!(
+            unmarshal(marshal(document.clone()).unwrap()).unwrap(),
+            document
+        );
+    }
+}
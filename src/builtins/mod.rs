@@ -14,6 +14,11 @@
 
 //! Handling of builtin functions.
 
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
 use anyhow::{bail, Result};
 
 use self::traits::{Builtin, BuiltinFunc};
@@ -22,168 +27,460 @@ use crate::EvaluationContext;
 pub mod impls;
 pub mod traits;
 
-/// Resolve a builtin based on its name
-///
-/// # Errors
-///
-/// Returns an error if the builtin is not known
+/// A function creating a builtin
+type Constructor<C> = fn() -> Box<dyn Builtin<C>>;
+
+/// The builtins supported by this build, sorted by name so that they can be
+/// looked up with a binary search
 #[allow(clippy::too_many_lines)]
-pub fn resolve<C: EvaluationContext>(name: &str) -> Result<Box<dyn Builtin<C>>> {
-    match name {
+fn defaults<C: EvaluationContext>() -> &'static [(&'static str, Constructor<C>)] {
+    &[
         #[cfg(feature = "base64url-builtins")]
-        "base64url.encode_no_pad" => Ok(self::impls::base64url::encode_no_pad.wrap()),
-
+        ("base64url.encode_no_pad", || {
+            self::impls::base64url::encode_no_pad.wrap()
+        }),
         #[cfg(all(feature = "crypto-md5-builtins", feature = "crypto-hmac-builtins"))]
-        "crypto.hmac.md5" => Ok(self::impls::crypto::hmac::md5.wrap()),
-
+        ("crypto.hmac.md5", || self::impls::crypto::hmac::md5.wrap()),
         #[cfg(all(feature = "crypto-sha1-builtins", feature = "crypto-hmac-builtins"))]
-        "crypto.hmac.sha1" => Ok(self::impls::crypto::hmac::sha1.wrap()),
-
+        ("crypto.hmac.sha1", || {
+            self::impls::crypto::hmac::sha1.wrap()
+        }),
         #[cfg(all(feature = "crypto-sha2-builtins", feature = "crypto-hmac-builtins"))]
-        "crypto.hmac.sha256" => Ok(self::impls::crypto::hmac::sha256.wrap()),
-
+        ("crypto.hmac.sha256", || {
+            self::impls::crypto::hmac::sha256.wrap()
+        }),
         #[cfg(all(feature = "crypto-sha2-builtins", feature = "crypto-hmac-builtins"))]
-        "crypto.hmac.sha512" => Ok(self::impls::crypto::hmac::sha512.wrap()),
-
+        ("crypto.hmac.sha512", || {
+            self::impls::crypto::hmac::sha512.wrap()
+        }),
         #[cfg(all(feature = "crypto-md5-builtins", feature = "crypto-digest-builtins"))]
-        "crypto.md5" => Ok(self::impls::crypto::digest::md5.wrap()),
-
+        ("crypto.md5", || self::impls::crypto::digest::md5.wrap()),
         #[cfg(all(feature = "crypto-sha1-builtins", feature = "crypto-digest-builtins"))]
-        "crypto.sha1" => Ok(self::impls::crypto::digest::sha1.wrap()),
-
+        ("crypto.sha1", || self::impls::crypto::digest::sha1.wrap()),
         #[cfg(all(feature = "crypto-sha2-builtins", feature = "crypto-digest-builtins"))]
-        "crypto.sha256" => Ok(self::impls::crypto::digest::sha256.wrap()),
-
-        "crypto.x509.parse_and_verify_certificates" => {
-            Ok(self::impls::crypto::x509::parse_and_verify_certificates.wrap())
-        }
-        "crypto.x509.parse_certificate_request" => {
-            Ok(self::impls::crypto::x509::parse_certificate_request.wrap())
-        }
-        "crypto.x509.parse_certificates" => {
-            Ok(self::impls::crypto::x509::parse_certificates.wrap())
-        }
-        "crypto.x509.parse_rsa_private_key" => {
-            Ok(self::impls::crypto::x509::parse_rsa_private_key.wrap())
-        }
-        "glob.quote_meta" => Ok(self::impls::glob::quote_meta.wrap()),
-        "graph.reachable_paths" => Ok(self::impls::graph::reachable_paths.wrap()),
-        "graphql.is_valid" => Ok(self::impls::graphql::is_valid.wrap()),
-        "graphql.parse" => Ok(self::impls::graphql::parse.wrap()),
-        "graphql.parse_and_verify" => Ok(self::impls::graphql::parse_and_verify.wrap()),
-        "graphql.parse_query" => Ok(self::impls::graphql::parse_query.wrap()),
-        "graphql.parse_schema" => Ok(self::impls::graphql::parse_schema.wrap()),
-
+        ("crypto.sha256", || {
+            self::impls::crypto::digest::sha256.wrap()
+        }),
+        ("crypto.x509.parse_and_verify_certificates", || {
+            self::impls::crypto::x509::parse_and_verify_certificates.wrap()
+        }),
+        ("crypto.x509.parse_certificate_request", || {
+            self::impls::crypto::x509::parse_certificate_request.wrap()
+        }),
+        ("crypto.x509.parse_certificates", || {
+            self::impls::crypto::x509::parse_certificates.wrap()
+        }),
+        ("crypto.x509.parse_rsa_private_key", || {
+            self::impls::crypto::x509::parse_rsa_private_key.wrap()
+        }),
+        ("glob.quote_meta", || self::impls::glob::quote_meta.wrap()),
+        ("graph.reachable_paths", || {
+            self::impls::graph::reachable_paths.wrap()
+        }),
+        ("graphql.is_valid", || self::impls::graphql::is_valid.wrap()),
+        ("graphql.parse", || self::impls::graphql::parse.wrap()),
+        ("graphql.parse_and_verify", || {
+            self::impls::graphql::parse_and_verify.wrap()
+        }),
+        ("graphql.parse_query", || {
+            self::impls::graphql::parse_query.wrap()
+        }),
+        ("graphql.parse_schema", || {
+            self::impls::graphql::parse_schema.wrap()
+        }),
         #[cfg(feature = "hex-builtins")]
-        "hex.decode" => Ok(self::impls::hex::decode.wrap()),
-
+        ("hex.decode", || self::impls::hex::decode.wrap()),
         #[cfg(feature = "hex-builtins")]
-        "hex.encode" => Ok(self::impls::hex::encode.wrap()),
-
-        "http.send" => Ok(self::impls::http::send.wrap()),
-        "indexof_n" => Ok(self::impls::indexof_n.wrap()),
-        "io.jwt.decode" => Ok(self::impls::io::jwt::decode.wrap()),
-        "io.jwt.decode_verify" => Ok(self::impls::io::jwt::decode_verify.wrap()),
-        "io.jwt.encode_sign" => Ok(self::impls::io::jwt::encode_sign.wrap()),
-        "io.jwt.encode_sign_raw" => Ok(self::impls::io::jwt::encode_sign_raw.wrap()),
-        "io.jwt.verify_es256" => Ok(self::impls::io::jwt::verify_es256.wrap()),
-        "io.jwt.verify_es384" => Ok(self::impls::io::jwt::verify_es384.wrap()),
-        "io.jwt.verify_es512" => Ok(self::impls::io::jwt::verify_es512.wrap()),
-        "io.jwt.verify_hs256" => Ok(self::impls::io::jwt::verify_hs256.wrap()),
-        "io.jwt.verify_hs384" => Ok(self::impls::io::jwt::verify_hs384.wrap()),
-        "io.jwt.verify_hs512" => Ok(self::impls::io::jwt::verify_hs512.wrap()),
-        "io.jwt.verify_ps256" => Ok(self::impls::io::jwt::verify_ps256.wrap()),
-        "io.jwt.verify_ps384" => Ok(self::impls::io::jwt::verify_ps384.wrap()),
-        "io.jwt.verify_ps512" => Ok(self::impls::io::jwt::verify_ps512.wrap()),
-        "io.jwt.verify_rs256" => Ok(self::impls::io::jwt::verify_rs256.wrap()),
-        "io.jwt.verify_rs384" => Ok(self::impls::io::jwt::verify_rs384.wrap()),
-        "io.jwt.verify_rs512" => Ok(self::impls::io::jwt::verify_rs512.wrap()),
-
+        ("hex.encode", || self::impls::hex::encode.wrap()),
+        #[cfg(feature = "http-builtins")]
+        ("http.send", || self::impls::http::send.wrap()),
+        ("indexof_n", || self::impls::indexof_n.wrap()),
+        #[cfg(feature = "jwt-builtins")]
+        ("io.jwt.decode", || self::impls::io::jwt::decode.wrap()),
+        #[cfg(feature = "jwt-builtins")]
+        ("io.jwt.decode_verify", || {
+            self::impls::io::jwt::decode_verify.wrap()
+        }),
+        ("io.jwt.encode_sign", || {
+            self::impls::io::jwt::encode_sign.wrap()
+        }),
+        ("io.jwt.encode_sign_raw", || {
+            self::impls::io::jwt::encode_sign_raw.wrap()
+        }),
+        ("io.jwt.verify_es256", || {
+            self::impls::io::jwt::verify_es256.wrap()
+        }),
+        ("io.jwt.verify_es384", || {
+            self::impls::io::jwt::verify_es384.wrap()
+        }),
+        ("io.jwt.verify_es512", || {
+            self::impls::io::jwt::verify_es512.wrap()
+        }),
+        ("io.jwt.verify_hs256", || {
+            self::impls::io::jwt::verify_hs256.wrap()
+        }),
+        ("io.jwt.verify_hs384", || {
+            self::impls::io::jwt::verify_hs384.wrap()
+        }),
+        ("io.jwt.verify_hs512", || {
+            self::impls::io::jwt::verify_hs512.wrap()
+        }),
+        ("io.jwt.verify_ps256", || {
+            self::impls::io::jwt::verify_ps256.wrap()
+        }),
+        ("io.jwt.verify_ps384", || {
+            self::impls::io::jwt::verify_ps384.wrap()
+        }),
+        ("io.jwt.verify_ps512", || {
+            self::impls::io::jwt::verify_ps512.wrap()
+        }),
+        ("io.jwt.verify_rs256", || {
+            self::impls::io::jwt::verify_rs256.wrap()
+        }),
+        ("io.jwt.verify_rs384", || {
+            self::impls::io::jwt::verify_rs384.wrap()
+        }),
+        ("io.jwt.verify_rs512", || {
+            self::impls::io::jwt::verify_rs512.wrap()
+        }),
         #[cfg(feature = "json-builtins")]
-        "json.patch" => Ok(self::impls::json::patch.wrap()),
+        ("json.patch", || self::impls::json::patch.wrap()),
+        ("net.cidr_contains_matches", || {
+            self::impls::net::cidr_contains_matches.wrap()
+        }),
+        ("net.cidr_expand", || self::impls::net::cidr_expand.wrap()),
+        ("net.cidr_merge", || self::impls::net::cidr_merge.wrap()),
+        ("net.lookup_ip_addr", || {
+            self::impls::net::lookup_ip_addr.wrap()
+        }),
+        ("object.union_n", || self::impls::object::union_n.wrap()),
+        ("opa.runtime", || self::impls::opa::runtime.wrap()),
+        #[cfg(feature = "rng")]
+        ("rand.intn", || self::impls::rand::intn.wrap()),
+        ("regex.find_n", || self::impls::regex::find_n.wrap()),
+        ("regex.globs_match", || {
+            self::impls::regex::globs_match.wrap()
+        }),
+        ("regex.split", || self::impls::regex::split.wrap()),
+        ("regex.template_match", || {
+            self::impls::regex::template_match.wrap()
+        }),
+        ("rego.parse_module", || {
+            self::impls::rego::parse_module.wrap()
+        }),
+        #[cfg(feature = "semver-builtins")]
+        ("semver.compare", || self::impls::semver::compare.wrap()),
+        #[cfg(feature = "semver-builtins")]
+        ("semver.is_valid", || self::impls::semver::is_valid.wrap()),
+        #[cfg(feature = "sprintf-builtins")]
+        ("sprintf", || self::impls::sprintf.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.add_date", || self::impls::time::add_date.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.clock", || self::impls::time::clock.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.date", || self::impls::time::date.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.diff", || self::impls::time::diff.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.now_ns", || self::impls::time::now_ns.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.parse_duration_ns", || {
+            self::impls::time::parse_duration_ns.wrap()
+        }),
+        #[cfg(feature = "time-builtins")]
+        ("time.parse_ns", || self::impls::time::parse_ns.wrap()),
+        #[cfg(feature = "time-builtins")]
+        ("time.parse_rfc3339_ns", || {
+            self::impls::time::parse_rfc3339_ns.wrap()
+        }),
+        #[cfg(feature = "time-builtins")]
+        ("time.weekday", || self::impls::time::weekday.wrap()),
+        ("trace", || self::impls::trace.wrap()),
+        #[cfg(feature = "units-builtins")]
+        ("units.parse", || self::impls::units::parse.wrap()),
+        #[cfg(feature = "units-builtins")]
+        ("units.parse_bytes", || {
+            self::impls::units::parse_bytes.wrap()
+        }),
+        #[cfg(feature = "urlquery-builtins")]
+        ("urlquery.decode", || self::impls::urlquery::decode.wrap()),
+        #[cfg(feature = "urlquery-builtins")]
+        ("urlquery.decode_object", || {
+            self::impls::urlquery::decode_object.wrap()
+        }),
+        #[cfg(feature = "urlquery-builtins")]
+        ("urlquery.encode", || self::impls::urlquery::encode.wrap()),
+        #[cfg(feature = "urlquery-builtins")]
+        ("urlquery.encode_object", || {
+            self::impls::urlquery::encode_object.wrap()
+        }),
+        ("uuid.rfc4122", || self::impls::uuid::rfc4122.wrap()),
+        #[cfg(feature = "yaml-builtins")]
+        ("yaml.is_valid", || self::impls::yaml::is_valid.wrap()),
+        #[cfg(feature = "yaml-builtins")]
+        ("yaml.marshal", || self::impls::yaml::marshal.wrap()),
+        #[cfg(feature = "yaml-builtins")]
+        ("yaml.unmarshal", || self::impls::yaml::unmarshal.wrap()),
+    ]
+}
 
-        "net.cidr_contains_matches" => Ok(self::impls::net::cidr_contains_matches.wrap()),
-        "net.cidr_expand" => Ok(self::impls::net::cidr_expand.wrap()),
-        "net.cidr_merge" => Ok(self::impls::net::cidr_merge.wrap()),
-        "net.lookup_ip_addr" => Ok(self::impls::net::lookup_ip_addr.wrap()),
-        "object.union_n" => Ok(self::impls::object::union_n.wrap()),
-        "opa.runtime" => Ok(self::impls::opa::runtime.wrap()),
+/// Marks the errors of builtins which halt the evaluation, like OPA's halt
+/// errors: they fail the evaluation even when [strict builtin
+/// errors](crate::Runtime::with_strict_builtin_errors) are disabled, instead
+/// of making the expression undefined.
+///
+/// Builtins add it to their errors with [`anyhow::Context::context`], and its
+/// message is the one of that context.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{0}")]
+pub struct HaltError(pub String);
+
+impl HaltError {
+    /// Whether the error, or one of its contexts, is a [`HaltError`]
+    pub(crate) fn is_halt(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
+    }
+}
 
-        #[cfg(feature = "rng")]
-        "rand.intn" => Ok(self::impls::rand::intn.wrap()),
+/// The set of builtins policies can use, mapping their names to their
+/// implementations.
+///
+/// It starts with the builtins supported by this build, and can be extended
+/// with custom builtins, or restricted to deny some of them.
+pub struct BuiltinRegistry<C> {
+    /// Whether the builtins supported by this build are available
+    defaults: bool,
 
-        "regex.find_n" => Ok(self::impls::regex::find_n.wrap()),
-        "regex.globs_match" => Ok(self::impls::regex::globs_match.wrap()),
-        "regex.split" => Ok(self::impls::regex::split.wrap()),
-        "regex.template_match" => Ok(self::impls::regex::template_match.wrap()),
-        "rego.parse_module" => Ok(self::impls::rego::parse_module.wrap()),
+    /// The builtins registered at runtime, which take precedence over the
+    /// builtins of this build
+    custom: HashMap<String, Arc<dyn Builtin<C>>>,
 
-        #[cfg(feature = "semver-builtins")]
-        "semver.compare" => Ok(self::impls::semver::compare.wrap()),
+    /// The builtins which can't be used, even if they are registered
+    denied: HashSet<String>,
 
-        #[cfg(feature = "semver-builtins")]
-        "semver.is_valid" => Ok(self::impls::semver::is_valid.wrap()),
+    /// If set, only these builtins can be used
+    allowed: Option<HashSet<String>>,
+}
 
-        #[cfg(feature = "sprintf-builtins")]
-        "sprintf" => Ok(self::impls::sprintf.wrap()),
+impl<C> Clone for BuiltinRegistry<C> {
+    fn clone(&self) -> Self {
+        Self {
+            defaults: self.defaults,
+            custom: self.custom.clone(),
+            denied: self.denied.clone(),
+            allowed: self.allowed.clone(),
+        }
+    }
+}
 
-        #[cfg(feature = "time-builtins")]
-        "time.add_date" => Ok(self::impls::time::add_date.wrap()),
+impl<C> std::fmt::Debug for BuiltinRegistry<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BuiltinRegistry")
+            .field("defaults", &self.defaults)
+            .field("custom", &self.custom.keys().collect::<Vec<_>>())
+            .field("denied", &self.denied)
+            .field("allowed", &self.allowed)
+            .finish()
+    }
+}
 
-        #[cfg(feature = "time-builtins")]
-        "time.clock" => Ok(self::impls::time::clock.wrap()),
+impl<C> Default for BuiltinRegistry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        #[cfg(feature = "time-builtins")]
-        "time.date" => Ok(self::impls::time::date.wrap()),
+impl<C> BuiltinRegistry<C> {
+    /// Create a registry with the builtins supported by this build
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            defaults: true,
+            custom: HashMap::new(),
+            denied: HashSet::new(),
+            allowed: None,
+        }
+    }
 
-        #[cfg(feature = "time-builtins")]
-        "time.diff" => Ok(self::impls::time::diff.wrap()),
+    /// Create a registry without any builtin, for policies which only use
+    /// custom builtins
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            defaults: false,
+            ..Self::new()
+        }
+    }
 
-        #[cfg(feature = "time-builtins")]
-        "time.now_ns" => Ok(self::impls::time::now_ns.wrap()),
+    /// Register a custom builtin, replacing the builtin of this build with
+    /// the same name if there is one
+    #[must_use]
+    pub fn register(mut self, name: impl Into<String>, builtin: impl Builtin<C> + 'static) -> Self {
+        self.custom.insert(name.into(), Arc::new(builtin));
+        self
+    }
 
-        #[cfg(feature = "time-builtins")]
-        "time.parse_duration_ns" => Ok(self::impls::time::parse_duration_ns.wrap()),
+    /// Deny the given builtin, so that policies using it fail to load
+    #[must_use]
+    pub fn deny(mut self, name: impl Into<String>) -> Self {
+        self.denied.insert(name.into());
+        self
+    }
 
-        #[cfg(feature = "time-builtins")]
-        "time.parse_ns" => Ok(self::impls::time::parse_ns.wrap()),
+    /// Only allow the given builtins, so that policies using any other one
+    /// fail to load. Denied builtins stay denied.
+    #[must_use]
+    pub fn allow_only<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
 
-        #[cfg(feature = "time-builtins")]
-        "time.parse_rfc3339_ns" => Ok(self::impls::time::parse_rfc3339_ns.wrap()),
+    /// Check if the given builtin can be used, regardless of whether it is
+    /// known
+    fn is_allowed(&self, name: &str) -> bool {
+        !self.denied.contains(name)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(name))
+    }
+}
 
-        #[cfg(feature = "time-builtins")]
-        "time.weekday" => Ok(self::impls::time::weekday.wrap()),
+/// The names of the builtins supported by this build
+#[cfg(feature = "fuzzing")]
+#[must_use]
+pub fn default_names() -> Vec<&'static str> {
+    defaults::<crate::DefaultContext>()
+        .iter()
+        .map(|(name, _)| *name)
+        .collect()
+}
 
-        "trace" => Ok(self::impls::trace.wrap()),
+impl<C: EvaluationContext> BuiltinRegistry<C> {
+    /// Check if the given builtin is known and allowed
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.check(name).is_ok()
+    }
 
-        #[cfg(feature = "units-builtins")]
-        "units.parse" => Ok(self::impls::units::parse.wrap()),
+    /// Check that the given builtin can be resolved, without creating it
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the builtin is not known, or not allowed
+    pub(crate) fn check(&self, name: &str) -> Result<()> {
+        if !self.is_allowed(name) {
+            bail!("builtin not allowed");
+        }
 
-        #[cfg(feature = "units-builtins")]
-        "units.parse_bytes" => Ok(self::impls::units::parse_bytes.wrap()),
+        let known = self.custom.contains_key(name)
+            || (self.defaults
+                && defaults::<C>()
+                    .binary_search_by_key(&name, |(name, _)| name)
+                    .is_ok());
+        if !known {
+            bail!("unknown builtin");
+        }
 
-        #[cfg(feature = "urlquery-builtins")]
-        "urlquery.decode" => Ok(self::impls::urlquery::decode.wrap()),
+        Ok(())
+    }
 
-        #[cfg(feature = "urlquery-builtins")]
-        "urlquery.decode_object" => Ok(self::impls::urlquery::decode_object.wrap()),
+    /// Resolve a builtin based on its name
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the builtin is not known, or not allowed
+    pub fn resolve(&self, name: &str) -> Result<Arc<dyn Builtin<C>>> {
+        if !self.is_allowed(name) {
+            bail!("builtin not allowed");
+        }
 
-        #[cfg(feature = "urlquery-builtins")]
-        "urlquery.encode" => Ok(self::impls::urlquery::encode.wrap()),
+        if let Some(builtin) = self.custom.get(name) {
+            return Ok(Arc::clone(builtin));
+        }
 
-        #[cfg(feature = "urlquery-builtins")]
-        "urlquery.encode_object" => Ok(self::impls::urlquery::encode_object.wrap()),
+        if self.defaults {
+            let defaults = defaults::<C>();
+            if let Ok(index) = defaults.binary_search_by_key(&name, |(name, _)| name) {
+                let (_, constructor) = defaults[index];
+                return Ok(Arc::from(constructor()));
+            }
+        }
 
-        "uuid.rfc4122" => Ok(self::impls::uuid::rfc4122.wrap()),
+        bail!("unknown builtin")
+    }
+}
 
-        #[cfg(feature = "yaml-builtins")]
-        "yaml.is_valid" => Ok(self::impls::yaml::is_valid.wrap()),
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, pin::Pin};
 
-        #[cfg(feature = "yaml-builtins")]
-        "yaml.marshal" => Ok(self::impls::yaml::marshal.wrap()),
+    use super::*;
+    use crate::DefaultContext;
 
-        #[cfg(feature = "yaml-builtins")]
-        "yaml.unmarshal" => Ok(self::impls::yaml::unmarshal.wrap()),
-        _ => bail!("unknown builtin"),
+    /// A custom builtin always returning `true`
+    struct AlwaysTrue;
+
+    impl<C> Builtin<C> for AlwaysTrue {
+        fn call<'a>(
+            &'a self,
+            _context: &'a mut C,
+            _args: &'a [&'a [u8]],
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+            Box::pin(std::future::ready(Ok(b"true".to_vec())))
+        }
+    }
+
+    #[test]
+    fn defaults_are_sorted() {
+        let defaults = defaults::<DefaultContext>();
+        assert!(defaults.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[tokio::test]
+    async fn builtins_are_resolved() {
+        let registry = BuiltinRegistry::<DefaultContext>::new()
+            .register("custom.always_true", AlwaysTrue)
+            .deny("opa.runtime");
+
+        assert!(registry.contains("trace"));
+        assert!(registry.contains("custom.always_true"));
+        assert!(!registry.contains("opa.runtime"));
+        assert!(!registry.contains("unknown"));
+        assert!(registry.resolve("opa.runtime").is_err());
+
+        let builtin = registry.resolve("custom.always_true").unwrap();
+        let mut out = b"[".to_vec();
+        builtin
+            .call_into(&mut DefaultContext::default(), &[], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"[true");
+
+        // The builtins wrapping functions serialize their result either way
+        let builtin = registry.resolve("trace").unwrap();
+        let out = builtin
+            .call(&mut DefaultContext::default(), &[b"\"hello\""])
+            .await
+            .unwrap();
+        assert_eq!(out, b"true");
+        let mut out = b"[".to_vec();
+        builtin
+            .call_into(&mut DefaultContext::default(), &[b"\"hello\""], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"[true");
+
+        let registry = registry.allow_only(["custom.always_true"]);
+        assert!(!registry.contains("trace"));
+        assert!(registry.contains("custom.always_true"));
+
+        let registry = BuiltinRegistry::<DefaultContext>::empty();
+        assert!(!registry.contains("trace"));
     }
 }
@@ -30,6 +30,25 @@ pub trait Builtin<C>: Send + Sync {
         context: &'a mut C,
         args: &'a [&'a [u8]],
     ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, anyhow::Error>> + Send + 'a>>;
+
+    /// Like [`call`](Self::call), appending the JSON representation of the
+    /// result to `out` instead of returning it.
+    ///
+    /// The policies call this one, with a buffer reused across calls. It
+    /// defaults to copying the result of [`call`](Self::call), so implement it
+    /// to serialize the result directly into the buffer.
+    fn call_into<'a>(
+        &'a self,
+        context: &'a mut C,
+        args: &'a [&'a [u8]],
+        out: &'a mut Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>> {
+        let result = self.call(context, args);
+        Box::pin(async move {
+            out.extend_from_slice(&result.await?);
+            Ok(())
+        })
+    }
 }
 
 /// A wrapper around a builtin function with various const markers, to help
@@ -43,8 +62,14 @@ struct WrappedBuiltin<F, C, const ASYNC: bool, const RESULT: bool, const CONTEXT
     _marker: PhantomData<fn() -> (C, P)>,
 }
 
-impl<F, C: 'static, const ASYNC: bool, const RESULT: bool, const CONTEXT: bool, P: 'static>
-    Builtin<C> for WrappedBuiltin<F, C, ASYNC, RESULT, CONTEXT, P>
+impl<
+        F,
+        C: Send + 'static,
+        const ASYNC: bool,
+        const RESULT: bool,
+        const CONTEXT: bool,
+        P: 'static,
+    > Builtin<C> for WrappedBuiltin<F, C, ASYNC, RESULT, CONTEXT, P>
 where
     F: BuiltinFunc<C, ASYNC, RESULT, CONTEXT, P>,
 {
@@ -53,7 +78,20 @@ where
         context: &'a mut C,
         args: &'a [&'a [u8]],
     ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, anyhow::Error>> + Send + 'a>> {
-        self.func.call(context, args)
+        Box::pin(async move {
+            let mut out = Vec::new();
+            self.func.call(context, args, &mut out).await?;
+            Ok(out)
+        })
+    }
+
+    fn call_into<'a>(
+        &'a self,
+        context: &'a mut C,
+        args: &'a [&'a [u8]],
+        out: &'a mut Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>> {
+        self.func.call(context, args, out)
     }
 }
 
@@ -61,7 +99,7 @@ where
 /// function, abstracting away the parameters deserialization, the return value
 /// serialization, for async/non-async variants, and Result/non-Result variants
 pub(crate) trait BuiltinFunc<
-    C: 'static,
+    C: Send + 'static,
     const ASYNC: bool,
     const RESULT: bool,
     const CONTEXT: bool,
@@ -69,12 +107,14 @@ pub(crate) trait BuiltinFunc<
 >: Sized + Send + Sync + 'static
 {
     /// Call the function, with a list of arguments, each argument being a JSON
-    /// reprensentation of the parameter value.
+    /// reprensentation of the parameter value. The JSON representation of the
+    /// result is appended to `out`.
     fn call<'a>(
         &'a self,
         context: &'a mut C,
         args: &'a [&'a [u8]],
-    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, anyhow::Error>> + Send + 'a>>;
+        out: &'a mut Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>>;
 
     /// Wrap the function into a [`Builtin`] trait object
     fn wrap(self) -> Box<dyn Builtin<C>> {
@@ -85,6 +125,47 @@ pub(crate) trait BuiltinFunc<
     }
 }
 
+/// A helper trait to express async functions taking the context as their first
+/// argument, and returning a future which borrows it.
+///
+/// This can't be expressed directly with a `Fn(&mut C, ...) -> Fut` bound, as
+/// the future type would then have to outlive any borrow of the context.
+pub(crate) trait ContextAsyncFn<'a, C: 'a, P>: Send + Sync + 'static {
+    /// The output of the future returned by the function
+    type Output;
+
+    /// The future returned by the function
+    type Future: Future<Output = Self::Output> + Send + 'a;
+
+    /// Call the function with the context and a tuple of parameters
+    fn call_with(&self, context: &'a mut C, params: P) -> Self::Future;
+}
+
+/// A macro which implements the [`ContextAsyncFn`] trait for a given number of
+/// parameters
+macro_rules! context_async_fn_impl {
+    ($($pname:ident: $ptype:ident),*) => {
+        impl<'a, F, C: 'a, $($ptype,)* Fut> ContextAsyncFn<'a, C, ($($ptype,)*)> for F
+        where
+            F: Fn(&'a mut C, $($ptype),*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'a,
+        {
+            type Output = Fut::Output;
+            type Future = Fut;
+
+            fn call_with(&self, context: &'a mut C, ($($pname,)*): ($($ptype,)*)) -> Fut {
+                self(context, $($pname),*)
+            }
+        }
+    };
+}
+
+context_async_fn_impl!();
+context_async_fn_impl!(first: P1);
+context_async_fn_impl!(first: P1, second: P2);
+context_async_fn_impl!(first: P1, second: P2, third: P3);
+context_async_fn_impl!(first: P1, second: P2, third: P3, fourth: P4);
+
 /// A macro to count the number of items
 macro_rules! count {
     () => (0usize);
@@ -114,6 +195,9 @@ macro_rules! call {
     ($self:ident, $ctx:expr, ($($pname:ident),*), context = true) => {
         $self($ctx, $($pname),*)
     };
+    ($self:ident, $ctx:expr, ($($pname:ident),*), context = borrowed) => {
+        $self.call_with($ctx, ($($pname,)*))
+    };
     ($self:ident, $ctx:expr, ($($pname:ident),*), context = false) => {
         {
             let _ctx = $ctx;
@@ -130,7 +214,8 @@ macro_rules! trait_body {
             &'a self,
             context: &'a mut C,
             args: &'a [&'a [u8]],
-        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, anyhow::Error>> + Send + 'a>> {
+            out: &'a mut Vec<u8>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>> {
             Box::pin(async move {
                 let [$($pname),*]: [&'a [u8]; count!($($pname)*)] =
                     args.try_into().ok().context("invalid arguments")?;
@@ -140,8 +225,8 @@ macro_rules! trait_body {
                 )*
                 let res = call!(self, context, ($($pname),*), context = $context);
                 let res = unwrap!(res, result = $result, async = $async);
-                let res = serde_json::to_vec(&res).context("could not serialize result")?;
-                Ok(res)
+                serde_json::to_writer(out, &res).context("could not serialize result")?;
+                Ok(())
             })
         }
     };
@@ -268,42 +353,40 @@ macro_rules! trait_impl {
         }
 
         // Implementation for an async, non-result function, with context
-        impl<F, C, $($ptype,)* R, Fut> BuiltinFunc<C, false, true, true, ($($ptype,)*)> for F
+        impl<F, C, $($ptype,)* R> BuiltinFunc<C, false, true, true, ($($ptype,)*)> for F
         where
             C: EvaluationContext,
-            F: Fn(&mut C, $($ptype),*) -> Fut + Send + Sync + 'static,
+            F: for<'a> ContextAsyncFn<'a, C, ($($ptype,)*), Output = R>,
             $(
                 $ptype: for<'de> Deserialize<'de> + Send + 'static,
             )*
             R: Serialize + 'static,
-            Fut: Future<Output = R> + Send,
         {
             trait_body! {
                 ($($pname: $ptype),*),
                 async = true,
                 result = false,
-                context = true
+                context = borrowed
             }
         }
 
         // Implementation for an async, result function, with context
-        impl<F, C, $($ptype,)* R, E, Fut> BuiltinFunc<C, true, true, true, ($($ptype,)*)> for F
+        impl<F, C, $($ptype,)* R, E> BuiltinFunc<C, true, true, true, ($($ptype,)*)> for F
         where
             C: EvaluationContext,
-            F: Fn(&mut C, $($ptype),*) -> Fut + Send + Sync + 'static,
+            F: for<'a> ContextAsyncFn<'a, C, ($($ptype,)*), Output = Result<R, E>>,
             $(
                 $ptype: for<'de> Deserialize<'de> + Send + 'static,
             )*
             R: Serialize + 'static,
             E: 'static,
             anyhow::Error: From<E>,
-            Fut: Future<Output = Result<R, E>> + Send,
         {
             trait_body! {
                 ($($pname: $ptype),*),
                 async = true,
                 result = true,
-                context = true
+                context = borrowed
             }
         }
     }
@@ -328,5 +411,12 @@ mod tests {
         let args = [b"\"hello\"" as &[u8]];
         let result = uppercase.call(&mut ctx, &args[..]).await.unwrap();
         assert_eq!(result, b"\"HELLO\"");
+
+        let mut result = b"[".to_vec();
+        uppercase
+            .call_into(&mut ctx, &args[..], &mut result)
+            .await
+            .unwrap();
+        assert_eq!(result, b"[\"HELLO\"");
     }
 }
@@ -0,0 +1,195 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded LRU cache used by [`DefaultContext`](crate::DefaultContext)
+
+use anyhow::Result;
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Statistics about a cache, used to size it and to detect policies which
+/// defeat caching
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// The number of lookups which found a value
+    pub hits: u64,
+
+    /// The number of lookups which found no value
+    pub misses: u64,
+
+    /// The number of entries evicted to keep the cache within its bounds,
+    /// including values too large to be cached at all
+    pub evictions: u64,
+
+    /// The current number of entries
+    pub entries: usize,
+
+    /// The current total size of the entries, in bytes
+    pub bytes: usize,
+}
+
+/// A cache of serialized values, bounded by number of entries and by total
+/// size, which evicts the least recently used entries first
+pub(crate) struct EvaluationCache {
+    /// The cached entries, keyed by their serialized key
+    entries: LruCache<String, String>,
+
+    /// The maximum number of entries
+    max_entries: Option<usize>,
+
+    /// The maximum total size of the entries, in bytes
+    max_bytes: Option<usize>,
+
+    /// The current total size of the entries, in bytes
+    bytes: usize,
+
+    /// The number of lookups which found a value
+    hits: u64,
+
+    /// The number of lookups which found no value
+    misses: u64,
+
+    /// The number of evicted entries
+    evictions: u64,
+}
+
+/// The size accounted for an entry: the length of its serialized key and value
+fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len()
+}
+
+impl EvaluationCache {
+    /// Create a new cache with the given bounds
+    pub(crate) fn new(max_entries: Option<usize>, max_bytes: Option<usize>) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Get the statistics of this cache. The counters accumulate across
+    /// [`EvaluationCache::clear`] calls.
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+        }
+    }
+
+    /// Remove all the entries
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    /// Get a value, marking it as recently used
+    pub(crate) fn get<K: Serialize, V: DeserializeOwned>(&mut self, key: &K) -> Result<Option<V>> {
+        let key = serde_json::to_string(key)?;
+        let Some(value) = self.entries.get(&key) else {
+            self.misses += 1;
+            return Ok(None);
+        };
+
+        self.hits += 1;
+
+        let value = serde_json::from_str(value)?;
+        Ok(Some(value))
+    }
+
+    /// Insert a value, evicting the least recently used entries if the cache
+    /// is over its bounds. Values larger than the whole cache are not cached.
+    pub(crate) fn set<K: Serialize, V: Serialize>(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = serde_json::to_string(key)?;
+        let value = serde_json::to_string(value)?;
+        let size = entry_size(&key, &value);
+
+        if let Some(old) = self.entries.pop(&key) {
+            self.bytes -= entry_size(&key, &old);
+        }
+
+        if self.max_entries == Some(0) || self.max_bytes.is_some_and(|max| size > max) {
+            tracing::trace!(size, "value too large to be cached");
+            self.evictions += 1;
+            return Ok(());
+        }
+
+        self.bytes += size;
+        self.entries.put(key, value);
+
+        while self.max_entries.is_some_and(|max| self.entries.len() > max)
+            || self.max_bytes.is_some_and(|max| self.bytes > max)
+        {
+            let Some((key, value)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.bytes -= entry_size(&key, &value);
+            self.evictions += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let mut cache = EvaluationCache::new(Some(2), None);
+        cache.set(&"a", &1).unwrap();
+        cache.set(&"b", &2).unwrap();
+
+        // Touch "a" so that "b" is the least recently used
+        assert_eq!(cache.get::<_, i32>(&"a").unwrap(), Some(1));
+        cache.set(&"c", &3).unwrap();
+
+        assert_eq!(cache.get::<_, i32>(&"a").unwrap(), Some(1));
+        assert_eq!(cache.get::<_, i32>(&"b").unwrap(), None);
+        assert_eq!(cache.get::<_, i32>(&"c").unwrap(), Some(3));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.entries, 2);
+    }
+
+    #[test]
+    fn accounts_for_entry_sizes() {
+        // Each entry is 3 bytes for the key and 7 for the value
+        let mut cache = EvaluationCache::new(None, Some(25));
+        cache.set(&"a", &"value").unwrap();
+        cache.set(&"b", &"value").unwrap();
+        assert_eq!(cache.bytes, 20);
+
+        cache.set(&"c", &"value").unwrap();
+        assert_eq!(cache.bytes, 20);
+        assert_eq!(cache.get::<_, String>(&"a").unwrap(), None);
+
+        // Values which don't fit at all are not cached
+        cache.set(&"d", &"a value which does not fit").unwrap();
+        assert_eq!(cache.get::<_, String>(&"d").unwrap(), None);
+        assert_eq!(cache.bytes, 20);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Evaluations with CBOR-encoded inputs and results, transcoded to and from
+//! JSON at the WASM boundary.
+//!
+//! Integer map keys become strings, as in JSON objects, and maps with other
+//! non-string keys are rejected. Byte strings become arrays of numbers, and
+//! tags are dropped.
+
+use anyhow::{Context, Result};
+use wasmtime::AsContextMut;
+
+use crate::{EvaluationContext, Policy, PolicyPool};
+
+/// Decode a CBOR-encoded input
+fn decode(input: &[u8]) -> Result<ciborium::Value> {
+    ciborium::from_reader(input).context("invalid CBOR input")
+}
+
+/// Convert a JSON number to a CBOR one. Integers out of the 64-bit range, only
+/// kept with the `arbitrary-precision` feature, stay integers down to -2^64.
+fn to_cbor_number(n: &serde_json::Number) -> ciborium::Value {
+    if let Some(n) = n.as_u64() {
+        return ciborium::Value::Integer(n.into());
+    }
+    if let Some(n) = n.as_i64() {
+        return ciborium::Value::Integer(n.into());
+    }
+
+    let integer = n
+        .to_string()
+        .parse::<i128>()
+        .ok()
+        .and_then(|n| ciborium::value::Integer::try_from(n).ok());
+    match integer {
+        Some(n) => ciborium::Value::Integer(n),
+        None => ciborium::Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+    }
+}
+
+/// Convert a JSON value to a CBOR one. It doesn't go through serde, which
+/// sees numbers as maps with the `arbitrary-precision` feature.
+fn to_cbor(value: &serde_json::Value) -> ciborium::Value {
+    match value {
+        serde_json::Value::Null => ciborium::Value::Null,
+        serde_json::Value::Bool(b) => ciborium::Value::Bool(*b),
+        serde_json::Value::Number(n) => to_cbor_number(n),
+        serde_json::Value::String(s) => ciborium::Value::Text(s.clone()),
+        serde_json::Value::Array(a) => ciborium::Value::Array(a.iter().map(to_cbor).collect()),
+        serde_json::Value::Object(o) => ciborium::Value::Map(
+            o.iter()
+                .map(|(k, v)| (ciborium::Value::Text(k.clone()), to_cbor(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Encode a result set as CBOR
+fn encode(result_set: &serde_json::Value) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    ciborium::into_writer(&to_cbor(result_set), &mut buffer)
+        .context("could not encode the result set as CBOR")?;
+    Ok(buffer)
+}
+
+impl<C> Policy<C> {
+    /// Evaluate a policy with the given entrypoint and CBOR-encoded input,
+    /// returning the CBOR-encoded result set
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input is not valid CBOR or has no JSON
+    /// equivalent, if the policy evaluation failed, or if this policy did not
+    /// belong to the given store.
+    pub async fn evaluate_cbor<T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &[u8],
+    ) -> Result<Vec<u8>>
+    where
+        C: EvaluationContext,
+    {
+        let input = decode(input)?;
+        let result_set: serde_json::Value = self.evaluate(store, entrypoint, &input).await?;
+        encode(&result_set)
+    }
+}
+
+impl<C, T: Send> PolicyPool<C, T> {
+    /// Evaluate the entrypoint with the CBOR-encoded input on the next
+    /// instance, returning the CBOR-encoded result set
+    ///
+    /// # Errors
+    ///
+    /// If the input is not valid CBOR or has no JSON equivalent, or if the
+    /// evaluation failed
+    pub async fn evaluate_cbor(&self, entrypoint: &str, input: &[u8]) -> Result<Vec<u8>>
+    where
+        C: EvaluationContext,
+    {
+        let input = decode(input)?;
+        let result_set: serde_json::Value = self.evaluate(entrypoint, &input).await?;
+        encode(&result_set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn values_are_transcoded() {
+        let input = encode(&json!({"user": "alice", "roles": ["admin"], "age": 42})).unwrap();
+        let value = serde_json::to_value(decode(&input).unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({"user": "alice", "roles": ["admin"], "age": 42})
+        );
+
+        let result_set = json!([{"result": true}]);
+        let output = encode(&result_set).unwrap();
+        let value: serde_json::Value = ciborium::from_reader(&output[..]).unwrap();
+        assert_eq!(value, result_set);
+
+        let error = decode(b"\xff").unwrap_err();
+        assert_eq!(error.to_string(), "invalid CBOR input");
+
+        // Integer keys become strings, while array keys have no JSON equivalent
+        let mut input = Vec::new();
+        ciborium::into_writer(&std::collections::BTreeMap::from([(1, "one")]), &mut input).unwrap();
+        let value = serde_json::to_value(decode(&input).unwrap()).unwrap();
+        assert_eq!(value, json!({"1": "one"}));
+
+        let mut input = Vec::new();
+        ciborium::into_writer(
+            &std::collections::BTreeMap::from([(vec![1], "one")]),
+            &mut input,
+        )
+        .unwrap();
+        assert!(serde_json::to_value(decode(&input).unwrap()).is_err());
+    }
+
+    #[cfg(feature = "arbitrary-precision")]
+    #[test]
+    fn big_numbers_are_kept() {
+        let result_set: serde_json::Value =
+            serde_json::from_str(r#"[{"result": [-18446744073709551616, 0.1]}]"#).unwrap();
+        let output = encode(&result_set).unwrap();
+        let value: ciborium::Value = ciborium::from_reader(&output[..]).unwrap();
+        let rows = value.as_array().unwrap();
+        let result = rows[0].as_map().unwrap()[0].1.as_array().unwrap();
+        assert_eq!(
+            result[0],
+            ciborium::Value::Integer(ciborium::value::Integer::try_from(-(1_i128 << 64)).unwrap())
+        );
+        assert_eq!(result[1], ciborium::Value::Float(0.1));
+    }
+}
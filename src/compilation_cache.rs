@@ -0,0 +1,180 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An on-disk cache of compiled modules, so that they don't have to be
+//! compiled again across process restarts
+
+use std::{
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, Module};
+
+/// A [`Hasher`] feeding a SHA-256 digest, to get a fingerprint of a value
+/// which is stable across processes
+struct DigestHasher(Sha256);
+
+impl Hasher for DigestHasher {
+    fn finish(&self) -> u64 {
+        // Only the digest is used
+        0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+/// A directory holding compiled modules, keyed by the hash of their WASM
+/// bytes and by a fingerprint of the engine configuration, so that a module
+/// is never loaded with an engine it was not compiled for
+#[derive(Debug, Clone)]
+pub struct CompilationCache {
+    /// The directory holding the compiled modules
+    directory: PathBuf,
+}
+
+impl CompilationCache {
+    /// Use the given directory to cache compiled modules. It is created when
+    /// the first module is stored.
+    ///
+    /// # Safety
+    ///
+    /// The modules in the directory are loaded with
+    /// [`Module::deserialize_file`], which is unsafe because it executes the
+    /// machine code stored in the files. The directory must only be written
+    /// by this cache, or by a trusted party.
+    #[must_use]
+    pub unsafe fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// The directory holding the compiled modules
+    #[must_use]
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Get the path of the compiled module for the given WASM bytes and
+    /// engine
+    fn path(&self, engine: &Engine, wasm: &[u8]) -> PathBuf {
+        let module = Sha256::digest(wasm);
+
+        let mut hasher = DigestHasher(Sha256::new());
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        let engine = hasher.0.finalize();
+
+        let name = format!(
+            "{}-{}.cwasm",
+            hex::encode(module),
+            hex::encode(&engine[..8])
+        );
+        self.directory.join(name)
+    }
+
+    /// Load the compiled module from the cache, or compile the WASM module and
+    /// store it in the cache. Cached modules which can't be loaded are
+    /// compiled and stored again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the module could not be compiled, or if it could
+    /// not be stored in the cache
+    #[tracing::instrument(skip_all, err)]
+    pub fn module(&self, engine: &Engine, wasm: &[u8]) -> Result<Module> {
+        let path = self.path(engine, wasm);
+
+        if path.exists() {
+            // SAFETY: the caller of `new` guarantees that the files in the
+            // directory were written by this cache. wasmtime also checks that
+            // they were compiled with a compatible engine.
+            match unsafe { Module::deserialize_file(engine, &path) } {
+                Ok(module) => {
+                    tracing::debug!(path = %path.display(), "loaded compiled module from cache");
+                    return Ok(module);
+                }
+                Err(error) => {
+                    tracing::warn!(path = %path.display(), "could not load cached module, compiling it again: {error:#}");
+                }
+            }
+        }
+
+        let compiled = engine.precompile_module(wasm)?;
+        self.store(&path, &compiled)?;
+        tracing::debug!(path = %path.display(), "stored compiled module in cache");
+
+        // SAFETY: the bytes were just produced by the same engine
+        unsafe { Module::deserialize(engine, &compiled) }
+    }
+
+    /// Write a compiled module to the cache, atomically so that concurrent
+    /// processes never see a partially written file
+    fn store(&self, path: &Path, compiled: &[u8]) -> Result<()> {
+        std::fs::create_dir_all(&self.directory).with_context(|| {
+            format!(
+                "could not create the cache directory {}",
+                self.directory.display()
+            )
+        })?;
+
+        let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&temporary, compiled)
+            .with_context(|| format!("could not write {}", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("could not write {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest valid WASM module
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn modules_are_cached() {
+        let directory =
+            std::env::temp_dir().join(format!("opa-wasm-cache-test-{}", std::process::id()));
+        // SAFETY: the directory is only written by this test
+        let cache = unsafe { CompilationCache::new(&directory) };
+        let engine = Engine::default();
+
+        cache.module(&engine, EMPTY_MODULE).unwrap();
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        // A different engine configuration gets its own entry
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(true);
+        let other = Engine::new(&config).unwrap();
+        cache.module(&other, EMPTY_MODULE).unwrap();
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+
+        // A corrupted entry is compiled again
+        let path = cache.path(&engine, EMPTY_MODULE);
+        std::fs::write(&path, b"garbage").unwrap();
+        cache.module(&engine, EMPTY_MODULE).unwrap();
+        assert_ne!(std::fs::read(&path).unwrap(), b"garbage");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
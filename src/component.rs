@@ -0,0 +1,297 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policies packaged as WebAssembly components, exporting the `evaluation`
+//! interface of `wit/opa.wit` instead of the OPA ABI of core modules.
+//!
+//! The builtins the component calls through the `host` interface are resolved
+//! from a [`BuiltinRegistry`], as with core modules.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use wasmtime::{
+    component::{Component, Linker, TypedFunc},
+    Engine, Store, StoreContextMut,
+};
+
+use crate::{
+    backend,
+    builtins::{traits::Builtin, BuiltinRegistry},
+    DefaultContext, EvaluationContext, EvaluationId, EvaluationMetadata, EvaluationOutcome,
+};
+
+/// The interface imported by policy components
+const HOST: &str = "opa:policy/host@0.1.0";
+
+/// The interface exported by policy components
+const EVALUATION: &str = "opa:policy/evaluation@0.1.0";
+
+/// The data of the store a component is instantiated in
+struct HostState<C> {
+    /// The evaluation context passed to builtins
+    context: C,
+
+    /// The registry the builtins are resolved from
+    registry: BuiltinRegistry<C>,
+
+    /// The builtins resolved so far, by name
+    builtins: HashMap<String, Arc<dyn Builtin<C>>>,
+}
+
+impl<C: EvaluationContext> HostState<C> {
+    /// Call a builtin with the JSON value of its arguments, resolving it the
+    /// first time it is called
+    async fn call_builtin(&mut self, name: &str, args: &[String]) -> Result<String> {
+        let builtin = if let Some(builtin) = self.builtins.get(name) {
+            builtin.clone()
+        } else {
+            let builtin = self
+                .registry
+                .resolve(name)
+                .with_context(|| format!("could not resolve {name}"))?;
+            self.builtins.insert(name.to_owned(), builtin.clone());
+            builtin
+        };
+
+        let args: Vec<&[u8]> = args.iter().map(String::as_bytes).collect();
+        let result = backend::call(&*builtin, name, &mut self.context, &args).await?;
+        Ok(String::from_utf8(result)?)
+    }
+}
+
+/// A policy packaged as a component, instantiated in its own store
+pub struct ComponentPolicy<C = DefaultContext> {
+    /// The store holding the instance
+    store: Store<HostState<C>>,
+
+    /// The entrypoints of the policy
+    entrypoints: HashSet<String>,
+
+    /// The `set-data` export
+    set_data: TypedFunc<(String,), (Result<(), String>,)>,
+
+    /// The `evaluate` export
+    evaluate: TypedFunc<(String, String), (Result<String, String>,)>,
+}
+
+impl<C> std::fmt::Debug for ComponentPolicy<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ComponentPolicy")
+            .field("entrypoints", &self.entrypoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ComponentPolicy<DefaultContext> {
+    /// Instantiate a policy component, with the default evaluation context.
+    ///
+    /// The engine must have async support enabled, which [`EngineConfig`]
+    /// does.
+    ///
+    /// [`EngineConfig`]: crate::EngineConfig
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the component does not export the
+    /// `evaluation` interface.
+    pub async fn new(engine: &Engine, component: &Component) -> Result<Self> {
+        Self::new_with_evaluation_context(engine, component, DefaultContext::default()).await
+    }
+}
+
+impl<C: EvaluationContext> ComponentPolicy<C> {
+    /// Instantiate a policy component, with the given evaluation context
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the component does not export the
+    /// `evaluation` interface.
+    pub async fn new_with_evaluation_context(
+        engine: &Engine,
+        component: &Component,
+        context: C,
+    ) -> Result<Self> {
+        Self::new_with_builtins(engine, component, context, BuiltinRegistry::new()).await
+    }
+
+    /// Instantiate a policy component, resolving the builtins it calls from
+    /// the given registry
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the component does not export the
+    /// `evaluation` interface.
+    pub async fn new_with_builtins(
+        engine: &Engine,
+        component: &Component,
+        context: C,
+        registry: BuiltinRegistry<C>,
+    ) -> Result<Self> {
+        let mut linker = Linker::new(engine);
+        let mut host = linker.instance(HOST)?;
+        host.func_wrap_async(
+            "call-builtin",
+            |mut store: StoreContextMut<'_, HostState<C>>, (name, args): (String, Vec<String>)| {
+                Box::new(async move {
+                    let result = store.data_mut().call_builtin(&name, &args).await;
+                    Ok((result.map_err(|e| format!("{e:#}")),))
+                })
+            },
+        )?;
+        host.func_wrap(
+            "print",
+            |mut store: StoreContextMut<'_, HostState<C>>, (message,): (String,)| {
+                store.data_mut().context.print(&message);
+                Ok(())
+            },
+        )?;
+
+        let mut store = Store::new(
+            engine,
+            HostState {
+                context,
+                registry,
+                builtins: HashMap::new(),
+            },
+        );
+        let instance = linker.instantiate_async(&mut store, component).await?;
+
+        let interface = instance
+            .get_export(&mut store, None, EVALUATION)
+            .with_context(|| format!("missing export {EVALUATION}"))?;
+        let export = |store: &mut Store<HostState<C>>, name: &str| {
+            instance
+                .get_export(&mut *store, Some(&interface), name)
+                .with_context(|| format!("missing export {EVALUATION}#{name}"))
+        };
+
+        let index = export(&mut store, "entrypoints")?;
+        let entrypoints: TypedFunc<(), (Vec<String>,)> =
+            instance.get_typed_func(&mut store, index)?;
+        let index = export(&mut store, "set-data")?;
+        let set_data = instance.get_typed_func(&mut store, index)?;
+        let index = export(&mut store, "evaluate")?;
+        let evaluate = instance.get_typed_func(&mut store, index)?;
+
+        let (names,) = entrypoints.call_async(&mut store, ()).await?;
+        entrypoints.post_return_async(&mut store).await?;
+
+        Ok(Self {
+            store,
+            entrypoints: names.into_iter().collect(),
+            set_data,
+            evaluate,
+        })
+    }
+
+    /// Get the list of entrypoints of this policy
+    #[must_use]
+    pub fn entrypoints(&self) -> HashSet<&str> {
+        self.entrypoints.iter().map(String::as_str).collect()
+    }
+
+    /// Replace the data document
+    ///
+    /// # Errors
+    ///
+    /// If it failed to serialize the data, or if the policy rejected it
+    pub async fn set_data<V: serde::Serialize>(&mut self, data: &V) -> Result<()> {
+        let data = serde_json::to_string(data)?;
+        let (result,) = self.set_data.call_async(&mut self.store, (data,)).await?;
+        self.set_data.post_return_async(&mut self.store).await?;
+        result.map_err(anyhow::Error::msg)
+    }
+
+    /// Evaluate a policy with the given entrypoint and input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed.
+    pub async fn evaluate<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>>(
+        &mut self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<R> {
+        let metadata = EvaluationMetadata {
+            entrypoint,
+            revision: None,
+            evaluation_id: EvaluationId::next(),
+        };
+        self.store
+            .data_mut()
+            .context
+            .evaluation_start_with_metadata(&metadata);
+
+        let start = Instant::now();
+        let result = self.evaluate_entrypoint(entrypoint, input).await;
+        let duration = start.elapsed();
+        let outcome = EvaluationOutcome {
+            metadata,
+            duration,
+            result: result.as_ref(),
+            memory: None,
+        };
+        let context = &mut self.store.data_mut().context;
+        context.record_evaluation_duration(entrypoint, duration);
+        context.evaluation_end(&outcome);
+
+        Ok(serde_json::from_value(result?)?)
+    }
+
+    /// Evaluate the given entrypoint
+    async fn evaluate_entrypoint<V: serde::Serialize>(
+        &mut self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        anyhow::ensure!(
+            self.entrypoints.contains(entrypoint),
+            "could not find entrypoint {entrypoint}"
+        );
+
+        let input = serde_json::to_string(input)?;
+        let (result,) = self
+            .evaluate
+            .call_async(&mut self.store, (entrypoint.to_owned(), input))
+            .await?;
+        self.evaluate.post_return_async(&mut self.store).await?;
+
+        let result = result.map_err(anyhow::Error::msg)?;
+        Ok(serde_json::from_str(&result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EngineConfig;
+
+    #[tokio::test]
+    async fn components_are_checked() {
+        let engine = EngineConfig::new().build().unwrap();
+
+        // An empty component, which doesn't export the evaluation interface
+        let component = Component::new(&engine, b"\0asm\x0d\0\x01\0").unwrap();
+        let error = ComponentPolicy::new(&engine, &component).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "missing export opa:policy/evaluation@0.1.0"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Run the WASM test cases published by OPA, to measure how far evaluations
+//! diverge from the reference implementation.
+//!
+//! The test cases are JSON files with a `cases` array, each case holding a
+//! policy compiled to WASM and encoded in base64, the input and data to
+//! evaluate it with, and the expected result set or error. They are read from
+//! a directory or from a gzipped tarball, and evaluated with a
+//! [`TestContext`].
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use async_compression::tokio::bufread::GzipDecoder;
+use base64::Engine as _;
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::AsyncReadExt;
+use tokio_tar::Archive;
+use wasmtime::{Engine, Module, Store};
+
+use crate::{EngineConfig, Runtime, TestContext};
+
+/// A test case, as published by OPA
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct TestCase {
+    /// The name of the case, prefixed with the feature it tests
+    pub note: String,
+
+    /// The query the policy was compiled from
+    #[serde(default)]
+    pub query: Option<String>,
+
+    /// The compiled policy, encoded in base64
+    #[serde(default)]
+    pub wasm: Option<String>,
+
+    /// The data document
+    #[serde(default)]
+    pub data: Option<Value>,
+
+    /// The input document
+    #[serde(default)]
+    pub input: Option<Value>,
+
+    /// Whether the query is expected to be defined
+    #[serde(default)]
+    pub want_defined: Option<bool>,
+
+    /// The expected result set, in any order
+    #[serde(default)]
+    pub want_result: Option<Vec<Value>>,
+
+    /// The expected error, if the evaluation should fail
+    #[serde(default)]
+    pub want_error: Option<String>,
+
+    /// Why the case is skipped, if it is
+    #[serde(default)]
+    pub skip_reason: Option<String>,
+}
+
+impl TestCase {
+    /// The feature the case tests, which is the part of its note before the
+    /// first `/`, usually the name of a builtin
+    #[must_use]
+    pub fn feature(&self) -> &str {
+        self.note
+            .split_once('/')
+            .map_or(self.note.as_str(), |(feature, _)| feature)
+            .trim()
+    }
+}
+
+/// A file of test cases
+#[derive(Deserialize)]
+struct TestFile {
+    /// The cases of the file
+    cases: Vec<TestCase>,
+}
+
+/// What happened to a test case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The evaluation matched the expectations
+    Passed,
+
+    /// The evaluation did not match the expectations, for the given reason
+    Failed(String),
+
+    /// The case was not evaluated, for the given reason
+    Skipped(String),
+}
+
+/// The outcome of a test case
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CaseReport {
+    /// The file the case comes from
+    pub file: String,
+
+    /// The feature the case tests
+    pub feature: String,
+
+    /// The name of the case
+    pub note: String,
+
+    /// What happened to the case
+    pub outcome: Outcome,
+}
+
+/// The number of passed, failed and skipped cases of a feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Summary {
+    /// The number of cases which passed
+    pub passed: usize,
+
+    /// The number of cases which failed
+    pub failed: usize,
+
+    /// The number of cases which were skipped
+    pub skipped: usize,
+}
+
+/// The outcomes of a run of the test cases
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Report {
+    /// The outcome of each case, in the order they were read
+    pub cases: Vec<CaseReport>,
+}
+
+impl Report {
+    /// Count the outcomes of each feature
+    #[must_use]
+    pub fn summary(&self) -> BTreeMap<&str, Summary> {
+        let mut summary: BTreeMap<&str, Summary> = BTreeMap::new();
+        for case in &self.cases {
+            let counts = summary.entry(case.feature.as_str()).or_default();
+            match case.outcome {
+                Outcome::Passed => counts.passed += 1,
+                Outcome::Failed(_) => counts.failed += 1,
+                Outcome::Skipped(_) => counts.skipped += 1,
+            }
+        }
+        summary
+    }
+
+    /// Whether no case failed
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        !self
+            .cases
+            .iter()
+            .any(|case| matches!(case.outcome, Outcome::Failed(_)))
+    }
+
+    /// The cases which failed
+    pub fn failures(&self) -> impl Iterator<Item = &CaseReport> {
+        self.cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Failed(_)))
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut total = Summary::default();
+        for (feature, counts) in self.summary() {
+            writeln!(
+                f,
+                "{feature}: {} passed, {} failed, {} skipped",
+                counts.passed, counts.failed, counts.skipped
+            )?;
+            total.passed += counts.passed;
+            total.failed += counts.failed;
+            total.skipped += counts.skipped;
+        }
+        write!(
+            f,
+            "total: {} passed, {} failed, {} skipped",
+            total.passed, total.failed, total.skipped
+        )
+    }
+}
+
+/// Runs test cases, sharing one engine for all of them
+pub struct ConformanceRunner {
+    /// The engine compiling the policies
+    engine: Engine,
+}
+
+impl std::fmt::Debug for ConformanceRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConformanceRunner").finish_non_exhaustive()
+    }
+}
+
+impl ConformanceRunner {
+    /// Create a runner
+    ///
+    /// # Errors
+    ///
+    /// If the engine could not be created
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            engine: EngineConfig::new().build()?,
+        })
+    }
+
+    /// Run the test cases of the JSON files in the given directory, or in the
+    /// given gzipped tarball
+    ///
+    /// # Errors
+    ///
+    /// If the test cases could not be read. The cases which fail are
+    /// reported, not returned as errors.
+    pub async fn run(&self, path: impl AsRef<Path>) -> Result<Report> {
+        let path = path.as_ref();
+        let files = read_files(path)
+            .await
+            .with_context(|| format!("could not read the test cases in {}", path.display()))?;
+
+        let mut report = Report::default();
+        for (file, contents) in files {
+            let cases: TestFile = serde_json::from_slice(&contents)
+                .with_context(|| format!("invalid test cases in {file}"))?;
+            for case in &cases.cases {
+                report.cases.push(CaseReport {
+                    file: file.clone(),
+                    feature: case.feature().to_owned(),
+                    note: case.note.clone(),
+                    outcome: self.run_case(case).await,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Run a single test case
+    pub async fn run_case(&self, case: &TestCase) -> Outcome {
+        if let Some(reason) = &case.skip_reason {
+            return Outcome::Skipped(reason.clone());
+        }
+        let Some(wasm) = &case.wasm else {
+            return Outcome::Skipped("no compiled policy".to_owned());
+        };
+
+        let result = self.evaluate(wasm, case).await;
+        check(case, result)
+    }
+
+    /// Compile and evaluate the policy of a case, returning its result set
+    async fn evaluate(&self, wasm: &str, case: &TestCase) -> Result<Vec<Value>> {
+        let wasm = base64::engine::general_purpose::STANDARD
+            .decode(wasm)
+            .context("invalid base64 policy")?;
+        let module = Module::new(&self.engine, wasm)?;
+
+        let mut store = Store::new(&self.engine, ());
+        let runtime =
+            Runtime::new_with_evaluation_context(&mut store, &module, TestContext::default())
+                .await?;
+        let policy = match &case.data {
+            Some(data) => runtime.with_data(&mut store, data).await?,
+            None => runtime.without_data(&mut store).await?,
+        };
+        let entrypoint = policy
+            .default_entrypoint()
+            .context("the policy has no default entrypoint")?
+            .to_owned();
+
+        let input = case.input.clone().unwrap_or(Value::Null);
+        policy.evaluate(&mut store, &entrypoint, &input).await
+    }
+}
+
+/// Compare the outcome of an evaluation with the expectations of a case
+fn check(case: &TestCase, result: Result<Vec<Value>>) -> Outcome {
+    let result_set = match (result, &case.want_error) {
+        (Ok(_), Some(want)) => return Outcome::Failed(format!("expected error {want:?}")),
+        (Err(_), Some(_)) => return Outcome::Passed,
+        (Err(error), None) => return Outcome::Failed(format!("{error:#}")),
+        (Ok(result_set), None) => result_set,
+    };
+
+    if let Some(want_defined) = case.want_defined {
+        if want_defined == result_set.is_empty() {
+            return Outcome::Failed(if want_defined {
+                "expected a defined result".to_owned()
+            } else {
+                format!("expected an undefined result, got {result_set:?}")
+            });
+        }
+    }
+
+    if let Some(want_result) = &case.want_result {
+        // Result sets are sets, their order is not significant
+        let sorted = |results: &[Value]| {
+            let mut results: Vec<String> = results.iter().map(Value::to_string).collect();
+            results.sort_unstable();
+            results
+        };
+        if sorted(want_result) != sorted(&result_set) {
+            return Outcome::Failed(format!(
+                "expected {}, got {}",
+                Value::from(want_result.clone()),
+                Value::from(result_set)
+            ));
+        }
+    }
+
+    Outcome::Passed
+}
+
+/// Read the JSON files of a directory or of a gzipped tarball, sorted by name
+async fn read_files(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = Vec::new();
+
+    if tokio::fs::metadata(path).await?.is_dir() {
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let name = path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+                files.push((name, tokio::fs::read(&path).await?));
+            }
+        }
+    } else {
+        let bundle = tokio::fs::read(path).await?;
+        let mut archive = Archive::new(GzipDecoder::new(&bundle[..]));
+        let mut entries = archive.entries()?;
+        while let Some(mut entry) = entries.try_next().await? {
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if entry.header().entry_type().is_file()
+                && Path::new(&name)
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+            {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).await?;
+                files.push((name, contents));
+            }
+        }
+    }
+
+    files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Parse a test case
+    fn case(case: Value) -> TestCase {
+        serde_json::from_value(case).unwrap()
+    }
+
+    #[test]
+    fn results_are_checked() {
+        let want = case(json!({
+            "note": "arrays/concat: basic",
+            "want_result": [{"x": [1, 2]}, {"x": [3]}],
+        }));
+        assert_eq!(want.feature(), "arrays");
+        assert_eq!(
+            check(&want, Ok(vec![json!({"x": [3]}), json!({"x": [1, 2]})])),
+            Outcome::Passed
+        );
+        assert_eq!(
+            check(&want, Ok(vec![json!({"x": [3]})])),
+            Outcome::Failed(r#"expected [{"x":[1,2]},{"x":[3]}], got [{"x":[3]}]"#.to_owned())
+        );
+
+        let undefined = case(json!({"note": "undefined", "want_defined": false}));
+        assert_eq!(undefined.feature(), "undefined");
+        assert_eq!(check(&undefined, Ok(Vec::new())), Outcome::Passed);
+        assert!(matches!(
+            check(&undefined, Ok(vec![json!({})])),
+            Outcome::Failed(_)
+        ));
+
+        let error = case(json!({"note": "errors/div: by zero", "want_error": "divide by zero"}));
+        assert_eq!(check(&error, Err(anyhow::anyhow!("trap"))), Outcome::Passed);
+        assert!(matches!(check(&error, Ok(Vec::new())), Outcome::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn cases_are_reported() {
+        let dir = std::env::temp_dir().join(format!("opa-wasm-conformance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cases = json!({"cases": [
+            {"note": "skipped/reason", "skip_reason": "not supported"},
+            {"note": "skipped/no wasm"},
+            // An empty module, which is not an OPA policy
+            {"note": "invalid/empty", "wasm": "AGFzbQEAAAA=", "want_defined": true},
+        ]});
+        std::fs::write(dir.join("cases.json"), cases.to_string()).unwrap();
+
+        let report = ConformanceRunner::new().unwrap().run(&dir).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!report.is_success());
+        assert_eq!(report.failures().count(), 1);
+        let summary = report.summary();
+        assert_eq!(summary["skipped"].skipped, 2);
+        assert_eq!(summary["invalid"].failed, 1);
+        assert_eq!(
+            report.to_string(),
+            "invalid: 0 passed, 1 failed, 0 skipped\nskipped: 0 passed, 0 failed, 2 skipped\ntotal: 0 passed, 1 failed, 2 skipped"
+        );
+    }
+}
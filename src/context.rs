@@ -16,13 +16,247 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use std::collections::HashMap;
+#[cfg(any(feature = "http-builtins", feature = "jwt-builtins"))]
+use std::future::Future;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 #[cfg(feature = "time")]
 use chrono::TimeZone;
 use serde::{de::DeserializeOwned, Serialize};
 
+use crate::{
+    cache::{CacheStats, EvaluationCache},
+    Secret, SecretsProvider,
+};
+#[cfg(feature = "http-client")]
+use crate::{
+    http_client::{HttpClient, HttpClientConfig},
+    ProxyConfig, TlsConfig,
+};
+
+/// A host capability which some builtins rely on, and which can be toggled at
+/// runtime on the evaluation context
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Capability {
+    /// Outgoing HTTP requests, as done by `http.send`
+    Http,
+
+    /// DNS resolution, as done by `net.lookup_ip_addr`
+    Dns,
+
+    /// Access to the process environment variables, as exposed by
+    /// `opa.runtime`
+    Env,
+}
+
+/// Metadata about the OPA runtime, as returned by the `opa.runtime` builtin
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuntimeInfo {
+    /// A map of environment variables
+    pub env: HashMap<String, String>,
+
+    /// The version of the OPA runtime
+    pub version: String,
+
+    /// The commit hash of the OPA runtime
+    pub commit: String,
+}
+
+/// Options for an outgoing HTTP request sent on behalf of `http.send`
+#[cfg(feature = "http-builtins")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct HttpSendOptions {
+    /// The timeout of the request, already clamped to the remaining evaluation
+    /// budget
+    pub timeout: Option<Duration>,
+
+    /// Whether redirects should be followed
+    pub enable_redirect: bool,
+}
+
+/// Key material used to verify JWT signatures
+#[cfg(feature = "jwt-builtins")]
+#[derive(Debug, Clone)]
+pub enum JwtKey {
+    /// A shared secret, for HMAC-based algorithms
+    Secret(String),
+
+    /// A PEM-encoded public key or certificate, or a JWK or JWK set encoded
+    /// as JSON
+    Certificate(String),
+}
+
+/// An opaque identifier, unique within the process, assigned to each
+/// evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EvaluationId(u64);
+
+impl EvaluationId {
+    /// Generate a new unique evaluation ID
+    pub(crate) fn next() -> Self {
+        /// The next ID to hand out
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl std::fmt::Display for EvaluationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Metadata about an evaluation, passed to
+/// [`EvaluationContext::evaluation_start_with_metadata`]
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct EvaluationMetadata<'a> {
+    /// The entrypoint being evaluated
+    pub entrypoint: &'a str,
+
+    /// The revision of the bundle the policy was loaded from, if known
+    pub revision: Option<&'a str>,
+
+    /// An opaque identifier of this evaluation
+    pub evaluation_id: EvaluationId,
+}
+
+/// The outcome of an evaluation, passed to
+/// [`EvaluationContext::evaluation_end`]
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct EvaluationOutcome<'a> {
+    /// The metadata of the evaluation, as passed when it started
+    pub metadata: EvaluationMetadata<'a>,
+
+    /// The time spent evaluating
+    pub duration: Duration,
+
+    /// The result of the evaluation, or the error which made it fail
+    pub result: Result<&'a serde_json::Value, &'a anyhow::Error>,
+
+    /// How much memory the evaluation used, if it could be read back from
+    /// the instance
+    pub memory: Option<MemoryUsage>,
+}
+
+impl EvaluationMetadata<'_> {
+    /// Create the `opa.evaluate` span every backend runs the evaluation in.
+    ///
+    /// The fields tell which policy the events and the builtin spans inside
+    /// come from. They follow the OpenTelemetry conventions, so that they end
+    /// up as attributes on the exported spans.
+    pub(crate) fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "opa.evaluate",
+            opa.entrypoint = self.entrypoint,
+            opa.bundle.revision = self.revision,
+            opa.decision_id = %self.evaluation_id,
+            opa.decision = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            otel.status_message = tracing::field::Empty,
+        )
+    }
+}
+
+impl EvaluationOutcome<'_> {
+    /// Record the decision and the error, if any, on the span created by
+    /// [`EvaluationMetadata::span`]
+    pub(crate) fn record(&self, span: &tracing::Span) {
+        #[cfg(feature = "otel")]
+        {
+            span.record("opa.decision", self.decision());
+            if let Err(error) = self.result {
+                span.record("otel.status_code", "ERROR");
+                span.record("otel.status_message", format!("{error:#}"));
+            }
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (self, span);
+    }
+}
+
+#[cfg(feature = "otel")]
+impl EvaluationOutcome<'_> {
+    /// Classify the decision, as recorded in the `opa.decision` attribute:
+    /// `true` or `false` for boolean results, `defined` for other results,
+    /// `undefined` for empty result sets and `error` for failed evaluations
+    pub(crate) fn decision(&self) -> &'static str {
+        let Ok(result_set) = self.result else {
+            return "error";
+        };
+
+        match result_set.get(0).and_then(|result| result.get("result")) {
+            None => "undefined",
+            Some(serde_json::Value::Bool(true)) => "true",
+            Some(serde_json::Value::Bool(false)) => "false",
+            Some(_) => "defined",
+        }
+    }
+}
+
+/// How much memory a policy instance uses, as reported in
+/// [`EvaluationOutcome::memory`] or by
+/// [`Policy::memory_usage`](crate::Policy::memory_usage)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MemoryUsage {
+    /// The size of the linear memory of the instance, in bytes. It never
+    /// shrinks, so it is the most the instance ever needed.
+    pub memory_size: usize,
+
+    /// The address of the top of the heap. The heap only grows during an
+    /// evaluation, so this is its high-water mark.
+    pub heap_ptr: usize,
+
+    /// How many bytes of heap were allocated past the loaded data, including
+    /// the input
+    pub heap_used: usize,
+}
+
+/// Where [`DefaultContext`] takes the time returned by `time.now_ns` from
+#[cfg(feature = "time")]
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum TimeSource {
+    /// The time is read once when the evaluation starts, and stays the same
+    /// during the whole evaluation, like OPA does
+    #[default]
+    FrozenAtStart,
+
+    /// The time is read each time it is needed
+    Live,
+
+    /// The time is always the given one
+    Fixed(chrono::DateTime<chrono::Utc>),
+
+    /// The time is read once when the evaluation starts from the given
+    /// function, for example to use a timestamp supplied with the request
+    External(Arc<dyn Fn() -> chrono::DateTime<chrono::Utc> + Send + Sync>),
+}
+
+#[cfg(feature = "time")]
+impl std::fmt::Debug for TimeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FrozenAtStart => f.write_str("FrozenAtStart"),
+            Self::Live => f.write_str("Live"),
+            Self::Fixed(time) => f.debug_tuple("Fixed").field(time).finish(),
+            Self::External(_) => f.write_str("External(..)"),
+        }
+    }
+}
+
 /// Context passed through builtin evaluation
 pub trait EvaluationContext: Send + 'static {
     /// The type of random number generator used by this context
@@ -40,6 +274,24 @@ pub trait EvaluationContext: Send + 'static {
     /// Notify the context on evaluation start, so it can clean itself up
     fn evaluation_start(&mut self);
 
+    /// Notify the context on evaluation start, with metadata about the
+    /// evaluation, so it can key per-evaluation behavior on it.
+    ///
+    /// This is what the policy calls when an evaluation starts. The default
+    /// implementation ignores the metadata and calls
+    /// [`EvaluationContext::evaluation_start`].
+    fn evaluation_start_with_metadata(&mut self, metadata: &EvaluationMetadata<'_>) {
+        let _ = metadata;
+        self.evaluation_start();
+    }
+
+    /// Notify the context on evaluation end, with its outcome, so it can
+    /// audit the decision or clean up per-evaluation resources. This is called
+    /// after each evaluation, whether it succeeded or not.
+    fn evaluation_end(&mut self, outcome: &EvaluationOutcome<'_>) {
+        let _ = outcome;
+    }
+
     /// Get a value from the evaluation cache
     ///
     /// # Errors
@@ -53,81 +305,800 @@ pub trait EvaluationContext: Send + 'static {
     ///
     /// If the key or the value failed to serialize
     fn cache_set<K: Serialize, C: Serialize>(&mut self, key: &K, content: &C) -> Result<()>;
+
+    /// Get the statistics of the evaluation cache, if the context keeps track
+    /// of them
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// Check whether builtins are allowed to use the given [`Capability`].
+    ///
+    /// Builtins relying on a disabled capability either fail or return a
+    /// degraded result. All capabilities are enabled by default.
+    fn capability_enabled(&self, capability: Capability) -> bool {
+        let _ = capability;
+        true
+    }
+
+    /// Record the time spent in a builtin call. This is called after each
+    /// builtin invocation, whether it succeeded or not.
+    fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+        let _ = (name, duration);
+    }
+
+    /// Record a builtin call, with the JSON representation of its arguments
+    /// and of its result. This is called after each builtin invocation,
+    /// whether it succeeded or not.
+    fn record_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+    ) {
+        let _ = (name, args, result);
+    }
+
+    /// Record the time spent evaluating an entrypoint. This is called after
+    /// each evaluation, whether it succeeded or not.
+    fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+        let _ = (entrypoint, duration);
+    }
+
+    /// Get the deadline of the current evaluation, if any
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Get the time left before the evaluation deadline, if any.
+    ///
+    /// Network builtins clamp their timeouts to this budget.
+    fn remaining_budget(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Send an HTTP request on behalf of the `http.send` builtin.
+    ///
+    /// The default implementation always fails, as sending HTTP requests is
+    /// left to the embedder.
+    ///
+    /// # Errors
+    ///
+    /// If the request could not be sent, or if the context does not support
+    /// sending HTTP requests
+    #[cfg(feature = "http-builtins")]
+    fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> impl Future<Output = Result<http::Response<String>>> + Send {
+        let _ = (request, options);
+        async { anyhow::bail!("this evaluation context does not support sending HTTP requests") }
+    }
+
+    /// Wait before retrying a failed `http.send` request.
+    ///
+    /// The default implementation uses the tokio timer when called from a
+    /// tokio runtime, and sleeps on a helper thread otherwise. Contexts used
+    /// with other executors can override it to use their timer instead.
+    #[cfg(feature = "http-builtins")]
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()> + Send {
+        crate::rt::sleep(duration)
+    }
+
+    /// Add headers to an outgoing `http.send` request, after the headers set
+    /// by the policy. This can be used to propagate the current trace
+    /// context, for example with W3C `traceparent` and `baggage` headers.
+    ///
+    /// The default implementation does not add any header.
+    #[cfg(feature = "http-builtins")]
+    fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+        let _ = headers;
+    }
+
+    /// Resolve the key used to verify a JWT, when the `io.jwt.decode_verify`
+    /// constraints provide neither a `cert` nor a `secret`.
+    ///
+    /// The `kid` is the key ID found in the JWT headers, and the `issuer` is
+    /// the issuer required by the constraints, if any. The default
+    /// implementation does not resolve any key.
+    ///
+    /// # Errors
+    ///
+    /// If the key could not be resolved
+    #[cfg(feature = "jwt-builtins")]
+    fn resolve_jwt_key(
+        &mut self,
+        kid: Option<&str>,
+        issuer: Option<&str>,
+    ) -> impl Future<Output = Result<Option<JwtKey>>> + Send {
+        let _ = (kid, issuer);
+        async { Ok(None) }
+    }
+
+    /// Get the metadata returned by the `opa.runtime` builtin.
+    ///
+    /// By default, this exposes the process environment variables, unless the
+    /// [`Capability::Env`] capability is disabled, and leaves the version and
+    /// commit empty.
+    fn runtime_info(&self) -> RuntimeInfo {
+        let env = if self.capability_enabled(Capability::Env) {
+            std::env::vars().collect()
+        } else {
+            HashMap::new()
+        };
+
+        RuntimeInfo {
+            env,
+            ..RuntimeInfo::default()
+        }
+    }
+
+    /// Handle a message printed by the policy through `print`. By default,
+    /// the message is emitted as a [`tracing`] event.
+    fn print(&mut self, message: &str) {
+        tracing::info!("opa_print: {}", message);
+    }
+
+    /// Handle an abort message emitted by the policy, right before the
+    /// evaluation fails. By default, the message is emitted as a [`tracing`]
+    /// event.
+    fn abort(&mut self, message: &str) {
+        tracing::error!("opa_abort: {}", message);
+    }
+
+    /// Handle a note emitted by the policy through `trace`. By default, the
+    /// note is emitted as a [`tracing`] event.
+    fn note(&mut self, note: &str) {
+        tracing::debug!("opa_trace: {}", note);
+    }
+
+    /// Get the notes emitted through `trace` during the last evaluation, for
+    /// contexts which collect them. The default implementation returns none.
+    fn notes(&self) -> &[String] {
+        &[]
+    }
+
+    /// Get the [`SecretsProvider`] available to builtins, if any. The default
+    /// implementation does not provide any.
+    fn secrets_provider(&self) -> Option<&dyn SecretsProvider> {
+        None
+    }
+
+    /// Get a secret by name from the [`SecretsProvider`].
+    ///
+    /// Each access is audited through a [`tracing`] event, which records the
+    /// name of the secret and whether it was found, but never its value.
+    ///
+    /// # Errors
+    ///
+    /// If the context has no secrets provider, or if the provider failed to
+    /// fetch the secret
+    fn secret(&self, name: &str) -> Result<Option<Secret>> {
+        let provider = self
+            .secrets_provider()
+            .context("this evaluation context has no secrets provider")?;
+
+        let secret = provider.get(name);
+        tracing::info!(
+            target: "opa_wasm::secrets",
+            secret_name = name,
+            found = matches!(secret, Ok(Some(_))),
+            "secret accessed"
+        );
+        secret
+    }
 }
 
 /// The default evaluation context implementation
 pub struct DefaultContext {
     /// The cache used to store values during evaluation
-    cache: HashMap<String, serde_json::Value>,
+    cache: EvaluationCache,
 
     /// The time at which the evaluation started
     #[cfg(feature = "time")]
     evaluation_time: chrono::DateTime<chrono::Utc>,
+
+    /// Where the evaluation time is taken from
+    #[cfg(feature = "time")]
+    time_source: TimeSource,
+
+    /// The maximum time an evaluation can take
+    evaluation_timeout: Option<Duration>,
+
+    /// The deadline of the current evaluation
+    deadline: Option<Instant>,
+
+    /// The seed used for the random number generator, if it should be
+    /// deterministic
+    #[cfg(feature = "rng")]
+    rng_seed: Option<u64>,
+
+    /// The random number generator seeded with `rng_seed`, reseeded on each
+    /// evaluation start
+    #[cfg(feature = "rng")]
+    rng: Option<rand::rngs::StdRng>,
+
+    /// Whether `http.send` is allowed to make outgoing requests
+    http: bool,
+
+    /// Whether DNS lookups are allowed
+    dns: bool,
+
+    /// Whether the environment variables are exposed through `opa.runtime`
+    env: bool,
+
+    /// The provider of secrets available to builtins
+    secrets: Option<Arc<dyn SecretsProvider>>,
+
+    /// The client used to send `http.send` requests
+    #[cfg(feature = "http-client")]
+    http_client: HttpClient,
+
+    /// The notes emitted through `trace` during the current evaluation
+    notes: Vec<String>,
 }
 
-#[allow(clippy::derivable_impls)]
 impl Default for DefaultContext {
+    fn default() -> Self {
+        DefaultContextBuilder::default().build()
+    }
+}
+
+impl DefaultContext {
+    /// Create a [`DefaultContextBuilder`] to configure the context
+    #[must_use]
+    pub fn builder() -> DefaultContextBuilder {
+        DefaultContextBuilder::default()
+    }
+}
+
+/// A builder for [`DefaultContext`], which allows toggling individual
+/// capabilities at runtime instead of through Cargo features.
+///
+/// All capabilities are enabled by default.
+#[derive(Clone)]
+pub struct DefaultContextBuilder {
+    /// The maximum number of entries the cache can hold
+    cache_capacity: Option<usize>,
+
+    /// The maximum total size of the cached entries, in bytes
+    cache_max_bytes: Option<usize>,
+
+    /// The maximum time an evaluation can take
+    evaluation_timeout: Option<Duration>,
+
+    /// Where the evaluation time is taken from
+    #[cfg(feature = "time")]
+    time_source: TimeSource,
+
+    /// The seed used for the random number generator
+    #[cfg(feature = "rng")]
+    rng_seed: Option<u64>,
+
+    /// Whether `http.send` is allowed to make outgoing requests
+    http: bool,
+
+    /// Whether DNS lookups are allowed
+    dns: bool,
+
+    /// Whether the environment variables are exposed through `opa.runtime`
+    env: bool,
+
+    /// The provider of secrets available to builtins
+    secrets: Option<Arc<dyn SecretsProvider>>,
+
+    /// The configuration of the client used to send `http.send` requests
+    #[cfg(feature = "http-client")]
+    http_client: HttpClientConfig,
+}
+
+impl std::fmt::Debug for DefaultContextBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("DefaultContextBuilder");
+        s.field("cache_capacity", &self.cache_capacity)
+            .field("cache_max_bytes", &self.cache_max_bytes)
+            .field("evaluation_timeout", &self.evaluation_timeout);
+
+        #[cfg(feature = "time")]
+        s.field("time_source", &self.time_source);
+
+        #[cfg(feature = "rng")]
+        s.field("rng_seed", &self.rng_seed);
+
+        s.field("http", &self.http)
+            .field("dns", &self.dns)
+            .field("env", &self.env)
+            .field("secrets", &self.secrets.is_some());
+
+        #[cfg(feature = "http-client")]
+        s.field("http_client", &self.http_client);
+
+        s.finish()
+    }
+}
+
+impl Default for DefaultContextBuilder {
     fn default() -> Self {
         Self {
-            cache: HashMap::new(),
+            cache_capacity: None,
+            cache_max_bytes: None,
+            evaluation_timeout: None,
+
+            #[cfg(feature = "time")]
+            time_source: TimeSource::default(),
+
+            #[cfg(feature = "rng")]
+            rng_seed: None,
+
+            http: true,
+            dns: true,
+            env: true,
+            secrets: None,
+
+            #[cfg(feature = "http-client")]
+            http_client: HttpClientConfig::default(),
+        }
+    }
+}
+
+impl DefaultContextBuilder {
+    /// Allow or deny outgoing HTTP requests from `http.send`
+    #[must_use]
+    pub fn http(mut self, enabled: bool) -> Self {
+        self.http = enabled;
+        self
+    }
+
+    /// Allow or deny DNS lookups from `net.lookup_ip_addr`
+    #[must_use]
+    pub fn dns(mut self, enabled: bool) -> Self {
+        self.dns = enabled;
+        self
+    }
+
+    /// Expose or hide the process environment variables in `opa.runtime`
+    #[must_use]
+    pub fn env(mut self, enabled: bool) -> Self {
+        self.env = enabled;
+        self
+    }
+
+    /// Make the random number generator deterministic, by seeding it with the
+    /// given value. It is reseeded when each evaluation starts, so every
+    /// evaluation draws the same sequence of values.
+    #[cfg(feature = "rng")]
+    #[must_use]
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Limit the number of entries the evaluation cache can hold. Once the
+    /// limit is reached, the least recently used entries are evicted.
+    #[must_use]
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Limit the total size of the evaluation cache, in bytes. The size of an
+    /// entry is the length of its serialized key and value. Once the limit is
+    /// reached, the least recently used entries are evicted, and values larger
+    /// than the limit are not cached at all.
+    #[must_use]
+    pub fn cache_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.cache_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Set a time budget for each evaluation. The deadline is computed when
+    /// the evaluation starts, and network builtins clamp their timeouts to the
+    /// remaining budget.
+    #[must_use]
+    pub fn evaluation_timeout(mut self, timeout: Duration) -> Self {
+        self.evaluation_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the [`SecretsProvider`] builtins can query through
+    /// [`EvaluationContext::secret`]
+    #[must_use]
+    pub fn secrets_provider(mut self, provider: impl SecretsProvider) -> Self {
+        self.secrets = Some(Arc::new(provider));
+        self
+    }
+
+    /// Send the `http.send` requests through the given proxies. By default,
+    /// the proxies are read from the environment.
+    #[cfg(feature = "http-client")]
+    #[must_use]
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.http_client.proxy = Some(proxy);
+        self
+    }
+
+    /// Use the given root certificates and client identity for all the
+    /// `http.send` requests
+    #[cfg(feature = "http-client")]
+    #[must_use]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.http_client.tls = tls;
+        self
+    }
+
+    /// Choose where the time returned by `time.now_ns` is taken from. By
+    /// default, it is frozen when the evaluation starts.
+    #[cfg(feature = "time")]
+    #[must_use]
+    pub fn time_source(mut self, source: TimeSource) -> Self {
+        self.time_source = source;
+        self
+    }
+
+    /// Build the [`DefaultContext`]
+    #[must_use]
+    pub fn build(self) -> DefaultContext {
+        DefaultContext {
+            cache: EvaluationCache::new(self.cache_capacity, self.cache_max_bytes),
 
             #[cfg(feature = "time")]
             evaluation_time: chrono::Utc.timestamp_nanos(0),
+
+            #[cfg(feature = "time")]
+            time_source: self.time_source,
+
+            evaluation_timeout: self.evaluation_timeout,
+            deadline: None,
+
+            #[cfg(feature = "rng")]
+            rng_seed: self.rng_seed,
+
+            #[cfg(feature = "rng")]
+            rng: None,
+
+            http: self.http,
+            dns: self.dns,
+            env: self.env,
+            secrets: self.secrets,
+
+            #[cfg(feature = "http-client")]
+            http_client: HttpClient::new(self.http_client),
+
+            notes: Vec::new(),
         }
     }
 }
 
 impl EvaluationContext for DefaultContext {
     #[cfg(feature = "rng")]
-    type Rng = rand::rngs::ThreadRng;
+    type Rng = rand::rngs::StdRng;
 
     #[cfg(feature = "rng")]
     fn get_rng(&mut self) -> Self::Rng {
-        rand::thread_rng()
+        use rand::{Rng, SeedableRng};
+
+        // Each generator handed out is seeded from the next values of the
+        // seeded one, so that they are deterministic without all being the same
+        let mut seed = <rand::rngs::StdRng as SeedableRng>::Seed::default();
+        if let Some(rng_seed) = self.rng_seed {
+            self.rng
+                .get_or_insert_with(|| rand::rngs::StdRng::seed_from_u64(rng_seed))
+                .fill(&mut seed);
+        } else {
+            rand::thread_rng().fill(&mut seed);
+        }
+        rand::rngs::StdRng::from_seed(seed)
     }
 
     #[cfg(feature = "time")]
     fn now(&self) -> chrono::DateTime<chrono::Utc> {
-        self.evaluation_time
+        if let TimeSource::Live = self.time_source {
+            chrono::Utc::now()
+        } else {
+            self.evaluation_time
+        }
     }
 
     fn evaluation_start(&mut self) {
         // Clear the cache
-        self.cache = HashMap::new();
+        self.cache.clear();
+        self.notes.clear();
+
+        // Replay the same random values on each evaluation
+        #[cfg(feature = "rng")]
+        {
+            use rand::SeedableRng;
+            self.rng = self.rng_seed.map(rand::rngs::StdRng::seed_from_u64);
+        }
+
+        // Compute the deadline of this evaluation
+        self.deadline = self
+            .evaluation_timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
 
         #[cfg(feature = "time")]
         {
-            // Set the evaluation time to now
-            self.evaluation_time = chrono::Utc::now();
+            // Set the evaluation time according to the time source
+            self.evaluation_time = match &self.time_source {
+                TimeSource::FrozenAtStart | TimeSource::Live => chrono::Utc::now(),
+                TimeSource::Fixed(time) => *time,
+                TimeSource::External(source) => source(),
+            };
         }
     }
 
     fn cache_get<K: Serialize, C: DeserializeOwned>(&mut self, key: &K) -> Result<Option<C>> {
-        let key = serde_json::to_string(&key)?;
-        let Some(value) = self.cache.get(&key) else {
-            return Ok(None);
-        };
-
-        let value = serde_json::from_value(value.clone())?;
-        Ok(value)
+        self.cache.get(key)
     }
 
     fn cache_set<K: Serialize, C: Serialize>(&mut self, key: &K, content: &C) -> Result<()> {
-        let key = serde_json::to_string(key)?;
-        let content = serde_json::to_value(content)?;
-        self.cache.insert(key, content);
-        Ok(())
+        self.cache.set(key, content)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.stats())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn note(&mut self, note: &str) {
+        tracing::debug!("opa_trace: {}", note);
+        self.notes.push(note.to_owned());
+    }
+
+    fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    fn capability_enabled(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Http => self.http,
+            Capability::Dns => self.dns,
+            Capability::Env => self.env,
+        }
+    }
+
+    fn secrets_provider(&self) -> Option<&dyn SecretsProvider> {
+        self.secrets.as_deref()
+    }
+
+    #[cfg(feature = "http-client")]
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        self.http_client.send(request, &options).await
     }
 }
 
 /// Test utilities
 pub mod tests {
+    use std::time::{Duration, Instant};
+
     use anyhow::Result;
     #[cfg(feature = "time")]
     use chrono::TimeZone;
     use serde::{de::DeserializeOwned, Serialize};
 
-    use crate::{DefaultContext, EvaluationContext};
+    #[cfg(feature = "http-builtins")]
+    use crate::HttpSendOptions;
+    use crate::{
+        CacheStats, Capability, DefaultContext, EvaluationContext, EvaluationOutcome, RuntimeInfo,
+        SecretsProvider,
+    };
+
+    /// Matches the requests sent by `http.send`, to answer them with a
+    /// [`MockResponse`] registered with [`TestContext::mock_http`]
+    #[cfg(feature = "http-builtins")]
+    #[derive(Debug, Clone)]
+    pub struct RequestMatcher {
+        /// The method to match, or any method if not set
+        method: Option<http::Method>,
+
+        /// The exact URL to match
+        url: String,
+
+        /// The headers the request must have
+        headers: Vec<(http::HeaderName, String)>,
+
+        /// The exact body to match, or any body if not set
+        body: Option<String>,
+    }
+
+    #[cfg(feature = "http-builtins")]
+    impl RequestMatcher {
+        /// Match requests sent to the given URL, with any method
+        #[must_use]
+        pub fn new(url: impl Into<String>) -> Self {
+            Self {
+                method: None,
+                url: url.into(),
+                headers: Vec::new(),
+                body: None,
+            }
+        }
+
+        /// Only match requests with the given method
+        #[must_use]
+        pub fn method(mut self, method: http::Method) -> Self {
+            self.method = Some(method);
+            self
+        }
+
+        /// Only match requests which have the given header value
+        #[must_use]
+        pub fn header(mut self, name: http::HeaderName, value: impl Into<String>) -> Self {
+            self.headers.push((name, value.into()));
+            self
+        }
+
+        /// Only match requests with the given body
+        #[must_use]
+        pub fn body(mut self, body: impl Into<String>) -> Self {
+            self.body = Some(body.into());
+            self
+        }
+
+        /// Check whether the request matches
+        pub(crate) fn matches(&self, request: &http::Request<String>) -> bool {
+            self.method
+                .as_ref()
+                .is_none_or(|method| method == request.method())
+                && request.uri() == self.url.as_str()
+                && self.headers.iter().all(|(name, value)| {
+                    request
+                        .headers()
+                        .get_all(name)
+                        .iter()
+                        .any(|v| v.as_bytes() == value.as_bytes())
+                })
+                && self.body.as_ref().is_none_or(|body| body == request.body())
+        }
+    }
+
+    /// A canned response to a request sent by `http.send`
+    #[cfg(feature = "http-builtins")]
+    #[derive(Debug, Clone)]
+    pub struct MockResponse {
+        /// The status code
+        status: http::StatusCode,
+
+        /// The response headers
+        headers: Vec<(http::HeaderName, String)>,
+
+        /// The response body
+        body: String,
+    }
+
+    #[cfg(feature = "http-builtins")]
+    impl MockResponse {
+        /// Create an empty response with the given status code
+        #[must_use]
+        pub fn new(status: http::StatusCode) -> Self {
+            Self {
+                status,
+                headers: Vec::new(),
+                body: String::new(),
+            }
+        }
+
+        /// Create a response with the given status code and JSON body
+        #[must_use]
+        pub fn json(status: http::StatusCode, body: &serde_json::Value) -> Self {
+            Self::new(status)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+        }
+
+        /// Add a header to the response
+        #[must_use]
+        pub fn header(mut self, name: http::HeaderName, value: impl Into<String>) -> Self {
+            self.headers.push((name, value.into()));
+            self
+        }
+
+        /// Set the response body
+        #[must_use]
+        pub fn body(mut self, body: impl Into<String>) -> Self {
+            self.body = body.into();
+            self
+        }
+
+        /// Build the [`http::Response`]
+        pub(crate) fn to_response(&self) -> Result<http::Response<String>> {
+            let mut builder = http::Response::builder().status(self.status);
+            for (name, value) in &self.headers {
+                builder = builder.header(name, value.as_str());
+            }
+            Ok(builder.body(self.body.clone())?)
+        }
+    }
+
+    /// A failure injected in the `http.send` requests, with
+    /// [`TestContext::inject_http_fault`] or
+    /// [`TestContext::inject_http_faults`]
+    #[cfg(feature = "http-builtins")]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum HttpFault {
+        /// The request times out, as if the server never answered. It fails
+        /// right away instead of waiting for the timeout.
+        Timeout,
+
+        /// The connection to the server is refused
+        ConnectionRefused,
+
+        /// The server answers with the given status code and an empty body,
+        /// like a 503 from an overloaded server
+        Status(http::StatusCode),
+
+        /// The response is delayed by the given duration, then the request
+        /// is answered as if no fault was injected. It times out instead if
+        /// the delay is longer than the timeout of the request. The delay is
+        /// not slept, but recorded in [`TestContext::slept`].
+        Delay(Duration),
+    }
+
+    /// The faults injected in the requests sent to the URLs matching a
+    /// pattern
+    #[cfg(feature = "http-builtins")]
+    #[derive(Debug, Clone)]
+    struct HttpFaultRule {
+        /// The URL pattern, where `*` matches any sequence of characters
+        pattern: String,
+
+        /// The faults left to inject, one per request
+        faults: std::collections::VecDeque<HttpFault>,
+
+        /// Whether the fault is injected in every request instead of being
+        /// consumed
+        repeat: bool,
+    }
+
+    /// Check whether the URL matches the pattern, where `*` matches any
+    /// sequence of characters
+    #[cfg(feature = "http-builtins")]
+    fn url_matches(pattern: &str, url: &str) -> bool {
+        let mut parts = pattern.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = url.strip_prefix(first) else {
+            return false;
+        };
+
+        let mut parts: Vec<&str> = parts.collect();
+        let Some(last) = parts.pop() else {
+            // No wildcard, the URL must match exactly
+            return rest.is_empty();
+        };
+
+        for part in parts {
+            match rest.find(part) {
+                Some(index) => rest = &rest[index + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+
+    /// A builtin call recorded by [`TestContext`]
+    #[derive(Debug, Clone, PartialEq)]
+    #[non_exhaustive]
+    pub struct BuiltinCall {
+        /// The name of the builtin
+        pub name: String,
+
+        /// The arguments passed to the builtin
+        pub args: Vec<serde_json::Value>,
+
+        /// The value returned by the builtin, or the error message if it
+        /// failed
+        pub result: Result<serde_json::Value, String>,
+    }
 
     /// A context used in tests
     pub struct TestContext {
@@ -138,9 +1109,174 @@ pub mod tests {
         #[cfg(feature = "time")]
         clock: chrono::DateTime<chrono::Utc>,
 
+        /// How much the mocked time advances after each evaluation
+        #[cfg(feature = "time")]
+        clock_step: Duration,
+
         /// The seed used for the random number generator
         #[cfg(feature = "rng")]
         seed: u64,
+
+        /// The canned responses to `http.send` requests
+        #[cfg(feature = "http-builtins")]
+        http_mocks: Vec<(RequestMatcher, MockResponse)>,
+
+        /// The `http.send` requests which matched none of the mocks
+        #[cfg(feature = "http-builtins")]
+        unmatched_requests: Vec<http::Request<String>>,
+
+        /// The faults injected in `http.send` requests
+        #[cfg(feature = "http-builtins")]
+        http_faults: Vec<HttpFaultRule>,
+
+        /// The total duration of the sleeps skipped because of injected
+        /// faults
+        #[cfg(feature = "http-builtins")]
+        slept: Duration,
+
+        /// Whether the last `http.send` request got an injected fault, so that
+        /// the wait before retrying it is skipped too
+        #[cfg(feature = "http-builtins")]
+        faulted: bool,
+
+        /// The builtin calls of the current evaluation, if recording is
+        /// enabled
+        builtin_calls: Option<Vec<BuiltinCall>>,
+
+        /// What `opa.runtime` returns
+        runtime_info: RuntimeInfo,
+    }
+
+    impl TestContext {
+        /// Record every builtin call made during an evaluation, so they can be
+        /// inspected with [`TestContext::builtin_calls`]. The recording is
+        /// reset when an evaluation starts.
+        pub fn record_builtin_calls(&mut self) {
+            self.builtin_calls = Some(Vec::new());
+        }
+
+        /// Get the builtin calls recorded during the last evaluation, in the
+        /// order they were made
+        #[must_use]
+        pub fn builtin_calls(&self) -> &[BuiltinCall] {
+            self.builtin_calls.as_deref().unwrap_or_default()
+        }
+
+        /// Set what `opa.runtime` returns. By default, it returns no
+        /// environment variable and empty version fields, so that the results
+        /// don't depend on the machine running the tests.
+        pub fn set_runtime_info(&mut self, runtime_info: RuntimeInfo) {
+            self.runtime_info = runtime_info;
+        }
+    }
+
+    #[cfg(feature = "time")]
+    impl TestContext {
+        /// Get the mocked time, which is what `time.now_ns` returns. It is
+        /// 2020-07-14T12:53:22Z by default.
+        #[must_use]
+        pub fn time(&self) -> chrono::DateTime<chrono::Utc> {
+            self.clock
+        }
+
+        /// Set the mocked time
+        pub fn set_time(&mut self, time: chrono::DateTime<chrono::Utc>) {
+            self.clock = time;
+        }
+
+        /// Move the mocked time forward, for example past the expiry of a
+        /// token between two evaluations. It stops at the latest time chrono
+        /// can represent.
+        pub fn advance(&mut self, duration: Duration) {
+            self.clock = chrono::Duration::from_std(duration)
+                .ok()
+                .and_then(|duration| self.clock.checked_add_signed(duration))
+                .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+        }
+
+        /// Move the mocked time forward by the given step after each
+        /// evaluation, so that consecutive evaluations see different times.
+        /// The time is constant during an evaluation, like in OPA.
+        pub fn auto_advance(&mut self, step: Duration) {
+            self.clock_step = step;
+        }
+    }
+
+    #[cfg(feature = "http-builtins")]
+    impl TestContext {
+        /// Answer the `http.send` requests matching `matcher` with the given
+        /// response. Matchers are tried in the order they were registered.
+        ///
+        /// Once at least one response is registered, requests which match
+        /// none of them fail and are recorded, instead of being sent.
+        pub fn mock_http(&mut self, matcher: RequestMatcher, response: MockResponse) {
+            self.http_mocks.push((matcher, response));
+        }
+
+        /// Get the `http.send` requests which matched none of the registered
+        /// responses
+        #[must_use]
+        pub fn unmatched_requests(&self) -> &[http::Request<String>] {
+            &self.unmatched_requests
+        }
+
+        /// Get the total duration of the sleeps skipped because of injected
+        /// faults: the [delays](HttpFault::Delay) of the responses, and the
+        /// waits before retrying a request which got a fault. The context
+        /// sleeps as usual otherwise.
+        #[must_use]
+        pub fn slept(&self) -> Duration {
+            self.slept
+        }
+
+        /// Inject the given fault in every `http.send` request sent to a URL
+        /// matching `pattern`, where `*` matches any sequence of characters.
+        ///
+        /// Faults are injected before the requests are matched against the
+        /// mocked responses, and each retry of `http.send` is a new request.
+        pub fn inject_http_fault(&mut self, pattern: impl Into<String>, fault: HttpFault) {
+            self.http_faults.push(HttpFaultRule {
+                pattern: pattern.into(),
+                faults: std::iter::once(fault).collect(),
+                repeat: true,
+            });
+        }
+
+        /// Inject the given faults, one per request, in the `http.send`
+        /// requests sent to a URL matching `pattern`, like
+        /// [`TestContext::inject_http_fault`]. Once they are all injected,
+        /// the requests are answered as usual, which tests how policies
+        /// retry, for example after a sequence of 503s.
+        pub fn inject_http_faults(
+            &mut self,
+            pattern: impl Into<String>,
+            faults: impl IntoIterator<Item = HttpFault>,
+        ) {
+            self.http_faults.push(HttpFaultRule {
+                pattern: pattern.into(),
+                faults: faults.into_iter().collect(),
+                repeat: false,
+            });
+        }
+
+        /// Get the next fault to inject in a request, if any
+        fn next_http_fault(&mut self, url: &str) -> Option<HttpFault> {
+            let rule = self
+                .http_faults
+                .iter_mut()
+                .find(|rule| !rule.faults.is_empty() && url_matches(&rule.pattern, url))?;
+            if rule.repeat {
+                rule.faults.front().cloned()
+            } else {
+                rule.faults.pop_front()
+            }
+        }
+
+        /// Record a sleep skipped because of an injected fault, see
+        /// [`TestContext::slept`]
+        fn skip_sleep(&mut self, duration: Duration) {
+            self.slept = self.slept.saturating_add(duration);
+        }
     }
 
     #[allow(clippy::derivable_impls)]
@@ -156,8 +1292,30 @@ pub mod tests {
                     .timestamp_opt(1_594_731_202, 0)
                     .unwrap(),
 
+                #[cfg(feature = "time")]
+                clock_step: Duration::ZERO,
+
                 #[cfg(feature = "rng")]
                 seed: 0,
+
+                #[cfg(feature = "http-builtins")]
+                http_mocks: Vec::new(),
+
+                #[cfg(feature = "http-builtins")]
+                unmatched_requests: Vec::new(),
+
+                #[cfg(feature = "http-builtins")]
+                http_faults: Vec::new(),
+
+                #[cfg(feature = "http-builtins")]
+                slept: Duration::ZERO,
+
+                #[cfg(feature = "http-builtins")]
+                faulted: false,
+
+                builtin_calls: None,
+
+                runtime_info: RuntimeInfo::default(),
             }
         }
     }
@@ -167,9 +1325,20 @@ pub mod tests {
         type Rng = rand::rngs::StdRng;
 
         fn evaluation_start(&mut self) {
+            if let Some(calls) = &mut self.builtin_calls {
+                calls.clear();
+            }
+
             self.inner.evaluation_start();
         }
 
+        fn evaluation_end(&mut self, outcome: &EvaluationOutcome<'_>) {
+            #[cfg(feature = "time")]
+            self.advance(self.clock_step);
+
+            self.inner.evaluation_end(outcome);
+        }
+
         #[cfg(feature = "time")]
         fn now(&self) -> chrono::DateTime<chrono::Utc> {
             self.clock
@@ -189,5 +1358,146 @@ pub mod tests {
         fn cache_set<K: Serialize, C: Serialize>(&mut self, key: &K, content: &C) -> Result<()> {
             self.inner.cache_set(key, content)
         }
+
+        fn cache_stats(&self) -> Option<CacheStats> {
+            self.inner.cache_stats()
+        }
+
+        fn capability_enabled(&self, capability: Capability) -> bool {
+            self.inner.capability_enabled(capability)
+        }
+
+        fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+            self.inner.record_builtin_duration(name, duration);
+        }
+
+        fn record_builtin_call(
+            &mut self,
+            name: &str,
+            args: &[&[u8]],
+            result: Result<&[u8], &anyhow::Error>,
+        ) {
+            if let Some(calls) = &mut self.builtin_calls {
+                let args = args
+                    .iter()
+                    .map(|arg| serde_json::from_slice(arg).unwrap_or(serde_json::Value::Null))
+                    .collect();
+                let result = match result {
+                    Ok(value) => {
+                        Ok(serde_json::from_slice(value).unwrap_or(serde_json::Value::Null))
+                    }
+                    Err(error) => Err(format!("{error:#}")),
+                };
+
+                calls.push(BuiltinCall {
+                    name: name.to_owned(),
+                    args,
+                    result,
+                });
+            }
+
+            self.inner.record_builtin_call(name, args, result);
+        }
+
+        fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+            self.inner.record_evaluation_duration(entrypoint, duration);
+        }
+
+        fn deadline(&self) -> Option<Instant> {
+            self.inner.deadline()
+        }
+
+        fn runtime_info(&self) -> RuntimeInfo {
+            self.runtime_info.clone()
+        }
+
+        fn print(&mut self, message: &str) {
+            self.inner.print(message);
+        }
+
+        fn abort(&mut self, message: &str) {
+            self.inner.abort(message);
+        }
+
+        fn note(&mut self, note: &str) {
+            self.inner.note(note);
+        }
+
+        fn notes(&self) -> &[String] {
+            self.inner.notes()
+        }
+
+        fn secrets_provider(&self) -> Option<&dyn SecretsProvider> {
+            self.inner.secrets_provider()
+        }
+
+        #[cfg(feature = "http-builtins")]
+        fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+            self.inner.inject_http_headers(headers);
+        }
+
+        #[cfg(feature = "http-builtins")]
+        async fn sleep(&mut self, duration: Duration) {
+            if self.faulted {
+                self.skip_sleep(duration);
+            } else {
+                self.inner.sleep(duration).await;
+            }
+        }
+
+        #[cfg(feature = "http-builtins")]
+        async fn send_http(
+            &mut self,
+            request: http::Request<String>,
+            mut options: HttpSendOptions,
+        ) -> Result<http::Response<String>> {
+            let fault = self.next_http_fault(&request.uri().to_string());
+            self.faulted = fault.is_some();
+            match fault {
+                None => {}
+                Some(HttpFault::Timeout) => {
+                    anyhow::bail!("request to {} timed out", request.uri())
+                }
+                Some(HttpFault::ConnectionRefused) => {
+                    anyhow::bail!("could not connect to {}: connection refused", request.uri())
+                }
+                Some(HttpFault::Status(status)) => {
+                    return Ok(http::Response::builder()
+                        .status(status)
+                        .body(String::new())?);
+                }
+                Some(HttpFault::Delay(delay)) => match options.timeout {
+                    Some(timeout) if timeout < delay => {
+                        self.skip_sleep(timeout);
+                        anyhow::bail!("request to {} timed out", request.uri());
+                    }
+                    timeout => {
+                        self.skip_sleep(delay);
+                        options.timeout = timeout.map(|timeout| timeout.saturating_sub(delay));
+                    }
+                },
+            }
+
+            if self.http_mocks.is_empty() {
+                return self.inner.send_http(request, options).await;
+            }
+
+            let mock = self
+                .http_mocks
+                .iter()
+                .find(|(matcher, _)| matcher.matches(&request));
+
+            if let Some((_, response)) = mock {
+                return response.to_response();
+            }
+
+            let error = anyhow::anyhow!(
+                "no mocked response for {} {}",
+                request.method(),
+                request.uri()
+            );
+            self.unmatched_requests.push(request);
+            Err(error)
+        }
     }
 }
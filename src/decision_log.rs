@@ -0,0 +1,613 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decision logs, in the format of the OPA decision log plugin, with support
+//! for mask policies
+
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasmtime::AsContextMut;
+
+use crate::{DecisionSink, EvaluationContext, EvaluationId, Policy, TracingSink};
+
+/// The entrypoint of the mask policy OPA evaluates by default
+const DEFAULT_MASK_ENTRYPOINT: &str = "system/log/mask";
+
+/// A decision log entry, serialized like the ones the OPA decision log plugin
+/// produces
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct DecisionLogEntry {
+    /// A unique identifier of the decision
+    pub decision_id: String,
+
+    /// The labels of the service making the decision
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// The entrypoint which was evaluated
+    pub path: String,
+
+    /// The input of the evaluation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<Value>,
+
+    /// The result of the evaluation, or `None` if it was undefined or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    /// The error which made the evaluation fail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The revision of the bundle the policy was loaded from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+
+    /// When the decision was made, in RFC 3339 format
+    pub timestamp: String,
+
+    /// The metrics of the evaluation, like `timer_rego_query_eval_ns`
+    pub metrics: BTreeMap<String, u64>,
+
+    /// The JSON pointers of the fields removed by the mask policy
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub erased: Vec<String>,
+
+    /// The JSON pointers of the fields replaced by the mask policy
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub masked: Vec<String>,
+}
+
+/// A rule returned by the mask policy, either a JSON pointer to remove, or an
+/// operation object
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MaskRule {
+    /// A JSON pointer to the field to remove
+    Remove(String),
+
+    /// An operation on a field
+    Operation {
+        /// Either `remove` or `upsert`
+        op: String,
+
+        /// The JSON pointer to the field
+        path: String,
+
+        /// The value to set, for `upsert` operations
+        #[serde(default)]
+        value: Value,
+    },
+}
+
+/// Split a JSON pointer into its first segment, which must be `input` or
+/// `result`, and the other segments
+fn parse_pointer(pointer: &str) -> Option<(&str, Vec<String>)> {
+    let mut segments = pointer
+        .strip_prefix('/')?
+        .split('/')
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"));
+    let root = match segments.next()?.as_str() {
+        "input" => "input",
+        "result" => "result",
+        _ => return None,
+    };
+    Some((root, segments.collect()))
+}
+
+/// Get the child of a value at the given JSON pointer segment: the field of
+/// an object, or the element of an array at this index
+fn child_mut<'a>(value: &'a mut Value, segment: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(object) => object.get_mut(segment),
+        Value::Array(array) => array.get_mut(segment.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+impl DecisionLogEntry {
+    /// Get the document a JSON pointer starts in
+    fn document(&mut self, root: &str) -> &mut Option<Value> {
+        if root == "input" {
+            &mut self.input
+        } else {
+            &mut self.result
+        }
+    }
+
+    /// Remove the field or the array element at the given JSON pointer,
+    /// returning whether it was there
+    fn erase(&mut self, pointer: &str) -> bool {
+        let Some((root, segments)) = parse_pointer(pointer) else {
+            return false;
+        };
+        let document = self.document(root);
+        let Some((last, parents)) = segments.split_last() else {
+            return document.take().is_some();
+        };
+
+        let mut value = document.as_mut();
+        for segment in parents {
+            value = value.and_then(|value| child_mut(value, segment));
+        }
+        match value {
+            Some(Value::Object(object)) => object.remove(last).is_some(),
+            Some(Value::Array(array)) => match last.parse::<usize>() {
+                Ok(index) if index < array.len() => {
+                    array.remove(index);
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Set the field at the given JSON pointer, creating the objects leading
+    /// to it. Arrays are only descended into, or have their elements replaced,
+    /// at existing indexes.
+    fn upsert(&mut self, pointer: &str, new: Value) -> bool {
+        let Some((root, segments)) = parse_pointer(pointer) else {
+            return false;
+        };
+        let document = self.document(root);
+        let Some((last, parents)) = segments.split_last() else {
+            *document = Some(new);
+            return true;
+        };
+
+        let mut value = document.get_or_insert_with(|| Value::Object(serde_json::Map::new()));
+        for segment in parents {
+            value = match value {
+                Value::Object(object) => object
+                    .entry(segment.as_str())
+                    .or_insert_with(|| Value::Object(serde_json::Map::new())),
+                Value::Array(_) => match child_mut(value, segment) {
+                    Some(element) => element,
+                    None => return false,
+                },
+                _ => return false,
+            };
+        }
+        match value {
+            Value::Object(object) => {
+                object.insert(last.clone(), new);
+                true
+            }
+            Value::Array(_) => match child_mut(value, last) {
+                Some(element) => {
+                    *element = new;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Apply the rules returned by the mask policy
+    fn apply_mask(&mut self, rules: Vec<MaskRule>) {
+        for rule in rules {
+            match rule {
+                MaskRule::Remove(path) => {
+                    if self.erase(&path) {
+                        self.erased.push(path);
+                    }
+                }
+                MaskRule::Operation { op, path, .. } if op == "remove" => {
+                    if self.erase(&path) {
+                        self.erased.push(path);
+                    }
+                }
+                MaskRule::Operation { op, path, value } if op == "upsert" => {
+                    if self.upsert(&path, value) {
+                        self.masked.push(path);
+                    }
+                }
+                MaskRule::Operation { op, .. } => {
+                    tracing::warn!(%op, "unsupported mask operation");
+                }
+            }
+        }
+    }
+}
+
+/// Where the decision log entries are sent
+type Sink = Arc<dyn DecisionSink>;
+
+/// Evaluates policies, logging a [`DecisionLogEntry`] for each decision.
+///
+/// Before being emitted, the entries go through the mask policy: the
+/// `system/log/mask` entrypoint, if the policy has it, is evaluated with the
+/// entry as its input, and returns JSON pointers to the fields of the input
+/// or the result to erase, or `upsert` operations to replace them, like with
+/// OPA. Entries which fail to be masked are dropped, instead of being emitted
+/// with sensitive fields. The mask policy is evaluated without notifying the
+/// evaluation context, so that it isn't counted as a decision of its own.
+///
+/// By default, the entries are emitted as `tracing` events, with the
+/// `opa_wasm::decision_log` target, and [`with_sink`](Self::with_sink) sends
+/// them elsewhere.
+#[derive(Clone)]
+pub struct DecisionLogger {
+    /// The labels added to each entry
+    labels: BTreeMap<String, String>,
+
+    /// The entrypoint of the mask policy, if any
+    mask_entrypoint: Option<String>,
+
+    /// Where the entries are sent
+    sink: Sink,
+}
+
+impl std::fmt::Debug for DecisionLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecisionLogger")
+            .field("labels", &self.labels)
+            .field("mask_entrypoint", &self.mask_entrypoint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DecisionLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecisionLogger {
+    /// Create a logger emitting the entries as `tracing` events, and masking
+    /// them with the `system/log/mask` entrypoint
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            labels: BTreeMap::new(),
+            mask_entrypoint: Some(DEFAULT_MASK_ENTRYPOINT.to_owned()),
+            sink: Arc::new(TracingSink),
+        }
+    }
+
+    /// Send the entries to the given sink instead, like a [`FileSink`], an
+    /// `HttpSink` shipping them to a decision log service, or a function
+    ///
+    /// [`FileSink`]: crate::FileSink
+    #[must_use]
+    pub fn with_sink(mut self, sink: impl DecisionSink + 'static) -> Self {
+        self.sink = Arc::new(sink);
+        self
+    }
+
+    /// Add a label to each entry, like `id` or `version`
+    #[must_use]
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Use another entrypoint as the mask policy, or disable masking with
+    /// `None`
+    #[must_use]
+    pub fn with_mask_entrypoint(mut self, entrypoint: Option<String>) -> Self {
+        self.mask_entrypoint = entrypoint;
+        self
+    }
+
+    /// Evaluate a policy with the given entrypoint and input, like
+    /// [`Policy::evaluate`], and log the decision
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed, or if this policy
+    /// did not belong to the given store. Failed evaluations are logged too.
+    pub async fn evaluate<V, R, C, T>(
+        &self,
+        policy: &Policy<C>,
+        mut store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<R>
+    where
+        V: Serialize,
+        R: for<'de> Deserialize<'de>,
+        C: EvaluationContext,
+        T: Send,
+    {
+        let input = serde_json::to_value(input)?;
+
+        let evaluation_id = EvaluationId::next();
+        let start = Instant::now();
+        let result = policy
+            .evaluate_with_id(&mut store, entrypoint, &input, evaluation_id)
+            .await;
+        let duration = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+
+        let (decision, error) = match &result {
+            Ok(result) => (
+                result
+                    .get(0)
+                    .and_then(|result| result.get("result"))
+                    .cloned(),
+                None,
+            ),
+            Err(error) => (None, Some(format!("{error:#}"))),
+        };
+
+        let mut entry = DecisionLogEntry {
+            decision_id: evaluation_id.to_string(),
+            labels: self.labels.clone(),
+            path: entrypoint.to_owned(),
+            input: Some(input),
+            result: decision,
+            error,
+            revision: policy.revision().map(ToOwned::to_owned),
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            metrics: BTreeMap::from([("timer_rego_query_eval_ns".to_owned(), duration)]),
+            erased: Vec::new(),
+            masked: Vec::new(),
+        };
+
+        match self.mask(policy, &mut store, &mut entry).await {
+            Ok(()) => self.sink.send(&entry),
+            Err(error) => {
+                tracing::error!(
+                    decision_id = entry.decision_id,
+                    "could not mask a decision log entry, dropping it: {error:#}"
+                );
+            }
+        }
+
+        Ok(serde_json::from_value(result?)?)
+    }
+
+    /// Evaluate the mask policy on the entry, if the policy has one, and
+    /// apply its rules
+    async fn mask<C, T>(
+        &self,
+        policy: &Policy<C>,
+        store: impl AsContextMut<Data = T>,
+        entry: &mut DecisionLogEntry,
+    ) -> Result<()>
+    where
+        C: EvaluationContext,
+        T: Send,
+    {
+        let Some(mask_entrypoint) = &self.mask_entrypoint else {
+            return Ok(());
+        };
+        if !policy.entrypoints().contains(mask_entrypoint.as_str()) {
+            return Ok(());
+        }
+
+        // The mask policy is evaluated on behalf of the decision, so it doesn't
+        // go through the evaluation hooks of the context, which would count it
+        // as a decision of its own
+        let result = policy
+            .evaluate_unobserved(store, mask_entrypoint, &*entry)
+            .await?;
+        let rules = match result.get(0).and_then(|result| result.get("result")) {
+            Some(rules) => Vec::<MaskRule>::deserialize(rules)?,
+            None => Vec::new(),
+        };
+        entry.apply_mask(rules);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use wasmtime::Store;
+
+    use super::*;
+    use crate::{stub::stub_module, DefaultContext, EngineConfig, EvaluationMetadata, Runtime};
+
+    /// A context which records the entrypoints and IDs of the evaluations
+    /// starting
+    struct RecordingContext<C> {
+        inner: C,
+        evaluations: Arc<Mutex<Vec<(String, EvaluationId)>>>,
+    }
+
+    impl<C: EvaluationContext> EvaluationContext for RecordingContext<C> {
+        crate::layers::forward!(
+            rng,
+            now,
+            evaluation_end,
+            cache,
+            capability_enabled,
+            records,
+            deadline,
+            http,
+            resolve_jwt_key,
+            runtime_info,
+            messages,
+            secrets_provider,
+        );
+
+        fn evaluation_start(&mut self) {
+            self.inner.evaluation_start();
+        }
+
+        fn evaluation_start_with_metadata(&mut self, metadata: &EvaluationMetadata<'_>) {
+            self.evaluations
+                .lock()
+                .unwrap()
+                .push((metadata.entrypoint.to_owned(), metadata.evaluation_id));
+            self.inner.evaluation_start_with_metadata(metadata);
+        }
+    }
+
+    #[tokio::test]
+    async fn decisions_are_logged_under_their_evaluation_id() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `app/allow` entrypoint is always true, and the mask policy erases
+        // the password from the input
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"app/allow":0,"system/log/mask":1}"#,
+            r#"
+              (data (i32.const 1024) "[{\"result\":true}]\00")
+              (data (i32.const 1100) "[{\"result\":[\"/input/password\"]}]\00")
+            "#,
+            "global.get $entrypoint
+             if (result i32)
+               i32.const 1100
+             else
+               i32.const 1024
+             end
+             global.set $result",
+        );
+        let evaluations = Arc::new(Mutex::new(Vec::new()));
+        let context = RecordingContext {
+            inner: DefaultContext::default(),
+            evaluations: evaluations.clone(),
+        };
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new_with_evaluation_context(&mut store, &module, context)
+            .await
+            .unwrap()
+            .without_data(&mut store)
+            .await
+            .unwrap();
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let logger = DecisionLogger::new().with_sink({
+            let entries = entries.clone();
+            move |entry: &DecisionLogEntry| entries.lock().unwrap().push(entry.clone())
+        });
+        let input = json!({ "user": "alice", "password": "hunter2" });
+        let allowed: Value = logger
+            .evaluate(&policy, &mut store, "app/allow", &input)
+            .await
+            .unwrap();
+        assert_eq!(allowed, json!([{ "result": true }]));
+
+        // The mask policy is not an evaluation of its own
+        let evaluations = evaluations.lock().unwrap();
+        assert_eq!(evaluations.len(), 1);
+        let (entrypoint, evaluation_id) = &evaluations[0];
+        assert_eq!(entrypoint, "app/allow");
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.decision_id, evaluation_id.to_string());
+        assert_eq!(entry.input, Some(json!({ "user": "alice" })));
+        assert_eq!(entry.erased, ["/input/password"]);
+    }
+
+    #[test]
+    fn array_elements_are_masked() {
+        let mut entry = DecisionLogEntry {
+            decision_id: "0".to_owned(),
+            labels: BTreeMap::new(),
+            path: "app/allow".to_owned(),
+            input: Some(json!({
+                "users": [
+                    { "name": "alice", "password": "hunter2" },
+                    { "name": "bob", "password": "correct horse" },
+                ],
+                "tokens": ["first", "second", "third"],
+            })),
+            result: None,
+            error: None,
+            revision: None,
+            timestamp: "2024-01-01T00:00:00Z".to_owned(),
+            metrics: BTreeMap::new(),
+            erased: Vec::new(),
+            masked: Vec::new(),
+        };
+
+        let rules = Vec::<MaskRule>::deserialize(json!([
+            "/input/users/0/password",
+            "/input/tokens/1",
+            "/input/tokens/5",
+            "/input/tokens/first",
+            { "op": "upsert", "path": "/input/users/1/password", "value": "**REDACTED**" },
+            { "op": "upsert", "path": "/input/tokens/7", "value": "**REDACTED**" },
+        ]))
+        .unwrap();
+        entry.apply_mask(rules);
+
+        assert_eq!(
+            entry.input,
+            Some(json!({
+                "users": [
+                    { "name": "alice" },
+                    { "name": "bob", "password": "**REDACTED**" },
+                ],
+                "tokens": ["first", "third"],
+            }))
+        );
+        assert_eq!(entry.erased, ["/input/users/0/password", "/input/tokens/1"]);
+        assert_eq!(entry.masked, ["/input/users/1/password"]);
+    }
+
+    #[test]
+    fn entries_are_masked() {
+        let mut entry = DecisionLogEntry {
+            decision_id: "0".to_owned(),
+            labels: BTreeMap::new(),
+            path: "app/allow".to_owned(),
+            input: Some(json!({
+                "user": { "name": "alice", "password": "hunter2" },
+                "token": "secret",
+                "a/b": 1,
+            })),
+            result: Some(json!(true)),
+            error: None,
+            revision: None,
+            timestamp: "2024-01-01T00:00:00Z".to_owned(),
+            metrics: BTreeMap::new(),
+            erased: Vec::new(),
+            masked: Vec::new(),
+        };
+
+        let rules = Vec::<MaskRule>::deserialize(json!([
+            "/input/user/password",
+            "/input/missing",
+            { "op": "remove", "path": "/input/a~1b" },
+            { "op": "upsert", "path": "/input/token", "value": "**REDACTED**" },
+            { "op": "upsert", "path": "/input/meta/masked", "value": true },
+            "/data/ignored",
+        ]))
+        .unwrap();
+        entry.apply_mask(rules);
+
+        assert_eq!(
+            entry.input,
+            Some(json!({
+                "user": { "name": "alice" },
+                "token": "**REDACTED**",
+                "meta": { "masked": true },
+            }))
+        );
+        assert_eq!(entry.erased, ["/input/user/password", "/input/a~1b"]);
+        assert_eq!(entry.masked, ["/input/token", "/input/meta/masked"]);
+
+        // The whole result can be erased
+        entry.apply_mask(vec![MaskRule::Remove("/result".to_owned())]);
+        assert!(entry.result.is_none());
+        let serialized = serde_json::to_value(&entry).unwrap();
+        assert!(serialized.get("result").is_none());
+    }
+}
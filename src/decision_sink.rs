@@ -0,0 +1,433 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The destinations of the decision log entries: `tracing` events, files,
+//! the OPA decision log service API, or a callback
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use crate::DecisionLogEntry;
+
+/// Where a [`DecisionLogger`](crate::DecisionLogger) sends its entries.
+///
+/// Sinks are called on the evaluation path, so they should buffer the
+/// entries instead of blocking on I/O. Functions taking a
+/// [`DecisionLogEntry`] are sinks too.
+pub trait DecisionSink: Send + Sync {
+    /// Send an entry
+    fn send(&self, entry: &DecisionLogEntry);
+}
+
+impl<F: Fn(&DecisionLogEntry) + Send + Sync> DecisionSink for F {
+    fn send(&self, entry: &DecisionLogEntry) {
+        self(entry);
+    }
+}
+
+/// Emits the entries as `tracing` events, with the `opa_wasm::decision_log`
+/// target
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+impl DecisionSink for TracingSink {
+    fn send(&self, entry: &DecisionLogEntry) {
+        match serde_json::to_string(entry) {
+            Ok(entry) => tracing::info!(target: "opa_wasm::decision_log", "{entry}"),
+            Err(error) => tracing::error!("could not serialize a decision log entry: {error}"),
+        }
+    }
+}
+
+/// Appends the entries to a file, one JSON document per line.
+///
+/// The writes are buffered, and flushed with [`flush`](Self::flush) or when
+/// the sink is dropped.
+#[derive(Debug)]
+pub struct FileSink {
+    /// The buffered file
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl FileSink {
+    /// Open the file at the given path for appending, creating it if it does
+    /// not exist
+    ///
+    /// # Errors
+    ///
+    /// If the file could not be opened
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Write the buffered entries to the file
+    ///
+    /// # Errors
+    ///
+    /// If the entries could not be written
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow::anyhow!("decision log file lock poisoned"))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write an entry to the buffer
+    fn write(&self, entry: &DecisionLogEntry) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow::anyhow!("decision log file lock poisoned"))?;
+        serde_json::to_writer(&mut *writer, entry)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl DecisionSink for FileSink {
+    fn send(&self, entry: &DecisionLogEntry) {
+        if let Err(error) = self.write(entry) {
+            tracing::error!("could not write a decision log entry: {error:#}");
+        }
+    }
+}
+
+#[cfg(feature = "decision-logs-http")]
+pub use self::http::{DecisionUploader, HttpSink};
+
+/// Uploads to the OPA decision log service API
+#[cfg(feature = "decision-logs-http")]
+mod http {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::{Context, Result};
+    use flate2::{write::GzEncoder, Compression};
+    use tokio::sync::mpsc;
+
+    use super::DecisionSink;
+    use crate::DecisionLogEntry;
+
+    /// The number of entries uploaded at once by default
+    const DEFAULT_BATCH_SIZE: usize = 100;
+
+    /// How long entries wait for the batch to fill up by default
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// The number of entries waiting to be uploaded by default
+    const DEFAULT_CAPACITY: usize = 10_000;
+
+    /// Queues the entries for a [`DecisionUploader`] to upload in batches, like
+    /// the OPA decision log plugin does.
+    ///
+    /// The queue is bounded, so that a slow or unreachable service does not
+    /// make it grow without limit. The entries sent while it is full are
+    /// dropped and counted in [`dropped`](Self::dropped).
+    #[derive(Debug, Clone)]
+    pub struct HttpSink {
+        /// The entries waiting to be uploaded
+        queue: mpsc::Sender<DecisionLogEntry>,
+
+        /// The number of entries dropped because the queue was full
+        dropped: Arc<AtomicU64>,
+    }
+
+    impl HttpSink {
+        /// Create a sink uploading the entries to the given endpoint of a
+        /// decision log service, like `https://example.com/logs`.
+        ///
+        /// The entries are uploaded by the returned [`DecisionUploader`],
+        /// which must be spawned on the executor.
+        ///
+        /// # Errors
+        ///
+        /// If the URL is invalid
+        pub fn new(url: &str) -> Result<(Self, DecisionUploader)> {
+            Self::with_capacity(url, DEFAULT_CAPACITY)
+        }
+
+        /// Like [`new`](Self::new), queueing at most `capacity` entries
+        /// instead of 10000.
+        ///
+        /// # Errors
+        ///
+        /// If the URL is invalid
+        pub fn with_capacity(url: &str, capacity: usize) -> Result<(Self, DecisionUploader)> {
+            let url = reqwest::Url::parse(url)
+                .with_context(|| format!("invalid decision log service URL {url}"))?;
+            let (queue, entries) = mpsc::channel(capacity.max(1));
+            let uploader = DecisionUploader {
+                client: reqwest::Client::new(),
+                url,
+                entries,
+                batch_size: DEFAULT_BATCH_SIZE,
+                interval: DEFAULT_INTERVAL,
+            };
+            let sink = Self {
+                queue,
+                dropped: Arc::default(),
+            };
+            Ok((sink, uploader))
+        }
+
+        /// The number of entries dropped so far because the queue was full
+        #[must_use]
+        pub fn dropped(&self) -> u64 {
+            self.dropped.load(Ordering::Relaxed)
+        }
+    }
+
+    impl DecisionSink for HttpSink {
+        fn send(&self, entry: &DecisionLogEntry) {
+            match self.queue.try_send(entry.clone()) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // Only warn when the count reaches a power of two, so that
+                    // an overloaded service does not also flood the logs
+                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if dropped.is_power_of_two() {
+                        tracing::warn!(
+                            decision_id = entry.decision_id,
+                            dropped,
+                            "the decision log queue is full, dropping the entry"
+                        );
+                    }
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    tracing::error!(
+                        decision_id = entry.decision_id,
+                        "the decision log uploader stopped, dropping the entry"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Uploads the entries queued by an [`HttpSink`], as gzipped JSON arrays
+    #[must_use]
+    pub struct DecisionUploader {
+        /// The client sending the batches
+        client: reqwest::Client,
+
+        /// The endpoint of the decision log service
+        url: reqwest::Url,
+
+        /// The entries queued by the sinks
+        entries: mpsc::Receiver<DecisionLogEntry>,
+
+        /// The maximum number of entries in a batch
+        batch_size: usize,
+
+        /// How long entries wait for the batch to fill up
+        interval: Duration,
+    }
+
+    impl std::fmt::Debug for DecisionUploader {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("DecisionUploader")
+                .field("url", &self.url.as_str())
+                .field("batch_size", &self.batch_size)
+                .field("interval", &self.interval)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl DecisionUploader {
+        /// Use the given client, for example to authenticate to the service.
+        /// Defaults to a client without any configuration.
+        pub fn with_client(mut self, client: reqwest::Client) -> Self {
+            self.client = client;
+            self
+        }
+
+        /// Upload at most this many entries at once. Defaults to 100.
+        pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+            self.batch_size = batch_size.max(1);
+            self
+        }
+
+        /// Upload the queued entries at least this often. Defaults to five
+        /// seconds.
+        pub fn with_interval(mut self, interval: Duration) -> Self {
+            self.interval = interval;
+            self
+        }
+
+        /// Upload the entries until all the sinks are dropped, then upload
+        /// the remaining ones and return. Batches which fail to upload are
+        /// logged and dropped.
+        pub async fn run(mut self) {
+            let mut batch = Vec::with_capacity(self.batch_size);
+            while let Some(entry) = self.entries.recv().await {
+                batch.push(entry);
+
+                let deadline = crate::rt::sleep(self.interval);
+                tokio::pin!(deadline);
+                while batch.len() < self.batch_size {
+                    tokio::select! {
+                        entry = self.entries.recv() => match entry {
+                            Some(entry) => batch.push(entry),
+                            None => break,
+                        },
+                        () = &mut deadline => break,
+                    }
+                }
+
+                if let Err(error) = self.upload(&batch).await {
+                    tracing::error!(
+                        entries = batch.len(),
+                        "could not upload decision log entries, dropping them: {error:#}"
+                    );
+                }
+                batch.clear();
+            }
+        }
+
+        /// Upload a batch of entries
+        async fn upload(&self, batch: &[DecisionLogEntry]) -> Result<()> {
+            let body = encode_batch(batch)?;
+            self.client
+                .post(self.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(reqwest::header::CONTENT_ENCODING, "gzip")
+                .body(body)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        }
+    }
+
+    /// Encode a batch of entries as a gzipped JSON array
+    pub(super) fn encode_batch(batch: &[DecisionLogEntry]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, batch)?;
+        encoder.flush()?;
+        Ok(encoder.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use super::*;
+
+    /// Create an entry with the given decision ID
+    fn entry(decision_id: &str) -> DecisionLogEntry {
+        DecisionLogEntry {
+            decision_id: decision_id.to_owned(),
+            labels: BTreeMap::new(),
+            path: "app/allow".to_owned(),
+            input: None,
+            result: Some(serde_json::Value::Bool(true)),
+            error: None,
+            revision: None,
+            timestamp: "2024-01-01T00:00:00Z".to_owned(),
+            metrics: BTreeMap::new(),
+            erased: Vec::new(),
+            masked: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn entries_are_sent() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let sent = Arc::clone(&sent);
+            move |entry: &DecisionLogEntry| sent.lock().unwrap().push(entry.decision_id.clone())
+        };
+        callback.send(&entry("1"));
+        TracingSink.send(&entry("2"));
+        assert_eq!(*sent.lock().unwrap(), ["1"]);
+
+        let path =
+            std::env::temp_dir().join(format!("opa-wasm-decisions-{}.log", std::process::id()));
+        let sink = FileSink::create(&path).unwrap();
+        sink.send(&entry("1"));
+        sink.send(&entry("2"));
+        sink.flush().unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["decision_id"], "2");
+    }
+
+    #[cfg(feature = "decision-logs-http")]
+    #[test]
+    fn batches_are_gzipped() {
+        use std::io::Read;
+
+        let body = http::encode_batch(&[entry("1"), entry("2")]).unwrap();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        let batch: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0]["path"], "app/allow");
+
+        let error = HttpSink::new("not a url").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid decision log service URL not a url"
+        );
+    }
+
+    #[cfg(feature = "decision-logs-http")]
+    #[test]
+    fn full_queues_drop_entries() {
+        let (sink, uploader) = HttpSink::with_capacity("https://example.com/logs", 2).unwrap();
+        for id in ["1", "2", "3", "4"] {
+            sink.send(&entry(id));
+        }
+        assert_eq!(sink.dropped(), 2);
+
+        // The clones share the queue and the count
+        sink.clone().send(&entry("5"));
+        assert_eq!(sink.dropped(), 3);
+
+        // Entries sent once the uploader is gone are not counted as dropped
+        // because of a full queue
+        drop(uploader);
+        sink.send(&entry("6"));
+        assert_eq!(sink.dropped(), 3);
+    }
+}
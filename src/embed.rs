@@ -0,0 +1,322 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policies compiled and loaded with their data ahead of time, then embedded
+//! in the binary, so that instantiating them at startup runs neither the
+//! compiler nor the JSON parser. This keeps the cold starts of serverless
+//! functions short.
+//!
+//! The build script of the crate embedding the policy prepares it with
+//! [`EmbeddedPolicyBuilder`], which needs the `embed-build` feature:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() -> anyhow::Result<()> {
+//!     let wasm = std::fs::read("policy.wasm")?;
+//!     let data: serde_json::Value = serde_json::from_slice(&std::fs::read("data.json")?)?;
+//!     opa_wasm::EmbeddedPolicyBuilder::new(wasm)
+//!         .data(data)
+//!         .target(std::env::var("TARGET")?)
+//!         .write(std::env::var("OUT_DIR")?, "authz")?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The crate then embeds it with [`include_policy!`](crate::include_policy),
+//! which only needs the `embed` feature, and instantiates it in one call:
+//!
+//! ```ignore
+//! static AUTHZ: opa_wasm::EmbeddedPolicy = opa_wasm::include_policy!("authz");
+//!
+//! let engine = EngineConfig::new().build()?;
+//! let mut store = Store::new(&engine, ());
+//! let policy = AUTHZ.instantiate(&mut store).await?;
+//! ```
+
+use anyhow::Result;
+use wasmtime::{AsContextMut, Engine, Module};
+
+use crate::{DataImage, DefaultContext, EvaluationContext, Policy, Runtime};
+
+/// A policy embedded in the binary with [`include_policy!`], as prepared by
+/// [`EmbeddedPolicyBuilder`]
+///
+/// [`include_policy!`]: crate::include_policy
+/// [`EmbeddedPolicyBuilder`]: crate::EmbeddedPolicyBuilder
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedPolicy {
+    /// The compiled module
+    module: &'static [u8],
+
+    /// The serialized data image
+    image: &'static [u8],
+}
+
+impl EmbeddedPolicy {
+    /// Wrap the files written by [`EmbeddedPolicyBuilder::write`]. This is
+    /// what [`include_policy!`] expands to.
+    ///
+    /// # Safety
+    ///
+    /// The module must have been compiled by wasmtime, as it is loaded with
+    /// [`Module::deserialize`], which executes its machine code.
+    ///
+    /// [`include_policy!`]: crate::include_policy
+    /// [`EmbeddedPolicyBuilder::write`]: crate::EmbeddedPolicyBuilder::write
+    #[must_use]
+    pub const unsafe fn from_static(module: &'static [u8], image: &'static [u8]) -> Self {
+        Self { module, image }
+    }
+
+    /// Load the compiled module. The engine must have the same configuration
+    /// as the one the module was compiled with.
+    ///
+    /// # Errors
+    ///
+    /// If the module was compiled for another target or with an incompatible
+    /// configuration
+    pub fn module(&self, engine: &Engine) -> Result<Module> {
+        // SAFETY: the caller of `from_static` guarantees that the module was
+        // compiled by wasmtime, which also checks that it is compatible
+        unsafe { Module::deserialize(engine, self.module) }
+    }
+
+    /// Load the image of the memory holding the data
+    ///
+    /// # Errors
+    ///
+    /// If the image is invalid
+    pub fn data_image(&self) -> Result<DataImage> {
+        DataImage::from_bytes(self.image)
+    }
+
+    /// Instantiate the policy with its data, with the default evaluation
+    /// context
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be loaded with the engine of the store, or if
+    /// it could not be instantiated
+    pub async fn instantiate<T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+    ) -> Result<Policy<DefaultContext>> {
+        self.instantiate_with_evaluation_context(store, DefaultContext::default())
+            .await
+    }
+
+    /// Instantiate the policy with its data, with the given evaluation context
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be loaded with the engine of the store, or if
+    /// it could not be instantiated
+    pub async fn instantiate_with_evaluation_context<C: EvaluationContext, T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        context: C,
+    ) -> Result<Policy<C>> {
+        let module = self.module(store.as_context_mut().engine())?;
+        let image = self.data_image()?;
+        let runtime = Runtime::new_with_evaluation_context(&mut store, &module, context).await?;
+        runtime.with_data_image(&mut store, &image).await
+    }
+}
+
+/// Embed a policy prepared under the given name by
+/// [`EmbeddedPolicyBuilder::write`] in the `OUT_DIR` of the build script, as
+/// an [`EmbeddedPolicy`].
+///
+/// [`EmbeddedPolicyBuilder::write`]: crate::EmbeddedPolicyBuilder::write
+#[macro_export]
+macro_rules! include_policy {
+    ($name:literal) => {
+        // SAFETY: the build script wrote the module with wasmtime
+        unsafe {
+            $crate::EmbeddedPolicy::from_static(
+                ::core::include_bytes!(::core::concat!(
+                    ::core::env!("OUT_DIR"),
+                    "/",
+                    $name,
+                    ".cwasm"
+                )),
+                ::core::include_bytes!(::core::concat!(
+                    ::core::env!("OUT_DIR"),
+                    "/",
+                    $name,
+                    ".image"
+                )),
+            )
+        }
+    };
+}
+
+/// Prepares a policy to embed with [`include_policy!`], from a build script:
+/// compiles the module, and loads the data in an instance to take its
+/// [`DataImage`].
+///
+/// [`include_policy!`]: crate::include_policy
+#[cfg(feature = "embed-build")]
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct EmbeddedPolicyBuilder {
+    /// The WASM module
+    wasm: Vec<u8>,
+
+    /// The data document
+    data: Option<serde_json::Value>,
+
+    /// The configuration of the engines the policy is instantiated with
+    config: crate::EngineConfig,
+
+    /// The target triple to compile the module for, if not the host
+    target: Option<String>,
+}
+
+#[cfg(feature = "embed-build")]
+impl EmbeddedPolicyBuilder {
+    /// Prepare the given WASM module, with an empty data document
+    pub fn new(wasm: impl Into<Vec<u8>>) -> Self {
+        Self {
+            wasm: wasm.into(),
+            data: None,
+            config: crate::EngineConfig::new(),
+            target: None,
+        }
+    }
+
+    /// Load the given data document
+    pub fn data(mut self, data: serde_json::Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Compile the module for engines with the given configuration, which the
+    /// engine the policy is instantiated with must match
+    pub fn engine_config(mut self, config: crate::EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Compile the module for the given target triple, usually the `TARGET`
+    /// environment variable of the build script, instead of the host
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Compile the module and take the data image, returning the compiled
+    /// module and the serialized image
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be compiled for the target, if it is not a
+    /// valid OPA policy, or if the data could not be loaded
+    pub fn build(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        use anyhow::Context as _;
+
+        // The memory image does not depend on the target, so it is taken
+        // with an engine for the host
+        let engine = self.config.build()?;
+        let module = Module::new(&engine, &self.wasm)?;
+        let mut store = wasmtime::Store::new(&engine, ());
+        self.config.configure_store(&mut store);
+        let image = crate::block_on(async {
+            let runtime = Runtime::new(&mut store, &module).await?;
+            let policy = match &self.data {
+                Some(data) => runtime.with_data(&mut store, data).await?,
+                None => runtime.without_data(&mut store).await?,
+            };
+            policy.data_image(&store)
+        })
+        .context("could not load the data in the policy")?;
+
+        let compiled = match &self.target {
+            Some(target) => {
+                let mut config = self.config.to_wasmtime();
+                config.target(target)?;
+                Engine::new(&config)?.precompile_module(&self.wasm)?
+            }
+            None => engine.precompile_module(&self.wasm)?,
+        };
+
+        Ok((compiled, image.to_bytes()))
+    }
+
+    /// Write the compiled module and the data image in the given directory,
+    /// usually the `OUT_DIR` of the build script, for [`include_policy!`] to
+    /// embed them under the given name
+    ///
+    /// [`include_policy!`]: crate::include_policy
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be prepared, or if the files could not be
+    /// written
+    pub fn write(&self, directory: impl AsRef<std::path::Path>, name: &str) -> Result<()> {
+        use anyhow::Context as _;
+
+        let (compiled, image) = self.build()?;
+        let directory = directory.as_ref();
+        // The extensions `include_policy!` expects
+        for (extension, content) in [("cwasm", compiled), ("image", image)] {
+            let path = directory.join(format!("{name}.{extension}"));
+            std::fs::write(&path, content)
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_images_are_serialized() {
+        let mut bytes = b"OPAIMG\0\x01".to_vec();
+        bytes.extend_from_slice(&1_u64.to_le_bytes());
+        for value in [64_i32, 128, 32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&1_u64.to_le_bytes());
+        bytes.extend_from_slice(&5_u64.to_le_bytes());
+        bytes.extend_from_slice(b"allow");
+        bytes.extend_from_slice(&0_i32.to_le_bytes());
+        bytes.extend_from_slice(&3_u64.to_le_bytes());
+        bytes.extend_from_slice(b"abc");
+
+        let image = DataImage::from_bytes(&bytes).unwrap();
+        assert_eq!(image.to_bytes(), bytes);
+
+        let error = DataImage::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.to_string(), "truncated data image");
+        let error = DataImage::from_bytes(b"not an image").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "not a data image, or from an incompatible version"
+        );
+    }
+
+    #[cfg(feature = "embed-build")]
+    #[test]
+    fn modules_are_checked_at_build_time() {
+        // An empty module, which is not an OPA policy
+        let error = EmbeddedPolicyBuilder::new(b"\0asm\x01\0\0\0".to_vec())
+            .build()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "could not load the data in the policy");
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A thin layer over the wasmtime engine configuration, exposing the options
+//! which matter when running OPA policies
+
+use std::time::Duration;
+
+use anyhow::Result;
+use wasmtime::{AsContextMut, Config, Engine};
+
+/// How much the compiler optimizes the generated code
+#[cfg(feature = "fast")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum OptimizationLevel {
+    /// No optimizations, for the fastest compilation
+    None,
+
+    /// Optimize for speed, which is what policies evaluated many times want
+    #[default]
+    Speed,
+
+    /// Optimize for speed and code size
+    SpeedAndSize,
+}
+
+/// The options of the wasmtime [`Engine`] used to compile and run policies,
+/// with defaults suited to OPA modules
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// How much the compiler optimizes the generated code
+    #[cfg(feature = "fast")]
+    optimization_level: OptimizationLevel,
+
+    /// Whether functions are compiled in parallel
+    #[cfg(feature = "fast")]
+    parallel_compilation: bool,
+
+    /// The address space reserved upfront for each linear memory
+    memory_reservation: Option<u64>,
+
+    /// The address space reserved after a linear memory for it to grow into
+    memory_reserved_for_growth: Option<u64>,
+
+    /// Whether memories are initialized from copy-on-write images
+    cow_images: bool,
+
+    /// How often running evaluations yield back to the async executor
+    yield_interval: Option<Duration>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "fast")]
+            optimization_level: OptimizationLevel::default(),
+            #[cfg(feature = "fast")]
+            parallel_compilation: true,
+            memory_reservation: None,
+            memory_reserved_for_growth: None,
+            cow_images: true,
+            yield_interval: None,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Create a configuration with the default options
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how much the compiler optimizes the generated code. Defaults to
+    /// [`OptimizationLevel::Speed`].
+    #[cfg(feature = "fast")]
+    #[must_use]
+    pub fn optimization_level(mut self, level: OptimizationLevel) -> Self {
+        self.optimization_level = level;
+        self
+    }
+
+    /// Compile the functions of a module in parallel, which makes compiling
+    /// large policies faster at the cost of using more threads. Enabled by
+    /// default.
+    #[cfg(feature = "fast")]
+    #[must_use]
+    pub fn parallel_compilation(mut self, enabled: bool) -> Self {
+        self.parallel_compilation = enabled;
+        self
+    }
+
+    /// Reserve this many bytes of address space upfront for each linear
+    /// memory. Memories fitting in the reservation never move when they grow,
+    /// and large reservations let the compiler skip bounds checks. Lowering
+    /// it reduces the virtual memory used by each policy instance, which
+    /// matters when running many of them. Defaults to wasmtime's default,
+    /// 4 GiB on 64-bit platforms.
+    #[must_use]
+    pub fn memory_reservation(mut self, bytes: u64) -> Self {
+        self.memory_reservation = Some(bytes);
+        self
+    }
+
+    /// Reserve this many bytes of address space after memories which don't
+    /// fit in the [upfront reservation](Self::memory_reservation), so that
+    /// they can grow without being moved
+    #[must_use]
+    pub fn memory_reserved_for_growth(mut self, bytes: u64) -> Self {
+        self.memory_reserved_for_growth = Some(bytes);
+        self
+    }
+
+    /// Initialize the memories defined by modules from copy-on-write images
+    /// instead of copying their data segments. Enabled by default.
+    ///
+    /// The memory of OPA modules is imported and created by the
+    /// [`Runtime`](crate::Runtime), so this only affects modules defining
+    /// their own memory.
+    #[must_use]
+    pub fn memory_init_cow(mut self, enabled: bool) -> Self {
+        self.cow_images = enabled;
+        self
+    }
+
+    /// Make running evaluations yield back to the async executor about every
+    /// `interval`, so that a long evaluation doesn't block the thread it runs
+    /// on, and other tasks get a chance to run. Lower intervals are fairer,
+    /// at the cost of more overhead. Disabled by default.
+    ///
+    /// This uses wasmtime's epoch-based interruption: the engine built by
+    /// [`EngineConfig::build`] increments its epoch from a background thread,
+    /// and stores have to be set up with [`EngineConfig::configure_store`].
+    #[must_use]
+    pub fn yield_interval(mut self, interval: Duration) -> Self {
+        self.yield_interval = Some(interval);
+        self
+    }
+
+    /// Set up a store created with the engine, so that its evaluations yield
+    /// at the [configured interval](Self::yield_interval). Does nothing if
+    /// yielding is not enabled.
+    pub fn configure_store<T>(&self, mut store: impl AsContextMut<Data = T>) {
+        if self.yield_interval.is_some() {
+            store
+                .as_context_mut()
+                .epoch_deadline_async_yield_and_update(1);
+        }
+    }
+
+    /// Get the wasmtime [`Config`] for these options, for example to tweak
+    /// options which are not exposed here. Async support, which the
+    /// [`Runtime`](crate::Runtime) requires, is enabled.
+    #[must_use]
+    pub fn to_wasmtime(&self) -> Config {
+        let mut config = Config::new();
+        config
+            .async_support(true)
+            .memory_init_cow(self.cow_images)
+            .epoch_interruption(self.yield_interval.is_some());
+
+        #[cfg(feature = "fast")]
+        {
+            let level = match self.optimization_level {
+                OptimizationLevel::None => wasmtime::OptLevel::None,
+                OptimizationLevel::Speed => wasmtime::OptLevel::Speed,
+                OptimizationLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+            };
+            config
+                .cranelift_opt_level(level)
+                .parallel_compilation(self.parallel_compilation);
+        }
+
+        if let Some(bytes) = self.memory_reservation {
+            config.static_memory_maximum_size(bytes);
+        }
+
+        if let Some(bytes) = self.memory_reserved_for_growth {
+            config.dynamic_memory_reserved_for_growth(bytes);
+        }
+
+        config
+    }
+
+    /// Create an [`Engine`] with these options. If yielding is enabled, this
+    /// also starts the thread incrementing its epoch, which stops once the
+    /// engine is dropped.
+    ///
+    /// # Errors
+    ///
+    /// If wasmtime rejects the configuration
+    pub fn build(&self) -> Result<Engine> {
+        let engine = Engine::new(&self.to_wasmtime())?;
+
+        if let Some(interval) = self.yield_interval {
+            let weak = engine.weak();
+            std::thread::Builder::new()
+                .name("opa-wasm-epoch".to_owned())
+                .spawn(move || {
+                    while let Some(engine) = weak.upgrade() {
+                        engine.increment_epoch();
+                        drop(engine);
+                        std::thread::sleep(interval);
+                    }
+                })?;
+        }
+
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, task::Poll};
+
+    use wasmtime::{Memory, MemoryType, Module, Store};
+
+    use super::*;
+
+    /// The smallest valid WASM module
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    /// A module exporting a `spin` function, which loops forever
+    const SPIN_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section: () -> ()
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x07, 0x08, 0x01, 0x04, b's', b'p', b'i', b'n', 0x00, 0x00, // export "spin"
+        0x0a, 0x09, 0x01, 0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // loop br 0 end end
+    ];
+
+    #[tokio::test]
+    async fn engines_are_built() {
+        let config = EngineConfig::new();
+        #[cfg(feature = "fast")]
+        let config = config
+            .optimization_level(OptimizationLevel::None)
+            .parallel_compilation(false);
+        let engine = config
+            .memory_reservation(1 << 20)
+            .memory_reserved_for_growth(1 << 20)
+            .memory_init_cow(false)
+            .build()
+            .unwrap();
+        Module::new(&engine, EMPTY_MODULE).unwrap();
+
+        // Memories can still grow past the reservation, by moving
+        let mut store = Store::new(&engine, ());
+        let memory = Memory::new_async(&mut store, MemoryType::new(2, None))
+            .await
+            .unwrap();
+        memory.grow_async(&mut store, 64).await.unwrap();
+        assert_eq!(memory.size(&store), 66);
+    }
+
+    #[tokio::test]
+    async fn long_functions_yield() {
+        let config = EngineConfig::new().yield_interval(Duration::from_millis(1));
+        let engine = config.build().unwrap();
+        let module = Module::new(&engine, SPIN_MODULE).unwrap();
+
+        let mut store = Store::new(&engine, ());
+        config.configure_store(&mut store);
+        let instance = wasmtime::Instance::new_async(&mut store, &module, &[])
+            .await
+            .unwrap();
+        let func = instance
+            .get_typed_func::<(), ()>(&mut store, "spin")
+            .unwrap();
+
+        // The function never returns, but yields back to the executor
+        let mut call = std::pin::pin!(func.call_async(&mut store, ()));
+        let pending =
+            std::future::poll_fn(|cx| Poll::Ready(call.as_mut().poll(cx).is_pending())).await;
+        assert!(pending);
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed entrypoints, tying an entrypoint to the type of its input and of its
+//! result
+
+use std::{borrow::Cow, marker::PhantomData};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// An entrypoint, with the type of its input and of its result, to evaluate
+/// with [`Policy::evaluate_typed`](crate::Policy::evaluate_typed)
+pub struct Entrypoint<I, O> {
+    /// The name of the entrypoint
+    name: Cow<'static, str>,
+
+    /// Marker for the input and output types
+    _marker: PhantomData<fn(&I) -> O>,
+}
+
+impl<I, O> Entrypoint<I, O> {
+    /// Create a typed entrypoint out of its name
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a typed entrypoint out of a name known at runtime
+    #[must_use]
+    pub fn from_name(name: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            name: name.into(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the name of the entrypoint
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<I, O> Clone for Entrypoint<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<I, O> std::fmt::Debug for Entrypoint<I, O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Entrypoint").field(&self.name).finish()
+    }
+}
+
+/// The input of an entrypoint, which knows the entrypoint it is evaluated
+/// with.
+///
+/// It can be derived, with the `derive` feature, from a struct with named
+/// fields:
+///
+/// ```ignore
+/// #[derive(PolicyInput)]
+/// #[policy(entrypoint = "authz/allow", output = bool)]
+/// struct Request {
+///     user: String,
+///     #[policy(rename = "type")]
+///     kind: String,
+///     #[policy(skip)]
+///     cache_key: u64,
+/// }
+/// ```
+///
+/// The derive implements [`Serialize`], so the struct must not derive it as
+/// well. Fields keep their Rust name unless renamed, with
+/// `rename_all = "camelCase"` on the struct or `rename` on the field.
+pub trait PolicyInput: Serialize + Sized {
+    /// The result of the entrypoint
+    type Output: PolicyOutput;
+
+    /// The name of the entrypoint
+    const ENTRYPOINT: &'static str;
+
+    /// Get the typed entrypoint this input is evaluated with
+    #[must_use]
+    fn entrypoint() -> Entrypoint<Self, Self::Output> {
+        Entrypoint::new(Self::ENTRYPOINT)
+    }
+}
+
+/// The result of an entrypoint.
+///
+/// It can be derived, with the `derive` feature, from a struct with named
+/// fields, which implements [`Deserialize`] with the same renaming rules as
+/// [`PolicyInput`]. Missing `Option` fields are `None`, and missing
+/// `#[policy(skip)]` fields take their default value.
+pub trait PolicyOutput: DeserializeOwned {}
+
+impl PolicyOutput for serde_json::Value {}
+impl PolicyOutput for bool {}
+impl PolicyOutput for String {}
+impl<T: PolicyOutput> PolicyOutput for Vec<T> {}
+
+/// The result of a typed entrypoint, which tells undefined decisions apart
+/// from the ones evaluating to an empty object or array
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[must_use]
+pub enum EvalResult<T> {
+    /// The entrypoint evaluated to the given value
+    Defined(T),
+
+    /// The entrypoint is undefined for this input, which OPA represents as an
+    /// empty result set
+    Undefined,
+}
+
+impl<T> EvalResult<T> {
+    /// Whether the entrypoint evaluated to a value
+    #[must_use]
+    pub fn is_defined(&self) -> bool {
+        matches!(self, Self::Defined(_))
+    }
+
+    /// Whether the entrypoint is undefined
+    #[must_use]
+    pub fn is_undefined(&self) -> bool {
+        matches!(self, Self::Undefined)
+    }
+
+    /// Get the value, or `None` if the entrypoint is undefined
+    #[must_use]
+    pub fn defined(self) -> Option<T> {
+        match self {
+            Self::Defined(value) => Some(value),
+            Self::Undefined => None,
+        }
+    }
+
+    /// Get the value, or the given default if the entrypoint is undefined,
+    /// like a `default allow := false` rule would
+    #[must_use]
+    pub fn unwrap_or(self, default: T) -> T {
+        self.defined().unwrap_or(default)
+    }
+
+    /// Get the value, or the default value of the type if the entrypoint is
+    /// undefined
+    #[must_use]
+    pub fn unwrap_or_default(self) -> T
+    where
+        T: Default,
+    {
+        self.defined().unwrap_or_default()
+    }
+
+    /// Borrow the value
+    pub fn as_ref(&self) -> EvalResult<&T> {
+        match self {
+            Self::Defined(value) => EvalResult::Defined(value),
+            Self::Undefined => EvalResult::Undefined,
+        }
+    }
+
+    /// Transform the value, if the entrypoint evaluated to one
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> EvalResult<U> {
+        match self {
+            Self::Defined(value) => EvalResult::Defined(f(value)),
+            Self::Undefined => EvalResult::Undefined,
+        }
+    }
+}
+
+impl<T> From<Option<T>> for EvalResult<T> {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Undefined, Self::Defined)
+    }
+}
+
+impl<T> From<EvalResult<T>> for Option<T> {
+    fn from(value: EvalResult<T>) -> Self {
+        value.defined()
+    }
+}
+
+/// A row of a result set
+#[derive(Deserialize)]
+struct ResultRow<O> {
+    /// The result of the entrypoint
+    result: O,
+}
+
+/// Get the result out of a result set, which is empty if the result is
+/// undefined
+pub(crate) fn from_result_set<O: DeserializeOwned>(
+    result_set: serde_json::Value,
+) -> Result<EvalResult<O>> {
+    let rows: Vec<ResultRow<O>> = serde_json::from_value(result_set)?;
+    Ok(rows.into_iter().next().map(|row| row.result).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn results_are_extracted() {
+        let result: EvalResult<bool> = from_result_set(json!([{"result": true}])).unwrap();
+        assert_eq!(result, EvalResult::Defined(true));
+
+        let result: EvalResult<bool> = from_result_set(json!([])).unwrap();
+        assert_eq!(result, EvalResult::Undefined);
+        assert!(!result.unwrap_or_default());
+
+        // Empty objects and arrays are defined
+        let result: EvalResult<serde_json::Value> =
+            from_result_set(json!([{"result": {}}])).unwrap();
+        assert_eq!(result, EvalResult::Defined(json!({})));
+        let result: EvalResult<Vec<String>> = from_result_set(json!([{"result": []}])).unwrap();
+        assert_eq!(result.as_ref().map(Vec::len), EvalResult::Defined(0));
+        assert_eq!(Option::from(result), Some(Vec::new()));
+
+        assert!(from_result_set::<bool>(json!([{"result": "yes"}])).is_err());
+
+        let entrypoint = Entrypoint::<(), bool>::new("authz/allow");
+        assert_eq!(entrypoint.clone().name(), "authz/allow");
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Explanations of evaluations, collected by
+//! [`Policy::evaluate_with_report`](crate::Policy::evaluate_with_report)
+
+use std::time::Duration;
+
+use serde_json::Value;
+
+/// A builtin call made during an evaluation
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ExplainedCall {
+    /// The name of the builtin
+    pub name: String,
+
+    /// The arguments passed to the builtin
+    pub args: Vec<Value>,
+
+    /// The result of the builtin, or the error it failed with
+    pub result: Result<Value, String>,
+
+    /// How long the call took
+    pub duration: Duration,
+}
+
+impl ExplainedCall {
+    /// Explain a builtin call, out of the JSON values of its arguments and of
+    /// its result
+    pub(crate) fn new(
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            args: args.iter().map(|arg| parse(arg)).collect(),
+            result: result.map(parse).map_err(|error| format!("{error:#}")),
+            duration,
+        }
+    }
+}
+
+/// Parse a JSON value, keeping it as a string if it is not valid JSON
+fn parse(json: &[u8]) -> Value {
+    serde_json::from_slice(json)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(json).into_owned()))
+}
+
+/// Something which happened during an evaluation
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExplanationEvent {
+    /// A builtin was called
+    BuiltinCall(ExplainedCall),
+
+    /// The policy emitted a note with `trace`, collected if the context
+    /// collects them, like [`DefaultContext`](crate::DefaultContext) does
+    Note(String),
+
+    /// The policy printed a message with `print`
+    Print(String),
+
+    /// The policy aborted with a message
+    Abort(String),
+}
+
+/// What happened during an evaluation, in order, approximating the output of
+/// `opa eval --explain` for compiled policies
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Explanation {
+    /// The evaluated entrypoint
+    pub entrypoint: String,
+
+    /// What happened, in order
+    pub events: Vec<ExplanationEvent>,
+
+    /// How long the evaluation took
+    pub duration: Duration,
+}
+
+impl Explanation {
+    /// The builtin calls, in order
+    pub fn builtin_calls(&self) -> impl Iterator<Item = &ExplainedCall> {
+        self.events.iter().filter_map(|event| match event {
+            ExplanationEvent::BuiltinCall(call) => Some(call),
+            _ => None,
+        })
+    }
+
+    /// The notes emitted with `trace`, in order
+    pub fn notes(&self) -> impl Iterator<Item = &str> {
+        self.events.iter().filter_map(|event| match event {
+            ExplanationEvent::Note(note) => Some(note.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The messages printed with `print`, in order
+    pub fn prints(&self) -> impl Iterator<Item = &str> {
+        self.events.iter().filter_map(|event| match event {
+            ExplanationEvent::Print(message) => Some(message.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The abort messages, in order
+    pub fn aborts(&self) -> impl Iterator<Item = &str> {
+        self.events.iter().filter_map(|event| match event {
+            ExplanationEvent::Abort(message) => Some(message.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Enter {}", self.entrypoint)?;
+        for event in &self.events {
+            match event {
+                ExplanationEvent::BuiltinCall(call) => {
+                    let args: Vec<String> = call.args.iter().map(Value::to_string).collect();
+                    let args = args.join(", ");
+                    match &call.result {
+                        Ok(result) => writeln!(
+                            f,
+                            "| Call {}({args}) = {result} ({:?})",
+                            call.name, call.duration
+                        )?,
+                        Err(error) => writeln!(
+                            f,
+                            "| Call {}({args}) failed: {error} ({:?})",
+                            call.name, call.duration
+                        )?,
+                    }
+                }
+                ExplanationEvent::Note(note) => writeln!(f, "| Note {note:?}")?,
+                ExplanationEvent::Print(message) => writeln!(f, "| Print {message:?}")?,
+                ExplanationEvent::Abort(message) => writeln!(f, "| Abort {message:?}")?,
+            }
+        }
+        write!(f, "Exit {} ({:?})", self.entrypoint, self.duration)
+    }
+}
+
+/// The result of an evaluation, with its explanation
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct EvaluationReport<R> {
+    /// The result of the evaluation, or the error it failed with
+    pub result: anyhow::Result<R>,
+
+    /// What happened during the evaluation, even if it failed
+    pub explanation: Explanation,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn explanations_are_printed() {
+        let error = anyhow::anyhow!("request timed out");
+        let explanation = Explanation {
+            entrypoint: "authz/allow".to_owned(),
+            events: vec![
+                ExplanationEvent::BuiltinCall(ExplainedCall::new(
+                    "time.now_ns",
+                    &[],
+                    Ok(b"1704067200000000000"),
+                    Duration::from_millis(1),
+                )),
+                ExplanationEvent::Note("checking the user".to_owned()),
+                ExplanationEvent::BuiltinCall(ExplainedCall::new(
+                    "http.send",
+                    &[br#"{"url":"https://example.com/"}"#],
+                    Err(&error),
+                    Duration::from_millis(2),
+                )),
+                ExplanationEvent::Print("denied".to_owned()),
+                ExplanationEvent::Abort("var assignment conflict".to_owned()),
+            ],
+            duration: Duration::from_millis(5),
+        };
+
+        assert_eq!(explanation.builtin_calls().count(), 2);
+        assert_eq!(
+            explanation.builtin_calls().nth(1).unwrap().args,
+            [json!({"url": "https://example.com/"})]
+        );
+        assert_eq!(
+            explanation.notes().collect::<Vec<_>>(),
+            ["checking the user"]
+        );
+        assert_eq!(explanation.prints().collect::<Vec<_>>(), ["denied"]);
+        assert_eq!(
+            explanation.aborts().collect::<Vec<_>>(),
+            ["var assignment conflict"]
+        );
+        assert_eq!(
+            explanation.to_string(),
+            "Enter authz/allow\n\
+             | Call time.now_ns() = 1704067200000000000 (1ms)\n\
+             | Note \"checking the user\"\n\
+             | Call http.send({\"url\":\"https://example.com/\"}) failed: request timed out (2ms)\n\
+             | Print \"denied\"\n\
+             | Abort \"var assignment conflict\"\n\
+             Exit authz/allow (5ms)"
+        );
+    }
+}
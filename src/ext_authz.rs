@@ -0,0 +1,475 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An Envoy external authorization (`ext_authz` v3) gRPC service, evaluating
+//! a policy for each request checked by Envoy.
+//!
+//! The input and the decisions follow the format of the Go
+//! `opa-envoy-plugin`, so that existing policies can be reused as-is.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use envoy_types::{
+    ext_authz::v3::{
+        pb::{Authorization, AuthorizationServer, CheckRequest, CheckResponse, HttpStatusCode},
+        CheckResponseExt, DeniedHttpResponseBuilder, OkHttpResponseBuilder,
+    },
+    pb::envoy::{
+        config::core::v3::{address, socket_address, Address},
+        service::auth::v3::attribute_context::{HttpRequest, Peer},
+    },
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tonic::{Request, Response, Status};
+
+use crate::{EvaluationContext, PolicyPool};
+
+/// An Envoy `ext_authz` v3 authorization service, evaluating an entrypoint on
+/// the instances of a [`PolicyPool`] for each request.
+///
+/// The policy gets the same input as with the `opa-envoy-plugin`: the
+/// attributes of the request in the `protojson` format, along with
+/// `parsed_path`, `parsed_query`, `parsed_body` and `truncated_body`. Its
+/// decision is either a boolean, or an object with an `allowed` field and
+/// optionally `headers`, `request_headers_to_remove`,
+/// `response_headers_to_add`, `http_status` and `body`.
+pub struct ExtAuthz<C, T = ()> {
+    /// The instances evaluating the requests
+    pool: Arc<PolicyPool<C, T>>,
+
+    /// The entrypoint to evaluate
+    entrypoint: Arc<str>,
+}
+
+impl<C, T> std::fmt::Debug for ExtAuthz<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtAuthz")
+            .field("entrypoint", &self.entrypoint)
+            .field("instances", &self.pool.size())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C, T> ExtAuthz<C, T> {
+    /// Check requests by evaluating the given entrypoint, e.g.
+    /// `envoy/authz/allow`, on the instances of the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>, entrypoint: impl Into<Arc<str>>) -> Self {
+        Self {
+            pool,
+            entrypoint: entrypoint.into(),
+        }
+    }
+
+    /// Wrap the service in a gRPC server, to add to a [`tonic`] router
+    #[must_use]
+    pub fn into_server(self) -> AuthorizationServer<Self>
+    where
+        Self: Authorization,
+    {
+        AuthorizationServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl<C, T> Authorization for ExtAuthz<C, T>
+where
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let input = input(&request.into_inner());
+        let result_set: Value = self
+            .pool
+            .evaluate(&self.entrypoint, &input)
+            .await
+            .map_err(|error| {
+                tracing::error!(entrypoint = %self.entrypoint, "could not evaluate the policy: {error:#}");
+                Status::internal("could not evaluate the policy")
+            })?;
+
+        let decision = Decision::from_result_set(&result_set)
+            .map_err(|error| Status::internal(format!("invalid decision: {error}")))?;
+
+        Ok(Response::new(decision.into_response()))
+    }
+}
+
+/// Insert a string in a JSON object, unless it is empty, like `protojson`
+/// omits fields with their default value
+fn insert_string(object: &mut Map<String, Value>, key: &str, value: &str) {
+    if !value.is_empty() {
+        object.insert(key.to_owned(), Value::String(value.to_owned()));
+    }
+}
+
+/// Format an address in the `protojson` format
+fn address(address: &Address) -> Value {
+    match &address.address {
+        Some(address::Address::SocketAddress(socket)) => {
+            let mut object = Map::new();
+            insert_string(&mut object, "address", &socket.address);
+            match &socket.port_specifier {
+                Some(socket_address::PortSpecifier::PortValue(port)) => {
+                    object.insert("portValue".to_owned(), json!(port));
+                }
+                Some(socket_address::PortSpecifier::NamedPort(name)) => {
+                    insert_string(&mut object, "namedPort", name);
+                }
+                None => {}
+            }
+            json!({ "socketAddress": object })
+        }
+        Some(address::Address::Pipe(pipe)) => json!({ "pipe": { "path": pipe.path } }),
+        _ => json!({}),
+    }
+}
+
+/// Format a peer in the `protojson` format
+fn peer(peer: &Peer) -> Value {
+    let mut object = Map::new();
+    if let Some(address) = &peer.address {
+        object.insert("address".to_owned(), self::address(address));
+    }
+    insert_string(&mut object, "service", &peer.service);
+    if !peer.labels.is_empty() {
+        object.insert("labels".to_owned(), json!(peer.labels));
+    }
+    insert_string(&mut object, "principal", &peer.principal);
+    insert_string(&mut object, "certificate", &peer.certificate);
+    Value::Object(object)
+}
+
+/// Format an HTTP request in the `protojson` format
+fn http_request(http: &HttpRequest) -> Value {
+    let mut object = Map::new();
+    insert_string(&mut object, "id", &http.id);
+    insert_string(&mut object, "method", &http.method);
+    if !http.headers.is_empty() {
+        object.insert("headers".to_owned(), json!(http.headers));
+    }
+    insert_string(&mut object, "path", &http.path);
+    insert_string(&mut object, "host", &http.host);
+    insert_string(&mut object, "scheme", &http.scheme);
+    insert_string(&mut object, "query", &http.query);
+    insert_string(&mut object, "fragment", &http.fragment);
+    if http.size != 0 {
+        // 64-bit integers are formatted as strings
+        object.insert("size".to_owned(), Value::String(http.size.to_string()));
+    }
+    insert_string(&mut object, "protocol", &http.protocol);
+    insert_string(&mut object, "body", &http.body);
+    Value::Object(object)
+}
+
+/// Map a check request to the input of the policy
+fn input(request: &CheckRequest) -> Value {
+    let mut attributes = Map::new();
+    let mut http = None;
+
+    if let Some(attributes_context) = &request.attributes {
+        if let Some(source) = &attributes_context.source {
+            attributes.insert("source".to_owned(), peer(source));
+        }
+        if let Some(destination) = &attributes_context.destination {
+            attributes.insert("destination".to_owned(), peer(destination));
+        }
+        if let Some(request) = &attributes_context.request {
+            let mut object = Map::new();
+            if let Some(time) = &request.time {
+                let time = u32::try_from(time.nanos)
+                    .ok()
+                    .and_then(|nanos| chrono::DateTime::from_timestamp(time.seconds, nanos));
+                if let Some(time) = time {
+                    let time = time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true);
+                    object.insert("time".to_owned(), Value::String(time));
+                }
+            }
+            if let Some(request) = &request.http {
+                object.insert("http".to_owned(), http_request(request));
+                http = Some(request);
+            }
+            attributes.insert("request".to_owned(), Value::Object(object));
+        }
+        if !attributes_context.context_extensions.is_empty() {
+            attributes.insert(
+                "contextExtensions".to_owned(),
+                json!(attributes_context.context_extensions),
+            );
+        }
+    }
+
+    let mut input = Map::new();
+    input.insert("attributes".to_owned(), Value::Object(attributes));
+
+    if let Some(http) = http {
+        let (path, query) = http.path.split_once('?').unwrap_or((&http.path, ""));
+
+        let parsed_path: Vec<_> = path
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                urlencoding::decode(segment)
+                    .map_or_else(|_| segment.to_owned(), std::borrow::Cow::into_owned)
+            })
+            .collect();
+        input.insert("parsed_path".to_owned(), json!(parsed_path));
+
+        let mut parsed_query: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            parsed_query
+                .entry(key.into_owned())
+                .or_default()
+                .push(value.into_owned());
+        }
+        input.insert("parsed_query".to_owned(), json!(parsed_query));
+
+        // Envoy only forwards the beginning of large bodies
+        let truncated = http
+            .headers
+            .get("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length > http.body.len());
+        input.insert("truncated_body".to_owned(), Value::Bool(truncated));
+
+        let is_json = http
+            .headers
+            .get("content-type")
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        let parsed_body = if is_json && !truncated && !http.body.is_empty() {
+            serde_json::from_str(&http.body).unwrap_or(Value::Null)
+        } else {
+            Value::Null
+        };
+        input.insert("parsed_body".to_owned(), parsed_body);
+    }
+
+    input.insert(
+        "version".to_owned(),
+        json!({ "encoding": "protojson", "ext_authz": "v3" }),
+    );
+
+    Value::Object(input)
+}
+
+/// The decision of the policy, as returned by the entrypoint
+#[derive(Debug, Default, Deserialize)]
+struct Decision {
+    /// Whether the request is allowed
+    #[serde(default)]
+    allowed: bool,
+
+    /// The headers to add to the request sent upstream when allowed, or to
+    /// the response sent downstream when denied
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+
+    /// The headers to remove from the request sent upstream when allowed
+    #[serde(default)]
+    request_headers_to_remove: Vec<String>,
+
+    /// The headers to add to the response sent downstream when allowed
+    #[serde(default)]
+    response_headers_to_add: BTreeMap<String, String>,
+
+    /// The status of the response sent downstream when denied
+    #[serde(default)]
+    http_status: Option<u16>,
+
+    /// The body of the response sent downstream when denied
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl Decision {
+    /// Get the decision from the result set returned by the policy. An
+    /// undefined decision denies the request.
+    fn from_result_set(result_set: &Value) -> Result<Self, serde_json::Error> {
+        match result_set.get(0).and_then(|result| result.get("result")) {
+            Some(Value::Bool(allowed)) => Ok(Self {
+                allowed: *allowed,
+                ..Self::default()
+            }),
+            Some(decision) => Self::deserialize(decision),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Build the response sent back to Envoy
+    fn into_response(self) -> CheckResponse {
+        let mut response = CheckResponse::new();
+
+        if self.allowed {
+            let mut http = OkHttpResponseBuilder::new();
+            for (key, value) in self.headers {
+                http.add_header(key, value, None, false);
+            }
+            for header in self.request_headers_to_remove {
+                http.remove_header(header);
+            }
+            for (key, value) in self.response_headers_to_add {
+                http.add_response_header(key, value, None, false);
+            }
+            response.set_status(Status::ok("")).set_http_response(http);
+        } else {
+            let mut http = DeniedHttpResponseBuilder::new();
+            for (key, value) in self.headers {
+                http.add_header(key, value, None, false);
+            }
+            let status = self
+                .http_status
+                .and_then(|status| HttpStatusCode::try_from(i32::from(status)).ok());
+            if let Some(status) = status {
+                http.set_http_status(status);
+            }
+            if let Some(body) = self.body {
+                http.set_body(body);
+            }
+            response
+                .set_status(Status::permission_denied(""))
+                .set_http_response(http);
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use envoy_types::{
+        ext_authz::v3::pb::HttpResponse,
+        pb::{
+            envoy::{
+                config::core::v3::SocketAddress,
+                service::auth::v3::{attribute_context, AttributeContext},
+            },
+            google::protobuf::Timestamp,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn requests_are_mapped_like_the_envoy_plugin() {
+        let request = CheckRequest {
+            attributes: Some(AttributeContext {
+                source: Some(Peer {
+                    address: Some(Address {
+                        address: Some(address::Address::SocketAddress(SocketAddress {
+                            address: "10.0.0.1".to_owned(),
+                            port_specifier: Some(socket_address::PortSpecifier::PortValue(51234)),
+                            ..SocketAddress::default()
+                        })),
+                    }),
+                    ..Peer::default()
+                }),
+                request: Some(attribute_context::Request {
+                    time: Some(Timestamp {
+                        seconds: 1_700_000_000,
+                        nanos: 0,
+                    }),
+                    http: Some(HttpRequest {
+                        method: "POST".to_owned(),
+                        path: "/people/alice%20smith?tag=a&tag=b".to_owned(),
+                        headers: HashMap::from([
+                            ("content-type".to_owned(), "application/json".to_owned()),
+                            ("content-length".to_owned(), "14".to_owned()),
+                        ]),
+                        size: 14,
+                        body: r#"{"name":"bob"}"#.to_owned(),
+                        ..HttpRequest::default()
+                    }),
+                }),
+                context_extensions: HashMap::from([("env".to_owned(), "prod".to_owned())]),
+                ..AttributeContext::default()
+            }),
+        };
+
+        assert_eq!(
+            input(&request),
+            json!({
+                "attributes": {
+                    "source": {
+                        "address": {
+                            "socketAddress": { "address": "10.0.0.1", "portValue": 51234 },
+                        },
+                    },
+                    "request": {
+                        "time": "2023-11-14T22:13:20Z",
+                        "http": {
+                            "method": "POST",
+                            "path": "/people/alice%20smith?tag=a&tag=b",
+                            "headers": {
+                                "content-type": "application/json",
+                                "content-length": "14",
+                            },
+                            "size": "14",
+                            "body": r#"{"name":"bob"}"#,
+                        },
+                    },
+                    "contextExtensions": { "env": "prod" },
+                },
+                "parsed_path": ["people", "alice smith"],
+                "parsed_query": { "tag": ["a", "b"] },
+                "parsed_body": { "name": "bob" },
+                "truncated_body": false,
+                "version": { "encoding": "protojson", "ext_authz": "v3" },
+            })
+        );
+    }
+
+    #[test]
+    fn decisions_are_mapped_to_responses() {
+        let response = Decision::from_result_set(&json!([{ "result": true }]))
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status.unwrap().code, 0);
+        assert!(matches!(
+            response.http_response,
+            Some(HttpResponse::OkResponse(_))
+        ));
+
+        let response = Decision::from_result_set(&json!([{
+            "result": {
+                "allowed": false,
+                "headers": { "x-reason": "nope" },
+                "http_status": 401,
+                "body": "unauthorized",
+            },
+        }]))
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status.unwrap().code, 7);
+        let denied = match response.http_response {
+            Some(HttpResponse::DeniedResponse(denied)) => Some(denied),
+            _ => None,
+        }
+        .unwrap();
+        assert_eq!(denied.status.unwrap().code, 401);
+        assert_eq!(denied.body, "unauthorized");
+        assert_eq!(denied.headers.len(), 1);
+
+        // Undefined decisions deny the request
+        let response = Decision::from_result_set(&json!([]))
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status.unwrap().code, 7);
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What is known about an evaluation which failed inside the policy module,
+//! attached to the error returned by the evaluation
+
+use wasmtime::{Trap, WasmBacktrace};
+
+use crate::types::AbiVersion;
+
+/// The error returned by `opa_abort`, to tell it apart from traps
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct Aborted(pub(crate) String);
+
+/// Why the policy module failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureCause {
+    /// The module trapped, for example because its memory could not grow
+    Trap(Trap),
+
+    /// The module called `opa_abort` with this message, for example because
+    /// a function returned conflicting values
+    Abort(String),
+}
+
+/// The context of an evaluation which trapped or aborted in the policy
+/// module.
+///
+/// It is attached as context to the error returned by the evaluation, and
+/// can be retrieved with [`anyhow::Error::downcast_ref`]. The message of
+/// the error starts with it, followed by the backtrace and the cause.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EvaluationFailure {
+    /// The entrypoint being evaluated
+    pub entrypoint: String,
+
+    /// The revision of the bundle the policy was loaded from, if any
+    pub revision: Option<String>,
+
+    /// The ABI version of the module
+    pub abi_version: AbiVersion,
+
+    /// Why the module failed
+    pub cause: FailureCause,
+
+    /// The WASM backtrace where the module failed, if the engine captures
+    /// them, which it does by default
+    pub backtrace: Option<String>,
+
+    /// The FNV-1a hash of the JSON input, if enabled with
+    /// [`Runtime::with_input_hash`](crate::Runtime::with_input_hash), to
+    /// find the failing input in logs without logging it
+    pub input_hash: Option<u64>,
+}
+
+impl EvaluationFailure {
+    /// Describe the failure of an evaluation, if it failed in the module
+    pub(crate) fn describe(
+        error: &anyhow::Error,
+        entrypoint: &str,
+        revision: Option<&str>,
+        abi_version: AbiVersion,
+        input: Option<&[u8]>,
+    ) -> Option<Self> {
+        let cause = if let Some(trap) = error.downcast_ref::<Trap>() {
+            FailureCause::Trap(*trap)
+        } else if let Some(Aborted(message)) = error.downcast_ref::<Aborted>() {
+            FailureCause::Abort(message.clone())
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            entrypoint: entrypoint.to_owned(),
+            revision: revision.map(ToOwned::to_owned),
+            abi_version,
+            cause,
+            backtrace: error
+                .downcast_ref::<WasmBacktrace>()
+                .map(ToString::to_string),
+            input_hash: input.map(fnv1a),
+        })
+    }
+}
+
+impl std::fmt::Display for EvaluationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed = match self.cause {
+            FailureCause::Trap(_) => "trapped",
+            FailureCause::Abort(_) => "aborted",
+        };
+        write!(
+            f,
+            "policy {failed} while evaluating {} (ABI version {}",
+            self.entrypoint, self.abi_version
+        )?;
+        if let Some(revision) = &self.revision {
+            write!(f, ", revision {revision}")?;
+        }
+        if let Some(hash) = self.input_hash {
+            write!(f, ", input {hash:016x}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Hash bytes with 64-bit FNV-1a, which is stable across processes and
+/// versions, unlike the hasher of the standard library
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Module, Store};
+
+    use super::*;
+    use crate::{stub::failing_wasm, EngineConfig, Runtime};
+
+    #[tokio::test]
+    async fn failures_are_described() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `trap` entrypoint traps, and the `abort` one aborts
+        let module = Module::new(&engine, failing_wasm()).unwrap();
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new(&mut store, &module)
+            .await
+            .unwrap()
+            .with_revision("v1")
+            .with_input_hash(true)
+            .without_data(&mut store)
+            .await
+            .unwrap();
+
+        let error = policy
+            .evaluate::<_, serde_json::Value, _>(&mut store, "trap", &"input")
+            .await
+            .unwrap_err();
+        let failure = error.downcast_ref::<EvaluationFailure>().unwrap();
+        assert_eq!(failure.entrypoint, "trap");
+        assert_eq!(failure.revision.as_deref(), Some("v1"));
+        assert_eq!(
+            failure.cause,
+            FailureCause::Trap(Trap::UnreachableCodeReached)
+        );
+        assert!(failure.backtrace.is_some());
+        assert_eq!(failure.input_hash, Some(fnv1a(b"\"input\"")));
+        assert!(error.to_string().starts_with(
+            "policy trapped while evaluating trap (ABI version 1.1, revision v1, input "
+        ));
+
+        let error = policy
+            .evaluate::<_, serde_json::Value, _>(&mut store, "abort", &"input")
+            .await
+            .unwrap_err();
+        let failure = error.downcast_ref::<EvaluationFailure>().unwrap();
+        assert_eq!(
+            failure.cause,
+            FailureCause::Abort("conflicting values".to_owned())
+        );
+        assert!(format!("{error:#}").ends_with("conflicting values"));
+
+        // Errors which don't come from the module are left as is
+        let error = policy
+            .evaluate::<_, serde_json::Value, _>(&mut store, "missing", &"input")
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<EvaluationFailure>().is_none());
+    }
+}
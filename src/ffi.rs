@@ -0,0 +1,299 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A C API to evaluate policies from other languages, declared in
+//! `include/opa_wasm.h`.
+//!
+//! The crate is not built as a `cdylib` by default, build the shared library
+//! with:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! Functions which can fail take an `error` out-parameter, which, if not
+//! `NULL`, is set to a message to free with `opa_wasm_string_free` when they
+//! do. A policy handle must not be used from multiple threads at once.
+
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use anyhow::{Context, Result};
+use wasmtime::{Engine, Module, Store};
+
+use crate::{DefaultContext, EngineConfig, Policy, Runtime};
+
+/// A policy instance, with everything needed to evaluate it synchronously
+pub struct OpaWasmPolicy {
+    /// The runtime driving the evaluations to completion
+    executor: tokio::runtime::Runtime,
+
+    /// The engine which compiled the module
+    engine: Engine,
+
+    /// The compiled module, to instantiate it again when the data changes
+    module: Module,
+
+    /// The store holding the instance
+    store: Store<()>,
+
+    /// The instance, with its data loaded
+    policy: Policy<DefaultContext>,
+}
+
+impl OpaWasmPolicy {
+    /// Compile and instantiate a module, with an empty data document
+    fn new(module: &[u8]) -> Result<Self> {
+        let executor = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let engine = EngineConfig::new().build()?;
+        let module = Module::new(&engine, module).context("could not compile the module")?;
+        let data = serde_json::Value::Object(serde_json::Map::default());
+        let (store, policy) = executor.block_on(instantiate(&engine, &module, &data))?;
+
+        Ok(Self {
+            executor,
+            engine,
+            module,
+            store,
+            policy,
+        })
+    }
+
+    /// Replace the data document, by instantiating the module again
+    fn set_data(&mut self, data: &str) -> Result<()> {
+        let data: serde_json::Value = serde_json::from_str(data).context("invalid data")?;
+        let (store, policy) =
+            self.executor
+                .block_on(instantiate(&self.engine, &self.module, &data))?;
+        self.store = store;
+        self.policy = policy;
+        Ok(())
+    }
+
+    /// Evaluate an entrypoint, returning the JSON-encoded result set
+    fn evaluate(&mut self, entrypoint: &str, input: &str) -> Result<String> {
+        let input: serde_json::Value = serde_json::from_str(input).context("invalid input")?;
+        let result: serde_json::Value =
+            self.executor
+                .block_on(self.policy.evaluate(&mut self.store, entrypoint, &input))?;
+        Ok(result.to_string())
+    }
+}
+
+/// Instantiate a module in a new store, with the given data
+async fn instantiate(
+    engine: &Engine,
+    module: &Module,
+    data: &serde_json::Value,
+) -> Result<(Store<()>, Policy<DefaultContext>)> {
+    let mut store = Store::new(engine, ());
+    let runtime = Runtime::new(&mut store, module).await?;
+    let policy = runtime.with_data(&mut store, data).await?;
+    Ok((store, policy))
+}
+
+/// Turn a string into a C string owned by the caller. Nul bytes, which C
+/// strings can't hold, are dropped.
+fn into_c_string(string: &str) -> *mut c_char {
+    let string = string.replace('\0', "");
+    CString::new(string).unwrap_or_default().into_raw()
+}
+
+/// Borrow a C string as UTF-8
+///
+/// # Safety
+///
+/// `string` must be `NULL` or point to a nul-terminated string, valid for
+/// the returned lifetime
+unsafe fn read_str<'a>(string: *const c_char, name: &str) -> Result<&'a str> {
+    anyhow::ensure!(!string.is_null(), "{name} is NULL");
+    // SAFETY: checked to be non-null, the rest is up to the caller
+    let string = unsafe { CStr::from_ptr(string) };
+    string
+        .to_str()
+        .with_context(|| format!("{name} is not valid UTF-8"))
+}
+
+/// Run `f`, returning `failed` and reporting the error through `error` if it
+/// fails or panics, as panics must not unwind into C
+///
+/// # Safety
+///
+/// `error` must be `NULL` or valid for writes
+unsafe fn guard<R>(error: *mut *mut c_char, failed: R, f: impl FnOnce() -> Result<R>) -> R {
+    let message = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(e)) => format!("{e:#}"),
+        Err(_) => "the evaluator panicked".to_owned(),
+    };
+
+    if !error.is_null() {
+        // SAFETY: the caller guarantees it is valid for writes
+        unsafe { *error = into_c_string(&message) };
+    }
+
+    failed
+}
+
+/// Compile and instantiate a policy module, with an empty data document.
+/// Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// `module` must point to `len` readable bytes, and `error` must be `NULL` or
+/// valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn opa_wasm_policy_new(
+    module: *const u8,
+    len: usize,
+    error: *mut *mut c_char,
+) -> *mut OpaWasmPolicy {
+    // SAFETY: forwarded to the caller
+    unsafe {
+        guard(error, ptr::null_mut(), || {
+            anyhow::ensure!(!module.is_null(), "module is NULL");
+            // SAFETY: the caller guarantees it points to `len` bytes
+            let module = std::slice::from_raw_parts(module, len);
+            let policy = OpaWasmPolicy::new(module)?;
+            Ok(Box::into_raw(Box::new(policy)))
+        })
+    }
+}
+
+/// Replace the data document of a policy with the given JSON document.
+/// Returns 0 on success, and -1 on failure, in which case the policy keeps its
+/// previous data.
+///
+/// # Safety
+///
+/// `policy` must come from `opa_wasm_policy_new`, `data` must be a
+/// nul-terminated string, and `error` must be `NULL` or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn opa_wasm_policy_set_data(
+    policy: *mut OpaWasmPolicy,
+    data: *const c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    // SAFETY: forwarded to the caller
+    unsafe {
+        guard(error, -1, || {
+            let policy = policy.as_mut().context("policy is NULL")?;
+            policy.set_data(read_str(data, "data")?)?;
+            Ok(0)
+        })
+    }
+}
+
+/// Evaluate an entrypoint with the given JSON input, returning the
+/// JSON-encoded result set, to free with `opa_wasm_string_free`. Returns
+/// `NULL` on failure.
+///
+/// # Safety
+///
+/// `policy` must come from `opa_wasm_policy_new`, `entrypoint` and `input`
+/// must be nul-terminated strings, and `error` must be `NULL` or valid for
+/// writes
+#[no_mangle]
+pub unsafe extern "C" fn opa_wasm_policy_evaluate(
+    policy: *mut OpaWasmPolicy,
+    entrypoint: *const c_char,
+    input: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    // SAFETY: forwarded to the caller
+    unsafe {
+        guard(error, ptr::null_mut(), || {
+            let policy = policy.as_mut().context("policy is NULL")?;
+            let result = policy.evaluate(
+                read_str(entrypoint, "entrypoint")?,
+                read_str(input, "input")?,
+            )?;
+            Ok(into_c_string(&result))
+        })
+    }
+}
+
+/// Destroy a policy. Does nothing if `policy` is `NULL`.
+///
+/// # Safety
+///
+/// `policy` must come from `opa_wasm_policy_new`, and must not be used
+/// afterwards
+#[no_mangle]
+pub unsafe extern "C" fn opa_wasm_policy_free(policy: *mut OpaWasmPolicy) {
+    if !policy.is_null() {
+        // SAFETY: the caller guarantees it was allocated by `opa_wasm_policy_new`
+        drop(unsafe { Box::from_raw(policy) });
+    }
+}
+
+/// Free a string returned by this API. Does nothing if `string` is `NULL`.
+///
+/// # Safety
+///
+/// `string` must have been returned by this API, and must not be used
+/// afterwards
+#[no_mangle]
+pub unsafe extern "C" fn opa_wasm_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        // SAFETY: the caller guarantees it was allocated by `into_c_string`
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_reported() {
+        let module = b"\0asm\x01\0\0\0";
+        let mut error = ptr::null_mut();
+        let policy = unsafe { opa_wasm_policy_new(module.as_ptr(), module.len(), &raw mut error) };
+        assert!(policy.is_null());
+        assert!(!error.is_null());
+        let message = unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        unsafe { opa_wasm_string_free(error) };
+        assert!(!message.is_empty());
+
+        // Null handles are reported instead of dereferenced
+        let mut error = ptr::null_mut();
+        let result = unsafe {
+            opa_wasm_policy_evaluate(
+                ptr::null_mut(),
+                c"allow".as_ptr(),
+                c"{}".as_ptr(),
+                &raw mut error,
+            )
+        };
+        assert!(result.is_null());
+        let message = unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        unsafe { opa_wasm_string_free(error) };
+        assert_eq!(message, "policy is NULL");
+
+        // Freeing NULL is allowed
+        unsafe { opa_wasm_policy_free(ptr::null_mut()) };
+    }
+}
@@ -15,28 +15,10 @@
 //! Typed functions exported by the OPA WASM module
 
 use anyhow::{Context, Result};
-use wasmtime::{AsContextMut, Caller, Instance, Memory, TypedFunc};
+use wasmtime::{AsContextMut, Instance, Memory, TypedFunc};
 
 use crate::types::{Addr, Ctx, EntrypointId, Heap, NulStr, OpaError, Value};
 
-/// Get a [`TypedFunc`] for the given export name from a wasmtime [`Caller`]
-fn from_caller<Params, Results, T>(
-    name: &'static str,
-    caller: &mut Caller<'_, T>,
-) -> Result<TypedFunc<Params, Results>>
-where
-    Params: wasmtime::WasmParams,
-    Results: wasmtime::WasmResults,
-{
-    caller
-        .get_export(name)
-        .with_context(|| format!("could not find export {name:?}"))?
-        .into_func()
-        .with_context(|| format!("export {name:?} is not a function"))?
-        .typed(caller)
-        .with_context(|| format!("exported function {name:?} does not have the right signature"))
-}
-
 /// Get a [`TypedFunc`] for the given export name from a wasmtime [`Instance`]
 fn from_instance<Params, Results, T>(
     name: &'static str,
@@ -69,11 +51,6 @@ pub trait Func: Sized {
     /// Create a new instance of the function from a `TypedFunc`
     fn from_func(func: TypedFunc<Self::Params, Self::Results>) -> Self;
 
-    /// Create a new instance of the function from a wasmtime [`Caller`]
-    fn from_caller<T>(caller: &mut Caller<'_, T>) -> Result<Self> {
-        Ok(Self::from_func(from_caller(Self::EXPORT, caller)?))
-    }
-
     /// Create a new instance of the function from a wasmtime [`Instance`]
     fn from_instance<T>(store: impl AsContextMut<Data = T>, instance: &Instance) -> Result<Self> {
         Ok(Self::from_func(from_instance(
@@ -98,6 +75,7 @@ impl Func for Eval {
 }
 
 impl Eval {
+    /// Call the `eval` exported function
     #[tracing::instrument(name = "eval", skip_all, err)]
     pub async fn call<T: Send>(
         &self,
@@ -123,6 +101,7 @@ impl Func for Builtins {
 }
 
 impl Builtins {
+    /// Call the `builtins` exported function
     #[tracing::instrument(name = "builtins", skip_all, err)]
     pub async fn call<T: Send>(&self, store: impl AsContextMut<Data = T>) -> Result<Value> {
         let res = self.0.call_async(store, ()).await?;
@@ -144,6 +123,7 @@ impl Func for Entrypoints {
 }
 
 impl Entrypoints {
+    /// Call the `entrypoints` exported function
     #[tracing::instrument(name = "entrypoints", skip_all, err)]
     pub async fn call<T: Send>(&self, store: impl AsContextMut<Data = T>) -> Result<Value> {
         let res = self.0.call_async(store, ()).await?;
@@ -165,7 +145,11 @@ impl Func for OpaEvalCtxNew {
 }
 
 impl OpaEvalCtxNew {
-    #[tracing::instrument(name = "opa_eval_ctx_new", skip_all, err)]
+    /// Call the `opa_eval_ctx_new` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_eval_ctx_new", skip_all, err)
+    )]
     pub async fn call<T: Send>(&self, store: impl AsContextMut<Data = T>) -> Result<Ctx> {
         let res = self.0.call_async(store, ()).await?;
         Ok(Ctx(res))
@@ -186,7 +170,11 @@ impl Func for OpaEvalCtxSetInput {
 }
 
 impl OpaEvalCtxSetInput {
-    #[tracing::instrument(name = "opa_eval_ctx_set_input", skip_all, err)]
+    /// Call the `opa_eval_ctx_set_input` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_eval_ctx_set_input", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -212,7 +200,11 @@ impl Func for OpaEvalCtxSetData {
 }
 
 impl OpaEvalCtxSetData {
-    #[tracing::instrument(name = "opa_eval_ctx_set_data", skip_all, err)]
+    /// Call the `opa_eval_ctx_set_data` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_eval_ctx_set_data", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -238,7 +230,11 @@ impl Func for OpaEvalCtxSetEntrypoint {
 }
 
 impl OpaEvalCtxSetEntrypoint {
-    #[tracing::instrument(name = "opa_eval_ctx_set_entrypoint", skip_all, err)]
+    /// Call the `opa_eval_ctx_set_entrypoint` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_eval_ctx_set_entrypoint", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -264,7 +260,11 @@ impl Func for OpaEvalCtxGetResult {
 }
 
 impl OpaEvalCtxGetResult {
-    #[tracing::instrument(name = "opa_eval_ctx_get_result", skip_all, err)]
+    /// Call the `opa_eval_ctx_get_result` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_eval_ctx_get_result", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -289,7 +289,11 @@ impl Func for OpaMalloc {
 }
 
 impl OpaMalloc {
-    #[tracing::instrument(name = "opa_malloc", skip_all, err)]
+    /// Call the `opa_malloc` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_malloc", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -319,7 +323,11 @@ impl Func for OpaFree {
 }
 
 impl OpaFree {
-    #[tracing::instrument(name = "opa_free", skip_all, err)]
+    /// Call the `opa_free` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_free", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -346,7 +354,11 @@ impl Func for OpaJsonParse {
 }
 
 impl OpaJsonParse {
-    #[tracing::instrument(name = "opa_json_parse", skip_all, err)]
+    /// Call the `opa_json_parse` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_json_parse", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -371,8 +383,12 @@ impl Func for OpaValueParse {
 }
 
 impl OpaValueParse {
+    /// Call the `opa_value_parse` exported function
     #[allow(dead_code)]
-    #[tracing::instrument(name = "opa_value_parse", skip_all, err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_value_parse", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -397,7 +413,11 @@ impl Func for OpaJsonDump {
 }
 
 impl OpaJsonDump {
-    #[tracing::instrument(name = "opa_json_dump", skip_all, err)]
+    /// Call the `opa_json_dump` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_json_dump", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -435,7 +455,11 @@ impl Func for OpaHeapPtrSet {
 }
 
 impl OpaHeapPtrSet {
-    #[tracing::instrument(name = "opa_heap_ptr_set", skip_all, err)]
+    /// Call the `opa_heap_ptr_set` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_heap_ptr_set", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -460,7 +484,11 @@ impl Func for OpaHeapPtrGet {
 }
 
 impl OpaHeapPtrGet {
-    #[tracing::instrument(name = "opa_heap_ptr_get", skip_all, err)]
+    /// Call the `opa_heap_ptr_get` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_heap_ptr_get", skip_all, err)
+    )]
     pub async fn call<T: Send>(&self, store: impl AsContextMut<Data = T>) -> Result<Addr> {
         let res = self.0.call_async(store, ()).await?;
         Ok(Addr(res))
@@ -481,8 +509,12 @@ impl Func for OpaValueAddPath {
 }
 
 impl OpaValueAddPath {
+    /// Call the `opa_value_add_path` exported function
     #[allow(dead_code)]
-    #[tracing::instrument(name = "opa_value_add_path", skip_all, err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_value_add_path", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -509,8 +541,12 @@ impl Func for OpaValueRemovePath {
 }
 
 impl OpaValueRemovePath {
+    /// Call the `opa_value_remove_path` exported function
     #[allow(dead_code)]
-    #[tracing::instrument(name = "opa_value_remove_path", skip_all, err)]
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_value_remove_path", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
@@ -536,15 +572,18 @@ impl Func for OpaValueDump {
 }
 
 impl OpaValueDump {
-    #[allow(dead_code)]
-    #[tracing::instrument(name = "opa_value_dump", skip_all, err)]
+    /// Call the `opa_value_dump` exported function
+    #[cfg_attr(
+        feature = "detailed-tracing",
+        tracing::instrument(name = "opa_value_dump", skip_all, err)
+    )]
     pub async fn call<T: Send>(
         &self,
         store: impl AsContextMut<Data = T>,
         value: &Value,
-    ) -> Result<Value> {
+    ) -> Result<NulStr> {
         let res = self.0.call_async(store, value.0).await?;
-        Ok(Value(res))
+        Ok(NulStr(res))
     }
 }
 
@@ -564,6 +603,7 @@ impl Func for OpaEval {
 }
 
 impl OpaEval {
+    /// Call the `opa_eval` exported function
     #[tracing::instrument(name = "opa_eval", skip_all, err)]
     pub async fn call<T: Send>(
         &self,
@@ -0,0 +1,308 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A tower layer authorizing gRPC calls with a policy, for tonic servers.
+//!
+//! This is not a tonic [`Interceptor`](tonic::service::Interceptor), as those
+//! are synchronous and cannot wait for the policy evaluation: the layer is
+//! added to the server with [`tonic::transport::Server::layer`] instead.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use serde::Serialize;
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Bytes},
+    transport::server::TcpConnectInfo,
+    Code, Status,
+};
+use tower_service::Service;
+
+use crate::{EvaluationContext, Identity, PolicyDecision, PolicyPool, PolicyService};
+
+/// The peer which made a gRPC call
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct GrpcPeer {
+    /// The address of the peer, e.g. `10.0.0.1:54321`
+    pub address: String,
+}
+
+/// The input passed to the policy for each gRPC call
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct GrpcInput {
+    /// The fully qualified name of the service, e.g. `helloworld.Greeter`
+    pub service: String,
+
+    /// The name of the method, e.g. `SayHello`
+    pub method: String,
+
+    /// The ASCII metadata of the call, with lowercased keys. Binary (`-bin`)
+    /// metadata is left out, and repeated keys have their values joined with
+    /// `, `.
+    pub metadata: BTreeMap<String, String>,
+
+    /// The peer which made the call, when the server exposes it
+    pub peer: Option<GrpcPeer>,
+
+    /// The identity of the caller, if an authentication layer set one
+    pub identity: Option<serde_json::Value>,
+}
+
+impl GrpcInput {
+    /// Build the input from a gRPC request
+    #[must_use]
+    pub fn from_request<B>(request: &http::Request<B>) -> Self {
+        // gRPC paths are `/{service}/{method}`
+        let path = request.uri().path().trim_start_matches('/');
+        let (service, method) = path.split_once('/').unwrap_or((path, ""));
+
+        let mut metadata: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in request.headers() {
+            if name.as_str().ends_with("-bin") {
+                continue;
+            }
+
+            let value = String::from_utf8_lossy(value.as_bytes());
+            metadata
+                .entry(name.as_str().to_owned())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+
+        let extensions = request.extensions();
+        Self {
+            service: service.to_owned(),
+            method: method.to_owned(),
+            metadata,
+            peer: extensions
+                .get::<TcpConnectInfo>()
+                .and_then(TcpConnectInfo::remote_addr)
+                .map(|address| GrpcPeer {
+                    address: address.to_string(),
+                }),
+            identity: extensions
+                .get::<Identity>()
+                .map(|identity| identity.0.clone()),
+        }
+    }
+}
+
+/// Get the status returned for a denied call: `PERMISSION_DENIED`, with the
+/// `message` of the decision, and its `details` serialized as JSON
+fn denied(decision: &PolicyDecision) -> Status {
+    let result = decision.result.as_ref();
+    let message = result
+        .and_then(|result| result.get("message"))
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or("denied by policy");
+
+    match result.and_then(|result| result.get("details")) {
+        Some(details) => Status::with_details(
+            Code::PermissionDenied,
+            message,
+            Bytes::from(details.to_string()),
+        ),
+        None => Status::permission_denied(message),
+    }
+}
+
+/// A [`tower_layer::Layer`] authorizing gRPC calls by evaluating an
+/// entrypoint with the call as [input](GrpcInput).
+///
+/// Denied calls fail with `PERMISSION_DENIED`, and calls failing to evaluate
+/// with `INTERNAL`. Allowed calls are passed to the inner service, with the
+/// [`PolicyDecision`] in their extensions.
+pub struct GrpcAuthorizationLayer<C, T = ()> {
+    /// The service evaluating the policy
+    service: PolicyService<GrpcInput, serde_json::Value, C, T>,
+}
+
+impl<C, T> Clone for GrpcAuthorizationLayer<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<C, T> std::fmt::Debug for GrpcAuthorizationLayer<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcAuthorizationLayer")
+            .field("service", &self.service)
+            .finish()
+    }
+}
+
+impl<C, T> GrpcAuthorizationLayer<C, T> {
+    /// Authorize calls by evaluating the given entrypoint on the instances of
+    /// the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>, entrypoint: impl Into<Arc<str>>) -> Self {
+        Self {
+            service: PolicyService::new(pool, entrypoint),
+        }
+    }
+}
+
+impl<S, C, T> tower_layer::Layer<S> for GrpcAuthorizationLayer<C, T> {
+    type Service = GrpcAuthorization<S, C, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcAuthorization {
+            inner,
+            service: self.service.clone(),
+        }
+    }
+}
+
+/// The service created by the [`GrpcAuthorizationLayer`]
+pub struct GrpcAuthorization<S, C, T = ()> {
+    /// The service handling allowed calls
+    inner: S,
+
+    /// The service evaluating the policy
+    service: PolicyService<GrpcInput, serde_json::Value, C, T>,
+}
+
+impl<S: Clone, C, T> Clone for GrpcAuthorization<S, C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S, C, T> std::fmt::Debug for GrpcAuthorization<S, C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcAuthorization")
+            .field("service", &self.service)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, B, C, T> Service<http::Request<B>> for GrpcAuthorization<S, C, T>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // Take the service which was polled ready, leaving a clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let mut service = self.service.clone();
+
+        Box::pin(async move {
+            let input = GrpcInput::from_request(&request);
+
+            let decision = match service.call(input).await {
+                Ok(decision) => {
+                    PolicyDecision::from_result_set(decision.entrypoint, &decision.result)
+                }
+                Err(error) => {
+                    tracing::error!(
+                        entrypoint = service.entrypoint(),
+                        "could not evaluate the policy: {error:#}"
+                    );
+                    return Ok(Status::internal("could not evaluate the policy").into_http());
+                }
+            };
+
+            if !decision.is_allowed() {
+                return Ok(denied(&decision).into_http());
+            }
+
+            request.extensions_mut().insert(decision);
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_mapped_to_inputs() {
+        let mut request = http::Request::post("http://example.com/helloworld.Greeter/SayHello")
+            .header("authorization", "Bearer token")
+            .header("x-trace-bin", "AAEC")
+            .header("x-tenant", "a")
+            .header("x-tenant", "b")
+            .body(())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(Identity(serde_json::json!({ "sub": "alice" })));
+
+        let input = serde_json::to_value(GrpcInput::from_request(&request)).unwrap();
+        assert_eq!(
+            input,
+            serde_json::json!({
+                "service": "helloworld.Greeter",
+                "method": "SayHello",
+                "metadata": {
+                    "authorization": "Bearer token",
+                    "x-tenant": "a, b",
+                },
+                "peer": null,
+                "identity": { "sub": "alice" },
+            })
+        );
+    }
+
+    #[test]
+    fn denials_carry_the_policy_details() {
+        let decision =
+            |result_set| PolicyDecision::from_result_set("grpc/allow".into(), &result_set);
+
+        let status = denied(&decision(serde_json::json!([{ "result": false }])));
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(status.message(), "denied by policy");
+        assert!(status.details().is_empty());
+
+        let status = denied(&decision(serde_json::json!([{
+            "result": {
+                "allow": false,
+                "message": "tenant mismatch",
+                "details": { "tenant": "b" },
+            },
+        }])));
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(status.message(), "tenant mismatch");
+        assert_eq!(status.details(), br#"{"tenant":"b"}"#);
+    }
+}
@@ -0,0 +1,530 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A reqwest-based HTTP client, used by [`DefaultContext`] to send the
+//! requests made by `http.send`
+//!
+//! [`DefaultContext`]: crate::DefaultContext
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Context, Result};
+
+use crate::HttpSendOptions;
+
+/// The maximum number of redirects followed when redirects are enabled
+const MAX_REDIRECTS: usize = 10;
+
+/// The methods shared by the builders of the async and of the blocking
+/// reqwest clients, so that both are configured the same way
+trait ClientBuilder: Sized {
+    /// Ignore the proxies set in the environment
+    fn no_proxy(self) -> Self;
+
+    /// Add a proxy
+    fn proxy(self, proxy: reqwest::Proxy) -> Self;
+
+    /// Set the redirect policy
+    fn redirect(self, policy: reqwest::redirect::Policy) -> Self;
+
+    /// Set whether the built-in root certificates are trusted
+    fn tls_built_in_root_certs(self, enabled: bool) -> Self;
+
+    /// Trust an additional root certificate
+    fn add_root_certificate(self, certificate: reqwest::Certificate) -> Self;
+
+    /// Set the client certificate presented to the servers
+    fn identity(self, identity: reqwest::Identity) -> Self;
+}
+
+/// Implement [`ClientBuilder`] by forwarding to the inherent methods
+macro_rules! impl_client_builder {
+    ($builder:ty) => {
+        impl ClientBuilder for $builder {
+            fn no_proxy(self) -> Self {
+                <$builder>::no_proxy(self)
+            }
+
+            fn proxy(self, proxy: reqwest::Proxy) -> Self {
+                <$builder>::proxy(self, proxy)
+            }
+
+            fn redirect(self, policy: reqwest::redirect::Policy) -> Self {
+                <$builder>::redirect(self, policy)
+            }
+
+            fn tls_built_in_root_certs(self, enabled: bool) -> Self {
+                <$builder>::tls_built_in_root_certs(self, enabled)
+            }
+
+            fn add_root_certificate(self, certificate: reqwest::Certificate) -> Self {
+                <$builder>::add_root_certificate(self, certificate)
+            }
+
+            fn identity(self, identity: reqwest::Identity) -> Self {
+                <$builder>::identity(self, identity)
+            }
+        }
+    };
+}
+
+impl_client_builder!(reqwest::ClientBuilder);
+#[cfg(feature = "blocking-http-client")]
+impl_client_builder!(reqwest::blocking::ClientBuilder);
+
+/// Proxy settings for outgoing HTTP requests, equivalent to the `HTTP_PROXY`,
+/// `HTTPS_PROXY` and `NO_PROXY` environment variables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The proxy used for `http://` URLs
+    http: Option<String>,
+
+    /// The proxy used for `https://` URLs
+    https: Option<String>,
+
+    /// The hosts, domains and IP ranges which are reached directly
+    no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// Create an empty proxy configuration, for which all requests are sent
+    /// directly
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the proxy configuration from the `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `NO_PROXY` environment variables, or their lowercase equivalents
+    #[must_use]
+    pub fn from_env() -> Self {
+        /// Read an environment variable, preferring the lowercase variant
+        fn var(name: &str) -> Option<String> {
+            std::env::var(name.to_lowercase())
+                .or_else(|_| std::env::var(name))
+                .ok()
+                .filter(|value| !value.is_empty())
+        }
+
+        Self {
+            http: var("HTTP_PROXY"),
+            https: var("HTTPS_PROXY"),
+            no_proxy: var("NO_PROXY")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|host| !host.is_empty())
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Set the proxy used for `http://` URLs
+    #[must_use]
+    pub fn http(mut self, url: impl Into<String>) -> Self {
+        self.http = Some(url.into());
+        self
+    }
+
+    /// Set the proxy used for `https://` URLs
+    #[must_use]
+    pub fn https(mut self, url: impl Into<String>) -> Self {
+        self.https = Some(url.into());
+        self
+    }
+
+    /// Reach the given host directly, without going through the proxy. This
+    /// follows the `NO_PROXY` syntax: a domain also matches its subdomains,
+    /// IP addresses can be given as CIDR ranges, and `*` matches all hosts.
+    #[must_use]
+    pub fn no_proxy(mut self, host: impl Into<String>) -> Self {
+        self.no_proxy.push(host.into());
+        self
+    }
+
+    /// Apply the configuration to a client builder
+    fn apply<B: ClientBuilder>(&self, builder: B) -> Result<B> {
+        // Ignore the proxies set in the environment, this configuration replaces them
+        let mut builder = builder.no_proxy();
+        let no_proxy = reqwest::NoProxy::from_string(&self.no_proxy.join(","));
+
+        if let Some(url) = &self.http {
+            let proxy = reqwest::Proxy::http(url)
+                .context("invalid HTTP proxy URL")?
+                .no_proxy(no_proxy.clone());
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(url) = &self.https {
+            let proxy = reqwest::Proxy::https(url)
+                .context("invalid HTTPS proxy URL")?
+                .no_proxy(no_proxy);
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// TLS settings used for all outgoing HTTP requests, so that policies can talk
+/// to internal services using private certificate authorities
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Additional trusted root certificates
+    root_certificates: Vec<reqwest::Certificate>,
+
+    /// Whether the built-in root certificates are trusted
+    built_in_roots: bool,
+
+    /// The client certificate and private key presented to the servers
+    identity: Option<reqwest::Identity>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            root_certificates: Vec::new(),
+            built_in_roots: true,
+            identity: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Create a TLS configuration which trusts the built-in root certificates
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the root certificates found in the given PEM bundle, in addition
+    /// to the built-in ones
+    ///
+    /// # Errors
+    ///
+    /// If the PEM bundle is invalid or contains no certificate
+    pub fn add_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self> {
+        let certificates = reqwest::Certificate::from_pem_bundle(pem)
+            .context("invalid PEM-encoded root certificates")?;
+        if certificates.is_empty() {
+            anyhow::bail!("no root certificate found in the PEM bundle");
+        }
+
+        self.root_certificates.extend(certificates);
+        Ok(self)
+    }
+
+    /// Only trust the root certificates added with
+    /// [`TlsConfig::add_root_certificates_pem`], and not the built-in ones
+    #[must_use]
+    pub fn disable_built_in_roots(mut self) -> Self {
+        self.built_in_roots = false;
+        self
+    }
+
+    /// Present a client certificate to the servers, for mutual TLS. The PEM
+    /// must contain the certificate chain and the private key.
+    ///
+    /// # Errors
+    ///
+    /// If the PEM is invalid, or does not contain both a certificate and a
+    /// private key
+    pub fn identity_pem(mut self, pem: &[u8]) -> Result<Self> {
+        let identity =
+            reqwest::Identity::from_pem(pem).context("invalid PEM-encoded client identity")?;
+        self.identity = Some(identity);
+        Ok(self)
+    }
+
+    /// Apply the configuration to a client builder
+    fn apply<B: ClientBuilder>(&self, builder: B) -> B {
+        let mut builder = builder.tls_built_in_root_certs(self.built_in_roots);
+
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+
+        builder
+    }
+}
+
+/// The configuration of the HTTP client, shared by all `http.send` calls
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HttpClientConfig {
+    /// The proxy settings. If not set, the proxies are read from the
+    /// environment by reqwest.
+    pub(crate) proxy: Option<ProxyConfig>,
+
+    /// The TLS settings
+    pub(crate) tls: TlsConfig,
+}
+
+impl HttpClientConfig {
+    /// Configure a client builder for the given options
+    fn configure<B: ClientBuilder>(&self, builder: B, key: ClientKey) -> Result<B> {
+        let redirect = if key.enable_redirect {
+            reqwest::redirect::Policy::limited(MAX_REDIRECTS)
+        } else {
+            reqwest::redirect::Policy::none()
+        };
+
+        let mut builder = builder.redirect(redirect);
+
+        if let Some(proxy) = &self.proxy {
+            builder = proxy.apply(builder)?;
+        }
+
+        Ok(self.tls.apply(builder))
+    }
+}
+
+/// The options a [`reqwest::Client`] is built with. The timeout is not part
+/// of it, as it is set on each request instead, and varies with the remaining
+/// evaluation budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ClientKey {
+    /// Whether redirects are followed
+    enable_redirect: bool,
+}
+
+impl From<&HttpSendOptions> for ClientKey {
+    fn from(options: &HttpSendOptions) -> Self {
+        Self {
+            enable_redirect: options.enable_redirect,
+        }
+    }
+}
+
+/// A pool of [`reqwest::Client`], built lazily for each set of options and
+/// reused across `http.send` calls, so that connections and TLS sessions are
+/// kept alive
+#[derive(Debug)]
+pub(crate) struct HttpClient {
+    /// The configuration used to build the clients
+    config: HttpClientConfig,
+
+    /// The clients built so far
+    clients: Mutex<HashMap<ClientKey, reqwest::Client>>,
+}
+
+impl HttpClient {
+    /// Create a pool of clients with the given configuration
+    pub(crate) fn new(config: HttpClientConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the client for the given options, building it if needed
+    fn client(&self, key: ClientKey) -> Result<reqwest::Client> {
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|_| anyhow::anyhow!("HTTP client pool poisoned"))?;
+
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = self
+            .config
+            .configure(reqwest::Client::builder(), key)?
+            .build()
+            .context("failed to build the HTTP client")?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Send a request and read the whole response
+    pub(crate) async fn send(
+        &self,
+        request: http::Request<String>,
+        options: &HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        let client = self.client(options.into())?;
+        let mut request = reqwest::Request::try_from(request).context("invalid HTTP request")?;
+        *request.timeout_mut() = options.timeout;
+        let response = client.execute(request).await?;
+
+        let mut builder = http::Response::builder()
+            .status(response.status())
+            .version(response.version());
+        for (name, value) in response.headers() {
+            builder = builder.header(name, value);
+        }
+
+        let body = response
+            .text()
+            .await
+            .context("failed to read the response body")?;
+        builder.body(body).context("invalid HTTP response")
+    }
+}
+
+/// A pool of blocking [`reqwest::blocking::Client`], the counterpart of
+/// [`HttpClient`] for contexts which are not driven by tokio.
+///
+/// The blocking clients run their own runtime on a background thread, and
+/// must not be built or dropped from within an async runtime.
+#[cfg(feature = "blocking-http-client")]
+#[derive(Debug)]
+pub(crate) struct BlockingHttpClient {
+    /// The configuration used to build the clients
+    config: HttpClientConfig,
+
+    /// The clients built so far
+    clients: Mutex<HashMap<ClientKey, reqwest::blocking::Client>>,
+}
+
+#[cfg(feature = "blocking-http-client")]
+impl BlockingHttpClient {
+    /// Create a pool of clients with the given configuration
+    pub(crate) fn new(config: HttpClientConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a mutable reference to the configuration, dropping the clients
+    /// built with the previous one
+    pub(crate) fn config_mut(&mut self) -> &mut HttpClientConfig {
+        self.clients.get_mut().map(HashMap::clear).ok();
+        &mut self.config
+    }
+
+    /// Get the client for the given options, building it if needed
+    fn client(&self, key: ClientKey) -> Result<reqwest::blocking::Client> {
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|_| anyhow::anyhow!("HTTP client pool poisoned"))?;
+
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = self
+            .config
+            .configure(reqwest::blocking::Client::builder(), key)?
+            .build()
+            .context("failed to build the HTTP client")?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Send a request and read the whole response, blocking the current
+    /// thread
+    pub(crate) fn send(
+        &self,
+        request: http::Request<String>,
+        options: &HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        let client = self.client(options.into())?;
+        let mut request =
+            reqwest::blocking::Request::try_from(request).context("invalid HTTP request")?;
+        *request.timeout_mut() = options.timeout;
+        let response = client.execute(request)?;
+
+        let mut builder = http::Response::builder()
+            .status(response.status())
+            .version(response.version());
+        for (name, value) in response.headers() {
+            builder = builder.header(name, value);
+        }
+
+        let body = response
+            .text()
+            .context("failed to read the response body")?;
+        builder.body(body).context("invalid HTTP response")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+
+        let client = HttpClient::new(HttpClientConfig {
+            proxy: Some(ProxyConfig::new().http(proxy).no_proxy("internal.example")),
+            ..HttpClientConfig::default()
+        });
+        let request = http::Request::get("http://service.example/path")
+            .body(String::new())
+            .unwrap();
+        let response = client
+            .send(request, &HttpSendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "ok");
+
+        // The proxy receives the request with the absolute URL
+        let received = server.await.unwrap();
+        assert!(received.starts_with("GET http://service.example/path HTTP/1.1"));
+    }
+
+    #[test]
+    fn clients_are_reused() {
+        let client = HttpClient::new(HttpClientConfig::default());
+        let mut options = HttpSendOptions {
+            timeout: Some(std::time::Duration::from_secs(1)),
+            enable_redirect: false,
+        };
+        client.client((&options).into()).unwrap();
+
+        // A different timeout reuses the same client
+        options.timeout = Some(std::time::Duration::from_secs(2));
+        client.client((&options).into()).unwrap();
+        assert_eq!(client.clients.lock().unwrap().len(), 1);
+
+        options.enable_redirect = true;
+        client.client((&options).into()).unwrap();
+        assert_eq!(client.clients.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_tls_material_is_rejected() {
+        assert!(TlsConfig::new().add_root_certificates_pem(b"").is_err());
+        assert!(TlsConfig::new().identity_pem(b"not a pem").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The input passed to policies authorizing HTTP requests, and their
+//! decision, shared by the web and gRPC framework integrations
+
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+use serde::Serialize;
+
+/// The identity of the caller, as found by an authentication middleware
+/// running before the authorization one. It is passed to the policy as
+/// `input.identity` when set in the request extensions.
+#[derive(Debug, Clone)]
+pub struct Identity(pub serde_json::Value);
+
+/// The input passed to the policy for each HTTP request
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct HttpInput {
+    /// The method of the request, e.g. `GET`
+    pub method: String,
+
+    /// The path of the request, split on `/`, e.g. `["v1", "users"]`
+    pub path: Vec<String>,
+
+    /// The raw query string, if any
+    pub query: Option<String>,
+
+    /// The request headers, with lowercased names. Headers repeated in the
+    /// request have their values joined with `, `.
+    pub headers: BTreeMap<String, String>,
+
+    /// The identity of the caller, if an authentication middleware set one
+    pub identity: Option<serde_json::Value>,
+}
+
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+impl HttpInput {
+    /// Build the input from the pieces of a request, whatever the HTTP types
+    /// of the framework it comes from
+    pub(crate) fn from_request<'a>(
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+        identity: Option<&Identity>,
+    ) -> Self {
+        let mut map: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value);
+            map.entry(name.to_owned())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+
+        Self {
+            method: method.to_owned(),
+            path: path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+            query: query.map(ToOwned::to_owned),
+            headers: map,
+            identity: identity.map(|identity| identity.0.clone()),
+        }
+    }
+}
+
+/// The decision of the policy about a request, which handlers behind the
+/// authorization middleware can extract
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PolicyDecision {
+    /// The entrypoint which was evaluated
+    pub entrypoint: Arc<str>,
+
+    /// The result of the evaluation, or `None` if it was undefined
+    pub result: Option<serde_json::Value>,
+}
+
+impl PolicyDecision {
+    /// Get the decision from the result set returned by the policy
+    pub(crate) fn from_result_set(entrypoint: Arc<str>, result_set: &serde_json::Value) -> Self {
+        let result = result_set
+            .get(0)
+            .and_then(|result| result.get("result"))
+            .cloned();
+        Self { entrypoint, result }
+    }
+
+    /// Whether the request is allowed: the result is either `true`, or an
+    /// object with its `allow` field set to `true`. Undefined decisions deny
+    /// the request.
+    #[must_use]
+    pub fn is_allowed(&self) -> bool {
+        match &self.result {
+            Some(serde_json::Value::Bool(allowed)) => *allowed,
+            Some(serde_json::Value::Object(object)) => {
+                object.get("allow") == Some(&serde_json::Value::Bool(true))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decisions_are_read_from_result_sets() {
+        let decision =
+            |result_set| PolicyDecision::from_result_set("app/allow".into(), &result_set);
+
+        assert!(decision(serde_json::json!([{ "result": true }])).is_allowed());
+        assert!(decision(serde_json::json!([{ "result": { "allow": true } }])).is_allowed());
+        assert!(!decision(serde_json::json!([{ "result": false }])).is_allowed());
+        assert!(!decision(serde_json::json!([{ "result": { "allow": "yes" } }])).is_allowed());
+
+        // Undefined decisions deny the request
+        let undefined = decision(serde_json::json!([]));
+        assert!(undefined.result.is_none());
+        assert!(!undefined.is_allowed());
+    }
+}
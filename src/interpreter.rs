@@ -0,0 +1,532 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An interpreter backend, evaluating policies with wasmi instead of wasmtime,
+//! for platforms where generating machine code at runtime is not allowed.
+//!
+//! Builtins are asynchronous, while wasmi host functions can't be: the host
+//! functions suspend the call instead, which is resumed once the builtin
+//! returned.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use wasmi::{
+    core::{HostError, TrapCode, ValType},
+    Caller, Engine, Func, Instance, Linker, Memory, MemoryType, Module, ResumableCall, Store, Val,
+};
+
+use crate::{
+    backend::{self, Backend, Data, Exports, Host},
+    builtins::BuiltinRegistry,
+    failure::Aborted,
+    AbiVersion, DefaultContext, EvaluationContext,
+};
+
+/// A call to the host the module is suspended on
+#[derive(Debug)]
+enum HostCall {
+    /// A builtin call, with the ID of the builtin and the values of its
+    /// arguments
+    Builtin {
+        /// The ID of the builtin
+        id: i32,
+
+        /// The addresses of the arguments
+        args: Vec<i32>,
+    },
+
+    /// A message printed by the policy
+    Print(String),
+
+    /// The policy aborted, with a message
+    Abort(String),
+}
+
+impl std::fmt::Display for HostCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Builtin { id, .. } => write!(f, "call to builtin {id}"),
+            Self::Print(message) => write!(f, "print: {message}"),
+            Self::Abort(message) => write!(f, "abort: {message}"),
+        }
+    }
+}
+
+impl HostError for HostCall {}
+
+/// Read a nul-terminated string from the memory
+fn read_c_str(memory: &[u8], addr: i32) -> Result<&[u8]> {
+    let start = usize::try_from(addr).context("invalid address")?;
+    let bytes = memory.get(start..).context("address out of bounds")?;
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .context("unterminated string")?;
+    Ok(&bytes[..len])
+}
+
+/// Define the imports of policy modules, which suspend the call with the
+/// matching [`HostCall`]
+fn link(linker: &mut Linker<()>, memory: Memory) -> Result<()> {
+    /// Suspend the call on a builtin
+    fn builtin(id: i32, args: Vec<i32>) -> Result<i32, wasmi::Error> {
+        Err(wasmi::Error::host(HostCall::Builtin { id, args }))
+    }
+
+    /// Read a message from the memory
+    fn message(caller: &Caller<'_, ()>, memory: Memory, addr: i32) -> String {
+        read_c_str(memory.data(caller), addr)
+            .map(|message| String::from_utf8_lossy(message).into_owned())
+            .unwrap_or_default()
+    }
+
+    linker.define("env", "memory", memory)?;
+    linker.func_wrap(
+        "env",
+        "opa_abort",
+        move |caller: Caller<'_, ()>, addr: i32| -> Result<(), wasmi::Error> {
+            let message = message(&caller, memory, addr);
+            Err(wasmi::Error::host(HostCall::Abort(message)))
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "opa_println",
+        move |caller: Caller<'_, ()>, addr: i32| -> Result<(), wasmi::Error> {
+            let message = message(&caller, memory, addr);
+            Err(wasmi::Error::host(HostCall::Print(message)))
+        },
+    )?;
+    linker.func_wrap("env", "opa_builtin0", |id: i32, _ctx: i32| {
+        builtin(id, vec![])
+    })?;
+    linker.func_wrap("env", "opa_builtin1", |id: i32, _ctx: i32, a: i32| {
+        builtin(id, vec![a])
+    })?;
+    linker.func_wrap(
+        "env",
+        "opa_builtin2",
+        |id: i32, _ctx: i32, a: i32, b: i32| builtin(id, vec![a, b]),
+    )?;
+    linker.func_wrap(
+        "env",
+        "opa_builtin3",
+        |id: i32, _ctx: i32, a: i32, b: i32, c: i32| builtin(id, vec![a, b, c]),
+    )?;
+    linker.func_wrap(
+        "env",
+        "opa_builtin4",
+        |id: i32, _ctx: i32, a: i32, b: i32, c: i32, d: i32| builtin(id, vec![a, b, c, d]),
+    )?;
+
+    Ok(())
+}
+
+/// Read an `i32` global from an instance
+fn global(store: &Store<()>, instance: Instance, name: &str) -> Result<i32> {
+    instance
+        .get_global(store, name)
+        .with_context(|| format!("missing global {name}"))?
+        .get(store)
+        .i32()
+        .with_context(|| format!("{name} is not an i32"))
+}
+
+/// Look the exports up in an instance, checking their signature
+fn lookup_exports(
+    store: &Store<()>,
+    instance: Instance,
+    version: AbiVersion,
+) -> Result<Exports<Func>> {
+    Exports::lookup(version, |name, params, results| {
+        let func = instance
+            .get_func(store, name)
+            .with_context(|| format!("could not find export {name}"))?;
+        let ty = func.ty(store);
+        let is_i32 = |types: &[ValType], len: usize| {
+            types.len() == len && types.iter().all(|ty| *ty == ValType::I32)
+        };
+        anyhow::ensure!(
+            is_i32(ty.params(), params) && is_i32(ty.results(), results),
+            "export {name} does not have the expected signature"
+        );
+        Ok(func)
+    })
+}
+
+/// Turn an error of wasmi into one
+/// [`EvaluationFailure`](crate::EvaluationFailure) can describe if it is a trap
+fn error(error: wasmi::Error) -> anyhow::Error {
+    use wasmtime::Trap;
+
+    let trap = match error.as_trap_code() {
+        Some(TrapCode::UnreachableCodeReached) => Trap::UnreachableCodeReached,
+        Some(TrapCode::MemoryOutOfBounds) => Trap::MemoryOutOfBounds,
+        Some(TrapCode::TableOutOfBounds) => Trap::TableOutOfBounds,
+        Some(TrapCode::IndirectCallToNull) => Trap::IndirectCallToNull,
+        Some(TrapCode::IntegerDivisionByZero) => Trap::IntegerDivisionByZero,
+        Some(TrapCode::IntegerOverflow) => Trap::IntegerOverflow,
+        Some(TrapCode::BadConversionToInteger) => Trap::BadConversionToInteger,
+        Some(TrapCode::StackOverflow) => Trap::StackOverflow,
+        Some(TrapCode::BadSignature) => Trap::BadSignature,
+        Some(TrapCode::OutOfFuel) => Trap::OutOfFuel,
+        Some(TrapCode::GrowthOperationLimited) | None => return error.into(),
+    };
+    trap.into()
+}
+
+/// An instance of a policy module, in its own store
+struct Interpreter<C> {
+    /// The store holding the instance
+    store: Store<()>,
+
+    /// The memory shared with the module
+    memory: Memory,
+
+    /// The exported functions
+    exports: Exports<Func>,
+
+    /// The state the builtins need
+    host: Host<C>,
+}
+
+impl<C: EvaluationContext> Backend for Interpreter<C> {
+    type Context = C;
+    type Func = Func;
+
+    fn exports(&self) -> Result<&Exports<Func>> {
+        Ok(&self.exports)
+    }
+
+    fn host(&mut self) -> &mut Host<C> {
+        &mut self.host
+    }
+
+    fn read_c_str(&mut self, addr: i32) -> Result<Vec<u8>> {
+        Ok(read_c_str(self.memory.data(&self.store), addr)?.to_vec())
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        self.memory.write(&mut self.store, offset, bytes)?;
+        Ok(())
+    }
+
+    fn read_memory(&mut self) -> Result<Vec<u8>> {
+        Ok(self.memory.data(&self.store).to_vec())
+    }
+
+    fn memory_size(&mut self) -> usize {
+        self.memory.data(&self.store).len()
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Result<()> {
+        self.memory.grow(&mut self.store, pages)?;
+        Ok(())
+    }
+
+    fn call(&mut self, func: &Func, params: &[i32]) -> Result<i32> {
+        let params: Vec<Val> = params.iter().copied().map(Val::I32).collect();
+        let mut results = [Val::I32(0)];
+        let len = func.ty(&self.store).results().len();
+        let results = results.get_mut(..len).context("too many results")?;
+        func.call(&mut self.store, &params, results)
+            .map_err(error)?;
+        Ok(results.first().and_then(Val::i32).unwrap_or(0))
+    }
+
+    /// Call the function, handling the host calls it is suspended on until it
+    /// returns
+    async fn eval(&mut self, func: &Func, params: &[i32]) -> Result<i32> {
+        let params: Vec<Val> = params.iter().copied().map(Val::I32).collect();
+        let mut results = [Val::I32(0)];
+        let mut call = func
+            .call_resumable(&mut self.store, &params, &mut results)
+            .map_err(error)?;
+
+        while let ResumableCall::Resumable(invocation) = call {
+            let host_call = invocation
+                .host_error()
+                .downcast_ref::<HostCall>()
+                .with_context(|| format!("unexpected host error: {}", invocation.host_error()))?;
+
+            let inputs = match host_call {
+                HostCall::Builtin { id, args } => {
+                    let (id, args) = (*id, args.clone());
+                    vec![Val::I32(self.call_builtin(id, &args).await?)]
+                }
+                HostCall::Print(message) => {
+                    self.host.context.print(message);
+                    Vec::new()
+                }
+                HostCall::Abort(message) => {
+                    self.host.context.abort(message);
+                    return Err(Aborted(message.clone()).into());
+                }
+            };
+
+            call = invocation
+                .resume(&mut self.store, &inputs, &mut results)
+                .map_err(error)?;
+        }
+
+        let [result] = results;
+        result.i32().context("eval did not return an i32")
+    }
+}
+
+/// An instance of a policy, interpreted by wasmi, with builtins and
+/// entrypoints resolved, but with no data provided yet.
+///
+/// Unlike [`Runtime`](crate::Runtime), it owns its store, and evaluations are
+/// a lot slower.
+pub struct InterpretedRuntime<C = DefaultContext> {
+    /// The instance of the module
+    instance: Interpreter<C>,
+
+    /// What is known about the module
+    module: backend::Module,
+}
+
+impl<C> std::fmt::Debug for InterpretedRuntime<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterpretedRuntime")
+            .field("version", &self.module.version)
+            .field("entrypoints", &self.module.entrypoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl InterpretedRuntime<DefaultContext> {
+    /// Load a new WASM policy module, with the default evaluation context
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is invalid, or if it needs
+    /// builtins which are not supported.
+    pub fn new(module: &[u8]) -> Result<Self> {
+        Self::new_with_evaluation_context(module, DefaultContext::default())
+    }
+}
+
+impl<C: EvaluationContext> InterpretedRuntime<C> {
+    /// Load a new WASM policy module, with the given evaluation context
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is invalid, or if it needs
+    /// builtins which are not supported.
+    pub fn new_with_evaluation_context(module: &[u8], context: C) -> Result<Self> {
+        Self::new_with_builtins(module, context, &BuiltinRegistry::new())
+    }
+
+    /// Load a new WASM policy module, resolving its builtins from the given
+    /// registry
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is invalid, or if it needs
+    /// builtins which the registry does not provide.
+    pub fn new_with_builtins(
+        module: &[u8],
+        context: C,
+        registry: &BuiltinRegistry<C>,
+    ) -> Result<Self> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, module).context("could not parse the module")?;
+        let mut store = Store::new(&engine, ());
+        let memory = Memory::new(&mut store, MemoryType::new(2, None)?)?;
+
+        let mut linker = Linker::new(&engine);
+        link(&mut linker, memory)?;
+        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+
+        let version = AbiVersion::new(
+            global(&store, instance, "opa_wasm_abi_version")?,
+            global(&store, instance, "opa_wasm_abi_minor_version")?,
+        )?;
+        let exports = lookup_exports(&store, instance, version)?;
+
+        let mut instance = Interpreter {
+            store,
+            memory,
+            exports,
+            host: Host::new(context),
+        };
+        let module = backend::Module::inspect(&mut instance, version, registry)?;
+
+        Ok(Self { instance, module })
+    }
+
+    /// Set the revision of the bundle this module was loaded from. It is
+    /// passed to the evaluation context when an evaluation starts.
+    #[must_use]
+    pub fn with_revision(mut self, revision: impl Into<String>) -> Self {
+        self.module.revision = Some(revision.into());
+        self
+    }
+
+    /// Snapshot the memory once the data is loaded, and restore it before
+    /// each evaluation. See
+    /// [`Runtime::with_memory_snapshot`](crate::Runtime::with_memory_snapshot).
+    #[must_use]
+    pub fn with_memory_snapshot(mut self, enabled: bool) -> Self {
+        self.module.memory_snapshot = enabled;
+        self
+    }
+
+    /// Fail evaluations when a builtin returns an error. See
+    /// [`Runtime::with_strict_builtin_errors`](crate::Runtime::with_strict_builtin_errors).
+    #[must_use]
+    pub fn with_strict_builtin_errors(mut self, enabled: bool) -> Self {
+        self.instance.host.strict_builtin_errors = enabled;
+        self
+    }
+
+    /// Instanciate the policy with an empty `data` object
+    ///
+    /// # Errors
+    ///
+    /// If it failed to load the empty data object in memory
+    pub fn without_data(self) -> Result<InterpretedPolicy<C>> {
+        self.with_data(&serde_json::Value::Object(serde_json::Map::default()))
+    }
+
+    /// Instanciate the policy with the given `data` object
+    ///
+    /// # Errors
+    ///
+    /// If it failed to serialize and load the `data` object
+    pub fn with_data<V: serde::Serialize>(mut self, data: &V) -> Result<InterpretedPolicy<C>> {
+        let data = self.module.load_data(&mut self.instance, data)?;
+        Ok(InterpretedPolicy {
+            runtime: self,
+            data,
+        })
+    }
+
+    /// Get the list of entrypoints found in this module.
+    #[must_use]
+    pub fn entrypoints(&self) -> HashSet<&str> {
+        self.module.entrypoints.keys().map(String::as_str).collect()
+    }
+
+    /// Get the ABI version detected for this module
+    #[must_use]
+    pub fn abi_version(&self) -> AbiVersion {
+        self.module.version
+    }
+}
+
+/// An instance of a policy, interpreted by wasmi, ready to be executed
+pub struct InterpretedPolicy<C = DefaultContext> {
+    /// The runtime this policy instance belongs to
+    runtime: InterpretedRuntime<C>,
+
+    /// The data loaded in the instance
+    data: Data,
+}
+
+impl<C> std::fmt::Debug for InterpretedPolicy<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterpretedPolicy")
+            .field("runtime", &self.runtime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C> std::ops::Deref for InterpretedPolicy<C> {
+    type Target = InterpretedRuntime<C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.runtime
+    }
+}
+
+impl<C: EvaluationContext> InterpretedPolicy<C> {
+    /// Evaluate a policy with the given entrypoint and input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed.
+    pub async fn evaluate<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>>(
+        &mut self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<R> {
+        let InterpretedRuntime { instance, module } = &mut self.runtime;
+        let result = module
+            .evaluate(instance, &self.data, entrypoint, input)
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, StubPolicy};
+
+    #[test]
+    fn modules_are_checked() {
+        // A valid module, but without the ABI of OPA policies
+        let error = InterpretedRuntime::new(b"\0asm\x01\0\0\0").unwrap_err();
+        assert_eq!(error.to_string(), "missing global opa_wasm_abi_version");
+
+        let error = InterpretedRuntime::new(b"not a module").unwrap_err();
+        assert_eq!(error.to_string(), "could not parse the module");
+    }
+
+    #[test]
+    fn evaluation_is_send() {
+        fn assert_send<F: std::future::Future + Send>(_: F) {}
+
+        fn check(policy: &mut InterpretedPolicy) {
+            assert_send(policy.evaluate::<_, serde_json::Value>("allow", &()));
+        }
+
+        let _ = check;
+    }
+
+    #[test]
+    fn strings_are_read_from_memory() {
+        let memory = b"hello\0world";
+        assert_eq!(read_c_str(memory, 0).unwrap(), b"hello");
+        assert!(read_c_str(memory, 6).is_err());
+        assert!(read_c_str(memory, 42).is_err());
+    }
+
+    impl StubPolicy for InterpretedPolicy {
+        async fn load(wasm: &[u8], revision: &str, strict_builtin_errors: bool) -> Result<Self> {
+            InterpretedRuntime::new(wasm)?
+                .with_revision(revision)
+                .with_strict_builtin_errors(strict_builtin_errors)
+                .without_data()
+        }
+
+        async fn evaluate(&mut self, entrypoint: &str) -> Result<serde_json::Value> {
+            InterpretedPolicy::evaluate(self, entrypoint, &()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn builtin_errors_are_undefined_unless_strict() {
+        stub::builtin_errors_are_undefined_unless_strict::<InterpretedPolicy>().await;
+    }
+
+    #[tokio::test]
+    async fn failures_are_described() {
+        stub::failures_are_described::<InterpretedPolicy>().await;
+    }
+}
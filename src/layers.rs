@@ -0,0 +1,1820 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Adapters wrapping an [`EvaluationContext`] to add or override some of its
+//! behaviors, so that they can be composed without writing a bespoke context
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(any(feature = "otel", feature = "prometheus"))]
+use crate::EvaluationOutcome;
+use crate::{
+    cache::EvaluationCache, CacheStats, Capability, EvaluationContext, EvaluationMetadata,
+};
+#[cfg(feature = "blocking-http-client")]
+use crate::{
+    http_client::{BlockingHttpClient, HttpClientConfig},
+    DefaultContext, ProxyConfig, TlsConfig,
+};
+#[cfg(feature = "http-builtins")]
+use crate::{HttpSendOptions, MockResponse, RequestMatcher};
+
+/// Forward the given [`EvaluationContext`] methods to the `inner` field. The
+/// context implementing them has to be generic over the type `C` of that
+/// field.
+macro_rules! forward {
+    ($($method:ident),* $(,)?) => {
+        $($crate::layers::forward!(@ $method);)*
+    };
+
+    (@ rng) => {
+        #[cfg(feature = "rng")]
+        type Rng = C::Rng;
+
+        #[cfg(feature = "rng")]
+        fn get_rng(&mut self) -> Self::Rng {
+            self.inner.get_rng()
+        }
+    };
+
+    (@ now) => {
+        #[cfg(feature = "time")]
+        fn now(&self) -> ::chrono::DateTime<::chrono::Utc> {
+            self.inner.now()
+        }
+    };
+
+    (@ evaluation_start) => {
+        fn evaluation_start(&mut self) {
+            self.inner.evaluation_start();
+        }
+
+        fn evaluation_start_with_metadata(&mut self, metadata: &$crate::EvaluationMetadata<'_>) {
+            self.inner.evaluation_start_with_metadata(metadata);
+        }
+    };
+
+    (@ evaluation_end) => {
+        fn evaluation_end(&mut self, outcome: &$crate::EvaluationOutcome<'_>) {
+            self.inner.evaluation_end(outcome);
+        }
+    };
+
+    (@ cache) => {
+        fn cache_get<K: ::serde::Serialize, V: ::serde::de::DeserializeOwned>(
+            &mut self,
+            key: &K,
+        ) -> ::anyhow::Result<Option<V>> {
+            self.inner.cache_get(key)
+        }
+
+        fn cache_set<K: ::serde::Serialize, V: ::serde::Serialize>(
+            &mut self,
+            key: &K,
+            content: &V,
+        ) -> ::anyhow::Result<()> {
+            self.inner.cache_set(key, content)
+        }
+
+        fn cache_stats(&self) -> Option<$crate::CacheStats> {
+            self.inner.cache_stats()
+        }
+    };
+
+    (@ capability_enabled) => {
+        fn capability_enabled(&self, capability: $crate::Capability) -> bool {
+            self.inner.capability_enabled(capability)
+        }
+    };
+
+    (@ records) => {
+        fn record_builtin_duration(&mut self, name: &str, duration: ::std::time::Duration) {
+            self.inner.record_builtin_duration(name, duration);
+        }
+
+        fn record_builtin_call(
+            &mut self,
+            name: &str,
+            args: &[&[u8]],
+            result: ::std::result::Result<&[u8], &::anyhow::Error>,
+        ) {
+            self.inner.record_builtin_call(name, args, result);
+        }
+
+        fn record_evaluation_duration(
+            &mut self,
+            entrypoint: &str,
+            duration: ::std::time::Duration,
+        ) {
+            self.inner.record_evaluation_duration(entrypoint, duration);
+        }
+    };
+
+    (@ deadline) => {
+        fn deadline(&self) -> Option<::std::time::Instant> {
+            self.inner.deadline()
+        }
+    };
+
+    (@ http) => {
+        #[cfg(feature = "http-builtins")]
+        fn inject_http_headers(&self, headers: &mut ::http::HeaderMap) {
+            self.inner.inject_http_headers(headers);
+        }
+
+        #[cfg(feature = "http-builtins")]
+        async fn sleep(&mut self, duration: ::std::time::Duration) {
+            self.inner.sleep(duration).await;
+        }
+
+        #[cfg(feature = "http-builtins")]
+        async fn send_http(
+            &mut self,
+            request: ::http::Request<String>,
+            options: $crate::HttpSendOptions,
+        ) -> ::anyhow::Result<::http::Response<String>> {
+            self.inner.send_http(request, options).await
+        }
+    };
+
+    (@ resolve_jwt_key) => {
+        #[cfg(feature = "jwt-builtins")]
+        async fn resolve_jwt_key(
+            &mut self,
+            kid: Option<&str>,
+            issuer: Option<&str>,
+        ) -> ::anyhow::Result<Option<$crate::JwtKey>> {
+            self.inner.resolve_jwt_key(kid, issuer).await
+        }
+    };
+
+    (@ runtime_info) => {
+        fn runtime_info(&self) -> $crate::RuntimeInfo {
+            self.inner.runtime_info()
+        }
+    };
+
+    (@ messages) => {
+        fn print(&mut self, message: &str) {
+            self.inner.print(message);
+        }
+
+        fn abort(&mut self, message: &str) {
+            self.inner.abort(message);
+        }
+
+        fn note(&mut self, note: &str) {
+            self.inner.note(note);
+        }
+
+        fn notes(&self) -> &[String] {
+            self.inner.notes()
+        }
+    };
+
+    (@ secrets_provider) => {
+        fn secrets_provider(&self) -> Option<&dyn $crate::SecretsProvider> {
+            self.inner.secrets_provider()
+        }
+    };
+}
+
+pub(crate) use forward;
+
+/// Generate the methods to access the inner context of a layer
+macro_rules! inner_accessors {
+    () => {
+        /// Get a reference to the inner context
+        pub fn inner(&self) -> &C {
+            &self.inner
+        }
+
+        /// Get a mutable reference to the inner context
+        pub fn inner_mut(&mut self) -> &mut C {
+            &mut self.inner
+        }
+
+        /// Unwrap this layer, returning the inner context
+        pub fn into_inner(self) -> C {
+            self.inner
+        }
+    };
+}
+
+/// A layer which replaces the evaluation cache of the inner context with a
+/// bounded LRU cache, cleared on each evaluation
+pub struct CachingLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The cache used instead of the inner one
+    cache: EvaluationCache,
+}
+
+impl<C> CachingLayer<C> {
+    /// Wrap a context with an unbounded cache
+    pub fn new(inner: C) -> Self {
+        Self::with_bounds(inner, None, None)
+    }
+
+    /// Wrap a context with a cache bounded by number of entries and by total
+    /// size in bytes
+    pub fn with_bounds(inner: C, max_entries: Option<usize>, max_bytes: Option<usize>) -> Self {
+        Self {
+            inner,
+            cache: EvaluationCache::new(max_entries, max_bytes),
+        }
+    }
+
+    inner_accessors!();
+}
+
+impl<C: EvaluationContext> EvaluationContext for CachingLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_end,
+        capability_enabled,
+        records,
+        deadline,
+        http,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn evaluation_start(&mut self) {
+        self.cache.clear();
+        self.inner.evaluation_start();
+    }
+
+    fn evaluation_start_with_metadata(&mut self, metadata: &EvaluationMetadata<'_>) {
+        self.cache.clear();
+        self.inner.evaluation_start_with_metadata(metadata);
+    }
+
+    fn cache_get<K: Serialize, V: DeserializeOwned>(&mut self, key: &K) -> Result<Option<V>> {
+        self.cache.get(key)
+    }
+
+    fn cache_set<K: Serialize, V: Serialize>(&mut self, key: &K, content: &V) -> Result<()> {
+        self.cache.set(key, content)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.stats())
+    }
+}
+
+/// A layer which denies outgoing HTTP requests and DNS lookups, regardless of
+/// what the inner context allows
+pub struct DenyNetworkLayer<C> {
+    /// The wrapped context
+    inner: C,
+}
+
+impl<C> DenyNetworkLayer<C> {
+    /// Wrap a context, denying its network access
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    inner_accessors!();
+}
+
+impl<C: EvaluationContext> EvaluationContext for DenyNetworkLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        evaluation_end,
+        cache,
+        records,
+        deadline,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn capability_enabled(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Http | Capability::Dns => false,
+            _ => self.inner.capability_enabled(capability),
+        }
+    }
+
+    #[cfg(feature = "http-builtins")]
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        let _ = (request, options);
+        anyhow::bail!("outgoing HTTP requests are denied")
+    }
+}
+
+/// A layer which answers `http.send` requests with canned responses, so that
+/// policies can be evaluated offline and deterministically
+#[cfg(feature = "http-builtins")]
+pub struct HttpMockLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The canned responses, tried in order
+    mocks: Vec<(RequestMatcher, MockResponse)>,
+
+    /// Whether requests matching none of the mocks are sent by the inner
+    /// context
+    passthrough: bool,
+}
+
+#[cfg(feature = "http-builtins")]
+impl<C> HttpMockLayer<C> {
+    /// Wrap a context, answering no request yet
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            mocks: Vec::new(),
+            passthrough: false,
+        }
+    }
+
+    /// Answer the requests matching `matcher` with the given response.
+    /// Matchers are tried in the order they were registered.
+    #[must_use]
+    pub fn mock(mut self, matcher: RequestMatcher, response: MockResponse) -> Self {
+        self.mocks.push((matcher, response));
+        self
+    }
+
+    /// Send the requests which match none of the mocks with the inner
+    /// context, instead of failing them. Defaults to `false`.
+    #[must_use]
+    pub fn passthrough(mut self, passthrough: bool) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    inner_accessors!();
+}
+
+#[cfg(feature = "http-builtins")]
+impl<C: EvaluationContext> EvaluationContext for HttpMockLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        evaluation_end,
+        cache,
+        records,
+        deadline,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn capability_enabled(&self, capability: Capability) -> bool {
+        match capability {
+            // Mocked requests don't need the network
+            Capability::Http if !self.mocks.is_empty() => true,
+            _ => self.inner.capability_enabled(capability),
+        }
+    }
+
+    fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+        self.inner.inject_http_headers(headers);
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        self.inner.sleep(duration).await;
+    }
+
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        let mock = self
+            .mocks
+            .iter()
+            .find(|(matcher, _)| matcher.matches(&request));
+
+        if let Some((_, response)) = mock {
+            return response.to_response();
+        }
+
+        if self.passthrough && self.inner.capability_enabled(Capability::Http) {
+            return self.inner.send_http(request, options).await;
+        }
+
+        anyhow::bail!(
+            "no mocked response for {} {}",
+            request.method(),
+            request.uri()
+        )
+    }
+}
+
+/// A request recorded in a [`Cassette`]. Its headers are not recorded, as
+/// they often hold credentials.
+#[cfg(feature = "http-builtins")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct RecordedRequest {
+    /// The method of the request
+    pub method: String,
+
+    /// The URL of the request
+    pub url: String,
+
+    /// The body of the request
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+}
+
+#[cfg(feature = "http-builtins")]
+impl RecordedRequest {
+    /// Record a request
+    fn new(request: &http::Request<String>) -> Self {
+        Self {
+            method: request.method().to_string(),
+            url: request.uri().to_string(),
+            body: request.body().clone(),
+        }
+    }
+}
+
+/// A response recorded in a [`Cassette`]
+#[cfg(feature = "http-builtins")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct RecordedResponse {
+    /// The status code of the response
+    pub status: u16,
+
+    /// The headers of the response
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub headers: std::collections::BTreeMap<String, String>,
+
+    /// The body of the response
+    #[serde(default)]
+    pub body: String,
+}
+
+#[cfg(feature = "http-builtins")]
+impl RecordedResponse {
+    /// Record a response. Headers which are not valid UTF-8 are left out.
+    fn new(response: &http::Response<String>) -> Self {
+        Self {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body: response.body().clone(),
+        }
+    }
+
+    /// Build the response to replay
+    fn to_response(&self) -> Result<http::Response<String>> {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        Ok(builder.body(self.body.clone())?)
+    }
+}
+
+/// A request and the response it got
+#[cfg(feature = "http-builtins")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct Interaction {
+    /// The request
+    pub request: RecordedRequest,
+
+    /// The response
+    pub response: RecordedResponse,
+}
+
+/// The `http.send` requests and responses recorded by an
+/// [`HttpCassetteLayer`], to replay them later, stored as JSON
+#[cfg(feature = "http-builtins")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct Cassette {
+    /// The interactions, in the order they were recorded
+    pub interactions: Vec<Interaction>,
+}
+
+#[cfg(feature = "http-builtins")]
+impl Cassette {
+    /// Read a cassette from a JSON file
+    ///
+    /// # Errors
+    ///
+    /// If the file could not be read, or is not a valid cassette
+    pub fn read(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        use anyhow::Context as _;
+
+        let path = path.as_ref();
+        let contents = std::fs::read(path)
+            .with_context(|| format!("could not read the cassette {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("invalid cassette {}", path.display()))
+    }
+
+    /// Write the cassette to a JSON file
+    ///
+    /// # Errors
+    ///
+    /// If the file could not be written
+    pub fn write(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        use anyhow::Context as _;
+
+        let path = path.as_ref();
+        let contents = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("could not write the cassette {}", path.display()))
+    }
+}
+
+/// Whether an [`HttpCassetteLayer`] records or replays
+#[cfg(feature = "http-builtins")]
+enum CassetteMode {
+    /// The requests are sent by the inner context, and recorded
+    Record,
+
+    /// The requests are answered from the cassette, and the flags tell which
+    /// interactions were already replayed
+    Replay(Vec<bool>),
+}
+
+/// A layer which records the `http.send` requests sent by the inner context
+/// and their responses in a [`Cassette`], or replays them from one, so that
+/// tests over real APIs can be captured once and then run offline.
+///
+/// When replaying, each request is answered by the first interaction not
+/// replayed yet with the same method, URL and body, and requests matching none
+/// of them fail.
+#[cfg(feature = "http-builtins")]
+pub struct HttpCassetteLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// Whether the layer records or replays
+    mode: CassetteMode,
+
+    /// The recorded interactions
+    cassette: Cassette,
+}
+
+#[cfg(feature = "http-builtins")]
+impl<C> HttpCassetteLayer<C> {
+    /// Wrap a context, recording the requests it sends in an empty cassette
+    pub fn record(inner: C) -> Self {
+        Self {
+            inner,
+            mode: CassetteMode::Record,
+            cassette: Cassette::default(),
+        }
+    }
+
+    /// Wrap a context, answering the requests from the given cassette
+    /// instead of sending them
+    pub fn replay(inner: C, cassette: Cassette) -> Self {
+        Self {
+            inner,
+            mode: CassetteMode::Replay(vec![false; cassette.interactions.len()]),
+            cassette,
+        }
+    }
+
+    /// Get the cassette, holding the interactions recorded so far
+    pub fn cassette(&self) -> &Cassette {
+        &self.cassette
+    }
+
+    inner_accessors!();
+}
+
+#[cfg(feature = "http-builtins")]
+impl<C: EvaluationContext> EvaluationContext for HttpCassetteLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        evaluation_end,
+        cache,
+        records,
+        deadline,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn capability_enabled(&self, capability: Capability) -> bool {
+        match (capability, &self.mode) {
+            // Replayed requests don't need the network
+            (Capability::Http, CassetteMode::Replay(_)) => true,
+            _ => self.inner.capability_enabled(capability),
+        }
+    }
+
+    fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+        self.inner.inject_http_headers(headers);
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        self.inner.sleep(duration).await;
+    }
+
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        let recorded = RecordedRequest::new(&request);
+        match &mut self.mode {
+            CassetteMode::Record => {
+                let response = self.inner.send_http(request, options).await?;
+                self.cassette.interactions.push(Interaction {
+                    request: recorded,
+                    response: RecordedResponse::new(&response),
+                });
+                Ok(response)
+            }
+
+            CassetteMode::Replay(replayed) => {
+                let index = self
+                    .cassette
+                    .interactions
+                    .iter()
+                    .zip(replayed.iter())
+                    .position(|(interaction, replayed)| {
+                        !replayed && interaction.request == recorded
+                    });
+                let Some(index) = index else {
+                    anyhow::bail!(
+                        "no recorded response for {} {}",
+                        recorded.method,
+                        recorded.url
+                    );
+                };
+
+                replayed[index] = true;
+                self.cassette.interactions[index].response.to_response()
+            }
+        }
+    }
+}
+
+/// A context sending the `http.send` requests with the blocking reqwest
+/// client, for applications which evaluate policies with [`block_on`] instead
+/// of running tokio. Everything else is forwarded to the inner context, a
+/// [`DefaultContext`] by default.
+///
+/// The client runs its own runtime on a background thread, so this context
+/// must not be created or dropped from within an async runtime.
+///
+/// [`block_on`]: crate::block_on
+#[cfg(feature = "blocking-http-client")]
+#[derive(Debug)]
+pub struct BlockingHttpContext<C = DefaultContext> {
+    /// The wrapped context
+    inner: C,
+
+    /// The client used to send the requests
+    client: BlockingHttpClient,
+}
+
+#[cfg(feature = "blocking-http-client")]
+impl Default for BlockingHttpContext {
+    fn default() -> Self {
+        Self::new(DefaultContext::default())
+    }
+}
+
+#[cfg(feature = "blocking-http-client")]
+impl<C> BlockingHttpContext<C> {
+    /// Wrap a context, reading the proxies from the environment
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            client: BlockingHttpClient::new(HttpClientConfig::default()),
+        }
+    }
+
+    /// Send the requests through the given proxies. By default, the proxies
+    /// are read from the environment.
+    #[must_use]
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.client.config_mut().proxy = Some(proxy);
+        self
+    }
+
+    /// Use the given root certificates and client identity for all the
+    /// requests
+    #[must_use]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.client.config_mut().tls = tls;
+        self
+    }
+
+    inner_accessors!();
+}
+
+#[cfg(feature = "blocking-http-client")]
+impl<C: EvaluationContext> EvaluationContext for BlockingHttpContext<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        evaluation_end,
+        cache,
+        capability_enabled,
+        records,
+        deadline,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+        self.inner.inject_http_headers(headers);
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        self.inner.sleep(duration).await;
+    }
+
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        // This blocks the executor, which is what synchronous callers expect
+        self.client.send(request, &options)
+    }
+}
+
+/// Aggregated durations of a builtin or an entrypoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DurationStats {
+    /// The number of recorded calls
+    pub count: u64,
+
+    /// The total time spent in those calls
+    pub total: Duration,
+
+    /// The longest call
+    pub max: Duration,
+}
+
+impl DurationStats {
+    /// Record the duration of a call
+    fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+}
+
+/// A layer which aggregates the durations of builtin calls and evaluations,
+/// before forwarding them to the inner context
+pub struct MetricsLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The durations of the builtin calls, by builtin name
+    builtins: HashMap<String, DurationStats>,
+
+    /// The durations of the evaluations, by entrypoint
+    evaluations: HashMap<String, DurationStats>,
+}
+
+impl<C> MetricsLayer<C> {
+    /// Wrap a context, aggregating its metrics
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            builtins: HashMap::new(),
+            evaluations: HashMap::new(),
+        }
+    }
+
+    /// Get the aggregated durations of the builtin calls, by builtin name
+    pub fn builtin_stats(&self) -> &HashMap<String, DurationStats> {
+        &self.builtins
+    }
+
+    /// Get the aggregated durations of the evaluations, by entrypoint
+    pub fn evaluation_stats(&self) -> &HashMap<String, DurationStats> {
+        &self.evaluations
+    }
+
+    /// Reset the aggregated metrics
+    pub fn reset(&mut self) {
+        self.builtins.clear();
+        self.evaluations.clear();
+    }
+
+    inner_accessors!();
+}
+
+impl<C: EvaluationContext> EvaluationContext for MetricsLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        evaluation_end,
+        cache,
+        capability_enabled,
+        deadline,
+        http,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn record_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+    ) {
+        self.inner.record_builtin_call(name, args, result);
+    }
+
+    fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+        self.builtins
+            .entry(name.to_owned())
+            .or_default()
+            .record(duration);
+        self.inner.record_builtin_duration(name, duration);
+    }
+
+    fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+        self.evaluations
+            .entry(entrypoint.to_owned())
+            .or_default()
+            .record(duration);
+        self.inner.record_evaluation_duration(entrypoint, duration);
+    }
+}
+
+/// The builtins called during an evaluation, recorded by a
+/// [`BuiltinUsageLayer`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BuiltinUsage {
+    /// The number of calls and their durations, by builtin name
+    pub builtins: HashMap<String, DurationStats>,
+
+    /// The number of `http.send` requests which were actually sent, which is
+    /// lower than the number of `http.send` calls when responses are cached
+    pub http_requests: u64,
+}
+
+impl BuiltinUsage {
+    /// The number of calls to the given builtin
+    #[must_use]
+    pub fn calls(&self, name: &str) -> u64 {
+        self.builtins.get(name).map_or(0, |stats| stats.count)
+    }
+
+    /// The total time spent in builtin calls
+    #[must_use]
+    pub fn total_duration(&self) -> Duration {
+        self.builtins.values().map(|stats| stats.total).sum()
+    }
+}
+
+/// A layer which records the builtins called during each evaluation, how
+/// many times and for how long, before forwarding the calls to the inner
+/// context. The usage is reset when an evaluation starts, and stays available
+/// once it ends.
+pub struct BuiltinUsageLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The builtins called during the current or the last evaluation
+    usage: BuiltinUsage,
+}
+
+impl<C> BuiltinUsageLayer<C> {
+    /// Wrap a context, recording its builtin usage
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            usage: BuiltinUsage::default(),
+        }
+    }
+
+    /// Get the builtins called during the current or the last evaluation
+    pub fn usage(&self) -> &BuiltinUsage {
+        &self.usage
+    }
+
+    /// Take the builtins called during the current or the last evaluation,
+    /// leaving an empty usage
+    pub fn take_usage(&mut self) -> BuiltinUsage {
+        std::mem::take(&mut self.usage)
+    }
+
+    inner_accessors!();
+}
+
+impl<C: EvaluationContext> EvaluationContext for BuiltinUsageLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_end,
+        cache,
+        capability_enabled,
+        deadline,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn evaluation_start(&mut self) {
+        self.usage = BuiltinUsage::default();
+        self.inner.evaluation_start();
+    }
+
+    fn evaluation_start_with_metadata(&mut self, metadata: &EvaluationMetadata<'_>) {
+        self.usage = BuiltinUsage::default();
+        self.inner.evaluation_start_with_metadata(metadata);
+    }
+
+    fn record_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+    ) {
+        self.inner.record_builtin_call(name, args, result);
+    }
+
+    fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+        self.usage
+            .builtins
+            .entry(name.to_owned())
+            .or_default()
+            .record(duration);
+        self.inner.record_builtin_duration(name, duration);
+    }
+
+    fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+        self.inner.record_evaluation_duration(entrypoint, duration);
+    }
+
+    #[cfg(feature = "http-builtins")]
+    fn inject_http_headers(&self, headers: &mut http::HeaderMap) {
+        self.inner.inject_http_headers(headers);
+    }
+
+    #[cfg(feature = "http-builtins")]
+    async fn sleep(&mut self, duration: Duration) {
+        self.inner.sleep(duration).await;
+    }
+
+    #[cfg(feature = "http-builtins")]
+    async fn send_http(
+        &mut self,
+        request: http::Request<String>,
+        options: HttpSendOptions,
+    ) -> Result<http::Response<String>> {
+        self.usage.http_requests += 1;
+        self.inner.send_http(request, options).await
+    }
+}
+
+/// A layer which records OpenTelemetry metrics for each evaluation and
+/// builtin call, before forwarding them to the inner context:
+///
+///  - `opa.evaluations`, a counter of evaluations, by `opa.entrypoint` and
+///    `opa.decision`
+///  - `opa.evaluation.duration`, a histogram of the evaluation durations in
+///    seconds, with the same attributes
+///  - `opa.builtin.duration`, a histogram of the builtin call durations in
+///    seconds, by `opa.builtin.name`
+///
+/// The `opa.decision` attribute is `true` or `false` for boolean results,
+/// `defined` for other results, `undefined` when the result set is empty, and
+/// `error` when the evaluation failed.
+#[cfg(feature = "otel")]
+pub struct OtelLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The number of evaluations
+    evaluations: opentelemetry::metrics::Counter<u64>,
+
+    /// The durations of the evaluations
+    evaluation_duration: opentelemetry::metrics::Histogram<f64>,
+
+    /// The durations of the builtin calls
+    builtin_duration: opentelemetry::metrics::Histogram<f64>,
+}
+
+#[cfg(feature = "otel")]
+impl<C> OtelLayer<C> {
+    /// Wrap a context, recording its metrics with the given meter
+    pub fn new(inner: C, meter: &opentelemetry::metrics::Meter) -> Self {
+        Self {
+            inner,
+            evaluations: meter
+                .u64_counter("opa.evaluations")
+                .with_description("The number of policy evaluations")
+                .with_unit("{evaluation}")
+                .build(),
+            evaluation_duration: meter
+                .f64_histogram("opa.evaluation.duration")
+                .with_description("The duration of policy evaluations")
+                .with_unit("s")
+                .build(),
+            builtin_duration: meter
+                .f64_histogram("opa.builtin.duration")
+                .with_description("The duration of builtin calls")
+                .with_unit("s")
+                .build(),
+        }
+    }
+
+    /// Wrap a context, recording its metrics with the meter of the global
+    /// meter provider
+    pub fn with_global_meter(inner: C) -> Self {
+        Self::new(inner, &opentelemetry::global::meter("opa-wasm"))
+    }
+
+    inner_accessors!();
+}
+
+#[cfg(feature = "otel")]
+impl<C: EvaluationContext> EvaluationContext for OtelLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        cache,
+        capability_enabled,
+        deadline,
+        http,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn evaluation_end(&mut self, outcome: &EvaluationOutcome<'_>) {
+        let attributes = [
+            opentelemetry::KeyValue::new("opa.entrypoint", outcome.metadata.entrypoint.to_owned()),
+            opentelemetry::KeyValue::new("opa.decision", outcome.decision()),
+        ];
+        self.evaluations.add(1, &attributes);
+        self.evaluation_duration
+            .record(outcome.duration.as_secs_f64(), &attributes);
+        self.inner.evaluation_end(outcome);
+    }
+
+    fn record_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+    ) {
+        self.inner.record_builtin_call(name, args, result);
+    }
+
+    fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+        self.builtin_duration.record(
+            duration.as_secs_f64(),
+            &[opentelemetry::KeyValue::new(
+                "opa.builtin.name",
+                name.to_owned(),
+            )],
+        );
+        self.inner.record_builtin_duration(name, duration);
+    }
+
+    fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+        self.inner.record_evaluation_duration(entrypoint, duration);
+    }
+}
+
+/// The labels of the per-entrypoint Prometheus metrics
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, prometheus_client::encoding::EncodeLabelSet)]
+struct EntrypointLabels {
+    /// The evaluated entrypoint
+    entrypoint: String,
+}
+
+/// The labels of the per-builtin Prometheus metrics
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, prometheus_client::encoding::EncodeLabelSet)]
+struct BuiltinLabels {
+    /// The name of the builtin
+    builtin: String,
+}
+
+/// The labels of the builtin call counter
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, prometheus_client::encoding::EncodeLabelSet)]
+struct BuiltinCallLabels {
+    /// The name of the builtin
+    builtin: String,
+
+    /// Either `ok` or `error`
+    result: &'static str,
+}
+
+/// The labels of the bundle info gauge
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, prometheus_client::encoding::EncodeLabelSet)]
+struct BundleLabels {
+    /// The revision of the active bundle
+    revision: String,
+}
+
+/// A histogram family, with buckets suited to the recorded durations
+#[cfg(feature = "prometheus")]
+type HistogramFamily<L> = prometheus_client::metrics::family::Family<
+    L,
+    prometheus_client::metrics::histogram::Histogram,
+    fn() -> prometheus_client::metrics::histogram::Histogram,
+>;
+
+/// Prometheus metrics about evaluations, shared by the [`PrometheusLayer`]s
+/// recording them. Cloning it gives another handle to the same metrics.
+///
+/// Once [registered](PrometheusMetrics::register), the registry exposes:
+///
+///  - `opa_evaluation_duration_seconds`, a histogram by `entrypoint`
+///  - `opa_builtin_calls_total`, a counter by `builtin` and `result`
+///  - `opa_builtin_duration_seconds`, a histogram by `builtin`
+///  - `opa_cache_hits_total`, `opa_cache_misses_total` and
+///    `opa_cache_evictions_total`, from which the cache hit ratio can be
+///    computed
+///  - `opa_bundle_info`, set to 1 for the `revision` of the active bundle, and
+///    `opa_bundle_last_activation_timestamp_seconds`
+///
+/// The registry can then be encoded with
+/// [`prometheus_client::encoding::text::encode`] by an existing `/metrics`
+/// endpoint.
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone)]
+pub struct PrometheusMetrics {
+    /// The durations of the evaluations
+    evaluation_duration: HistogramFamily<EntrypointLabels>,
+
+    /// The number of builtin calls
+    builtin_calls: prometheus_client::metrics::family::Family<
+        BuiltinCallLabels,
+        prometheus_client::metrics::counter::Counter,
+    >,
+
+    /// The durations of the builtin calls
+    builtin_duration: HistogramFamily<BuiltinLabels>,
+
+    /// The number of cache lookups which found a value
+    cache_hits: prometheus_client::metrics::counter::Counter,
+
+    /// The number of cache lookups which found no value
+    cache_misses: prometheus_client::metrics::counter::Counter,
+
+    /// The number of evicted cache entries
+    cache_evictions: prometheus_client::metrics::counter::Counter,
+
+    /// The revision of the active bundle
+    bundle_info: prometheus_client::metrics::family::Family<
+        BundleLabels,
+        prometheus_client::metrics::gauge::Gauge,
+    >,
+
+    /// When the active bundle was activated, as a UNIX timestamp
+    bundle_activation: prometheus_client::metrics::gauge::Gauge,
+}
+
+#[cfg(feature = "prometheus")]
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    /// Create the metrics, which still have to be registered
+    #[must_use]
+    pub fn new() -> Self {
+        use prometheus_client::metrics::{family::Family, histogram};
+
+        Self {
+            // From 100µs to ~3s
+            evaluation_duration: Family::new_with_constructor(|| {
+                histogram::Histogram::new(histogram::exponential_buckets(0.0001, 2.0, 16))
+            }),
+            builtin_calls: Family::default(),
+            // From 10µs to ~300ms
+            builtin_duration: Family::new_with_constructor(|| {
+                histogram::Histogram::new(histogram::exponential_buckets(0.000_01, 2.0, 16))
+            }),
+            cache_hits: prometheus_client::metrics::counter::Counter::default(),
+            cache_misses: prometheus_client::metrics::counter::Counter::default(),
+            cache_evictions: prometheus_client::metrics::counter::Counter::default(),
+            bundle_info: Family::default(),
+            bundle_activation: prometheus_client::metrics::gauge::Gauge::default(),
+        }
+    }
+
+    /// Register the metrics in the given registry, with the `opa` prefix
+    pub fn register(&self, registry: &mut prometheus_client::registry::Registry) {
+        use prometheus_client::registry::Unit;
+
+        let registry = registry.sub_registry_with_prefix("opa");
+        registry.register_with_unit(
+            "evaluation_duration",
+            "The duration of policy evaluations",
+            Unit::Seconds,
+            self.evaluation_duration.clone(),
+        );
+        registry.register(
+            "builtin_calls",
+            "The number of builtin calls",
+            self.builtin_calls.clone(),
+        );
+        registry.register_with_unit(
+            "builtin_duration",
+            "The duration of builtin calls",
+            Unit::Seconds,
+            self.builtin_duration.clone(),
+        );
+        registry.register(
+            "cache_hits",
+            "The number of evaluation cache lookups which found a value",
+            self.cache_hits.clone(),
+        );
+        registry.register(
+            "cache_misses",
+            "The number of evaluation cache lookups which found no value",
+            self.cache_misses.clone(),
+        );
+        registry.register(
+            "cache_evictions",
+            "The number of evicted evaluation cache entries",
+            self.cache_evictions.clone(),
+        );
+        registry.register(
+            "bundle_info",
+            "The revision of the active bundle",
+            self.bundle_info.clone(),
+        );
+        registry.register_with_unit(
+            "bundle_last_activation_timestamp",
+            "When the active bundle was activated",
+            Unit::Seconds,
+            self.bundle_activation.clone(),
+        );
+    }
+
+    /// Record that a new bundle was activated, for example after a
+    /// `BundleFetcher` fetched a new revision
+    pub fn record_bundle_activation(&self, revision: Option<&str>) {
+        self.bundle_info.clear();
+        self.bundle_info
+            .get_or_create(&BundleLabels {
+                revision: revision.unwrap_or_default().to_owned(),
+            })
+            .set(1);
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.bundle_activation
+            .set(i64::try_from(now.as_secs()).unwrap_or(i64::MAX));
+    }
+}
+
+/// A layer which records [`PrometheusMetrics`] for each evaluation and
+/// builtin call, before forwarding them to the inner context. The instances
+/// of a pool can each have their own layer, recording into the same metrics.
+#[cfg(feature = "prometheus")]
+pub struct PrometheusLayer<C> {
+    /// The wrapped context
+    inner: C,
+
+    /// The metrics to record into
+    metrics: PrometheusMetrics,
+
+    /// The cache statistics of the inner context at the end of the last
+    /// evaluation, to only record what changed since then
+    cache_stats: CacheStats,
+}
+
+#[cfg(feature = "prometheus")]
+impl<C> PrometheusLayer<C> {
+    /// Wrap a context, recording its metrics into the given ones
+    pub fn new(inner: C, metrics: PrometheusMetrics) -> Self {
+        Self {
+            inner,
+            metrics,
+            cache_stats: CacheStats::default(),
+        }
+    }
+
+    /// Get the metrics this layer records into
+    pub fn metrics(&self) -> &PrometheusMetrics {
+        &self.metrics
+    }
+
+    inner_accessors!();
+}
+
+#[cfg(feature = "prometheus")]
+impl<C: EvaluationContext> EvaluationContext for PrometheusLayer<C> {
+    forward!(
+        rng,
+        now,
+        evaluation_start,
+        cache,
+        capability_enabled,
+        deadline,
+        http,
+        resolve_jwt_key,
+        runtime_info,
+        messages,
+        secrets_provider,
+    );
+
+    fn evaluation_end(&mut self, outcome: &EvaluationOutcome<'_>) {
+        self.metrics
+            .evaluation_duration
+            .get_or_create(&EntrypointLabels {
+                entrypoint: outcome.metadata.entrypoint.to_owned(),
+            })
+            .observe(outcome.duration.as_secs_f64());
+
+        // The cache counters accumulate, so only record what changed
+        if let Some(stats) = self.inner.cache_stats() {
+            let metrics = &self.metrics;
+            metrics
+                .cache_hits
+                .inc_by(stats.hits.saturating_sub(self.cache_stats.hits));
+            metrics
+                .cache_misses
+                .inc_by(stats.misses.saturating_sub(self.cache_stats.misses));
+            metrics
+                .cache_evictions
+                .inc_by(stats.evictions.saturating_sub(self.cache_stats.evictions));
+            self.cache_stats = stats;
+        }
+
+        self.inner.evaluation_end(outcome);
+    }
+
+    fn record_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[&[u8]],
+        result: Result<&[u8], &anyhow::Error>,
+    ) {
+        self.metrics
+            .builtin_calls
+            .get_or_create(&BuiltinCallLabels {
+                builtin: name.to_owned(),
+                result: if result.is_ok() { "ok" } else { "error" },
+            })
+            .inc();
+        self.inner.record_builtin_call(name, args, result);
+    }
+
+    fn record_builtin_duration(&mut self, name: &str, duration: Duration) {
+        self.metrics
+            .builtin_duration
+            .get_or_create(&BuiltinLabels {
+                builtin: name.to_owned(),
+            })
+            .observe(duration.as_secs_f64());
+        self.inner.record_builtin_duration(name, duration);
+    }
+
+    fn record_evaluation_duration(&mut self, entrypoint: &str, duration: Duration) {
+        self.inner.record_evaluation_duration(entrypoint, duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DefaultContext;
+
+    #[test]
+    fn layers_compose() {
+        let inner = DefaultContext::builder().cache_capacity(0).build();
+        let mut ctx = MetricsLayer::new(DenyNetworkLayer::new(CachingLayer::new(inner)));
+        ctx.evaluation_start();
+
+        // The network is denied, but other capabilities are still allowed
+        assert!(!ctx.capability_enabled(Capability::Http));
+        assert!(!ctx.capability_enabled(Capability::Dns));
+        assert!(ctx.capability_enabled(Capability::Env));
+
+        // The caching layer replaces the inner cache, which can't hold anything
+        ctx.cache_set(&"key", &"value").unwrap();
+        assert_eq!(
+            ctx.cache_get::<_, String>(&"key").unwrap().as_deref(),
+            Some("value")
+        );
+        assert_eq!(ctx.cache_stats().unwrap().hits, 1);
+
+        ctx.record_builtin_duration("time.now_ns", Duration::from_millis(2));
+        ctx.record_builtin_duration("time.now_ns", Duration::from_millis(5));
+        let stats = ctx.builtin_stats()["time.now_ns"];
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.total, Duration::from_millis(7));
+        assert_eq!(stats.max, Duration::from_millis(5));
+
+        // The cache is cleared when the next evaluation starts
+        ctx.evaluation_start();
+        assert!(ctx.cache_get::<_, String>(&"key").unwrap().is_none());
+    }
+
+    #[test]
+    fn builtin_usage_is_per_evaluation() {
+        let mut ctx = BuiltinUsageLayer::new(DefaultContext::default());
+        ctx.evaluation_start();
+        ctx.record_builtin_duration("http.send", Duration::from_millis(20));
+        ctx.record_builtin_duration("http.send", Duration::from_millis(1));
+        ctx.record_builtin_duration("time.now_ns", Duration::from_millis(1));
+
+        let usage = ctx.usage();
+        assert_eq!(usage.calls("http.send"), 2);
+        assert_eq!(usage.calls("json.patch"), 0);
+        assert_eq!(usage.builtins["http.send"].max, Duration::from_millis(20));
+        assert_eq!(usage.total_duration(), Duration::from_millis(22));
+
+        // The usage is reset when the next evaluation starts
+        ctx.evaluation_start();
+        assert_eq!(ctx.usage(), &BuiltinUsage::default());
+
+        ctx.record_builtin_duration("time.now_ns", Duration::from_millis(1));
+        assert_eq!(ctx.take_usage().calls("time.now_ns"), 1);
+        assert_eq!(ctx.usage().calls("time.now_ns"), 0);
+    }
+
+    #[cfg(feature = "http-builtins")]
+    #[tokio::test]
+    async fn cassettes_are_replayed() {
+        let inner = HttpMockLayer::new(DefaultContext::default()).mock(
+            RequestMatcher::new("https://example.com/"),
+            MockResponse::json(http::StatusCode::OK, &serde_json::json!({"ok": true})),
+        );
+        let request = || {
+            http::Request::get("https://example.com/")
+                .body(String::new())
+                .unwrap()
+        };
+
+        let mut ctx = HttpCassetteLayer::record(inner);
+        ctx.send_http(request(), HttpSendOptions::default())
+            .await
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("opa-wasm-cassette-{}.json", std::process::id()));
+        ctx.cassette().write(&path).unwrap();
+        let cassette = Cassette::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&cassette, ctx.cassette());
+        assert_eq!(cassette.interactions.len(), 1);
+
+        // The network is not needed to replay the requests
+        let inner = DefaultContext::builder().http(false).build();
+        let mut ctx = HttpCassetteLayer::replay(inner, cassette);
+        assert!(ctx.capability_enabled(Capability::Http));
+        let response = ctx
+            .send_http(request(), HttpSendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.body(), r#"{"ok":true}"#);
+
+        // Each interaction is replayed once
+        let error = ctx
+            .send_http(request(), HttpSendOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "no recorded response for GET https://example.com/"
+        );
+    }
+
+    #[cfg(feature = "http-builtins")]
+    #[tokio::test]
+    async fn builtin_usage_counts_requests() {
+        let inner = HttpMockLayer::new(DefaultContext::default()).mock(
+            RequestMatcher::new("https://example.com/"),
+            MockResponse::json(http::StatusCode::OK, &serde_json::json!({})),
+        );
+        let mut ctx = BuiltinUsageLayer::new(inner);
+        ctx.evaluation_start();
+
+        let request = http::Request::get("https://example.com/")
+            .body(String::new())
+            .unwrap();
+        ctx.send_http(request, HttpSendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(ctx.usage().http_requests, 1);
+    }
+
+    #[cfg(feature = "http-builtins")]
+    #[tokio::test]
+    async fn http_mock_layer() {
+        let inner = DefaultContext::builder().http(false).build();
+        let mut ctx = HttpMockLayer::new(inner).mock(
+            RequestMatcher::new("https://example.com/").method(http::Method::GET),
+            MockResponse::json(http::StatusCode::OK, &serde_json::json!({"ok": true})),
+        );
+
+        // Mocked requests are allowed even if the inner context denies HTTP
+        assert!(ctx.capability_enabled(Capability::Http));
+
+        let request = http::Request::get("https://example.com/")
+            .body(String::new())
+            .unwrap();
+        let response = ctx
+            .send_http(request, HttpSendOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.body(), r#"{"ok":true}"#);
+
+        let request = http::Request::post("https://example.com/")
+            .body(String::new())
+            .unwrap();
+        let error = ctx
+            .send_http(request, HttpSendOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "no mocked response for POST https://example.com/"
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn decisions_are_classified() {
+        let metadata = EvaluationMetadata {
+            entrypoint: "app/allow",
+            revision: None,
+            evaluation_id: crate::EvaluationId::next(),
+        };
+        let decision = |result: Result<&serde_json::Value, &anyhow::Error>| {
+            EvaluationOutcome {
+                metadata,
+                duration: Duration::ZERO,
+                result,
+                memory: None,
+            }
+            .decision()
+        };
+
+        assert_eq!(
+            decision(Ok(&serde_json::json!([{ "result": true }]))),
+            "true"
+        );
+        assert_eq!(
+            decision(Ok(&serde_json::json!([{ "result": false }]))),
+            "false"
+        );
+        assert_eq!(
+            decision(Ok(&serde_json::json!([{ "result": {} }]))),
+            "defined"
+        );
+        assert_eq!(decision(Ok(&serde_json::json!([]))), "undefined");
+        assert_eq!(decision(Err(&anyhow::anyhow!("oops"))), "error");
+    }
+
+    /// A metric reader which can be shared between the meter provider and the
+    /// test collecting the metrics
+    #[cfg(feature = "otel")]
+    #[derive(Debug, Clone)]
+    struct SharedReader(std::sync::Arc<opentelemetry_sdk::metrics::ManualReader>);
+
+    #[cfg(feature = "otel")]
+    impl opentelemetry_sdk::metrics::reader::MetricReader for SharedReader {
+        fn register_pipeline(
+            &self,
+            pipeline: std::sync::Weak<opentelemetry_sdk::metrics::Pipeline>,
+        ) {
+            self.0.register_pipeline(pipeline);
+        }
+
+        fn collect(
+            &self,
+            rm: &mut opentelemetry_sdk::metrics::data::ResourceMetrics,
+        ) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.shutdown()
+        }
+
+        fn temporality(
+            &self,
+            kind: opentelemetry_sdk::metrics::InstrumentKind,
+        ) -> opentelemetry_sdk::metrics::Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn otel_layer() {
+        use opentelemetry::metrics::MeterProvider;
+        use opentelemetry_sdk::metrics::{
+            data::{Histogram, ResourceMetrics, Sum},
+            reader::MetricReader,
+            ManualReader, SdkMeterProvider,
+        };
+
+        let reader = SharedReader(std::sync::Arc::new(ManualReader::builder().build()));
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        let mut ctx = OtelLayer::new(DefaultContext::default(), &provider.meter("opa-wasm"));
+
+        let metadata = EvaluationMetadata {
+            entrypoint: "app/allow",
+            revision: None,
+            evaluation_id: crate::EvaluationId::next(),
+        };
+        for (millis, result) in [
+            (3, serde_json::json!([])),
+            (5, serde_json::json!([{ "result": true }])),
+            (7, serde_json::json!([{ "result": true }])),
+        ] {
+            ctx.evaluation_start();
+            ctx.evaluation_end(&EvaluationOutcome {
+                metadata,
+                duration: Duration::from_millis(millis),
+                result: Ok(&result),
+                memory: None,
+            });
+        }
+        ctx.record_builtin_duration("time.now_ns", Duration::from_millis(2));
+
+        let mut metrics = ResourceMetrics {
+            resource: opentelemetry_sdk::Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics).unwrap();
+        let metric = |name: &str| {
+            metrics
+                .scope_metrics
+                .iter()
+                .flat_map(|scope| &scope.metrics)
+                .find(|metric| metric.name == name)
+                .unwrap()
+                .data
+                .as_any()
+        };
+        let labels = |attributes: &[opentelemetry::KeyValue]| {
+            let mut labels: Vec<_> = attributes
+                .iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+                .collect();
+            labels.sort();
+            labels
+        };
+        let decision = |decision: &str| {
+            vec![
+                ("opa.decision".to_owned(), decision.to_owned()),
+                ("opa.entrypoint".to_owned(), "app/allow".to_owned()),
+            ]
+        };
+
+        // The evaluations are counted by entrypoint and decision
+        let evaluations = metric("opa.evaluations")
+            .downcast_ref::<Sum<u64>>()
+            .unwrap();
+        let mut counts: Vec<_> = evaluations
+            .data_points
+            .iter()
+            .map(|point| (labels(&point.attributes), point.value))
+            .collect();
+        counts.sort();
+        assert_eq!(counts, [(decision("true"), 2), (decision("undefined"), 1)]);
+
+        // Their durations are recorded in seconds, with the same attributes
+        let durations = metric("opa.evaluation.duration")
+            .downcast_ref::<Histogram<f64>>()
+            .unwrap();
+        let mut durations: Vec<_> = durations
+            .data_points
+            .iter()
+            .map(|point| (labels(&point.attributes), point.count, point.sum))
+            .collect();
+        durations.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(durations.len(), 2);
+        assert_eq!((&durations[0].0, durations[0].1), (&decision("true"), 2));
+        assert!((durations[0].2 - 0.012).abs() < 1e-9);
+        assert_eq!(
+            (&durations[1].0, durations[1].1),
+            (&decision("undefined"), 1)
+        );
+        assert!((durations[1].2 - 0.003).abs() < 1e-9);
+
+        // Builtin call durations are recorded by builtin name
+        let builtins = metric("opa.builtin.duration")
+            .downcast_ref::<Histogram<f64>>()
+            .unwrap();
+        assert_eq!(builtins.data_points.len(), 1);
+        let point = &builtins.data_points[0];
+        assert_eq!(
+            labels(&point.attributes),
+            [("opa.builtin.name".to_owned(), "time.now_ns".to_owned())]
+        );
+        assert_eq!(point.count, 1);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_layer() {
+        let metrics = PrometheusMetrics::new();
+        let mut registry = prometheus_client::registry::Registry::default();
+        metrics.register(&mut registry);
+
+        let inner = DefaultContext::builder().cache_capacity(16).build();
+        let mut ctx = PrometheusLayer::new(inner, metrics.clone());
+        ctx.evaluation_start();
+        ctx.cache_set(&"key", &"value").unwrap();
+        ctx.cache_get::<_, String>(&"key").unwrap();
+        ctx.cache_get::<_, String>(&"other").unwrap();
+        ctx.record_builtin_call("time.now_ns", &[], Ok(b"0"));
+        ctx.record_builtin_duration("time.now_ns", Duration::from_micros(20));
+        ctx.evaluation_end(&EvaluationOutcome {
+            metadata: EvaluationMetadata {
+                entrypoint: "app/allow",
+                revision: None,
+                evaluation_id: crate::EvaluationId::next(),
+            },
+            duration: Duration::from_millis(3),
+            result: Ok(&serde_json::json!([])),
+            memory: None,
+        });
+        metrics.record_bundle_activation(Some("rev-1"));
+
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &registry).unwrap();
+        for line in [
+            r#"opa_evaluation_duration_seconds_count{entrypoint="app/allow"} 1"#,
+            r#"opa_builtin_calls_total{builtin="time.now_ns",result="ok"} 1"#,
+            r#"opa_builtin_duration_seconds_count{builtin="time.now_ns"} 1"#,
+            "opa_cache_hits_total 1",
+            "opa_cache_misses_total 1",
+            r#"opa_bundle_info{revision="rev-1"} 1"#,
+        ] {
+            assert!(
+                encoded.lines().any(|l| l == line),
+                "{line} not in {encoded}"
+            );
+        }
+    }
+}
@@ -27,21 +27,204 @@
 )]
 #![allow(clippy::blocks_in_conditions)]
 
+#[cfg(feature = "actix-web")]
+mod actix;
+#[cfg(feature = "fast")]
+mod agent;
+#[cfg(any(feature = "wasmi", feature = "wasmer", feature = "component-model"))]
+mod backend;
+#[cfg(feature = "uniffi")]
+mod bindings;
 mod builtins;
+mod cache;
+#[cfg(feature = "cbor")]
+mod cbor;
+#[cfg(feature = "compilation-cache")]
+mod compilation_cache;
+#[cfg(feature = "component-model")]
+mod component;
+#[cfg(feature = "conformance")]
+pub mod conformance;
 mod context;
+#[cfg(feature = "decision-logs")]
+mod decision_log;
+#[cfg(feature = "decision-logs")]
+mod decision_sink;
+#[cfg(feature = "embed")]
+mod embed;
+mod engine;
+mod entrypoint;
+mod explain;
+#[cfg(feature = "envoy-ext-authz")]
+mod ext_authz;
+mod failure;
+#[cfg(feature = "ffi")]
+mod ffi;
 mod funcs;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http-client")]
+mod http_client;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "grpc"))]
+mod http_input;
+#[cfg(feature = "wasmi")]
+mod interpreter;
+mod layers;
 #[cfg(feature = "loader")]
 mod loader;
+#[cfg(feature = "axum")]
+mod middleware;
 mod policy;
+mod pool;
+#[cfg(feature = "pooling-allocator")]
+mod pooling;
+#[cfg(feature = "axum")]
+mod rest;
+mod rt;
+mod secrets;
+#[cfg(feature = "tower")]
+mod service;
+#[cfg(test)]
+mod stub;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod types;
+#[cfg(feature = "validate")]
+mod validate;
+#[cfg(feature = "wasi")]
+mod wasi;
+#[cfg(feature = "wasmer")]
+mod wasmer_backend;
 
+#[cfg(feature = "derive")]
+pub use opa_wasm_derive::{PolicyInput, PolicyOutput};
 // Re-export wasmtime to make it easier to keep the verisons in sync
 pub use wasmtime;
 
+/// What the derive macros and the exported macros refer to
+#[cfg(any(feature = "derive", feature = "testing"))]
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "testing")]
+    pub use insta;
+    pub use serde;
+    pub use serde_json;
+}
+
+/// What the fuzz targets of `fuzz/` call into
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod __fuzz {
+    use std::ffi::CStr;
+
+    use anyhow::Result;
+
+    pub use crate::builtins::default_names as builtin_names;
+
+    /// Read the null-terminated string at the given address of the bytes of
+    /// a WASM memory
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address is out of bounds, or if there is no
+    /// nul byte after it
+    pub fn read_nul_str(memory: &[u8], addr: i32) -> Result<&CStr> {
+        crate::types::NulStr(addr).read_from(memory)
+    }
+}
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+#[cfg(feature = "actix-web")]
+pub use self::actix::{ActixAuthorization, ActixAuthorizationMiddleware, PolicyGuard};
+#[cfg(feature = "fast")]
+pub use self::agent::{PolicyAgent, PolicyAgentBuilder};
+#[cfg(feature = "uniffi")]
+pub use self::bindings::{PolicyError, PolicyEvaluator};
+#[cfg(feature = "compilation-cache")]
+pub use self::compilation_cache::CompilationCache;
+#[cfg(feature = "component-model")]
+pub use self::component::ComponentPolicy;
+#[cfg(feature = "http-builtins")]
+pub use self::context::tests::{HttpFault, MockResponse, RequestMatcher};
+#[cfg(feature = "http-builtins")]
+pub use self::context::HttpSendOptions;
+#[cfg(feature = "jwt-builtins")]
+pub use self::context::JwtKey;
+#[cfg(feature = "time")]
+pub use self::context::TimeSource;
+#[cfg(feature = "decision-logs")]
+pub use self::decision_log::{DecisionLogEntry, DecisionLogger};
+#[cfg(feature = "decision-logs")]
+pub use self::decision_sink::{DecisionSink, FileSink, TracingSink};
+#[cfg(feature = "decision-logs-http")]
+pub use self::decision_sink::{DecisionUploader, HttpSink};
+#[cfg(feature = "embed")]
+pub use self::embed::EmbeddedPolicy;
+#[cfg(feature = "embed-build")]
+pub use self::embed::EmbeddedPolicyBuilder;
+#[cfg(feature = "fast")]
+pub use self::engine::OptimizationLevel;
+#[cfg(feature = "envoy-ext-authz")]
+pub use self::ext_authz::ExtAuthz;
+#[cfg(feature = "grpc")]
+pub use self::grpc::{GrpcAuthorization, GrpcAuthorizationLayer, GrpcInput, GrpcPeer};
+#[cfg(feature = "http-client")]
+pub use self::http_client::{ProxyConfig, TlsConfig};
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+pub use self::http_input::HttpInput;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "grpc"))]
+pub use self::http_input::{Identity, PolicyDecision};
+#[cfg(feature = "wasmi")]
+pub use self::interpreter::{InterpretedPolicy, InterpretedRuntime};
+#[cfg(feature = "blocking-http-client")]
+pub use self::layers::BlockingHttpContext;
+#[cfg(feature = "otel")]
+pub use self::layers::OtelLayer;
+#[cfg(feature = "http-builtins")]
+pub use self::layers::{
+    Cassette, HttpCassetteLayer, HttpMockLayer, Interaction, RecordedRequest, RecordedResponse,
+};
+#[cfg(feature = "prometheus")]
+pub use self::layers::{PrometheusLayer, PrometheusMetrics};
+#[cfg(all(feature = "loader", feature = "http-client"))]
+pub use self::loader::BundleFetcher;
 #[cfg(feature = "loader")]
-pub use self::loader::{load_bundle, read_bundle};
+pub use self::loader::{load_bundle, read_bundle, Bundle, BundleFile, BundleManifest};
+#[cfg(feature = "axum")]
+pub use self::middleware::{Authorization, AuthorizationLayer};
+#[cfg(feature = "pooling-allocator")]
+pub use self::pooling::engine_config_for_pooling;
+#[cfg(feature = "axum")]
+pub use self::rest::RestApi;
+#[cfg(feature = "tower")]
+pub use self::service::{Decision, PolicyService};
+#[cfg(feature = "validate")]
+pub use self::validate::{validate, validate_with_builtins, ValidationReport};
+#[cfg(feature = "wasi")]
+pub use self::wasi::WasiState;
+#[cfg(feature = "wasmer")]
+pub use self::wasmer_backend::{WasmerPolicy, WasmerRuntime};
 pub use self::{
-    context::{tests::TestContext, DefaultContext, EvaluationContext},
-    policy::{Policy, Runtime},
+    builtins::{traits::Builtin, BuiltinRegistry, HaltError},
+    cache::CacheStats,
+    context::{
+        tests::{BuiltinCall, TestContext},
+        Capability, DefaultContext, DefaultContextBuilder, EvaluationContext, EvaluationId,
+        EvaluationMetadata, EvaluationOutcome, MemoryUsage, RuntimeInfo,
+    },
+    engine::EngineConfig,
+    entrypoint::{Entrypoint, EvalResult, PolicyInput, PolicyOutput},
+    explain::{EvaluationReport, ExplainedCall, Explanation, ExplanationEvent},
+    failure::{EvaluationFailure, FailureCause},
+    layers::{
+        BuiltinUsage, BuiltinUsageLayer, CachingLayer, DenyNetworkLayer, DurationStats,
+        MetricsLayer,
+    },
+    policy::{DataImage, ModuleInfo, Policy, PolicyDump, Runtime},
+    pool::{PolicyPool, PooledPolicy},
+    rt::block_on,
+    secrets::{Secret, SecretsProvider},
     types::AbiVersion,
 };
@@ -19,19 +19,34 @@ use std::path::Path;
 use anyhow::Context;
 use async_compression::tokio::bufread::GzipDecoder;
 use futures_util::TryStreamExt;
-use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncReadExt};
 use tokio_tar::Archive;
 use tracing::{info_span, Instrument};
 
+/// The path of the compiled policy in bundles
+const POLICY_PATH: &str = "/policy.wasm";
+
+/// The path of the manifest in bundles
+const MANIFEST_PATH: &str = "/.manifest";
+
 /// Read an OPA compiled bundle from disk
+///
+/// # Errors
+///
+/// Returns an error if the file could not be read, or if the bundle is not
+/// a valid OPA compiled bundle
 #[tracing::instrument(err)]
 pub async fn read_bundle(path: impl AsRef<Path> + std::fmt::Debug) -> anyhow::Result<Vec<u8>> {
-    let file = tokio::fs::File::open(path).await?;
-    let reader = BufReader::new(file);
-    load_bundle(reader).await
+    let bundle = crate::rt::read_file(path.as_ref().to_owned()).await?;
+    load_bundle(&bundle[..]).await
 }
 
 /// Load an OPA compiled bundle
+///
+/// # Errors
+///
+/// Returns an error if the bundle is not a valid gzipped tarball, or if it
+/// does not contain a `/policy.wasm` file
 #[tracing::instrument(skip_all, err)]
 pub async fn load_bundle(
     reader: impl AsyncBufRead + Unpin + Send + Sync,
@@ -43,13 +58,7 @@ pub async fn load_bundle(
     // Go through the archive entries to find the /policy.wasm one
     let entries = archive.entries()?;
     let mut entry = entries
-        .try_filter(|e| {
-            std::future::ready(
-                e.path()
-                    .map(|p| p.as_os_str() == "/policy.wasm")
-                    .unwrap_or(false),
-            )
-        })
+        .try_filter(|e| std::future::ready(e.path().is_ok_and(|p| p.as_os_str() == POLICY_PATH)))
         .try_next()
         .instrument(info_span!("find_bundle_entry"))
         .await?
@@ -64,3 +73,279 @@ pub async fn load_bundle(
 
     Ok(buf)
 }
+
+/// The manifest of a bundle, read from its `/.manifest` file
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct BundleManifest {
+    /// The revision of the bundle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+
+    /// The paths of the data document owned by the bundle. If not set, the
+    /// bundle owns the whole document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roots: Option<Vec<String>>,
+
+    /// Arbitrary metadata set when building the bundle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A file contained in a bundle
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[non_exhaustive]
+pub struct BundleFile {
+    /// The path of the file in the bundle
+    pub path: String,
+
+    /// The size of the file, in bytes
+    pub size: u64,
+}
+
+/// The contents of an OPA compiled bundle
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Bundle {
+    /// The manifest, if the bundle has one
+    pub manifest: Option<BundleManifest>,
+
+    /// The files contained in the bundle, in order
+    pub files: Vec<BundleFile>,
+
+    /// The compiled policy
+    pub policy: Vec<u8>,
+}
+
+impl Bundle {
+    /// Read an OPA compiled bundle from disk, with its manifest and the list
+    /// of its files
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be read, or if the bundle is not
+    /// a valid OPA compiled bundle
+    #[tracing::instrument(err)]
+    pub async fn read(path: impl AsRef<Path> + std::fmt::Debug) -> anyhow::Result<Self> {
+        let bundle = crate::rt::read_file(path.as_ref().to_owned()).await?;
+        Self::load(&bundle[..]).await
+    }
+
+    /// Load an OPA compiled bundle, with its manifest and the list of its
+    /// files
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bundle is not a valid gzipped tarball, if its
+    /// manifest is invalid, or if it does not contain a `/policy.wasm` file
+    #[tracing::instrument(skip_all, err)]
+    pub async fn load(reader: impl AsyncBufRead + Unpin + Send + Sync) -> anyhow::Result<Self> {
+        let reader = GzipDecoder::new(reader);
+        let mut archive = Archive::new(reader);
+
+        let mut manifest = None;
+        let mut files = Vec::new();
+        let mut policy = None;
+
+        let mut entries = archive.entries()?;
+        while let Some(mut entry) = entries.try_next().await? {
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let size = entry.header().size()?;
+
+            if path == POLICY_PATH {
+                let mut buf = Vec::new();
+                entry
+                    .read_to_end(&mut buf)
+                    .instrument(info_span!("read_module"))
+                    .await?;
+                policy = Some(buf);
+            } else if path == MANIFEST_PATH {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf).await?;
+                let parsed = serde_json::from_slice(&buf).context("invalid bundle manifest")?;
+                manifest = Some(parsed);
+            }
+
+            files.push(BundleFile { path, size });
+        }
+
+        Ok(Self {
+            manifest,
+            files,
+            policy: policy.context("could not find WASM policy in tar archive")?,
+        })
+    }
+}
+
+/// Downloads an OPA compiled bundle over HTTP, remembering its `ETag` so
+/// that polling for a new revision doesn't download it again
+#[cfg(feature = "http-client")]
+#[derive(Debug, Clone)]
+pub struct BundleFetcher {
+    /// The client used to download the bundle
+    client: reqwest::Client,
+
+    /// The URL of the bundle
+    url: reqwest::Url,
+
+    /// The `ETag` of the last downloaded bundle
+    etag: Option<reqwest::header::HeaderValue>,
+}
+
+#[cfg(feature = "http-client")]
+impl BundleFetcher {
+    /// Create a fetcher for the bundle at the given URL
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL is invalid, or if the HTTP client could not
+    /// be built
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url).with_context(|| format!("invalid bundle URL {url}"))?;
+        let client = reqwest::Client::builder()
+            .build()
+            .context("failed to build the HTTP client")?;
+
+        Ok(Self {
+            client,
+            url,
+            etag: None,
+        })
+    }
+
+    /// The URL of the bundle
+    #[must_use]
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Download the bundle, returning `None` if it did not change since the
+    /// last successful download
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request failed, if the server did not answer
+    /// with a success, or if the bundle is not a valid OPA compiled bundle
+    #[tracing::instrument(skip(self), fields(url = %self.url), err)]
+    pub async fn fetch(&mut self) -> anyhow::Result<Option<Bundle>> {
+        let mut request = self.client.get(self.url.clone());
+        if let Some(etag) = &self.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status()?;
+        let etag = response.headers().get(reqwest::header::ETAG).cloned();
+        let body = response
+            .bytes()
+            .instrument(info_span!("download_bundle"))
+            .await
+            .context("failed to download the bundle")?;
+
+        let bundle = Bundle::load(&body[..]).await?;
+        self.etag = etag;
+        Ok(Some(bundle))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// Build a gzipped bundle with a manifest of the given revision, some
+    /// data and the given policy
+    pub(crate) async fn build_bundle(revision: &str, policy: &[u8]) -> Vec<u8> {
+        let manifest = format!(r#"{{"revision":"{revision}","roots":["example"]}}"#);
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for (path, content) in [
+            ("/.manifest", manifest.as_bytes()),
+            ("/data.json", b"{}"),
+            ("/policy.wasm", policy),
+        ] {
+            // OPA uses absolute paths, which `set_path` refuses
+            let mut header = tokio_tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(content.len().try_into().unwrap());
+            header.set_cksum();
+            builder.append(&header, content).await.unwrap();
+        }
+        let tarball = builder.into_inner().await.unwrap();
+
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(&tarball).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    #[tokio::test]
+    async fn bundle_contents() {
+        let bundle = build_bundle("abc", b"\0asm").await;
+        let bundle = Bundle::load(&bundle[..]).await.unwrap();
+        assert_eq!(bundle.policy, b"\0asm");
+        let manifest = bundle.manifest.unwrap();
+        assert_eq!(manifest.revision.as_deref(), Some("abc"));
+        assert_eq!(manifest.roots, Some(vec!["example".to_owned()]));
+        let paths: Vec<_> = bundle.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["/.manifest", "/data.json", "/policy.wasm"]);
+        assert_eq!(bundle.files[1].size, 2);
+    }
+
+    #[cfg(feature = "http-client")]
+    #[tokio::test]
+    async fn fetcher_skips_unchanged_bundles() {
+        use tokio::{io::AsyncReadExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/bundle.tar.gz", listener.local_addr().unwrap());
+        let bundle = build_bundle("abc", b"\0asm").await;
+
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+
+                if request.contains("if-none-match: \"v1\"") {
+                    stream
+                        .write_all(b"HTTP/1.1 304 Not Modified\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                } else {
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: {}\r\n\r\n",
+                        bundle.len()
+                    );
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    stream.write_all(&bundle).await.unwrap();
+                }
+                requests.push(request);
+            }
+            requests
+        });
+
+        let mut fetcher = BundleFetcher::new(&url).unwrap();
+        let downloaded = fetcher.fetch().await.unwrap().unwrap();
+        assert_eq!(downloaded.policy, b"\0asm");
+
+        // The second request sends the ETag, and the server answers it did
+        // not change
+        assert!(fetcher.fetch().await.unwrap().is_none());
+
+        let requests = server.await.unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match"));
+    }
+}
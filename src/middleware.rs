@@ -0,0 +1,316 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An axum middleware authorizing requests with a policy, and an extractor
+//! exposing its decision to the handlers
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tower_service::Service;
+
+use crate::{EvaluationContext, HttpInput, Identity, PolicyDecision, PolicyPool, PolicyService};
+
+impl HttpInput {
+    /// Build the input from the parts of a request
+    #[must_use]
+    pub fn from_parts(parts: &Parts) -> Self {
+        let headers = parts
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes()));
+
+        Self::from_request(
+            parts.method.as_str(),
+            parts.uri.path(),
+            parts.uri.query(),
+            headers,
+            parts.extensions.get::<Identity>(),
+        )
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for PolicyDecision {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "the route is not behind an AuthorizationLayer",
+        ))
+    }
+}
+
+/// A [`tower_layer::Layer`] authorizing requests by evaluating an entrypoint
+/// with the request as [input](HttpInput).
+///
+/// Denied requests get a `403 Forbidden` response, and requests failing to
+/// evaluate a `500 Internal Server Error` one. Allowed requests are passed to
+/// the inner service, with the [`PolicyDecision`] in their extensions.
+pub struct AuthorizationLayer<C, T = ()> {
+    /// The service evaluating the policy
+    service: PolicyService<HttpInput, serde_json::Value, C, T>,
+}
+
+impl<C, T> Clone for AuthorizationLayer<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<C, T> std::fmt::Debug for AuthorizationLayer<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthorizationLayer")
+            .field("service", &self.service)
+            .finish()
+    }
+}
+
+impl<C, T> AuthorizationLayer<C, T> {
+    /// Authorize requests by evaluating the given entrypoint on the instances
+    /// of the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>, entrypoint: impl Into<Arc<str>>) -> Self {
+        Self {
+            service: PolicyService::new(pool, entrypoint),
+        }
+    }
+}
+
+impl<S, C, T> tower_layer::Layer<S> for AuthorizationLayer<C, T> {
+    type Service = Authorization<S, C, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authorization {
+            inner,
+            service: self.service.clone(),
+        }
+    }
+}
+
+/// The service created by the [`AuthorizationLayer`]
+pub struct Authorization<S, C, T = ()> {
+    /// The service handling allowed requests
+    inner: S,
+
+    /// The service evaluating the policy
+    service: PolicyService<HttpInput, serde_json::Value, C, T>,
+}
+
+impl<S: Clone, C, T> Clone for Authorization<S, C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S, C, T> std::fmt::Debug for Authorization<S, C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authorization")
+            .field("service", &self.service)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, C, T> Service<Request> for Authorization<S, C, T>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Take the service which was polled ready, leaving a clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let mut service = self.service.clone();
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let input = HttpInput::from_parts(&parts);
+
+            let decision = match service.call(input).await {
+                Ok(decision) => {
+                    PolicyDecision::from_result_set(decision.entrypoint, &decision.result)
+                }
+                Err(error) => {
+                    tracing::error!(
+                        entrypoint = service.entrypoint(),
+                        "could not evaluate the policy: {error:#}"
+                    );
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            };
+
+            if !decision.is_allowed() {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+
+            parts.extensions.insert(decision);
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use axum::{body::Body, http::Method, routing::any, Json, Router};
+    use tower::ServiceExt;
+    use wasmtime::Store;
+
+    use super::*;
+    use crate::{stub::stub_module, DefaultContext, EngineConfig};
+
+    /// Send a request with the given method to a handler returning the
+    /// decision, behind a policy allowing `GET` requests, denying the others
+    /// and failing to evaluate for `DELETE` ones
+    async fn send(method: Method) -> (StatusCode, Vec<u8>) {
+        let engine = EngineConfig::new().build().unwrap();
+        // The input starts with `{"method":"`, so the method starts at its
+        // 11th byte
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"allow":0}"#,
+            r#"
+              (data (i32.const 1024) "[{\"result\":{\"allow\":true,\"user\":\"alice\"}}]\00")
+              (data (i32.const 1100) "[{\"result\":false}]\00")
+            "#,
+            "global.get $input
+             i32.load8_u offset=11
+             i32.const 68
+             i32.eq
+             if
+               unreachable
+             end
+             i32.const 1100
+             global.set $result
+             global.get $input
+             i32.load8_u offset=11
+             i32.const 71
+             i32.eq
+             if
+               i32.const 1024
+               global.set $result
+             end",
+        );
+        let pool = PolicyPool::instantiate_with_stores(
+            &module,
+            &(),
+            NonZeroUsize::MIN,
+            || Store::new(&engine, ()),
+            DefaultContext::default,
+        )
+        .await
+        .unwrap();
+
+        let router = Router::new()
+            .route(
+                "/",
+                any(|decision: PolicyDecision| async move { Json(decision.result) }),
+            )
+            .layer(AuthorizationLayer::new(Arc::new(pool), "allow"));
+        let request = Request::builder()
+            .method(method)
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn allowed_requests_get_the_decision() {
+        let (status, body) = send(Method::GET).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "allow": true, "user": "alice" })
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_requests_are_forbidden() {
+        let (status, body) = send(Method::POST).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn evaluation_errors_are_internal_errors() {
+        let (status, body) = send(Method::DELETE).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn requests_are_mapped_to_inputs() {
+        let (mut parts, ()) = Request::builder()
+            .method(Method::POST)
+            .uri("https://example.com/v1/users/?limit=10")
+            .header("Accept", "application/json")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("x-forwarded-for", "10.0.0.2")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+            .extensions
+            .insert(Identity(serde_json::json!({ "sub": "alice" })));
+
+        let input = serde_json::to_value(HttpInput::from_parts(&parts)).unwrap();
+        assert_eq!(
+            input,
+            serde_json::json!({
+                "method": "POST",
+                "path": ["v1", "users"],
+                "query": "limit=10",
+                "headers": {
+                    "accept": "application/json",
+                    "x-forwarded-for": "10.0.0.1, 10.0.0.2",
+                },
+                "identity": { "sub": "alice" },
+            })
+        );
+    }
+}
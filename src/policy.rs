@@ -16,71 +16,131 @@
 //! structures.
 
 use std::{
-    collections::{HashMap, HashSet},
-    ffi::CString,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     ops::Deref,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Instant,
 };
 
 use anyhow::{Context, Result};
 use tokio::sync::{Mutex, OnceCell};
 use tracing::Instrument;
-use wasmtime::{AsContextMut, Caller, Linker, Memory, MemoryType, Module};
+use wasmtime::{
+    AsContext, AsContextMut, Caller, Instance, Linker, Memory, MemoryType, Module, StoreContextMut,
+};
 
 use crate::{
-    builtins::traits::Builtin,
+    builtins::{traits::Builtin, BuiltinRegistry, HaltError},
+    entrypoint::{Entrypoint, EvalResult, PolicyInput},
+    explain::{EvaluationReport, ExplainedCall, Explanation, ExplanationEvent},
+    failure::{Aborted, EvaluationFailure},
     funcs::{self, Func},
     types::{AbiVersion, Addr, BuiltinId, EntrypointId, Heap, NulStr, Value},
-    DefaultContext, EvaluationContext,
+    DefaultContext, EvaluationContext, EvaluationId, EvaluationMetadata, EvaluationOutcome,
+    MemoryUsage,
 };
 
-/// Utility to allocate a string in the Wasm memory and return a pointer to it.
-async fn alloc_str<V: Into<Vec<u8>>, T: Send>(
+/// The initial size of the memory given to policy modules, in pages
+pub(crate) const MEMORY_PAGES: u32 = 2;
+
+/// Utility to load a serialized JSON value into the WASM memory. The nul
+/// terminator is appended to the buffer.
+async fn load_json_bytes<T: Send>(
     opa_malloc: &funcs::OpaMalloc,
+    opa_free: &funcs::OpaFree,
+    opa_json_parse: &funcs::OpaJsonParse,
     mut store: impl AsContextMut<Data = T>,
     memory: &Memory,
-    value: V,
-) -> Result<Heap> {
-    let value = CString::new(value)?;
-    let value = value.as_bytes_with_nul();
-    let heap = opa_malloc.call(&mut store, value.len()).await?;
+    json: &mut Vec<u8>,
+) -> Result<Value> {
+    // Serialized JSON never contains a nul byte, so this is a valid C string
+    // without having to check it again
+    json.push(0);
 
+    let heap = opa_malloc.call(&mut store, json.len()).await?;
     memory.write(
         &mut store,
         heap.ptr
             .try_into()
             .context("opa_malloc returned an invalid pointer value")?,
-        value,
+        json,
     )?;
 
-    Ok(heap)
+    let data = opa_json_parse.call(&mut store, &heap).await?;
+    opa_free.call(&mut store, heap).await?;
+    Ok(data)
 }
 
-/// Utility to load a JSON value into the WASM memory.
-async fn load_json<V: serde::Serialize, T: Send>(
-    opa_malloc: &funcs::OpaMalloc,
-    opa_free: &funcs::OpaFree,
-    opa_json_parse: &funcs::OpaJsonParse,
-    mut store: impl AsContextMut<Data = T>,
-    memory: &Memory,
-    data: &V,
-) -> Result<Value> {
-    let json = serde_json::to_vec(data)?;
-    let json = alloc_str(opa_malloc, &mut store, memory, json).await?;
-    let data = opa_json_parse.call(&mut store, &json).await?;
-    opa_free.call(&mut store, json).await?;
-    Ok(data)
+/// The exports used when calling a builtin, resolved once when the module is
+/// instantiated instead of on each call
+struct BuiltinFuncs {
+    /// The `opa_json_dump` export, to serialize the arguments
+    json_dump: funcs::OpaJsonDump,
+
+    /// The `opa_json_parse` export, to deserialize the result
+    json_parse: funcs::OpaJsonParse,
+
+    /// The `opa_malloc` export, to allocate the serialized result
+    malloc: funcs::OpaMalloc,
+
+    /// The `opa_free` export, to free the serialized result
+    free: funcs::OpaFree,
 }
 
+impl BuiltinFuncs {
+    /// Resolve the exports from the instance
+    fn from_instance<T>(
+        mut store: impl AsContextMut<Data = T>,
+        instance: &Instance,
+    ) -> Result<Self> {
+        Ok(Self {
+            json_dump: funcs::OpaJsonDump::from_instance(&mut store, instance)?,
+            json_parse: funcs::OpaJsonParse::from_instance(&mut store, instance)?,
+            malloc: funcs::OpaMalloc::from_instance(&mut store, instance)?,
+            free: funcs::OpaFree::from_instance(&mut store, instance)?,
+        })
+    }
+}
+
+/// Result buffers which grew larger than this are not kept for the next
+/// builtin calls, so that a single large result doesn't hold memory forever
+const MAX_RESULT_BUFFER_CAPACITY: usize = 1 << 20;
+
+/// A builtin resolved from the registry the first time it is called
+type LazyBuiltin<C> = OnceLock<Arc<dyn Builtin<C>>>;
+
 /// A structure which holds the builtins referenced by the policy.
 struct LoadedBuiltins<C> {
-    /// A map of builtin IDs to the name and the builtin itself.
-    builtins: HashMap<i32, (String, Box<dyn Builtin<C>>)>,
+    /// A map of builtin IDs to the name and the builtin itself. Builtins are
+    /// only resolved the first time they are called, so that loading a
+    /// policy doesn't pay for the builtins it never calls.
+    builtins: HashMap<i32, (String, LazyBuiltin<C>)>,
+
+    /// The registry the builtins are resolved from
+    registry: BuiltinRegistry<C>,
+
+    /// The exports used to pass the arguments and the result of the builtins
+    funcs: BuiltinFuncs,
 
     /// The inner [`EvaluationContext`] which will be passed when calling
     /// some builtins
     context: Mutex<C>,
+
+    /// A buffer reused across builtin calls to serialize their result, reset
+    /// after each call, so that policies calling builtins many times don't
+    /// allocate for each of them
+    result_buffer: std::sync::Mutex<Vec<u8>>,
+
+    /// The events of the evaluation being explained, if any
+    explanation: std::sync::Mutex<Option<Vec<ExplanationEvent>>>,
+
+    /// Whether builtin errors fail the current evaluation, instead of making
+    /// the expression calling the builtin undefined
+    strict_errors: AtomicBool,
 }
 
 impl<C> std::fmt::Debug for LoadedBuiltins<C> {
@@ -95,21 +155,67 @@ impl<C> LoadedBuiltins<C>
 where
     C: EvaluationContext,
 {
-    /// Resolve the builtins from a map of builtin IDs to their names.
-    fn from_map(map: HashMap<String, BuiltinId>, context: C) -> Result<Self> {
-        let res: Result<_> = map
+    /// Check the builtins from a map of builtin IDs to their names against
+    /// the given registry. If `strict` is false, the builtins which can't be
+    /// resolved are recorded instead of failing.
+    fn from_map(
+        map: HashMap<String, BuiltinId>,
+        registry: &BuiltinRegistry<C>,
+        funcs: BuiltinFuncs,
+        context: C,
+        strict: bool,
+    ) -> Result<Self> {
+        // Only strict loads check the builtins upfront, to fail early on
+        // unsupported ones. Otherwise, they are only checked when calling them
+        // or when asking for the module info.
+        if strict {
+            for name in map.keys() {
+                registry
+                    .check(name)
+                    .with_context(|| format!("could not resolve {name}"))?;
+            }
+        }
+
+        let builtins = map
             .into_iter()
-            .map(|(k, v)| {
-                let builtin = crate::builtins::resolve(&k)?;
-                Ok((v.0, (k, builtin)))
-            })
+            .map(|(name, id)| (id.0, (name, OnceLock::new())))
             .collect();
+
         Ok(Self {
-            builtins: res?,
+            builtins,
+            registry: registry.clone(),
+            funcs,
             context: Mutex::new(context),
+            result_buffer: std::sync::Mutex::default(),
+            explanation: std::sync::Mutex::default(),
+            strict_errors: AtomicBool::new(false),
         })
     }
 
+    /// Take the result buffer, leaving an empty one in its place. The lock is
+    /// only held while swapping, never across await points.
+    fn take_result_buffer(&self) -> Vec<u8> {
+        let mut buffer = self
+            .result_buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        std::mem::take(&mut *buffer)
+    }
+
+    /// Reset the result buffer and give it back, for the next call to reuse it
+    fn return_result_buffer(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > MAX_RESULT_BUFFER_CAPACITY {
+            return;
+        }
+
+        buffer.clear();
+        let mut slot = self
+            .result_buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *slot = buffer;
+    }
+
     /// Call the given builtin given its ID and arguments.
     async fn builtin<T: Send, const N: usize>(
         &self,
@@ -122,56 +228,187 @@ where
             .builtins
             .get(&builtin_id)
             .with_context(|| format!("unknown builtin id {builtin_id}"))?;
-
-        let span = tracing::info_span!("builtin", %name);
+        let builtin = if let Some(builtin) = builtin.get() {
+            builtin
+        } else {
+            let resolved = self
+                .registry
+                .resolve(name)
+                .with_context(|| format!("could not resolve {name}"))?;
+            builtin.get_or_init(|| resolved)
+        };
+
+        // Spans around each builtin call are costly for policies calling many
+        // of them, so they are only created with the `detailed-tracing` feature.
+        // The entrypoint, revision and decision ID are on the parent
+        // `opa.evaluate` span.
+        let span = if cfg!(feature = "detailed-tracing") {
+            tracing::info_span!("builtin", %name, opa.builtin.id = builtin_id, opa.builtin.args = N)
+        } else {
+            tracing::Span::none()
+        };
         let _enter = span.enter();
 
-        let opa_json_dump = funcs::OpaJsonDump::from_caller(&mut caller)?;
-        let opa_json_parse = funcs::OpaJsonParse::from_caller(&mut caller)?;
-        let opa_malloc = funcs::OpaMalloc::from_caller(&mut caller)?;
-        let opa_free = funcs::OpaFree::from_caller(&mut caller)?;
-
-        // Call opa_json_dump on each argument
-        let mut args_json = Vec::with_capacity(N);
-        for arg in args {
-            args_json.push(opa_json_dump.call(&mut caller, &Value(arg)).await?);
+        let opa_json_dump = &self.funcs.json_dump;
+
+        // Call opa_json_dump on each argument. The number of arguments is known
+        // at compile time, so this doesn't need to allocate.
+        //
+        // The ABI doesn't offer a cheaper way to read them: `opa_value_dump`
+        // produces Rego, not JSON, and the in-memory representation of values
+        // is internal to the OPA runtime, so it can't be walked safely.
+        let mut args_json = [0; N];
+        for (arg, json) in args.into_iter().zip(&mut args_json) {
+            *json = opa_json_dump.call(&mut caller, &Value(arg)).await?.0;
         }
 
-        // Extract the JSON value of each argument
-        let mut mapped_args = Vec::with_capacity(N);
-        for arg_json in args_json {
-            let arg = arg_json.read(&caller, memory)?;
-            mapped_args.push(arg.to_bytes());
+        // Borrow the JSON value of each argument straight from the WASM memory,
+        // instead of copying them. The builtin itself can't touch the memory.
+        let mut mapped_args: [&[u8]; N] = [&[]; N];
+        for (json, arg) in args_json.into_iter().zip(&mut mapped_args) {
+            *arg = NulStr(json).read(&caller, memory)?.to_bytes();
         }
 
         let mut ctx = self.context.lock().await;
+        let mut buffer = self.take_result_buffer();
+        let notes = ctx.notes().len();
+        let strict = self.strict_errors.load(Ordering::Relaxed);
 
         // Actually call the function
-        let ret = (async move { builtin.call(&mut ctx, &mapped_args).await })
-            .instrument(tracing::info_span!("builtin.call"))
-            .await?;
+        let start = Instant::now();
+        let ret = builtin
+            .call_into(&mut ctx, &mapped_args, &mut buffer)
+            .instrument(if cfg!(feature = "detailed-tracing") {
+                tracing::info_span!("builtin.call")
+            } else {
+                tracing::Span::none()
+            })
+            .await;
+        let duration = start.elapsed();
+        ctx.record_builtin_duration(name, duration);
+        ctx.record_builtin_call(name, &mapped_args, ret.as_ref().map(|()| &buffer[..]));
+        self.explain(|| {
+            let call = ExplainedCall::new(
+                name,
+                &mapped_args,
+                ret.as_ref().map(|()| &buffer[..]),
+                duration,
+            );
+            let notes = ctx.notes().get(notes..).unwrap_or_default();
+            std::iter::once(ExplanationEvent::BuiltinCall(call))
+                .chain(notes.iter().cloned().map(ExplanationEvent::Note))
+                .collect()
+        });
+        // A builtin running out of time or halting still fails the evaluation
+        let timed_out = ctx
+            .remaining_budget()
+            .is_some_and(|budget| budget.is_zero());
+        drop(ctx);
+
+        let data = match ret {
+            Ok(()) => Self::load_result(&self.funcs, &mut caller, memory, &mut buffer).await,
+            Err(error) if strict || timed_out || HaltError::is_halt(&error) => Err(error),
+            Err(error) => {
+                // Like OPA without `--strict-builtin-errors`, the expression
+                // calling the builtin is undefined, which the module knows
+                // from the null address
+                tracing::debug!(builtin = %name, "builtin failed, the expression is undefined: {error:#}");
+                self.return_result_buffer(buffer);
+                return Ok(0);
+            }
+        };
+        self.return_result_buffer(buffer);
 
-        let json = alloc_str(&opa_malloc, &mut caller, memory, ret).await?;
-        let data = opa_json_parse.call(&mut caller, &json).await?;
-        opa_free.call(&mut caller, json).await?;
+        Ok(data?.0)
+    }
 
-        Ok(data.0)
+    /// Load the serialized result of a builtin in the WASM memory
+    async fn load_result<T: Send>(
+        funcs: &BuiltinFuncs,
+        caller: &mut Caller<'_, T>,
+        memory: &Memory,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Value> {
+        load_json_bytes(
+            &funcs.malloc,
+            &funcs.free,
+            &funcs.json_parse,
+            caller,
+            memory,
+            buffer,
+        )
+        .await
     }
 
     /// Called when the policy evaluation starts, to reset the context and
     /// record the evaluation starting time
-    async fn evaluation_start(&self) {
-        self.context.lock().await.evaluation_start();
+    async fn evaluation_start(&self, metadata: &EvaluationMetadata<'_>) {
+        self.context
+            .lock()
+            .await
+            .evaluation_start_with_metadata(metadata);
+    }
+
+    /// Forward a message printed by the policy to the context
+    async fn print(&self, message: &str) {
+        self.explain(|| vec![ExplanationEvent::Print(message.to_owned())]);
+        self.context.lock().await.print(message);
+    }
+
+    /// Forward an abort message emitted by the policy to the context
+    async fn abort(&self, message: &str) {
+        self.explain(|| vec![ExplanationEvent::Abort(message.to_owned())]);
+        self.context.lock().await.abort(message);
+    }
+
+    /// Record the events of the evaluation being explained, only building
+    /// them if an evaluation is being explained
+    fn explain(&self, events: impl FnOnce() -> Vec<ExplanationEvent>) {
+        let mut explanation = self
+            .explanation
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(explanation) = &mut *explanation {
+            explanation.extend(events());
+        }
+    }
+
+    /// Start or stop collecting the events of the evaluations, returning the
+    /// ones collected so far
+    fn set_explaining(&self, explaining: bool) -> Vec<ExplanationEvent> {
+        let mut explanation = self
+            .explanation
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let events = explanation.take().unwrap_or_default();
+        if explaining {
+            *explanation = Some(Vec::new());
+        }
+        events
+    }
+
+    /// Called when the policy evaluation ends, to record how long it took and
+    /// notify the context of the outcome
+    async fn evaluation_done(&self, outcome: &EvaluationOutcome<'_>) {
+        let mut context = self.context.lock().await;
+        context.record_evaluation_duration(outcome.metadata.entrypoint, outcome.duration);
+        context.evaluation_end(outcome);
     }
 }
 
 /// An instance of a policy with builtins and entrypoints resolved, but with no
 /// data provided yet
 #[allow(clippy::missing_docs_in_private_items)]
+#[allow(clippy::struct_excessive_bools)] // They are independent options
 pub struct Runtime<C> {
     version: AbiVersion,
     memory: Memory,
     entrypoints: HashMap<String, EntrypointId>,
+    revision: Option<String>,
+    fast_path: bool,
+    memory_snapshot: bool,
+    input_hash: bool,
+    strict_builtin_errors: bool,
     loaded_builtins: Arc<OnceCell<LoadedBuiltins<C>>>,
 
     eval_func: funcs::Eval,
@@ -186,6 +423,7 @@ pub struct Runtime<C> {
     opa_json_dump_func: funcs::OpaJsonDump,
     opa_heap_ptr_set_func: funcs::OpaHeapPtrSet,
     opa_heap_ptr_get_func: funcs::OpaHeapPtrGet,
+    opa_value_dump_func: Option<funcs::OpaValueDump>,
     opa_eval_func: Option<funcs::OpaEval>,
 }
 
@@ -195,6 +433,11 @@ impl<C> Debug for Runtime<C> {
             .field("version", &self.version)
             .field("memory", &self.memory)
             .field("entrypoints", &self.entrypoints)
+            .field("revision", &self.revision)
+            .field("fast_path", &self.fast_path)
+            .field("memory_snapshot", &self.memory_snapshot)
+            .field("input_hash", &self.input_hash)
+            .field("strict_builtin_errors", &self.strict_builtin_errors)
             .finish_non_exhaustive()
     }
 }
@@ -218,6 +461,55 @@ impl Runtime<DefaultContext> {
         let context = DefaultContext::default();
         Self::new_with_evaluation_context(store, module, context).await
     }
+
+    /// Load a WASM policy module to inspect it, without failing if it
+    /// requires builtins which are not supported by this build. Those are
+    /// reported by [`Runtime::module_info`], and evaluations calling them
+    /// fail. Unlike [`Runtime::new`], this doesn't check every builtin
+    /// against this build while loading.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Runtime::new`], except for unsupported builtins
+    pub async fn inspect<T: Send>(
+        store: impl AsContextMut<Data = T>,
+        module: &Module,
+    ) -> Result<Self> {
+        let registry = BuiltinRegistry::new();
+        Self::load(
+            store,
+            module,
+            DefaultContext::default(),
+            &registry,
+            false,
+            None,
+        )
+        .await
+    }
+}
+
+/// Defines extra imports in the linker of a module being loaded, with access
+/// to its builtins once they are loaded
+type LinkHook<T, C> = dyn Fn(&mut Linker<T>, StoreContextMut<'_, T>, &Arc<OnceCell<LoadedBuiltins<C>>>) -> Result<()>
+    + Send
+    + Sync;
+
+/// Information about a loaded policy module, as returned by
+/// [`Runtime::module_info`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ModuleInfo {
+    /// The ABI version of the module
+    pub abi_version: AbiVersion,
+
+    /// The entrypoints of the module, with their IDs
+    pub entrypoints: BTreeMap<String, i32>,
+
+    /// The builtins required by the module, with their IDs
+    pub builtins: BTreeMap<String, i32>,
+
+    /// The required builtins which are not supported by this build
+    pub unsupported_builtins: BTreeSet<String>,
 }
 
 impl<C> Runtime<C> {
@@ -234,16 +526,100 @@ impl<C> Runtime<C> {
     ///  - the WASM module is not a valid OPA WASM compiled policy, and lacks
     ///    some of the exported functions
     ///  - it failed to load the entrypoints or the builtins list
-    #[allow(clippy::too_many_lines)]
     pub async fn new_with_evaluation_context<T: Send>(
+        store: impl AsContextMut<Data = T>,
+        module: &Module,
+        context: C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        Self::load(store, module, context, &BuiltinRegistry::new(), true, None).await
+    }
+
+    /// Load a new WASM policy module into the given store, with a given
+    /// evaluation context, resolving the builtins it requires from the given
+    /// registry instead of the builtins of this build.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Runtime::new_with_evaluation_context`], which includes the
+    /// policy requiring builtins which are not in the registry or are denied
+    pub async fn new_with_builtins<T: Send>(
+        store: impl AsContextMut<Data = T>,
+        module: &Module,
+        context: C,
+        registry: &BuiltinRegistry<C>,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        Self::load(store, module, context, registry, true, None).await
+    }
+
+    /// Load a new WASM policy module which imports WASI functions into the
+    /// given store, with a given evaluation context.
+    ///
+    /// The WASI clock and random number generator are the ones of the
+    /// evaluation context, so that they are as deterministic as the `time.*`
+    /// and `rand.*` builtins. The WASI context of the store is replaced by one
+    /// without access to the filesystem, the environment, the network or the
+    /// standard streams.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Runtime::new`]
+    #[cfg(feature = "wasi")]
+    pub async fn new_with_wasi<T: crate::WasiState>(
+        store: impl AsContextMut<Data = T>,
+        module: &Module,
+        context: C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+        C::Rng: Send,
+    {
+        let link: &LinkHook<T, C> = &|linker, store, eventually_builtins| {
+            let clock = eventually_builtins.clone();
+            let rng = eventually_builtins.clone();
+            crate::wasi::link(
+                linker,
+                store,
+                move || {
+                    let now = clock.get()?.context.try_lock().ok()?.now();
+                    let nanos = u64::try_from(now.timestamp_nanos_opt()?).ok()?;
+                    Some(std::time::Duration::from_nanos(nanos))
+                },
+                move || Some(rng.get()?.context.try_lock().ok()?.get_rng()),
+            )
+        };
+
+        Self::load(
+            store,
+            module,
+            context,
+            &BuiltinRegistry::new(),
+            true,
+            Some(link),
+        )
+        .await
+    }
+
+    /// Load the module, failing on unsupported builtins only if `strict` is
+    /// true, and defining extra imports with `link` if set
+    #[allow(clippy::too_many_lines)]
+    async fn load<T: Send>(
         mut store: impl AsContextMut<Data = T>,
         module: &Module,
         context: C,
+        registry: &BuiltinRegistry<C>,
+        strict: bool,
+        link: Option<&LinkHook<T, C>>,
     ) -> Result<Self>
     where
         C: EvaluationContext,
     {
-        let ty = MemoryType::new(2, None);
+        let ty = MemoryType::new(MEMORY_PAGES, None);
         let memory = Memory::new_async(&mut store, ty).await?;
 
         // TODO: make the context configurable and reset it on evaluation
@@ -252,28 +628,49 @@ impl<C> Runtime<C> {
         let mut linker = Linker::new(store.as_context_mut().engine());
         linker.define(&store, "env", "memory", memory)?;
 
-        linker.func_wrap(
-            "env",
-            "opa_abort",
-            move |caller: Caller<'_, _>, addr: i32| -> Result<(), anyhow::Error> {
-                let addr = NulStr(addr);
-                let msg = addr.read(&caller, &memory)?;
-                let msg = msg.to_string_lossy().into_owned();
-                tracing::error!("opa_abort: {}", msg);
-                anyhow::bail!(msg)
-            },
-        )?;
+        {
+            let eventually_builtins = eventually_builtins.clone();
+            linker.func_wrap_async(
+                "env",
+                "opa_abort",
+                move |caller: Caller<'_, _>, (addr,): (i32,)| {
+                    let eventually_builtins = eventually_builtins.clone();
+                    Box::new(async move {
+                        let addr = NulStr(addr);
+                        let msg = addr.read(&caller, &memory)?;
+                        let msg = msg.to_string_lossy().into_owned();
+                        if let Some(builtins) = eventually_builtins.get() {
+                            builtins.abort(&msg).await;
+                        } else {
+                            tracing::error!("opa_abort: {}", msg);
+                        }
+                        Err::<(), _>(anyhow::Error::new(Aborted(msg)))
+                    })
+                },
+            )?;
+        }
 
-        linker.func_wrap(
-            "env",
-            "opa_println",
-            move |caller: Caller<'_, _>, addr: i32| {
-                let addr = NulStr(addr);
-                let msg = addr.read(&caller, &memory)?;
-                tracing::info!("opa_print: {}", msg.to_string_lossy());
-                Ok(())
-            },
-        )?;
+        {
+            let eventually_builtins = eventually_builtins.clone();
+            linker.func_wrap_async(
+                "env",
+                "opa_println",
+                move |caller: Caller<'_, _>, (addr,): (i32,)| {
+                    let eventually_builtins = eventually_builtins.clone();
+                    Box::new(async move {
+                        let addr = NulStr(addr);
+                        let msg = addr.read(&caller, &memory)?;
+                        let msg = msg.to_string_lossy().into_owned();
+                        if let Some(builtins) = eventually_builtins.get() {
+                            builtins.print(&msg).await;
+                        } else {
+                            tracing::info!("opa_print: {}", msg);
+                        }
+                        Ok(())
+                    })
+                },
+            )?;
+        }
 
         {
             let eventually_builtins = eventually_builtins.clone();
@@ -384,6 +781,10 @@ impl<C> Runtime<C> {
             )?;
         }
 
+        if let Some(link) = link {
+            link(&mut linker, store.as_context_mut(), &eventually_builtins)?;
+        }
+
         let instance = linker.instantiate_async(&mut store, module).await?;
 
         let version = AbiVersion::from_instance(&mut store, &instance)?;
@@ -398,7 +799,8 @@ impl<C> Runtime<C> {
         let builtins = opa_json_dump_func
             .decode(&mut store, &memory, &builtins)
             .await?;
-        let builtins = LoadedBuiltins::from_map(builtins, context)?;
+        let funcs = BuiltinFuncs::from_instance(&mut store, &instance)?;
+        let builtins = LoadedBuiltins::from_map(builtins, registry, funcs, context, strict)?;
         eventually_builtins.set(builtins)?;
 
         // Load the entrypoints map
@@ -418,6 +820,11 @@ impl<C> Runtime<C> {
             version,
             memory,
             entrypoints,
+            revision: None,
+            fast_path: true,
+            memory_snapshot: false,
+            input_hash: false,
+            strict_builtin_errors: false,
             loaded_builtins: eventually_builtins,
 
             eval_func: funcs::Eval::from_instance(&mut store, &instance)?,
@@ -440,6 +847,8 @@ impl<C> Runtime<C> {
             opa_json_dump_func,
             opa_heap_ptr_set_func: funcs::OpaHeapPtrSet::from_instance(&mut store, &instance)?,
             opa_heap_ptr_get_func: funcs::OpaHeapPtrGet::from_instance(&mut store, &instance)?,
+            // Only used to debug policies with `Policy::dump`
+            opa_value_dump_func: funcs::OpaValueDump::from_instance(&mut store, &instance).ok(),
             opa_eval_func,
         })
     }
@@ -450,13 +859,36 @@ impl<C> Runtime<C> {
         store: impl AsContextMut<Data = T>,
         data: &V,
     ) -> Result<Value> {
-        load_json(
+        let mut json = serde_json::to_vec(data)?;
+        self.load_json_bytes(store, &mut json).await
+    }
+
+    /// Load a JSON value into the WASM memory, serializing it in the given
+    /// buffer instead of allocating a new one
+    async fn load_json_with_buffer<V: serde::Serialize, T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        data: &V,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Value> {
+        buffer.clear();
+        serde_json::to_writer(&mut *buffer, data)?;
+        self.load_json_bytes(store, buffer).await
+    }
+
+    /// Load a serialized JSON value into the WASM memory
+    async fn load_json_bytes<T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        json: &mut Vec<u8>,
+    ) -> Result<Value> {
+        load_json_bytes(
             &self.opa_malloc_func,
             &self.opa_free_func,
             &self.opa_json_parse_func,
             store,
             &self.memory,
-            data,
+            json,
         )
         .await
     }
@@ -484,15 +916,147 @@ impl<C> Runtime<C> {
         mut store: impl AsContextMut<Data = T>,
         data: &V,
     ) -> Result<Policy<C>> {
+        let initial_heap_ptr = self.opa_heap_ptr_get_func.call(&mut store).await?;
         let data = self.load_json(&mut store, data).await?;
         let heap_ptr = self.opa_heap_ptr_get_func.call(&mut store).await?;
+        self.into_policy(&store, initial_heap_ptr, data, heap_ptr)
+    }
+
+    /// Instanciate the policy with the data loaded in another instance of the
+    /// same module, by copying its memory instead of serializing and parsing
+    /// the data again. This makes creating many instances with the same data,
+    /// like in a pool, cheaper.
+    ///
+    /// # Errors
+    ///
+    /// If the image was not taken from an instance of the same module, or if
+    /// the memory could not be grown to fit it
+    pub async fn with_data_image<T: Send>(
+        self,
+        mut store: impl AsContextMut<Data = T>,
+        image: &DataImage,
+    ) -> Result<Policy<C>> {
+        // The heap pointer right after instantiating only depends on the
+        // module, so it is a cheap way to catch images from other modules
+        let initial_heap_ptr = self.opa_heap_ptr_get_func.call(&mut store).await?;
+        if initial_heap_ptr.0 != image.initial_heap_ptr
+            || self.entrypoint_ids() != image.entrypoints
+        {
+            anyhow::bail!("the data image was taken from an instance of a different module");
+        }
+
+        // The memory has to be at least as large as the original one, as the
+        // allocator keeps track of its size
+        let current_pages = self.memory.size(&store);
+        if current_pages < image.pages {
+            self.memory
+                .grow_async(&mut store, image.pages - current_pages)
+                .await?;
+        }
+        self.memory.write(&mut store, 0, &image.memory)?;
+
+        let heap_ptr = Addr(image.heap_ptr);
+        self.opa_heap_ptr_set_func
+            .call(&mut store, &heap_ptr)
+            .await?;
+
+        self.into_policy(&store, initial_heap_ptr, Value(image.data), heap_ptr)
+    }
+
+    /// Build the policy once its data is loaded, taking the memory snapshot
+    /// if enabled
+    fn into_policy<T>(
+        self,
+        store: &impl AsContext<Data = T>,
+        initial_heap_ptr: Addr,
+        data: Value,
+        heap_ptr: Addr,
+    ) -> Result<Policy<C>> {
+        let snapshot = if self.memory_snapshot {
+            Some(MemorySnapshot(
+                self.read_memory(&store, &heap_ptr)?.to_vec(),
+            ))
+        } else {
+            None
+        };
+
         Ok(Policy {
             runtime: self,
             data,
+            initial_heap_ptr,
             heap_ptr,
+            input_buffer: std::sync::Mutex::default(),
+            snapshot,
         })
     }
 
+    /// Borrow the memory up to the given pointer
+    fn read_memory<'a, T: 'a>(
+        &self,
+        store: &'a impl AsContext<Data = T>,
+        end: &Addr,
+    ) -> Result<&'a [u8]> {
+        let len: usize = end.0.try_into().context("invalid heap pointer")?;
+        self.memory
+            .data(store)
+            .get(..len)
+            .context("heap pointer out of bounds")
+    }
+
+    /// Get the entrypoints and their IDs, sorted by name
+    fn entrypoint_ids(&self) -> Vec<(String, i32)> {
+        let mut entrypoints: Vec<_> = self
+            .entrypoints
+            .iter()
+            .map(|(name, id)| (name.clone(), id.0))
+            .collect();
+        entrypoints.sort_unstable();
+        entrypoints
+    }
+
+    /// Grow the WASM memory upfront so that at least `bytes` more bytes fit
+    /// after the current heap pointer. Calling this with the expected size of
+    /// the data, or of the largest input, avoids growing the memory several
+    /// times during the first load or evaluation.
+    ///
+    /// # Errors
+    ///
+    /// If the memory could not be grown to the requested size
+    pub async fn reserve_input_capacity<T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        bytes: usize,
+    ) -> Result<()> {
+        let heap_ptr = self.opa_heap_ptr_get_func.call(&mut store).await?;
+        self.reserve_memory(store, &heap_ptr, bytes).await
+    }
+
+    /// Grow the WASM memory so that `bytes` bytes fit after `start`
+    async fn reserve_memory<T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        start: &Addr,
+        bytes: usize,
+    ) -> Result<()> {
+        /// The size of a WASM memory page
+        const PAGE_SIZE: u64 = 64 * 1024;
+
+        let start: u64 = start.0.try_into().context("invalid heap pointer")?;
+        let bytes: u64 = bytes.try_into().context("capacity too large")?;
+        let end = start.checked_add(bytes).context("capacity too large")?;
+        let needed_pages = end.div_ceil(PAGE_SIZE);
+
+        let current_pages = self.memory.size(&store);
+        if current_pages < needed_pages {
+            self.memory
+                .grow_async(&mut store, needed_pages - current_pages)
+                .await
+                .with_context(|| format!("could not grow the memory to {needed_pages} pages"))?;
+        }
+
+        Ok(())
+    }
+
     /// Get the default entrypoint of this module. May return [`None`] if no
     /// entrypoint with ID 0 was found
     #[must_use]
@@ -513,6 +1077,308 @@ impl<C> Runtime<C> {
     pub fn abi_version(&self) -> AbiVersion {
         self.version
     }
+
+    /// Set the revision of the bundle this module was loaded from. It is
+    /// passed to the evaluation context when an evaluation starts.
+    #[must_use]
+    pub fn with_revision(mut self, revision: impl Into<String>) -> Self {
+        self.revision = Some(revision.into());
+        self
+    }
+
+    /// Get the revision of the bundle this module was loaded from, if set
+    #[must_use]
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// Run the given function with the evaluation context, for example to
+    /// read the metrics gathered by a [`MetricsLayer`] after some evaluations
+    ///
+    /// # Errors
+    ///
+    /// If the builtins were never initialized
+    ///
+    /// [`MetricsLayer`]: crate::MetricsLayer
+    pub async fn with_context<R>(&self, f: impl FnOnce(&mut C) -> R) -> Result<R> {
+        let loaded_builtins = self
+            .loaded_builtins
+            .get()
+            .context("builtins where never initialized")?;
+        let mut context = loaded_builtins.context.lock().await;
+        Ok(f(&mut context))
+    }
+
+    /// Get the ABI version, entrypoints and builtins of the module
+    ///
+    /// # Errors
+    ///
+    /// If the builtins were never initialized
+    pub fn module_info(&self) -> Result<ModuleInfo>
+    where
+        C: EvaluationContext,
+    {
+        let loaded_builtins = self
+            .loaded_builtins
+            .get()
+            .context("builtins where never initialized")?;
+
+        let builtins: BTreeMap<String, i32> = loaded_builtins
+            .builtins
+            .iter()
+            .map(|(id, (name, _))| (name.clone(), *id))
+            .collect();
+        let unsupported_builtins = builtins
+            .keys()
+            .filter(|name| loaded_builtins.registry.check(name).is_err())
+            .cloned()
+            .collect();
+
+        Ok(ModuleInfo {
+            abi_version: self.version,
+            entrypoints: self
+                .entrypoints
+                .iter()
+                .map(|(name, id)| (name.clone(), id.0))
+                .collect(),
+            builtins,
+            unsupported_builtins,
+        })
+    }
+
+    /// Whether the module exports the `opa_eval` fast path
+    #[must_use]
+    pub fn has_fast_path(&self) -> bool {
+        self.opa_eval_func.is_some()
+    }
+
+    /// Enable or disable the `opa_eval` fast path. When disabled, or if the
+    /// module does not export it, evaluations go through the slower
+    /// `eval` context-based path. It is enabled by default.
+    #[must_use]
+    pub fn with_fast_path(mut self, enabled: bool) -> Self {
+        self.fast_path = enabled;
+        self
+    }
+
+    /// Snapshot the memory once the data is loaded, and restore it before
+    /// each evaluation. This guarantees that an evaluation can't see anything
+    /// left in memory by the previous ones, at the cost of copying the
+    /// memory holding the data on each evaluation. It is disabled by default.
+    #[must_use]
+    pub fn with_memory_snapshot(mut self, enabled: bool) -> Self {
+        self.memory_snapshot = enabled;
+        self
+    }
+
+    /// Include a hash of the input in the [`EvaluationFailure`] attached to
+    /// the errors of evaluations which trapped or aborted. It is disabled by
+    /// default.
+    ///
+    /// [`EvaluationFailure`]: crate::EvaluationFailure
+    #[must_use]
+    pub fn with_input_hash(mut self, enabled: bool) -> Self {
+        self.input_hash = enabled;
+        self
+    }
+
+    /// Fail evaluations when a builtin returns an error, like OPA does with
+    /// `--strict-builtin-errors`. It is disabled by default: the error makes
+    /// the expression calling the builtin undefined, and is only logged,
+    /// unless the evaluation ran out of time or the error is a [`HaltError`],
+    /// like the ones of `http.send` with `raise_error`.
+    #[must_use]
+    pub fn with_strict_builtin_errors(mut self, enabled: bool) -> Self {
+        self.strict_builtin_errors = enabled;
+        self
+    }
+}
+
+/// The state of a policy instance, as dumped by [`Policy::dump`] to debug
+/// evaluations returning unexpected results
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PolicyDump {
+    /// The data document the policy evaluates with, as dumped by
+    /// `opa_value_dump`. Unlike JSON, it keeps the Rego types, like sets.
+    pub data: String,
+
+    /// The revision of the bundle the policy was loaded from, if any
+    pub revision: Option<String>,
+
+    /// The ABI version of the module
+    pub abi_version: AbiVersion,
+
+    /// The address of the top of the heap before the data was loaded
+    pub initial_heap_ptr: usize,
+
+    /// The address of the top of the heap once the data was loaded, where
+    /// evaluations start allocating
+    pub data_heap_ptr: usize,
+
+    /// The address of the top of the heap. After an evaluation, this is how
+    /// far it went.
+    pub heap_ptr: usize,
+
+    /// The size of the linear memory of the instance, in bytes
+    pub memory_size: usize,
+}
+
+impl std::fmt::Display for PolicyDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "revision: {}",
+            self.revision.as_deref().unwrap_or("none")
+        )?;
+        writeln!(f, "ABI version: {}", self.abi_version)?;
+        writeln!(f, "memory size: {} bytes", self.memory_size)?;
+        writeln!(
+            f,
+            "heap: {:#x} (data from {:#x} to {:#x})",
+            self.heap_ptr, self.initial_heap_ptr, self.data_heap_ptr
+        )?;
+        write!(f, "data: {}", self.data)
+    }
+}
+
+/// The memory of a policy instance once its data is loaded, which can be
+/// copied into new instances of the same module with
+/// [`Runtime::with_data_image`]. Cloning it is cheap.
+#[derive(Clone)]
+pub struct DataImage {
+    /// The memory up to the heap pointer
+    memory: Arc<[u8]>,
+
+    /// The size of the memory, in pages
+    pages: u64,
+
+    /// The address of the data value
+    data: i32,
+
+    /// The heap pointer after the data was loaded
+    heap_ptr: i32,
+
+    /// The heap pointer before the data was loaded
+    initial_heap_ptr: i32,
+
+    /// The entrypoints of the module, to check that the image is used with
+    /// the same module
+    entrypoints: Vec<(String, i32)>,
+}
+
+impl Debug for DataImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataImage")
+            .field("len", &self.memory.len())
+            .field("pages", &self.pages)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DataImage {
+    /// The magic number and format version starting serialized images
+    const MAGIC: &'static [u8; 8] = b"OPAIMG\0\x01";
+
+    /// Serialize the image, to store it and load it in another process with
+    /// [`DataImage::from_bytes`]
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.memory.len() + 64);
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.extend_from_slice(&self.pages.to_le_bytes());
+        bytes.extend_from_slice(&self.data.to_le_bytes());
+        bytes.extend_from_slice(&self.heap_ptr.to_le_bytes());
+        bytes.extend_from_slice(&self.initial_heap_ptr.to_le_bytes());
+
+        // Lengths are written as u64, which always fit a usize read back
+        bytes.extend_from_slice(&(self.entrypoints.len() as u64).to_le_bytes());
+        for (name, id) in &self.entrypoints {
+            bytes.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+        bytes
+    }
+
+    /// Load an image serialized with [`DataImage::to_bytes`]
+    ///
+    /// # Errors
+    ///
+    /// If the bytes are not a serialized image
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        /// A cursor over the serialized image
+        struct Reader<'a>(&'a [u8]);
+
+        impl<'a> Reader<'a> {
+            /// Read the given number of bytes
+            fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+                anyhow::ensure!(self.0.len() >= len, "truncated data image");
+                let (head, tail) = self.0.split_at(len);
+                self.0 = tail;
+                Ok(head)
+            }
+
+            /// Read a fixed-size array
+            fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+                Ok(self.bytes(N)?.try_into()?)
+            }
+
+            /// Read a length
+            fn len(&mut self) -> Result<usize> {
+                Ok(u64::from_le_bytes(self.array()?).try_into()?)
+            }
+        }
+
+        let mut reader = Reader(bytes);
+        anyhow::ensure!(
+            reader.bytes(Self::MAGIC.len()).ok() == Some(&Self::MAGIC[..]),
+            "not a data image, or from an incompatible version"
+        );
+
+        let pages = u64::from_le_bytes(reader.array()?);
+        let data = i32::from_le_bytes(reader.array()?);
+        let heap_ptr = i32::from_le_bytes(reader.array()?);
+        let initial_heap_ptr = i32::from_le_bytes(reader.array()?);
+
+        let count = reader.len()?;
+        let mut entrypoints = Vec::new();
+        for _ in 0..count {
+            let len = reader.len()?;
+            let name = std::str::from_utf8(reader.bytes(len)?)
+                .context("invalid entrypoint name in data image")?
+                .to_owned();
+            entrypoints.push((name, i32::from_le_bytes(reader.array()?)));
+        }
+
+        let len = reader.len()?;
+        let memory = Arc::from(reader.bytes(len)?);
+        anyhow::ensure!(reader.0.is_empty(), "trailing bytes after data image");
+
+        Ok(Self {
+            memory,
+            pages,
+            data,
+            heap_ptr,
+            initial_heap_ptr,
+            entrypoints,
+        })
+    }
+}
+
+/// A copy of the WASM memory up to the heap pointer, taken after the data was
+/// loaded
+struct MemorySnapshot(Vec<u8>);
+
+impl Debug for MemorySnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemorySnapshot")
+            .field("len", &self.0.len())
+            .finish()
+    }
 }
 
 /// An instance of a policy, ready to be executed
@@ -524,26 +1390,439 @@ pub struct Policy<C> {
     /// The data object loaded for this policy
     data: Value,
 
+    /// The heap pointer before the data was loaded
+    initial_heap_ptr: Addr,
+
     /// A pointer to the heap, used for efficient allocations
     heap_ptr: Addr,
+
+    /// A buffer reused across evaluations to serialize the input, so that
+    /// each evaluation doesn't allocate a new one
+    input_buffer: std::sync::Mutex<Vec<u8>>,
+
+    /// The memory restored before each evaluation, if enabled with
+    /// [`Runtime::with_memory_snapshot`]
+    snapshot: Option<MemorySnapshot>,
 }
 
+/// Buffers which grew larger than this are not kept for the next
+/// evaluations, so that a single large input doesn't hold memory forever
+const MAX_INPUT_BUFFER_CAPACITY: usize = 1 << 20;
+
 impl<C> Policy<C> {
+    /// Take the input buffer, leaving an empty one in its place. The lock is
+    /// only held while swapping, never across await points.
+    fn take_input_buffer(&self) -> Vec<u8> {
+        let mut buffer = self
+            .input_buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        std::mem::take(&mut *buffer)
+    }
+
+    /// Give back the input buffer, for the next evaluation to reuse it
+    fn return_input_buffer(&self, buffer: Vec<u8>) {
+        if buffer.capacity() > MAX_INPUT_BUFFER_CAPACITY {
+            return;
+        }
+
+        let mut slot = self
+            .input_buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *slot = buffer;
+    }
+
+    /// Grow the WASM memory upfront so that an input of at least `bytes`
+    /// bytes fits without growing it during the evaluation.
+    /// See [`Runtime::reserve_input_capacity`].
+    ///
+    /// # Errors
+    ///
+    /// If the memory could not be grown to the requested size
+    pub async fn reserve_input_capacity<T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        bytes: usize,
+    ) -> Result<()> {
+        self.runtime
+            .reserve_memory(store, &self.heap_ptr, bytes)
+            .await
+    }
+
+    /// Take an image of the memory holding the data, to create other
+    /// instances of the same module with the same data through
+    /// [`Runtime::with_data_image`]
+    ///
+    /// # Errors
+    ///
+    /// If the memory could not be read
+    pub fn data_image<T>(&self, store: impl AsContext<Data = T>) -> Result<DataImage> {
+        let memory = match &self.snapshot {
+            Some(snapshot) => Arc::from(&snapshot.0[..]),
+            None => Arc::from(self.runtime.read_memory(&store, &self.heap_ptr)?),
+        };
+
+        Ok(DataImage {
+            memory,
+            pages: self.runtime.memory.size(&store),
+            data: self.data.0,
+            heap_ptr: self.heap_ptr.0,
+            initial_heap_ptr: self.initial_heap_ptr.0,
+            entrypoints: self.runtime.entrypoint_ids(),
+        })
+    }
+
+    /// Get how much memory the instance uses: the size of its linear memory,
+    /// and where the top of the heap is. Right after an evaluation, this is
+    /// what the evaluation used, which is also reported to the context in
+    /// [`EvaluationOutcome::memory`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the heap pointer could not be read, or if this
+    /// policy did not belong to the given store.
+    pub async fn memory_usage<T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+    ) -> Result<MemoryUsage> {
+        let heap_ptr = self.runtime.opa_heap_ptr_get_func.call(&mut store).await?;
+        let heap_ptr: usize = heap_ptr.0.try_into().context("invalid heap pointer")?;
+        let base: usize = self.heap_ptr.0.try_into().context("invalid heap pointer")?;
+        Ok(MemoryUsage {
+            memory_size: self.runtime.memory.data_size(&store),
+            heap_ptr,
+            heap_used: heap_ptr.saturating_sub(base),
+        })
+    }
+
+    /// Dump the state of the instance: the data document as the policy sees
+    /// it, where the heap is and how large the memory is. This helps
+    /// understanding why a policy evaluates differently in one environment,
+    /// for example because the data is not the expected one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the module does not export `opa_value_dump`, if
+    /// the data could not be dumped, or if this policy did not belong to the
+    /// given store.
+    pub async fn dump<T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+    ) -> Result<PolicyDump> {
+        let opa_value_dump = self
+            .runtime
+            .opa_value_dump_func
+            .as_ref()
+            .context("the module does not export opa_value_dump")?;
+
+        // Read the heap pointer before dumping the data allocates on the heap
+        let heap_ptr = self.runtime.opa_heap_ptr_get_func.call(&mut store).await?;
+        let memory_size = self.runtime.memory.data_size(&store);
+
+        let data = opa_value_dump.call(&mut store, &self.data).await?;
+        let data = data
+            .read(&store, &self.runtime.memory)?
+            .to_string_lossy()
+            .into_owned();
+
+        // Give back what the dump allocated
+        self.runtime
+            .opa_heap_ptr_set_func
+            .call(&mut store, &heap_ptr)
+            .await?;
+
+        let address =
+            |addr: &Addr| -> Result<usize> { addr.0.try_into().context("invalid heap pointer") };
+        Ok(PolicyDump {
+            data,
+            revision: self.runtime.revision.clone(),
+            abi_version: self.runtime.version,
+            initial_heap_ptr: address(&self.initial_heap_ptr)?,
+            data_heap_ptr: address(&self.heap_ptr)?,
+            heap_ptr: address(&heap_ptr)?,
+            memory_size,
+        })
+    }
+
+    /// Enable or disable the `opa_eval` fast path for the next evaluations.
+    /// See [`Runtime::with_fast_path`].
+    pub fn set_fast_path(&mut self, enabled: bool) {
+        self.runtime.fast_path = enabled;
+    }
+
+    /// Enable or disable strict builtin errors for the next evaluations.
+    /// See [`Runtime::with_strict_builtin_errors`].
+    pub fn set_strict_builtin_errors(&mut self, enabled: bool) {
+        self.runtime.strict_builtin_errors = enabled;
+    }
+
     /// Evaluate a policy with the given entrypoint and input.
     ///
     /// # Errors
     ///
     /// Returns an error if the policy evaluation failed, or if this policy did
-    /// not belong to the given store.
+    /// not belong to the given store. If the policy trapped or aborted, the
+    /// error has an [`EvaluationFailure`] context describing the evaluation.
     pub async fn evaluate<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>, T: Send>(
         &self,
-        mut store: impl AsContextMut<Data = T>,
+        store: impl AsContextMut<Data = T>,
         entrypoint: &str,
         input: &V,
     ) -> Result<R>
     where
         C: EvaluationContext,
     {
+        let result = self
+            .evaluate_with_id(store, entrypoint, input, EvaluationId::next())
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Evaluate a policy with the given entrypoint and input, like
+    /// [`Policy::evaluate`], under the given evaluation ID
+    pub(crate) async fn evaluate_with_id<V: serde::Serialize, T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+        evaluation_id: EvaluationId,
+    ) -> Result<serde_json::Value>
+    where
+        C: EvaluationContext,
+    {
+        let mut buffer = self.take_input_buffer();
+        let result = self
+            .evaluate_with_buffer(store, entrypoint, input, evaluation_id, &mut buffer)
+            .await;
+        self.return_input_buffer(buffer);
+        result
+    }
+
+    /// Evaluate a policy with the given entrypoint and input, without
+    /// notifying the context, nor recording the evaluation in its span. This
+    /// is for evaluations made on behalf of another one, like the mask policy
+    /// of decision logs.
+    #[cfg(feature = "decision-logs")]
+    pub(crate) async fn evaluate_unobserved<V: serde::Serialize, T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        let mut buffer = self.take_input_buffer();
+        let result = self
+            .evaluate_entrypoint(store, entrypoint, input, &mut buffer)
+            .await;
+        self.return_input_buffer(buffer);
+        result
+    }
+
+    /// Evaluate a policy with the given entrypoint and input, like
+    /// [`Policy::evaluate`], also collecting an [`Explanation`] of the
+    /// evaluation: the builtin calls with their arguments, results and
+    /// timings, the notes, the printed messages and the abort messages.
+    ///
+    /// Collecting the explanation slows the builtin calls down, so this is
+    /// meant for debugging.
+    pub async fn evaluate_with_report<
+        V: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de>,
+        T: Send,
+    >(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+    ) -> EvaluationReport<R>
+    where
+        C: EvaluationContext,
+    {
+        let loaded_builtins = self.loaded_builtins.get();
+        if let Some(loaded_builtins) = loaded_builtins {
+            loaded_builtins.set_explaining(true);
+        }
+
+        let start = Instant::now();
+        let result = self.evaluate(store, entrypoint, input).await;
+        let duration = start.elapsed();
+        let events = loaded_builtins
+            .map(|loaded_builtins| loaded_builtins.set_explaining(false))
+            .unwrap_or_default();
+
+        EvaluationReport {
+            result,
+            explanation: Explanation {
+                entrypoint: entrypoint.to_owned(),
+                events,
+                duration,
+            },
+        }
+    }
+
+    /// Evaluate a typed entrypoint, returning its result, or
+    /// [`EvalResult::Undefined`] if it is undefined
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed, if the result did not
+    /// match the type of the entrypoint, or if this policy did not belong to
+    /// the given store.
+    pub async fn evaluate_typed<I: serde::Serialize, O: serde::de::DeserializeOwned, T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        entrypoint: &Entrypoint<I, O>,
+        input: &I,
+    ) -> Result<EvalResult<O>>
+    where
+        C: EvaluationContext,
+    {
+        let result_set = self.evaluate(store, entrypoint.name(), input).await?;
+        crate::entrypoint::from_result_set(result_set)
+    }
+
+    /// Evaluate the entrypoint of a [`PolicyInput`], returning its result, or
+    /// [`EvalResult::Undefined`] if it is undefined
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed, if the result did not
+    /// match the output of the input, or if this policy did not belong to the
+    /// given store.
+    pub async fn evaluate_input<I: PolicyInput, T: Send>(
+        &self,
+        store: impl AsContextMut<Data = T>,
+        input: &I,
+    ) -> Result<EvalResult<I::Output>>
+    where
+        C: EvaluationContext,
+    {
+        self.evaluate_typed(store, &I::entrypoint(), input).await
+    }
+
+    /// Evaluate a policy with the given entrypoint for each of the inputs, and
+    /// return the results in the same order. This is equivalent to calling
+    /// [`Policy::evaluate`] for each input, but the buffer used to serialize
+    /// the inputs is shared by the whole batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as soon as one of the evaluations failed, or if this
+    /// policy did not belong to the given store.
+    pub async fn evaluate_many<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>, T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        inputs: &[V],
+    ) -> Result<Vec<R>>
+    where
+        C: EvaluationContext,
+    {
+        let mut buffer = self.take_input_buffer();
+        let mut results = Vec::with_capacity(inputs.len());
+        for (index, input) in inputs.iter().enumerate() {
+            let result = self
+                .evaluate_with_buffer(
+                    &mut store,
+                    entrypoint,
+                    input,
+                    EvaluationId::next(),
+                    &mut buffer,
+                )
+                .await
+                .and_then(|result| Ok(serde_json::from_value(result)?))
+                .with_context(|| format!("could not evaluate input #{index}"));
+
+            match result {
+                Ok(result) => results.push(result),
+                Err(error) => {
+                    self.return_input_buffer(buffer);
+                    return Err(error);
+                }
+            }
+        }
+        self.return_input_buffer(buffer);
+
+        Ok(results)
+    }
+
+    /// Evaluate the given entrypoint, notifying the context of the start and
+    /// the outcome of the evaluation
+    async fn evaluate_with_buffer<V: serde::Serialize, T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+        evaluation_id: EvaluationId,
+        buffer: &mut Vec<u8>,
+    ) -> Result<serde_json::Value>
+    where
+        C: EvaluationContext,
+    {
+        let loaded_builtins = self
+            .loaded_builtins
+            .get()
+            .context("builtins where never initialized")?;
+
+        let metadata = EvaluationMetadata {
+            entrypoint,
+            revision: self.runtime.revision.as_deref(),
+            evaluation_id,
+        };
+        loaded_builtins
+            .strict_errors
+            .store(self.runtime.strict_builtin_errors, Ordering::Relaxed);
+        loaded_builtins.evaluation_start(&metadata).await;
+
+        let span = metadata.span();
+
+        let start = Instant::now();
+        let result = self
+            .evaluate_entrypoint(&mut store, entrypoint, input, buffer)
+            .instrument(span.clone())
+            .await;
+        let duration = start.elapsed();
+
+        // Tell where the evaluation failed if it failed in the module. The
+        // buffer holds the serialized input, followed by the nul terminator
+        // on the slow path.
+        let result = result.map_err(|error| {
+            let input = buffer.strip_suffix(&[0]).unwrap_or(buffer.as_slice());
+            let failure = EvaluationFailure::describe(
+                &error,
+                entrypoint,
+                self.runtime.revision.as_deref(),
+                self.runtime.version,
+                self.runtime.input_hash.then_some(input),
+            );
+            match failure {
+                Some(failure) => error.context(failure),
+                None => error,
+            }
+        });
+        let memory = self.memory_usage(&mut store).await.ok();
+        let outcome = EvaluationOutcome {
+            metadata,
+            duration,
+            result: result.as_ref(),
+            memory,
+        };
+
+        outcome.record(&span);
+
+        loaded_builtins.evaluation_done(&outcome).await;
+
+        result
+    }
+
+    /// Evaluate the given entrypoint, through the fast path if available
+    async fn evaluate_entrypoint<V: serde::Serialize, T: Send>(
+        &self,
+        mut store: impl AsContextMut<Data = T>,
+        entrypoint: &str,
+        input: &V,
+        buffer: &mut Vec<u8>,
+    ) -> Result<serde_json::Value> {
         // Lookup the entrypoint
         let entrypoint = self
             .runtime
@@ -551,16 +1830,22 @@ impl<C> Policy<C> {
             .get(entrypoint)
             .with_context(|| format!("could not find entrypoint {entrypoint}"))?;
 
-        self.loaded_builtins
-            .get()
-            .context("builtins where never initialized")?
-            .evaluation_start()
-            .await;
+        // Restore the memory as it was right after loading the data
+        if let Some(snapshot) = &self.snapshot {
+            self.runtime.memory.write(&mut store, 0, &snapshot.0)?;
+        }
 
         // Take the fast path if it is awailable
-        if let Some(opa_eval) = &self.runtime.opa_eval_func {
+        let opa_eval = self
+            .runtime
+            .opa_eval_func
+            .as_ref()
+            .filter(|_| self.runtime.fast_path);
+        if let Some(opa_eval) = opa_eval {
             // Write the input
-            let input = serde_json::to_vec(&input)?;
+            buffer.clear();
+            serde_json::to_writer(&mut *buffer, input)?;
+            let input = &buffer[..];
             let input_heap = Heap {
                 ptr: self.heap_ptr.0,
                 len: input.len().try_into().context("input too long")?,
@@ -569,20 +1854,15 @@ impl<C> Policy<C> {
             };
 
             // Check if we need to grow the memory first
-            let current_pages = self.runtime.memory.size(&store);
-            let needed_pages = input_heap.pages();
-            if current_pages < needed_pages {
-                self.runtime
-                    .memory
-                    .grow_async(&mut store, needed_pages - current_pages)
-                    .await?;
-            }
+            self.runtime
+                .reserve_memory(&mut store, &self.heap_ptr, input.len())
+                .await?;
 
             // Write the JSON input to memory
             self.runtime.memory.write(
                 &mut store,
                 input_heap.ptr.try_into().context("invalid heap pointer")?,
-                &input[..],
+                input,
             )?;
 
             let heap_ptr = Addr(input_heap.end());
@@ -604,7 +1884,10 @@ impl<C> Policy<C> {
                 .await?;
 
             // Load the input
-            let input = self.runtime.load_json(&mut store, input).await?;
+            let input = self
+                .runtime
+                .load_json_with_buffer(&mut store, input, buffer)
+                .await?;
 
             // Create a new evaluation context
             let ctx = self.runtime.opa_eval_ctx_new_func.call(&mut store).await?;
@@ -653,3 +1936,306 @@ impl<C> Deref for Policy<C> {
         &self.runtime
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::Store;
+
+    use super::*;
+    use crate::{
+        stub::{self, stub_module, StubPolicy},
+        EngineConfig,
+    };
+
+    /// A policy with the store it belongs to
+    struct StorePolicy {
+        store: Store<()>,
+        policy: Policy<DefaultContext>,
+    }
+
+    impl StubPolicy for StorePolicy {
+        async fn load(wasm: &[u8], revision: &str, strict_builtin_errors: bool) -> Result<Self> {
+            let engine = EngineConfig::new().build()?;
+            let module = Module::new(&engine, wasm)?;
+            let mut store = Store::new(&engine, ());
+            let mut policy = Runtime::new(&mut store, &module)
+                .await?
+                .with_revision(revision)
+                .without_data(&mut store)
+                .await?;
+            policy.set_strict_builtin_errors(strict_builtin_errors);
+            Ok(Self { store, policy })
+        }
+
+        async fn evaluate(&mut self, entrypoint: &str) -> Result<serde_json::Value> {
+            self.policy.evaluate(&mut self.store, entrypoint, &()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn builtin_errors_are_undefined_unless_strict() {
+        stub::builtin_errors_are_undefined_unless_strict::<StorePolicy>().await;
+    }
+
+    #[tokio::test]
+    async fn failures_are_described() {
+        stub::failures_are_described::<StorePolicy>().await;
+    }
+
+    #[tokio::test]
+    async fn unsupported_builtins_only_fail_strict_loads() {
+        let engine = EngineConfig::new().build().unwrap();
+        let module = stub_module(
+            &engine,
+            r#"{"indexof_n":0,"custom.missing":1}"#,
+            r#"{"test":0}"#,
+            r#"(data (i32.const 1024) "[{\"result\":true}]\00")"#,
+            "i32.const 1024
+             global.set $result",
+        );
+
+        let mut store = Store::new(&engine, ());
+        let error = Runtime::new(&mut store, &module)
+            .await
+            .map(drop)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "could not resolve custom.missing");
+
+        let mut store = Store::new(&engine, ());
+        let runtime = Runtime::inspect(&mut store, &module).await.unwrap();
+        let info = runtime.module_info().unwrap();
+        assert_eq!(
+            info.builtins,
+            BTreeMap::from([
+                ("custom.missing".to_owned(), 1),
+                ("indexof_n".to_owned(), 0)
+            ])
+        );
+        assert_eq!(
+            info.unsupported_builtins,
+            BTreeSet::from(["custom.missing".to_owned()])
+        );
+
+        // The policy can still be evaluated, as long as it doesn't call them
+        let policy = runtime.without_data(&mut store).await.unwrap();
+        let result: serde_json::Value = policy.evaluate(&mut store, "test", &()).await.unwrap();
+        assert_eq!(result, serde_json::json!([{"result": true}]));
+    }
+
+    #[cfg(feature = "http-builtins")]
+    #[tokio::test]
+    async fn halt_errors_fail_the_evaluation() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `raise` entrypoint sends a request raising its errors, and the
+        // `soft` one a request returning them in the response
+        let module = stub_module(
+            &engine,
+            r#"{"http.send":0}"#,
+            r#"{"raise":0,"soft":1}"#,
+            r#"
+              (import "env" "opa_builtin1" (func $builtin1 (param i32 i32 i32) (result i32)))
+              (data (i32.const 1024) "[{\"result\":true}]\00")
+              (data (i32.const 1100) "{\"method\":\"get\",\"url\":\"http://localhost\",\"raise_error\":true}\00")
+              (data (i32.const 1200) "{\"method\":\"get\",\"url\":\"http://localhost\",\"raise_error\":false}\00")
+            "#,
+            "i32.const 0
+             i32.const 0
+             global.get $entrypoint
+             if (result i32)
+               i32.const 1200
+             else
+               i32.const 1100
+             end
+             call $builtin1
+             if
+               i32.const 1024
+               global.set $result
+             end",
+        );
+        let mut context = crate::TestContext::default();
+        context.inject_http_fault("*", crate::HttpFault::ConnectionRefused);
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new_with_evaluation_context(&mut store, &module, context)
+            .await
+            .unwrap()
+            .without_data(&mut store)
+            .await
+            .unwrap();
+
+        let result: serde_json::Value = policy.evaluate(&mut store, "soft", &()).await.unwrap();
+        assert_eq!(result, serde_json::json!([{ "result": true }]));
+
+        let error = policy
+            .evaluate::<_, serde_json::Value, _>(&mut store, "raise", &())
+            .await
+            .unwrap_err();
+        assert!(HaltError::is_halt(&error));
+        assert!(format!("{error:#}").contains("http.send failed"));
+    }
+
+    #[tokio::test]
+    async fn memory_is_reserved_upfront() {
+        let engine = EngineConfig::new().build().unwrap();
+        let module = stub_module(&engine, "{}", r#"{"test":0}"#, "", "");
+        let mut store = Store::new(&engine, ());
+        let runtime = Runtime::new(&mut store, &module).await.unwrap();
+
+        // The stub module starts with 2 pages, and its heap at 4096
+        runtime
+            .reserve_input_capacity(&mut store, 256 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(runtime.memory.size(&store), 5);
+
+        // Reserving less than what is available does not grow the memory
+        runtime.reserve_input_capacity(&mut store, 1).await.unwrap();
+        assert_eq!(runtime.memory.size(&store), 5);
+
+        let policy = runtime.without_data(&mut store).await.unwrap();
+        policy
+            .reserve_input_capacity(&mut store, 1024 * 1024)
+            .await
+            .unwrap();
+        let usage = policy.memory_usage(&mut store).await.unwrap();
+        assert_eq!(usage.memory_size, 17 * 64 * 1024);
+        assert!(usage.memory_size - usage.heap_ptr >= 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn memory_snapshots_isolate_evaluations() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `test` entrypoint increments the digit of its result in memory
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"test":0}"#,
+            r#"(data (i32.const 1024) "[{\"result\":0}]\00")"#,
+            "i32.const 1035
+             i32.const 1035
+             i32.load8_u
+             i32.const 1
+             i32.add
+             i32.store8
+             i32.const 1024
+             global.set $result",
+        );
+
+        for (snapshot, second) in [(false, 2), (true, 1)] {
+            let mut store = Store::new(&engine, ());
+            let policy = Runtime::new(&mut store, &module)
+                .await
+                .unwrap()
+                .with_memory_snapshot(snapshot)
+                .without_data(&mut store)
+                .await
+                .unwrap();
+
+            let first: serde_json::Value = policy.evaluate(&mut store, "test", &()).await.unwrap();
+            assert_eq!(first, serde_json::json!([{ "result": 1 }]));
+
+            // With the snapshot, the second evaluation does not see what the
+            // first one left in memory
+            let result: serde_json::Value = policy.evaluate(&mut store, "test", &()).await.unwrap();
+            assert_eq!(result, serde_json::json!([{ "result": second }]));
+        }
+    }
+
+    #[tokio::test]
+    async fn data_images_are_loaded() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `data` entrypoint returns the data document
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"data":0}"#,
+            "",
+            "global.get $data
+             global.set $result",
+        );
+        let data = serde_json::json!({ "users": ["alice", "bob"] });
+
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new(&mut store, &module)
+            .await
+            .unwrap()
+            .with_data(&mut store, &data)
+            .await
+            .unwrap();
+        let image = policy.data_image(&store).unwrap();
+        let expected: serde_json::Value = policy.evaluate(&mut store, "data", &()).await.unwrap();
+        assert_eq!(expected, data);
+
+        // An instance created from the image evaluates like the original one
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new(&mut store, &module)
+            .await
+            .unwrap()
+            .with_data_image(&mut store, &image)
+            .await
+            .unwrap();
+        let result: serde_json::Value = policy.evaluate(&mut store, "data", &()).await.unwrap();
+        assert_eq!(result, expected);
+        assert_eq!(policy.data_image(&store).unwrap().memory, image.memory);
+
+        // Images of other modules are rejected
+        let other = stub_module(&engine, "{}", r#"{"other":0}"#, "", "");
+        let mut store = Store::new(&engine, ());
+        let error = Runtime::new(&mut store, &other)
+            .await
+            .unwrap()
+            .with_data_image(&mut store, &image)
+            .await
+            .map(drop)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the data image was taken from an instance of a different module"
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_are_evaluated_in_order() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `echo` entrypoint returns the input, unless it is a string
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"echo":0}"#,
+            "",
+            "global.get $input
+             i32.load8_u
+             i32.const 34
+             i32.eq
+             if
+               unreachable
+             end
+             global.get $input
+             global.set $result",
+        );
+        let mut store = Store::new(&engine, ());
+        let policy = Runtime::new(&mut store, &module)
+            .await
+            .unwrap()
+            .without_data(&mut store)
+            .await
+            .unwrap();
+
+        let inputs = [
+            serde_json::json!(1),
+            serde_json::json!([2]),
+            serde_json::json!(3),
+        ];
+        let results: Vec<serde_json::Value> = policy
+            .evaluate_many(&mut store, "echo", &inputs)
+            .await
+            .unwrap();
+        assert_eq!(results, inputs);
+
+        let inputs = [serde_json::json!(1), serde_json::json!("two")];
+        let error = policy
+            .evaluate_many::<_, serde_json::Value, _>(&mut store, "echo", &inputs)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "could not evaluate input #1");
+    }
+}
@@ -0,0 +1,530 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pool of policy instances, to evaluate a policy concurrently
+
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::{Mutex, MutexGuard};
+use wasmtime::{Module, Store};
+
+#[cfg(feature = "decision-logs")]
+use crate::DecisionLogger;
+use crate::{EngineConfig, EvaluationContext, Policy, Runtime};
+
+/// A policy instance, with the store it lives in
+#[derive(Debug)]
+pub struct PooledPolicy<C, T = ()> {
+    /// The store holding the instance
+    pub store: Store<T>,
+
+    /// The instantiated policy, with the data loaded
+    pub policy: Policy<C>,
+}
+
+/// A hook called on the store of an instance before it evaluates a policy
+type Hook<T> = Arc<dyn Fn(&mut Store<T>) + Send + Sync>;
+
+/// A fixed set of instances of the same policy, handed out in a round-robin
+/// fashion. Each instance evaluates one input at a time, so the size of the
+/// pool bounds how many evaluations run at the same time.
+pub struct PolicyPool<C, T = ()> {
+    /// The policy instances
+    instances: Vec<Mutex<PooledPolicy<C, T>>>,
+
+    /// The index of the next instance to use
+    next: AtomicUsize,
+
+    /// Called on the store of an instance each time it is handed out
+    before_evaluation: Option<Hook<T>>,
+
+    /// The logger the decisions go through, if any
+    #[cfg(feature = "decision-logs")]
+    logger: Option<DecisionLogger>,
+}
+
+impl<C: std::fmt::Debug, T: std::fmt::Debug> std::fmt::Debug for PolicyPool<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyPool")
+            .field("instances", &self.instances)
+            .field("next", &self.next)
+            .field("before_evaluation", &self.before_evaluation.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C> PolicyPool<C> {
+    /// Instantiate the module `size` times with the given data, calling
+    /// `context` to get the evaluation context of each instance
+    ///
+    /// The data is only loaded in the first instance, and its memory is copied
+    /// into the others with [`Runtime::with_data_image`].
+    ///
+    /// If the module was compiled with an engine built from an
+    /// [`EngineConfig`] which [yields](EngineConfig::yield_interval), use
+    /// [`PolicyPool::instantiate_with_config`] instead.
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be instantiated, or the data could not be
+    /// loaded
+    pub async fn instantiate<V: serde::Serialize>(
+        module: &Module,
+        data: &V,
+        size: NonZeroUsize,
+        context: impl FnMut() -> C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        Self::instantiate_with_config(module, &EngineConfig::new(), data, size, context).await
+    }
+
+    /// Same as [`PolicyPool::instantiate`], setting up the store of each
+    /// instance with [`EngineConfig::configure_store`]. The configuration
+    /// should be the one the engine of the module was built from.
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be instantiated, or the data could not be
+    /// loaded
+    pub async fn instantiate_with_config<V: serde::Serialize>(
+        module: &Module,
+        config: &EngineConfig,
+        data: &V,
+        size: NonZeroUsize,
+        context: impl FnMut() -> C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        let store = || {
+            let mut store = Store::new(module.engine(), ());
+            config.configure_store(&mut store);
+            store
+        };
+
+        Self::instantiate_with_stores(module, data, size, store, context).await
+    }
+}
+
+impl<C, T: Send> PolicyPool<C, T> {
+    /// Same as [`PolicyPool::instantiate`], calling `store` to create the
+    /// store of each instance, for example to set custom data or limits
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be instantiated, or the data could not be
+    /// loaded
+    pub async fn instantiate_with_stores<V: serde::Serialize>(
+        module: &Module,
+        data: &V,
+        size: NonZeroUsize,
+        store: impl FnMut() -> Store<T>,
+        context: impl FnMut() -> C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        Self::instantiate_revision(module, data, size, None, store, context).await
+    }
+
+    /// Same as [`PolicyPool::instantiate_with_stores`], setting the revision
+    /// of the bundle the module was loaded from on each instance
+    pub(crate) async fn instantiate_revision<V: serde::Serialize>(
+        module: &Module,
+        data: &V,
+        size: NonZeroUsize,
+        revision: Option<&str>,
+        mut store: impl FnMut() -> Store<T>,
+        mut context: impl FnMut() -> C,
+    ) -> Result<Self>
+    where
+        C: EvaluationContext,
+    {
+        let mut instances = Vec::with_capacity(size.get());
+        let with_revision = |runtime: Runtime<C>| match revision {
+            Some(revision) => runtime.with_revision(revision),
+            None => runtime,
+        };
+
+        let mut first = store();
+        let runtime = Runtime::new_with_evaluation_context(&mut first, module, context()).await?;
+        let policy = with_revision(runtime).with_data(&mut first, data).await?;
+        let image = policy.data_image(&first)?;
+        instances.push(PooledPolicy {
+            store: first,
+            policy,
+        });
+
+        for _ in 1..size.get() {
+            let mut store = store();
+            let runtime =
+                Runtime::new_with_evaluation_context(&mut store, module, context()).await?;
+            let policy = with_revision(runtime)
+                .with_data_image(&mut store, &image)
+                .await?;
+            instances.push(PooledPolicy { store, policy });
+        }
+
+        Self::new(instances)
+    }
+}
+
+impl<C, T> PolicyPool<C, T> {
+    /// Create a pool from existing instances, for example to use stores with
+    /// custom data or limits
+    ///
+    /// # Errors
+    ///
+    /// If there are no instances
+    pub fn new(instances: impl IntoIterator<Item = PooledPolicy<C, T>>) -> Result<Self> {
+        let instances: Vec<_> = instances.into_iter().map(Mutex::new).collect();
+        if instances.is_empty() {
+            anyhow::bail!("a policy pool needs at least one instance");
+        }
+
+        Ok(Self {
+            instances,
+            next: AtomicUsize::new(0),
+            before_evaluation: None,
+
+            #[cfg(feature = "decision-logs")]
+            logger: None,
+        })
+    }
+
+    /// Call `hook` on the store of an instance each time it is handed out,
+    /// before it evaluates anything, for example to set an epoch deadline
+    #[must_use]
+    pub fn before_evaluation(
+        mut self,
+        hook: impl Fn(&mut Store<T>) + Send + Sync + 'static,
+    ) -> Self {
+        self.before_evaluation = Some(Arc::new(hook));
+        self
+    }
+
+    /// Log the decisions made by [`evaluate`](Self::evaluate) through the
+    /// given logger
+    #[cfg(feature = "decision-logs")]
+    #[must_use]
+    pub fn decision_logger(mut self, logger: DecisionLogger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// The number of instances in the pool
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Call the hook, if any, on the store of an instance
+    fn prepare(&self, store: &mut Store<T>) {
+        if let Some(hook) = &self.before_evaluation {
+            hook(store);
+        }
+    }
+
+    /// Get an available instance, or wait for the next instance if they are
+    /// all busy.
+    ///
+    /// The instances are tried in turn from the next one, so that they are
+    /// evenly used when the pool is idle.
+    pub async fn get(&self) -> MutexGuard<'_, PooledPolicy<C, T>> {
+        let len = self.instances.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let available = (0..len)
+            .map(|offset| &self.instances[(start + offset) % len])
+            .find_map(|instance| instance.try_lock().ok());
+        let mut instance = match available {
+            Some(instance) => instance,
+            None => self.instances[start].lock().await,
+        };
+        self.prepare(&mut instance.store);
+        instance
+    }
+
+    /// Replace the instances with the ones of another pool, for example after
+    /// a new version of the policy was loaded. Each instance is replaced once
+    /// its ongoing evaluation finished, and the hook and the logger of this
+    /// pool are kept.
+    ///
+    /// # Errors
+    ///
+    /// If the other pool does not have the same size
+    pub async fn replace(&self, other: Self) -> Result<()> {
+        if other.size() != self.size() {
+            anyhow::bail!(
+                "can not replace {} instances with {}",
+                self.size(),
+                other.size()
+            );
+        }
+
+        for (slot, instance) in self.instances.iter().zip(other.instances) {
+            *slot.lock().await = instance.into_inner();
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate the entrypoint with the next instance, and log the decision if
+    /// the pool has a logger
+    ///
+    /// # Errors
+    ///
+    /// If the evaluation failed
+    pub async fn evaluate<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<R>
+    where
+        C: EvaluationContext,
+        T: Send,
+    {
+        let mut instance = self.get().await;
+        let PooledPolicy { store, policy } = &mut *instance;
+
+        #[cfg(feature = "decision-logs")]
+        if let Some(logger) = &self.logger {
+            return logger.evaluate(policy, store, entrypoint, input).await;
+        }
+
+        policy.evaluate(store, entrypoint, input).await
+    }
+}
+
+impl<C, T> From<PooledPolicy<C, T>> for PolicyPool<C, T> {
+    fn from(instance: PooledPolicy<C, T>) -> Self {
+        Self {
+            instances: vec![Mutex::new(instance)],
+            next: AtomicUsize::new(0),
+            before_evaluation: None,
+
+            #[cfg(feature = "decision-logs")]
+            logger: None,
+        }
+    }
+}
+
+impl<C, T> PolicyPool<C, T>
+where
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    /// Evaluate the entrypoint for each of the inputs, and return the results
+    /// in the same order
+    ///
+    /// The inputs are split in one contiguous shard per instance, and the
+    /// shards are evaluated in parallel, each on its own task. This needs to
+    /// run within a tokio runtime. The [hook](Self::before_evaluation) is
+    /// called before each input.
+    ///
+    /// # Errors
+    ///
+    /// If one of the evaluations failed. The other shards still run to
+    /// completion.
+    pub async fn evaluate_bulk<V, R>(
+        self: &Arc<Self>,
+        entrypoint: &str,
+        inputs: Vec<V>,
+    ) -> Result<Vec<R>>
+    where
+        V: serde::Serialize + Send + Sync + 'static,
+        R: for<'de> serde::Deserialize<'de> + Send + 'static,
+    {
+        let total = inputs.len();
+        let shard_size = total.div_ceil(self.size()).max(1);
+        let entrypoint: Arc<str> = entrypoint.into();
+        let inputs = Arc::new(inputs);
+
+        let mut tasks = Vec::with_capacity(self.size());
+        for (index, start) in (0..total).step_by(shard_size).enumerate() {
+            let end = (start + shard_size).min(total);
+            let pool = Arc::clone(self);
+            let entrypoint = Arc::clone(&entrypoint);
+            let inputs = Arc::clone(&inputs);
+
+            tasks.push(tokio::spawn(async move {
+                let mut instance = pool.instances[index].lock().await;
+                let PooledPolicy { store, policy } = &mut *instance;
+
+                let mut results = Vec::with_capacity(end - start);
+                for (offset, input) in inputs[start..end].iter().enumerate() {
+                    pool.prepare(store);
+                    let result = policy
+                        .evaluate(&mut *store, &entrypoint, input)
+                        .await
+                        .with_context(|| format!("could not evaluate input #{}", start + offset))?;
+                    results.push(result);
+                }
+
+                Ok::<_, anyhow::Error>(results)
+            }));
+        }
+
+        let mut results = Vec::with_capacity(total);
+        for task in tasks {
+            results.extend(task.await.context("an evaluation task panicked")??);
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stub::stub_module, DefaultContext};
+
+    #[test]
+    fn pools_are_not_empty() {
+        assert!(PolicyPool::<DefaultContext>::new(Vec::new()).is_err());
+    }
+
+    /// The number of evaluations each instance of the pool was prepared for
+    async fn evaluations(pool: &PolicyPool<DefaultContext, usize>) -> Vec<usize> {
+        let mut evaluations = Vec::new();
+        for instance in &pool.instances {
+            evaluations.push(*instance.lock().await.store.data());
+        }
+        evaluations
+    }
+
+    #[tokio::test]
+    async fn bulk_evaluations_are_sharded() {
+        let engine = EngineConfig::new().build().unwrap();
+        // The `echo` entrypoint returns the input, unless it is a string
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"echo":0}"#,
+            "",
+            "global.get $input
+             i32.load8_u
+             i32.const 34
+             i32.eq
+             if
+               unreachable
+             end
+             global.get $input
+             global.set $result",
+        );
+        let instantiate = |size| {
+            let size = NonZeroUsize::new(size).unwrap();
+            PolicyPool::instantiate_with_stores(
+                &module,
+                &(),
+                size,
+                || Store::new(&engine, 0_usize),
+                DefaultContext::default,
+            )
+        };
+
+        let pool = Arc::new(
+            instantiate(2)
+                .await
+                .unwrap()
+                .before_evaluation(|store| *store.data_mut() += 1),
+        );
+        let inputs: Vec<_> = (0..5).map(|i| serde_json::json!([i])).collect();
+        let results: Vec<serde_json::Value> =
+            pool.evaluate_bulk("echo", inputs.clone()).await.unwrap();
+        assert_eq!(results, inputs);
+        assert_eq!(evaluations(&pool).await, [3, 2]);
+
+        let inputs = vec![
+            serde_json::json!(0),
+            serde_json::json!(1),
+            serde_json::json!(2),
+            serde_json::json!("three"),
+        ];
+        let error = pool
+            .evaluate_bulk::<_, serde_json::Value>("echo", inputs)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "could not evaluate input #3");
+
+        // With fewer inputs than instances, the extra instances are idle
+        let pool = Arc::new(
+            instantiate(3)
+                .await
+                .unwrap()
+                .before_evaluation(|store| *store.data_mut() += 1),
+        );
+        let results: Vec<serde_json::Value> = pool
+            .evaluate_bulk("echo", vec![serde_json::json!(1)])
+            .await
+            .unwrap();
+        assert_eq!(results, [serde_json::json!(1)]);
+        assert_eq!(evaluations(&pool).await, [1, 0, 0]);
+
+        let results: Vec<serde_json::Value> =
+            pool.evaluate_bulk("echo", Vec::<()>::new()).await.unwrap();
+        assert!(results.is_empty());
+
+        // Replacing the instances needs a pool of the same size
+        assert!(pool.replace(instantiate(2).await.unwrap()).await.is_err());
+        pool.replace(instantiate(3).await.unwrap()).await.unwrap();
+        assert_eq!(evaluations(&pool).await, [0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn busy_instances_are_skipped() {
+        let engine = EngineConfig::new().build().unwrap();
+        let module = stub_module(&engine, "{}", r#"{"allow":0}"#, "", "");
+        let pool = PolicyPool::instantiate_with_stores(
+            &module,
+            &(),
+            NonZeroUsize::new(3).unwrap(),
+            || Store::new(&engine, 0_usize),
+            DefaultContext::default,
+        )
+        .await
+        .unwrap()
+        .before_evaluation(|store| *store.data_mut() += 1);
+
+        // Get an instance without waiting for it, if one is available
+        let get_now = || async {
+            tokio::select! {
+                biased;
+                instance = pool.get() => Some(instance),
+                () = std::future::ready(()) => None,
+            }
+        };
+
+        // The first and third instances are busy, so the next turn goes to
+        // the second one instead of waiting for the first one
+        let first = get_now().await.unwrap();
+        drop(get_now().await.unwrap());
+        let third = get_now().await.unwrap();
+        drop(get_now().await.unwrap());
+
+        // Once they are all busy, it waits for the next one
+        let second = get_now().await.unwrap();
+        assert!(get_now().await.is_none());
+        drop((first, second, third));
+        assert_eq!(evaluations(&pool).await, [1, 3, 1]);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Engine configuration using the wasmtime pooling instance allocator
+
+use wasmtime::{Config, InstanceAllocationStrategy, PoolingAllocationConfig};
+
+/// The number of unused slots kept warm, per kind of resource, so that
+/// instantiating after a burst doesn't have to map memory again
+const MAX_UNUSED_WARM_SLOTS: u32 = 16;
+
+/// Get an engine configuration suited to running up to `max_instances`
+/// policy instances at the same time, with wasmtime's pooling instance
+/// allocator.
+///
+/// The pooling allocator reserves the instance, table and stack slots upfront,
+/// and reuses them when an instance is dropped, which makes instantiating a
+/// policy cheaper under load than mapping new memory each time.
+///
+/// Each policy instance uses one instance, one table and one async stack, so
+/// the pool is sized for exactly `max_instances` of each. Instantiating more
+/// policies than that at the same time fails until one of them is dropped.
+/// The linear memory of OPA modules is imported, and created by the
+/// [`Runtime`](crate::Runtime) outside of the pool, so no memory slot is
+/// reserved. The configuration also enables async support, which the runtime
+/// requires.
+#[must_use]
+pub fn engine_config_for_pooling(max_instances: u32) -> Config {
+    let mut pooling = PoolingAllocationConfig::default();
+    pooling
+        .total_core_instances(max_instances)
+        .total_memories(0)
+        .total_tables(max_instances)
+        .total_stacks(max_instances)
+        .max_unused_warm_slots(MAX_UNUSED_WARM_SLOTS.min(max_instances));
+
+    let mut config = Config::new();
+    config
+        .async_support(true)
+        .allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Engine, Instance, Module, Store};
+
+    use super::*;
+
+    /// The smallest valid WASM module
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[tokio::test]
+    async fn instances_come_from_the_pool() {
+        let engine = Engine::new(&engine_config_for_pooling(1)).unwrap();
+        let module = Module::new(&engine, EMPTY_MODULE).unwrap();
+
+        let mut store = Store::new(&engine, ());
+        Instance::new_async(&mut store, &module, &[]).await.unwrap();
+
+        // The only instance slot of the pool is taken
+        let mut other = Store::new(&engine, ());
+        assert!(Instance::new_async(&mut other, &module, &[]).await.is_err());
+
+        // Dropping the store gives the slot back
+        drop(store);
+        Instance::new_async(&mut other, &module, &[]).await.unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A router implementing a subset of the OPA REST API, so that OPA clients
+//! can talk to services embedding policies
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{EvaluationContext, PolicyPool, PooledPolicy};
+
+/// A subset of the OPA REST API, evaluating the entrypoints of a
+/// [`PolicyPool`]:
+///
+///  - `GET /v1/data/{path}` and `POST /v1/data/{path}` evaluate the entrypoint
+///    matching the path, with the `input` from the URL-encoded JSON `input`
+///    query parameter or from the body if any. `/v1/data` evaluates the
+///    entrypoint with an empty name, if the policy has one
+///  - `GET /v1/policies` and `GET /v1/policies/{id}` list the policy modules
+///    added with [`RestApi::with_policy`], read-only
+///  - `GET /health` always succeeds
+///
+/// The [`Router`] it builds is a [`tower_service::Service`], which can be
+/// served with axum, or with hyper directly.
+pub struct RestApi<C, T = ()> {
+    /// The instances evaluating the requests
+    pool: Arc<PolicyPool<C, T>>,
+
+    /// The source of the policy modules, by ID
+    policies: BTreeMap<String, String>,
+}
+
+impl<C, T> std::fmt::Debug for RestApi<C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RestApi")
+            .field("instances", &self.pool.size())
+            .field("policies", &self.policies.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// The state shared by the handlers
+struct Shared<C, T> {
+    /// The instances evaluating the requests
+    pool: Arc<PolicyPool<C, T>>,
+
+    /// The source of the policy modules, by ID
+    policies: BTreeMap<String, String>,
+}
+
+impl<C, T> RestApi<C, T>
+where
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    /// Serve the entrypoints of the policies in the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>) -> Self {
+        Self {
+            pool,
+            policies: BTreeMap::new(),
+        }
+    }
+
+    /// List a policy module under `/v1/policies`, with its Rego source
+    #[must_use]
+    pub fn with_policy(mut self, id: impl Into<String>, raw: impl Into<String>) -> Self {
+        self.policies.insert(id.into(), raw.into());
+        self
+    }
+
+    /// Build the router, to serve as-is or to nest in an existing one
+    pub fn router(self) -> Router {
+        let shared = Arc::new(Shared {
+            pool: self.pool,
+            policies: self.policies,
+        });
+
+        Router::new()
+            .route("/v1/data", get(get_data::<C, T>).post(post_data::<C, T>))
+            .route(
+                "/v1/data/*path",
+                get(get_data::<C, T>).post(post_data::<C, T>),
+            )
+            .route("/v1/policies", get(list_policies::<C, T>))
+            .route("/v1/policies/*id", get(get_policy::<C, T>))
+            .route("/health", get(health))
+            .with_state(shared)
+    }
+}
+
+/// The query parameters of a `GET /v1/data/{path}` request
+#[derive(Deserialize)]
+struct DataQuery {
+    /// The input document, as JSON
+    input: Option<String>,
+}
+
+/// The body of a `POST /v1/data/{path}` request
+#[derive(Deserialize, Default)]
+struct DataRequest {
+    /// The input document
+    #[serde(default)]
+    input: Option<serde_json::Value>,
+}
+
+/// An error, formatted like the OPA server does
+struct ApiError {
+    /// The HTTP status code
+    status: StatusCode,
+
+    /// The OPA error code
+    code: &'static str,
+
+    /// A human-readable message
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "code": self.code,
+            "message": self.message,
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Evaluate the entrypoint matching the path, and format the result like
+/// the OPA server does
+async fn evaluate<C, T>(
+    pool: &PolicyPool<C, T>,
+    path: &str,
+    input: Option<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, ApiError>
+where
+    C: EvaluationContext,
+    T: Send,
+{
+    let entrypoint = path.trim_matches('/');
+    let mut instance = pool.get().await;
+    let PooledPolicy { store, policy } = &mut *instance;
+
+    if !policy.entrypoints().contains(entrypoint) {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            code: "resource_not_found",
+            message: format!("no entrypoint named {entrypoint:?}"),
+        });
+    }
+
+    let input = input.unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::default()));
+    let result: serde_json::Value =
+        policy
+            .evaluate(store, entrypoint, &input)
+            .await
+            .map_err(|error| ApiError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                code: "internal_error",
+                message: format!("{error:#}"),
+            })?;
+
+    // The policy returns a result set, which is empty if the decision is undefined
+    let body = match result.get(0).and_then(|r| r.get("result")) {
+        Some(result) => serde_json::json!({ "result": result }),
+        None => serde_json::json!({}),
+    };
+
+    Ok(Json(body))
+}
+
+/// Handle `GET /v1/data/{path}`, evaluating with the input from the query
+/// parameters if any
+async fn get_data<C, T>(
+    State(shared): State<Arc<Shared<C, T>>>,
+    path: Option<Path<String>>,
+    Query(query): Query<DataQuery>,
+) -> Result<Json<serde_json::Value>, ApiError>
+where
+    C: EvaluationContext,
+    T: Send,
+{
+    let input = query
+        .input
+        .map(|input| serde_json::from_str(&input))
+        .transpose()
+        .map_err(|error| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_parameter",
+            message: format!("invalid input parameter: {error}"),
+        })?;
+    let path = path.map(|Path(path)| path).unwrap_or_default();
+    evaluate(&shared.pool, &path, input).await
+}
+
+/// Handle `POST /v1/data/{path}`, evaluating with the input from the body
+async fn post_data<C, T>(
+    State(shared): State<Arc<Shared<C, T>>>,
+    path: Option<Path<String>>,
+    body: Option<Json<DataRequest>>,
+) -> Result<Json<serde_json::Value>, ApiError>
+where
+    C: EvaluationContext,
+    T: Send,
+{
+    let Json(body) = body.unwrap_or_default();
+    let path = path.map(|Path(path)| path).unwrap_or_default();
+    evaluate(&shared.pool, &path, body.input).await
+}
+
+/// Handle `GET /v1/policies`
+async fn list_policies<C, T>(State(shared): State<Arc<Shared<C, T>>>) -> Json<serde_json::Value> {
+    let policies: Vec<_> = shared
+        .policies
+        .iter()
+        .map(|(id, raw)| serde_json::json!({ "id": id, "raw": raw }))
+        .collect();
+    Json(serde_json::json!({ "result": policies }))
+}
+
+/// Handle `GET /v1/policies/{id}`
+async fn get_policy<C, T>(
+    State(shared): State<Arc<Shared<C, T>>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let id = id.trim_start_matches('/');
+    let raw = shared.policies.get(id).ok_or_else(|| ApiError {
+        status: StatusCode::NOT_FOUND,
+        code: "resource_not_found",
+        message: format!("storage_not_found_error: policy id {id:?}"),
+    })?;
+    Ok(Json(
+        serde_json::json!({ "result": { "id": id, "raw": raw } }),
+    ))
+}
+
+/// Handle `GET /health`
+async fn health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({}))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+    use wasmtime::Store;
+
+    use super::*;
+    use crate::{stub::stub_module, DefaultContext, EngineConfig};
+
+    /// Build a router over a policy whose `echo` entrypoint, and its root one,
+    /// return the input as their result set
+    async fn router() -> Router {
+        let engine = EngineConfig::new().build().unwrap();
+        let module = stub_module(
+            &engine,
+            "{}",
+            r#"{"echo":0,"":1}"#,
+            "",
+            "global.get $input
+             global.set $result",
+        );
+        let pool = PolicyPool::instantiate_with_stores(
+            &module,
+            &(),
+            NonZeroUsize::MIN,
+            || Store::new(&engine, ()),
+            DefaultContext::default,
+        )
+        .await
+        .unwrap();
+
+        RestApi::new(Arc::new(pool))
+            .with_policy("example.rego", "package example")
+            .router()
+    }
+
+    /// Send a request to the router, and get the status and JSON body of the
+    /// response
+    async fn send(request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router().await.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post(uri: &str, body: &serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn data_is_evaluated() {
+        // `[{"result":1}]`, URL-encoded
+        let (status, body) = send(get("/v1/data/echo?input=%5B%7B%22result%22%3A1%7D%5D")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "result": 1 }));
+
+        let input = serde_json::json!({ "input": [{ "result": { "allow": true } }] });
+        let (status, body) = send(post("/v1/data/echo", &input)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "result": { "allow": true } }));
+
+        let input = serde_json::json!({ "input": [{ "result": "root" }] });
+        let (status, body) = send(post("/v1/data", &input)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "result": "root" }));
+
+        let (status, _) = send(get("/v1/data/echo?input=%5B")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn undefined_results_are_empty() {
+        let input = serde_json::json!({ "input": [] });
+        let (status, body) = send(post("/v1/data/echo", &input)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({}));
+
+        // Without an input, the policy gets an empty object, which is no result
+        let (status, body) = send(get("/v1/data/echo")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn unknown_entrypoints_are_not_found() {
+        let (status, body) = send(get("/v1/data/missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "resource_not_found");
+    }
+
+    #[tokio::test]
+    async fn policies_are_listed() {
+        let (status, body) = send(get("/v1/policies")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({ "result": [{ "id": "example.rego", "raw": "package example" }] })
+        );
+
+        let (status, body) = send(get("/v1/policies/example.rego")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({ "result": { "id": "example.rego", "raw": "package example" } })
+        );
+
+        let (status, body) = send(get("/v1/policies/missing.rego")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "resource_not_found");
+    }
+
+    #[tokio::test]
+    async fn health_succeeds() {
+        let (status, body) = send(get("/health")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({}));
+    }
+}
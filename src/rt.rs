@@ -0,0 +1,181 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers abstracting the async runtime, so that policies can be loaded and
+//! evaluated on any executor, or from synchronous code
+
+#[cfg(feature = "loader")]
+use std::path::PathBuf;
+#[cfg(any(feature = "http-builtins", all(feature = "loader", feature = "fast")))]
+use std::time::Duration;
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::Thread,
+};
+
+/// Run a blocking function on a thread of its own, and wait for its result
+/// without blocking the executor
+#[cfg(any(feature = "http-builtins", feature = "loader"))]
+pub(crate) async fn unblock<T, F>(f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        // The receiver is gone only if the future was dropped
+        let _ = tx.send(f());
+    });
+
+    rx.await
+        .map_err(|_| anyhow::anyhow!("the blocking task panicked"))
+}
+
+/// Wait for the given duration, with the tokio timer in a tokio runtime, or
+/// on a helper thread with other executors
+#[cfg(any(feature = "http-builtins", all(feature = "loader", feature = "fast")))]
+pub(crate) async fn sleep(duration: Duration) {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        tokio::time::sleep(duration).await;
+    } else {
+        // Only fails if the helper thread panicked, which `sleep` does not
+        let _ = unblock(move || std::thread::sleep(duration)).await;
+    }
+}
+
+/// Read a whole file, with `tokio::fs` in a tokio runtime, or on a helper
+/// thread with other executors
+#[cfg(feature = "loader")]
+pub(crate) async fn read_file(path: PathBuf) -> anyhow::Result<Vec<u8>> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        Ok(tokio::fs::read(path).await?)
+    } else {
+        Ok(unblock(move || std::fs::read(path)).await??)
+    }
+}
+
+/// Wakes the thread blocked on a future
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread, parking it while the
+/// future is pending.
+///
+/// This is enough for the futures of this crate, as wasmtime drives the
+/// policies on its own fiber, and pairs with [`BlockingHttpContext`] for
+/// policies calling `http.send`, as [`DefaultContext`] sends them with the
+/// async reqwest client, which needs tokio:
+///
+/// ```ignore
+/// let context = BlockingHttpContext::default();
+/// let runtime = block_on(Runtime::new(&mut store, &module))?;
+/// let policy = block_on(runtime.with_data(&mut store, &data))?;
+/// let result: serde_json::Value = block_on(policy.evaluate(&mut store, "authz/allow", &input))?;
+/// ```
+///
+/// Futures relying on tokio resources, like its timer or its sockets, panic
+/// when polled this way.
+///
+/// [`BlockingHttpContext`]: crate::BlockingHttpContext
+/// [`DefaultContext`]: crate::DefaultContext
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpers_work_without_tokio() {
+        assert_eq!(block_on(std::future::ready(42)), 42);
+
+        #[cfg(any(feature = "http-builtins", feature = "loader"))]
+        assert_eq!(block_on(unblock(|| 42)).unwrap(), 42);
+
+        #[cfg(any(feature = "http-builtins", all(feature = "loader", feature = "fast")))]
+        {
+            use std::time::Instant;
+
+            let start = Instant::now();
+            block_on(sleep(Duration::from_millis(10)));
+            assert!(start.elapsed() >= Duration::from_millis(10));
+        }
+
+        #[cfg(feature = "loader")]
+        {
+            let path = std::env::temp_dir().join(format!("opa-wasm-rt-{}", std::process::id()));
+            std::fs::write(&path, b"hello").unwrap();
+            let content = block_on(read_file(path.clone())).unwrap();
+            std::fs::remove_file(path).unwrap();
+            assert_eq!(content, b"hello");
+        }
+    }
+
+    #[cfg(feature = "blocking-http-client")]
+    #[test]
+    fn requests_are_sent_without_tokio() {
+        use std::{
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+        };
+
+        use crate::{BlockingHttpContext, EvaluationContext, HttpSendOptions, ProxyConfig};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/path", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .unwrap();
+            request_line
+        });
+
+        let mut context = BlockingHttpContext::default().proxy(ProxyConfig::new());
+        let request = http::Request::get(url).body(String::new()).unwrap();
+        let response = block_on(context.send_http(request, HttpSendOptions::default())).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "ok");
+
+        assert!(server.join().unwrap().starts_with("GET /path HTTP/1.1"));
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access to secrets from builtins, without embedding them in the policy data
+
+use std::{collections::HashMap, hash::BuildHasher};
+
+use anyhow::Result;
+
+/// A secret value.
+///
+/// Its [`Debug`] implementation does not reveal the value, so that it does not
+/// leak in traces and logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wrap a secret value
+    #[must_use]
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Get the secret value
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+/// A source of secrets, which builtins can query by name through
+/// [`EvaluationContext::secret`](crate::EvaluationContext::secret)
+pub trait SecretsProvider: Send + Sync + 'static {
+    /// Get the secret with the given name, if it exists
+    ///
+    /// # Errors
+    ///
+    /// If the secret could not be fetched
+    fn get(&self, name: &str) -> Result<Option<Secret>>;
+}
+
+impl<S: BuildHasher + Send + Sync + 'static> SecretsProvider for HashMap<String, Secret, S> {
+    fn get(&self, name: &str) -> Result<Option<Secret>> {
+        Ok(HashMap::get(self, name).cloned())
+    }
+}
+
+impl<S: BuildHasher + Send + Sync + 'static> SecretsProvider for HashMap<String, String, S> {
+    fn get(&self, name: &str) -> Result<Option<Secret>> {
+        Ok(HashMap::get(self, name).cloned().map(Secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DefaultContext, EvaluationContext};
+
+    #[test]
+    fn secrets_are_redacted_and_resolved_through_the_context() {
+        let secrets = HashMap::from([("token".to_owned(), "hunter2".to_owned())]);
+        let ctx = DefaultContext::builder().secrets_provider(secrets).build();
+
+        let secret = ctx.secret("token").unwrap().unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+        assert!(!format!("{secret:?}").contains("hunter2"));
+        assert!(ctx.secret("missing").unwrap().is_none());
+
+        assert!(DefaultContext::default().secret("token").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`tower_service::Service`] evaluating a policy, to use policy decisions
+//! in tower middleware stacks
+
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use wasmtime::Store;
+
+use crate::{EvaluationContext, Policy, PolicyPool, PooledPolicy};
+
+/// The decision of a policy, as returned by [`PolicyService`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Decision<O> {
+    /// The entrypoint which was evaluated
+    pub entrypoint: Arc<str>,
+
+    /// The result of the evaluation
+    pub result: O,
+}
+
+/// A [`tower_service::Service`] evaluating an entrypoint with the requests as
+/// the input, on the instances of a [`PolicyPool`].
+///
+/// The service is always ready: requests wait for an instance to be available
+/// when they are called. Timeouts, retries and load shedding can be applied
+/// with the usual tower middlewares.
+pub struct PolicyService<I, O, C, T = ()> {
+    /// The instances evaluating the requests
+    pool: Arc<PolicyPool<C, T>>,
+
+    /// The entrypoint to evaluate
+    entrypoint: Arc<str>,
+
+    /// Marker for the input and output types
+    _marker: PhantomData<fn(I) -> O>,
+}
+
+impl<I, O, C, T> Clone for PolicyService<I, O, C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: Arc::clone(&self.pool),
+            entrypoint: Arc::clone(&self.entrypoint),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<I, O, C, T> std::fmt::Debug for PolicyService<I, O, C, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyService")
+            .field("entrypoint", &self.entrypoint)
+            .field("instances", &self.pool.size())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I, O, C, T> PolicyService<I, O, C, T> {
+    /// Create a service evaluating the given entrypoint on the instances of
+    /// the pool
+    #[must_use]
+    pub fn new(pool: Arc<PolicyPool<C, T>>, entrypoint: impl Into<Arc<str>>) -> Self {
+        Self {
+            pool,
+            entrypoint: entrypoint.into(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a service evaluating the given entrypoint on a single policy
+    /// instance. Requests are evaluated one at a time.
+    #[must_use]
+    pub fn from_policy(
+        store: Store<T>,
+        policy: Policy<C>,
+        entrypoint: impl Into<Arc<str>>,
+    ) -> Self {
+        let pool = PolicyPool::from(PooledPolicy { store, policy });
+        Self::new(Arc::new(pool), entrypoint)
+    }
+
+    /// The entrypoint evaluated by the service
+    #[must_use]
+    pub fn entrypoint(&self) -> &str {
+        &self.entrypoint
+    }
+}
+
+impl<I, O, C, T> tower_service::Service<I> for PolicyService<I, O, C, T>
+where
+    I: serde::Serialize + Send + Sync + 'static,
+    O: for<'de> serde::Deserialize<'de> + Send + 'static,
+    C: EvaluationContext + 'static,
+    T: Send + 'static,
+{
+    type Response = Decision<O>;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Decision<O>>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, input: I) -> Self::Future {
+        let pool = Arc::clone(&self.pool);
+        let entrypoint = Arc::clone(&self.entrypoint);
+        Box::pin(async move {
+            let result = pool.evaluate(&entrypoint, &input).await?;
+            Ok(Decision { entrypoint, result })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DefaultContext;
+
+    /// Check that a type is a [`tower_service::Service`] usable behind tower
+    /// middlewares, which need the futures to be `Send` and the errors to be
+    /// boxable
+    fn assert_service<S>()
+    where
+        S: tower_service::Service<serde_json::Value> + Clone + Send + 'static,
+        S::Future: Send,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+    }
+
+    #[test]
+    fn policies_are_services() {
+        assert_service::<PolicyService<serde_json::Value, serde_json::Value, DefaultContext>>();
+    }
+}
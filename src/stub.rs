@@ -0,0 +1,181 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stub policy module, implementing just enough of the ABI to be loaded,
+//! for the unit tests which don't need a policy compiled by OPA
+
+use anyhow::Result;
+use wasmtime::{Engine, Module};
+
+use crate::{EvaluationFailure, FailureCause};
+
+/// Build a module with the given `builtins` and `entrypoints` maps, whose
+/// `eval` function runs the `eval` instructions.
+///
+/// Values are parsed to the address of their JSON text. The `$entrypoint`,
+/// `$data` and `$input` globals hold the entrypoint being evaluated and the
+/// documents it is evaluated with, and `eval` sets the `$result` global to the
+/// address of the result set, which is an empty one by default. The `fields`
+/// are added to the module, for example imports, or data segments starting at
+/// address 1024.
+///
+/// # Panics
+///
+/// If the module is not valid
+pub(crate) fn stub_wasm(builtins: &str, entrypoints: &str, fields: &str, eval: &str) -> Vec<u8> {
+    let escape = |json: &str| json.replace('"', "\\\"");
+    let wat = format!(
+        r#"
+        (module
+          (import "env" "memory" (memory 2))
+          {fields}
+          (global $heap (mut i32) (i32.const 4096))
+          (global $entrypoint (mut i32) (i32.const 0))
+          (global $data (mut i32) (i32.const 0))
+          (global $input (mut i32) (i32.const 0))
+          (global $result (mut i32) (i32.const 24))
+          (global (export "opa_wasm_abi_version") i32 (i32.const 1))
+          (global (export "opa_wasm_abi_minor_version") i32 (i32.const 1))
+          (data (i32.const 24) "[]\00")
+          (data (i32.const 32) "{builtins}\00")
+          (data (i32.const 512) "{entrypoints}\00")
+          (func (export "opa_heap_ptr_get") (result i32) global.get $heap)
+          (func (export "opa_heap_ptr_set") (param i32) local.get 0 global.set $heap)
+          (func (export "opa_malloc") (param i32) (result i32)
+            global.get $heap
+            global.get $heap
+            local.get 0
+            i32.add
+            global.set $heap)
+          (func (export "opa_free") (param i32))
+          (func (export "opa_json_parse") (param i32 i32) (result i32) local.get 0)
+          (func (export "opa_json_dump") (param i32) (result i32) local.get 0)
+          (func (export "builtins") (result i32) i32.const 32)
+          (func (export "entrypoints") (result i32) i32.const 512)
+          (func (export "opa_eval_ctx_new") (result i32) i32.const 0)
+          (func (export "opa_eval_ctx_set_input") (param i32 i32)
+            local.get 1
+            global.set $input)
+          (func (export "opa_eval_ctx_set_data") (param i32 i32)
+            local.get 1
+            global.set $data)
+          (func (export "opa_eval_ctx_set_entrypoint") (param i32 i32)
+            local.get 1
+            global.set $entrypoint)
+          (func (export "opa_eval_ctx_get_result") (param i32) (result i32) global.get $result)
+          (func (export "eval") (param i32) (result i32)
+            {eval}
+            i32.const 0))
+        "#,
+        builtins = escape(builtins),
+        entrypoints = escape(entrypoints),
+    );
+
+    wat::parse_str(wat).unwrap()
+}
+
+/// Compile the module built by [`stub_wasm`] with the given engine
+///
+/// # Panics
+///
+/// If the module is not valid
+pub(crate) fn stub_module(
+    engine: &Engine,
+    builtins: &str,
+    entrypoints: &str,
+    fields: &str,
+    eval: &str,
+) -> Module {
+    Module::new(engine, stub_wasm(builtins, entrypoints, fields, eval)).unwrap()
+}
+
+/// A policy of one of the backends, loaded from a module built by
+/// [`stub_wasm`], for the tests shared by all the backends
+pub(crate) trait StubPolicy: Sized {
+    /// Load the module without data, with the given revision, failing
+    /// evaluations on builtin errors if `strict_builtin_errors` is set
+    async fn load(wasm: &[u8], revision: &str, strict_builtin_errors: bool) -> Result<Self>;
+
+    /// Evaluate an entrypoint with an empty input
+    async fn evaluate(&mut self, entrypoint: &str) -> Result<serde_json::Value>;
+}
+
+/// Build a module whose `trap` entrypoint traps, and whose `abort` one aborts
+pub(crate) fn failing_wasm() -> Vec<u8> {
+    stub_wasm(
+        "{}",
+        r#"{"trap":0,"abort":1}"#,
+        r#"
+          (import "env" "opa_abort" (func $abort (param i32)))
+          (data (i32.const 1024) "conflicting values\00")
+        "#,
+        "global.get $entrypoint
+         if
+           i32.const 1024
+           call $abort
+         end
+         unreachable",
+    )
+}
+
+/// Check that builtin errors make the expression calling the builtin
+/// undefined, unless strict builtin errors are enabled
+pub(crate) async fn builtin_errors_are_undefined_unless_strict<P: StubPolicy>() {
+    // The `test` entrypoint calls `indexof_n` without arguments, which fails.
+    // It is `true` if the builtin returned a value, and undefined otherwise.
+    let wasm = stub_wasm(
+        r#"{"indexof_n":0}"#,
+        r#"{"test":0}"#,
+        r#"
+          (import "env" "opa_builtin0" (func $builtin0 (param i32 i32) (result i32)))
+          (data (i32.const 1024) "[{\"result\":true}]\00")
+        "#,
+        "i32.const 0
+         i32.const 0
+         call $builtin0
+         if
+           i32.const 1024
+           global.set $result
+         end",
+    );
+
+    let mut policy = P::load(&wasm, "1", false).await.unwrap();
+    let result = policy.evaluate("test").await.unwrap();
+    assert_eq!(result, serde_json::json!([]));
+
+    let mut policy = P::load(&wasm, "1", true).await.unwrap();
+    let error = policy.evaluate("test").await.unwrap_err();
+    assert!(format!("{error:#}").ends_with("invalid arguments"));
+}
+
+/// Check that traps and aborts are described by an [`EvaluationFailure`]
+pub(crate) async fn failures_are_described<P: StubPolicy>() {
+    let mut policy = P::load(&failing_wasm(), "42", false).await.unwrap();
+
+    let error = policy.evaluate("trap").await.unwrap_err();
+    let failure = error.downcast_ref::<EvaluationFailure>().unwrap();
+    assert_eq!(failure.entrypoint, "trap");
+    assert_eq!(failure.revision.as_deref(), Some("42"));
+    assert_eq!(
+        failure.cause,
+        FailureCause::Trap(wasmtime::Trap::UnreachableCodeReached)
+    );
+
+    let error = policy.evaluate("abort").await.unwrap_err();
+    let failure = error.downcast_ref::<EvaluationFailure>().unwrap();
+    assert_eq!(
+        failure.cause,
+        FailureCause::Abort("conflicting values".to_owned())
+    );
+}
@@ -0,0 +1,397 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to test policies, by evaluating the bundles built from them with a
+//! deterministic [`TestContext`], and snapshotting the results.
+//!
+//! Bundles built with the `test_*` rules of their `*_test.rego` files as
+//! entrypoints can also run those, like `opa test` does, with
+//! [`TestBundle::run_tests`].
+//!
+//! ```ignore
+//! use opa_wasm::testing::{read_json, TestBundle};
+//!
+//! #[tokio::test]
+//! async fn admins_are_allowed() {
+//!     let bundle = TestBundle::load("policies/authz.tar.gz").await.unwrap();
+//!     let input = read_json("policies/inputs/admin.json").await.unwrap();
+//!     insta::assert_yaml_snapshot!(bundle.evaluate("authz/allow", &input).await.unwrap());
+//! }
+//! ```
+//!
+//! The [`assert_policy_snapshot!`](crate::assert_policy_snapshot) macro does
+//! all of this in one line.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use wasmtime::{Engine, Module, Store};
+
+use crate::{EngineConfig, Policy, Runtime, TestContext};
+
+/// A compiled policy, evaluated with a fresh [`TestContext`] each time, so
+/// that the results only depend on the input and the data
+pub struct TestBundle {
+    /// The engine which compiled the module
+    engine: Engine,
+
+    /// The compiled policy
+    module: Module,
+
+    /// The data document, if any
+    data: Option<serde_json::Value>,
+}
+
+impl std::fmt::Debug for TestBundle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TestBundle")
+            .field("data", &self.data)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TestBundle {
+    /// Read and compile the policy of the bundle at the given path
+    ///
+    /// # Errors
+    ///
+    /// If the bundle could not be read, or its policy could not be compiled
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let module = crate::read_bundle(path)
+            .await
+            .with_context(|| format!("could not read the bundle {}", path.display()))?;
+        Self::from_module(&module)
+    }
+
+    /// Compile the given WASM module
+    ///
+    /// # Errors
+    ///
+    /// If the module could not be compiled
+    pub fn from_module(module: &[u8]) -> Result<Self> {
+        let engine = EngineConfig::new().build()?;
+        let module = Module::new(&engine, module)?;
+        Ok(Self {
+            engine,
+            module,
+            data: None,
+        })
+    }
+
+    /// Evaluate the policy with the given data document
+    #[must_use]
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Evaluate the entrypoint with the given input, and return the result
+    /// set
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be instantiated, or if the evaluation failed
+    pub async fn evaluate<V: serde::Serialize>(
+        &self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        self.evaluate_with_context(TestContext::default(), entrypoint, input)
+            .await
+    }
+
+    /// Evaluate the entrypoint with the given input and context, for example
+    /// one with mocked `http.send` responses, and return the result set
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be instantiated, or if the evaluation failed
+    pub async fn evaluate_with_context<V: serde::Serialize>(
+        &self,
+        context: TestContext,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<serde_json::Value> {
+        let (mut store, policy) = self.instantiate(context).await?;
+        policy.evaluate(&mut store, entrypoint, input).await
+    }
+
+    /// Run the Rego unit tests compiled in the policy: the entrypoints whose
+    /// rule name starts with `test_`, each evaluated with an empty input.
+    /// Tests pass if they evaluate to `true`, and the `todo_test_` ones are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// If the policy could not be instantiated. Tests which fail to evaluate
+    /// are reported, not returned as errors.
+    pub async fn run_tests(&self) -> Result<RegoTestReport> {
+        let (_, policy) = self.instantiate(TestContext::default()).await?;
+        let mut entrypoints: Vec<String> = policy
+            .entrypoints()
+            .into_iter()
+            .filter(|entrypoint| {
+                let rule = entrypoint.rsplit('/').next().unwrap_or(entrypoint);
+                rule.starts_with("test_") || rule.starts_with("todo_test_")
+            })
+            .map(ToOwned::to_owned)
+            .collect();
+        entrypoints.sort_unstable();
+
+        let mut tests = Vec::with_capacity(entrypoints.len());
+        for entrypoint in entrypoints {
+            if entrypoint
+                .rsplit('/')
+                .next()
+                .is_some_and(|rule| rule.starts_with("todo_"))
+            {
+                tests.push(RegoTest {
+                    entrypoint,
+                    outcome: TestOutcome::Skipped,
+                    duration: Duration::ZERO,
+                });
+                continue;
+            }
+
+            let start = Instant::now();
+            let result = self
+                .evaluate(
+                    &entrypoint,
+                    &serde_json::Value::Object(serde_json::Map::new()),
+                )
+                .await;
+            let duration = start.elapsed();
+            let outcome = match result {
+                Ok(result_set) if result_set[0]["result"] == serde_json::Value::Bool(true) => {
+                    TestOutcome::Passed
+                }
+                Ok(_) => TestOutcome::Failed,
+                Err(error) => TestOutcome::Error(format!("{error:#}")),
+            };
+            tests.push(RegoTest {
+                entrypoint,
+                outcome,
+                duration,
+            });
+        }
+
+        Ok(RegoTestReport { tests })
+    }
+
+    /// Instantiate the policy in a new store, with its data
+    async fn instantiate(&self, context: TestContext) -> Result<(Store<()>, Policy<TestContext>)> {
+        let mut store = Store::new(&self.engine, ());
+        let runtime =
+            Runtime::new_with_evaluation_context(&mut store, &self.module, context).await?;
+        let policy = match &self.data {
+            Some(data) => runtime.with_data(&mut store, data).await?,
+            None => runtime.without_data(&mut store).await?,
+        };
+        Ok((store, policy))
+    }
+}
+
+/// What happened to a Rego unit test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    /// The test evaluated to `true`
+    Passed,
+
+    /// The test evaluated to something else, or was undefined
+    Failed,
+
+    /// The evaluation failed, with the given error
+    Error(String),
+
+    /// The test is a `todo_test_` one, which was not evaluated
+    Skipped,
+}
+
+/// The outcome of a Rego unit test
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RegoTest {
+    /// The entrypoint of the test
+    pub entrypoint: String,
+
+    /// What happened to the test
+    pub outcome: TestOutcome,
+
+    /// How long the evaluation took
+    pub duration: Duration,
+}
+
+/// The outcomes of the Rego unit tests of a policy, printed like `opa test`
+/// does
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RegoTestReport {
+    /// The outcome of each test, sorted by entrypoint
+    pub tests: Vec<RegoTest>,
+}
+
+impl RegoTestReport {
+    /// Whether no test failed, or failed to evaluate
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.tests
+            .iter()
+            .all(|test| matches!(test.outcome, TestOutcome::Passed | TestOutcome::Skipped))
+    }
+
+    /// The number of tests which passed
+    #[must_use]
+    pub fn passed(&self) -> usize {
+        self.tests
+            .iter()
+            .filter(|test| test.outcome == TestOutcome::Passed)
+            .count()
+    }
+}
+
+impl std::fmt::Display for RegoTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for test in &self.tests {
+            let entrypoint = &test.entrypoint;
+            let duration = test.duration;
+            match &test.outcome {
+                TestOutcome::Passed => writeln!(f, "PASS {entrypoint} ({duration:?})")?,
+                TestOutcome::Failed => writeln!(f, "FAIL {entrypoint} ({duration:?})")?,
+                TestOutcome::Error(error) => writeln!(f, "ERROR {entrypoint}: {error}")?,
+                TestOutcome::Skipped => writeln!(f, "SKIPPED {entrypoint}")?,
+            }
+        }
+        write!(f, "PASS: {}/{}", self.passed(), self.tests.len())
+    }
+}
+
+/// Read a JSON document, like an input or a data fixture
+///
+/// # Errors
+///
+/// If the file could not be read, or is not valid JSON
+pub async fn read_json(path: impl AsRef<Path>) -> Result<serde_json::Value> {
+    let path = path.as_ref();
+    let bytes = crate::rt::read_file(path.to_owned())
+        .await
+        .with_context(|| format!("could not read {}", path.display()))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("invalid JSON in {}", path.display()))
+}
+
+/// Evaluate an entrypoint of a bundle with the given input, with a fresh
+/// [`TestContext`], and snapshot the result set with [`insta`].
+///
+/// The bundle is loaded from the given path, and the input is anything which
+/// serializes to JSON. The snapshot is described with the entrypoint and the
+/// input, and named after the test function like other insta snapshots. It
+/// expands to an `.await`, so it is used from async tests:
+///
+/// ```ignore
+/// #[tokio::test]
+/// async fn admins_are_allowed() {
+///     opa_wasm::assert_policy_snapshot!(
+///         "policies/authz.tar.gz",
+///         "authz/allow",
+///         serde_json::json!({ "user": "admin" })
+///     );
+/// }
+/// ```
+///
+/// # Panics
+///
+/// If the bundle could not be loaded, if the input could not be serialized,
+/// if the evaluation failed, or if the result doesn't match the snapshot.
+///
+/// [`insta`]: https://insta.rs
+#[macro_export]
+macro_rules! assert_policy_snapshot {
+    ($bundle:expr, $entrypoint:expr, $input:expr $(,)?) => {{
+        let entrypoint: &str = $entrypoint;
+        let input = $crate::__private::serde_json::to_value(&$input)
+            .expect("could not serialize the input");
+        let bundle = $crate::testing::TestBundle::load($bundle)
+            .await
+            .expect("could not load the bundle");
+        let result = bundle
+            .evaluate(entrypoint, &input)
+            .await
+            .expect("could not evaluate the policy");
+        $crate::__private::insta::with_settings!({
+            description => entrypoint,
+            info => &input,
+            omit_expression => true,
+        }, {
+            $crate::__private::insta::assert_yaml_snapshot!(result);
+        });
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fixtures_are_checked() {
+        let error = TestBundle::load("missing.tar.gz").await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not read the bundle missing.tar.gz"
+        );
+
+        let error = read_json("missing.json").await.unwrap_err();
+        assert_eq!(error.to_string(), "could not read missing.json");
+
+        // An empty module compiles, but is not an OPA policy
+        let bundle = TestBundle::from_module(b"\0asm\x01\0\0\0").unwrap();
+        let error = bundle
+            .evaluate("allow", &serde_json::Value::Null)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "missing global opa_wasm_abi_version");
+        assert!(bundle.run_tests().await.is_err());
+    }
+
+    #[test]
+    fn test_reports_are_printed() {
+        let test = |entrypoint: &str, outcome| RegoTest {
+            entrypoint: entrypoint.to_owned(),
+            outcome,
+            duration: Duration::from_millis(1),
+        };
+        let mut report = RegoTestReport {
+            tests: vec![
+                test("authz/test_admin", TestOutcome::Passed),
+                test("authz/todo_test_guest", TestOutcome::Skipped),
+            ],
+        };
+        assert!(report.is_success());
+
+        report
+            .tests
+            .push(test("authz/test_user", TestOutcome::Failed));
+        report.tests.push(test(
+            "authz/test_http",
+            TestOutcome::Error("unknown builtin".to_owned()),
+        ));
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "PASS authz/test_admin (1ms)\nSKIPPED authz/todo_test_guest\nFAIL authz/test_user (1ms)\nERROR authz/test_http: unknown builtin\nPASS: 1/4"
+        );
+    }
+}
@@ -58,19 +58,6 @@ impl Heap {
     pub const fn end(&self) -> i32 {
         self.ptr + self.len
     }
-
-    /// Calculate the number of pages this heap allocation occupies
-    pub fn pages(&self) -> u64 {
-        let page_size = 64 * 1024;
-        let addr = self.end();
-        let page = addr / page_size;
-        // This is safe as the heap pointers will never be negative. We use i32 for
-        // convenience to avoid having to cast all the time.
-        #[allow(clippy::expect_used)]
-        if addr % page_size > 0 { page + 1 } else { page }
-            .try_into()
-            .expect("invalid heap address")
-    }
 }
 
 impl Drop for Heap {
@@ -87,19 +74,19 @@ impl Drop for Heap {
 pub struct NulStr(pub(crate) i32);
 
 impl NulStr {
-    /// Read the null-terminated string from the WASM memory
+    /// Read the null-terminated string from the WASM memory, borrowing it
+    /// instead of copying it
     pub fn read<'s, T: AsContext>(&self, store: &'s T, memory: &Memory) -> Result<&'s CStr> {
-        let mem = memory.data(store);
+        self.read_from(memory.data(store))
+    }
+
+    /// Read the null-terminated string from the bytes of the WASM memory
+    pub(crate) fn read_from<'m>(&self, mem: &'m [u8]) -> Result<&'m CStr> {
         let start: usize = self.0.try_into().context("invalid address")?;
         let mem = mem.get(start..).context("memory address out of bounds")?;
-        let nul = mem
-            .iter()
-            .position(|c| *c == 0)
-            .context("malformed string")?;
-        let mem = mem
-            .get(..=nul)
-            .context("issue while extracting nul-terminated string")?;
-        let res = CStr::from_bytes_with_nul(mem)?;
+        // This looks for the nul byte only once, unlike checking the slice
+        // with `CStr::from_bytes_with_nul` after finding it
+        let res = CStr::from_bytes_until_nul(mem).context("malformed string")?;
         Ok(res)
     }
 }
@@ -142,7 +129,7 @@ impl OpaError {
 }
 
 /// Represents the ABI version of a WASM OPA module
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AbiVersion {
     /// Version 1.0
     V1_0,
@@ -185,13 +172,13 @@ impl AbiVersion {
     }
 
     /// Create a new ABI version out of the minor and major version numbers.
-    fn new(major: i32, minor: i32) -> Result<Self> {
+    pub(crate) fn new(major: i32, minor: i32) -> Result<Self> {
         match (major, minor) {
             (1, 0) => Ok(Self::V1_0),
             (1, 1) => Ok(Self::V1_1),
             (1, 2) => Ok(Self::V1_2),
             (1, n @ 2..) => Ok(Self::V1_2Plus(n)),
-            (major, minor) => bail!("unsupported ABI version {}.{}", major, minor),
+            (major, minor) => bail!("unsupported ABI version {major}.{minor}"),
         }
     }
 
@@ -212,3 +199,22 @@ impl std::fmt::Display for AbiVersion {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nul_strs_are_bounded() {
+        let memory = b"hello\0world";
+        assert_eq!(NulStr(0).read_from(memory).unwrap().to_bytes(), b"hello");
+        assert_eq!(NulStr(5).read_from(memory).unwrap().to_bytes(), b"");
+
+        let error = NulStr(6).read_from(memory).unwrap_err();
+        assert_eq!(error.to_string(), "malformed string");
+        let error = NulStr(12).read_from(memory).unwrap_err();
+        assert_eq!(error.to_string(), "memory address out of bounds");
+        let error = NulStr(-1).read_from(memory).unwrap_err();
+        assert_eq!(error.to_string(), "invalid address");
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Preflight validation of policy modules, checking what loading them would
+//! check without compiling or instantiating them, to reject a bundle before
+//! it is rolled out
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use wasmparser::{DataKind, ExternalKind, Operator, Parser, Payload, TypeRef, Validator};
+
+use crate::{
+    funcs::{self, Func},
+    policy::MEMORY_PAGES,
+    types::AbiVersion,
+    BuiltinRegistry, DefaultContext, EvaluationContext,
+};
+
+/// The exports every policy module must have
+const REQUIRED_EXPORTS: &[&str] = &[
+    funcs::Eval::EXPORT,
+    funcs::Builtins::EXPORT,
+    funcs::Entrypoints::EXPORT,
+    funcs::OpaEvalCtxNew::EXPORT,
+    funcs::OpaEvalCtxSetInput::EXPORT,
+    funcs::OpaEvalCtxSetData::EXPORT,
+    funcs::OpaEvalCtxSetEntrypoint::EXPORT,
+    funcs::OpaEvalCtxGetResult::EXPORT,
+    funcs::OpaMalloc::EXPORT,
+    funcs::OpaFree::EXPORT,
+    funcs::OpaJsonParse::EXPORT,
+    funcs::OpaJsonDump::EXPORT,
+    funcs::OpaHeapPtrSet::EXPORT,
+    funcs::OpaHeapPtrGet::EXPORT,
+];
+
+/// The functions the runtime provides to policy modules, in the `env` module
+const PROVIDED_FUNCS: &[&str] = &[
+    "opa_abort",
+    "opa_println",
+    "opa_builtin0",
+    "opa_builtin1",
+    "opa_builtin2",
+    "opa_builtin3",
+    "opa_builtin4",
+];
+
+/// What [`validate`] found out about a policy module, and the problems which
+/// would make loading it fail
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// The ABI version of the module, if it could be read
+    pub abi_version: Option<AbiVersion>,
+
+    /// The entrypoints of the module with their IDs, if they could be read
+    /// without running the module
+    pub entrypoints: Option<BTreeMap<String, i32>>,
+
+    /// The builtins required by the module with their IDs, if they could be
+    /// read without running the module
+    pub builtins: Option<BTreeMap<String, i32>>,
+
+    /// The required builtins which are not supported by this build, or not
+    /// allowed by the registry
+    pub unsupported_builtins: BTreeSet<String>,
+
+    /// The problems which would make loading the module fail
+    pub errors: Vec<String>,
+
+    /// The checks which could not be done without running the module
+    pub warnings: Vec<String>,
+}
+
+impl ValidationReport {
+    /// Whether no problem was found
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(version) = &self.abi_version {
+            writeln!(f, "ABI version: {version}")?;
+        }
+        if let Some(entrypoints) = &self.entrypoints {
+            writeln!(f, "Entrypoints: {}", entrypoints.len())?;
+        }
+        if let Some(builtins) = &self.builtins {
+            writeln!(f, "Builtins: {}", builtins.len())?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+
+        if self.is_valid() {
+            write!(f, "the module is valid")
+        } else {
+            write!(f, "the module is invalid: {} error(s)", self.errors.len())
+        }
+    }
+}
+
+/// Check a policy module the way loading it would, against the builtins
+/// supported by this build, without compiling or instantiating it: that it
+/// is a valid WASM module, that it has a supported ABI version, that it
+/// imports only what the runtime provides and exports what the runtime
+/// needs, and that the builtins it requires are supported.
+///
+/// The entrypoints and builtins are read from the data segments the
+/// `entrypoints` and `builtins` exports parse, as the OPA compiler emits
+/// them. If they are not found there, the builtins are not checked, and a
+/// warning is reported.
+#[must_use]
+pub fn validate(module: &[u8]) -> ValidationReport {
+    validate_with_builtins(module, &BuiltinRegistry::<DefaultContext>::new())
+}
+
+/// Same as [`validate`], checking the builtins against the given registry
+#[must_use]
+pub fn validate_with_builtins<C: EvaluationContext>(
+    module: &[u8],
+    registry: &BuiltinRegistry<C>,
+) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    if let Err(error) = Validator::new().validate_all(module) {
+        report.errors.push(format!("invalid WASM module: {error}"));
+        return report;
+    }
+
+    let parsed = match ParsedModule::parse(module) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            report.errors.push(format!("{error:#}"));
+            return report;
+        }
+    };
+
+    parsed.check_imports(&mut report);
+
+    match parsed.abi_version() {
+        Ok(version) => report.abi_version = Some(version),
+        Err(error) => report.errors.push(format!("{error:#}")),
+    }
+
+    let mut required: Vec<&str> = REQUIRED_EXPORTS.to_vec();
+    if report
+        .abi_version
+        .is_some_and(AbiVersion::has_eval_fastpath)
+    {
+        required.push(funcs::OpaEval::EXPORT);
+    }
+    for name in required {
+        if !parsed.funcs.contains_key(name) {
+            report
+                .errors
+                .push(format!("could not find export {name:?}"));
+        }
+    }
+
+    report.entrypoints = parsed.mapping(funcs::Entrypoints::EXPORT, &mut report);
+    report.builtins = parsed.mapping(funcs::Builtins::EXPORT, &mut report);
+    if let Some(builtins) = &report.builtins {
+        report.unsupported_builtins = builtins
+            .keys()
+            .filter(|name| !registry.contains(name))
+            .cloned()
+            .collect();
+        for name in &report.unsupported_builtins {
+            report.errors.push(format!("unsupported builtin {name}"));
+        }
+    }
+
+    report
+}
+
+/// The parts of a module the validation looks at
+#[derive(Default)]
+struct ParsedModule<'a> {
+    /// The imports, as module, name and type
+    imports: Vec<(&'a str, &'a str, TypeRef)>,
+
+    /// The exported functions, by name
+    funcs: HashMap<&'a str, u32>,
+
+    /// The exported globals, by name
+    globals: HashMap<&'a str, u32>,
+
+    /// The constant initial value of the globals, `None` if it is not a
+    /// constant `i32`
+    global_values: Vec<Option<i32>>,
+
+    /// How many functions are imported, which come first in the function
+    /// index space
+    imported_funcs: u32,
+
+    /// How many globals are imported, which come first in the global index
+    /// space
+    imported_globals: u32,
+
+    /// The string arguments passed to `opa_json_parse` by function, as
+    /// address and length
+    json_parse_args: HashMap<u32, (i32, i32)>,
+
+    /// The active data segments of the memory, with their address
+    data: Vec<(i32, &'a [u8])>,
+}
+
+impl<'a> ParsedModule<'a> {
+    /// Parse the sections of the module
+    fn parse(module: &'a [u8]) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut next_func = 0;
+
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        match import.ty {
+                            TypeRef::Func(_) => parsed.imported_funcs += 1,
+                            TypeRef::Global(_) => parsed.imported_globals += 1,
+                            _ => {}
+                        }
+                        parsed.imports.push((import.module, import.name, import.ty));
+                    }
+                    next_func = parsed.imported_funcs;
+                }
+
+                Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        let mut operators = global?.init_expr.get_operators_reader();
+                        let value = match operators.read()? {
+                            Operator::I32Const { value } => Some(value),
+                            _ => None,
+                        };
+                        parsed.global_values.push(value);
+                    }
+                }
+
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        match export.kind {
+                            ExternalKind::Func => {
+                                parsed.funcs.insert(export.name, export.index);
+                            }
+                            ExternalKind::Global => {
+                                parsed.globals.insert(export.name, export.index);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
+                Payload::CodeSectionEntry(body) => {
+                    let index = next_func;
+                    next_func += 1;
+
+                    let Some(&json_parse) = parsed.funcs.get(funcs::OpaJsonParse::EXPORT) else {
+                        continue;
+                    };
+
+                    // Look for the `i32.const addr; i32.const len; call opa_json_parse`
+                    // sequence the compiler emits to return a JSON value
+                    let mut operators = body.get_operators_reader()?;
+                    let mut previous = [None, None];
+                    while !operators.eof() {
+                        let operator = operators.read()?;
+                        if let Operator::Call { function_index } = operator {
+                            if let (Some(addr), Some(len)) = (previous[0], previous[1]) {
+                                if function_index == json_parse {
+                                    parsed.json_parse_args.entry(index).or_insert((addr, len));
+                                }
+                            }
+                        }
+
+                        let value = match operator {
+                            Operator::I32Const { value } => Some(value),
+                            _ => None,
+                        };
+                        previous = [previous[1], value];
+                    }
+                }
+
+                Payload::DataSection(reader) => {
+                    for data in reader {
+                        let data = data?;
+                        let DataKind::Active {
+                            memory_index: 0,
+                            offset_expr,
+                        } = data.kind
+                        else {
+                            continue;
+                        };
+
+                        if let Operator::I32Const { value } =
+                            offset_expr.get_operators_reader().read()?
+                        {
+                            parsed.data.push((value, data.data));
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Check that the module only imports what the runtime provides
+    fn check_imports(&self, report: &mut ValidationReport) {
+        for (module, name, ty) in &self.imports {
+            match (*module, *name, ty) {
+                ("env", "memory", TypeRef::Memory(memory)) => {
+                    if memory.initial > u64::from(MEMORY_PAGES) {
+                        report.errors.push(format!(
+                            "the module requires {} memory pages, more than the {MEMORY_PAGES} provided",
+                            memory.initial
+                        ));
+                    }
+                    if memory.maximum.is_some() {
+                        report
+                            .errors
+                            .push("the module requires a bounded memory".to_owned());
+                    }
+                }
+                ("env", name, TypeRef::Func(_)) if PROVIDED_FUNCS.contains(&name) => {}
+                ("wasi_snapshot_preview1", name, TypeRef::Func(_)) => {
+                    if cfg!(feature = "wasi") {
+                        report.warnings.push(format!(
+                            "the module imports the WASI function {name}, it must be loaded with `Runtime::new_with_wasi`"
+                        ));
+                    } else {
+                        report.errors.push(format!(
+                            "the module imports the WASI function {name}, which requires the `wasi` feature"
+                        ));
+                    }
+                }
+                (module, name, _) => report
+                    .errors
+                    .push(format!("unknown import {module}.{name}")),
+            }
+        }
+    }
+
+    /// Read the ABI version from the exported globals
+    fn abi_version(&self) -> Result<AbiVersion> {
+        let global = |name: &str| -> Result<i32> {
+            let index = self
+                .globals
+                .get(name)
+                .with_context(|| format!("missing global {name}"))?;
+            index
+                .checked_sub(self.imported_globals)
+                .and_then(|index| self.global_values.get(usize::try_from(index).ok()?))
+                .copied()
+                .flatten()
+                .with_context(|| format!("{name} is not a constant i32"))
+        };
+
+        AbiVersion::new(
+            global("opa_wasm_abi_version")?,
+            global("opa_wasm_abi_minor_version")?,
+        )
+    }
+
+    /// Read the JSON mapping returned by the given export, from the data
+    /// segment it parses
+    fn mapping(
+        &self,
+        export: &str,
+        report: &mut ValidationReport,
+    ) -> Option<BTreeMap<String, i32>> {
+        let index = self.funcs.get(export)?;
+        let bytes = self.json_parse_args.get(index).and_then(|&(addr, len)| {
+            let len = usize::try_from(len).ok()?;
+            self.data.iter().find_map(|&(start, data)| {
+                let offset = usize::try_from(addr.checked_sub(start)?).ok()?;
+                data.get(offset..offset.checked_add(len)?)
+            })
+        });
+
+        let Some(bytes) = bytes else {
+            report.warnings.push(format!(
+                "could not find what {export:?} returns without running the module, it is not checked"
+            ));
+            return None;
+        };
+
+        match serde_json::from_slice(bytes) {
+            Ok(mapping) => Some(mapping),
+            Err(error) => {
+                report
+                    .errors
+                    .push(format!("invalid JSON returned by {export:?}: {error}"));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module shaped like the ones the OPA compiler emits, lacking most
+    /// exports
+    const MODULE: &str = r#"
+        (module
+          (import "env" "memory" (memory 2))
+          (import "env" "opa_abort" (func $abort (param i32)))
+          (func $json_parse (export "opa_json_parse") (param i32 i32) (result i32)
+            i32.const 0)
+          (func (export "builtins") (result i32)
+            i32.const 1024
+            i32.const 30
+            call $json_parse)
+          (func (export "entrypoints") (result i32)
+            i32.const 2048
+            i32.const 16
+            call $json_parse)
+          (global (export "opa_wasm_abi_version") i32 (i32.const 1))
+          (global (export "opa_wasm_abi_minor_version") i32 (i32.const 1))
+          (data (i32.const 1024) "{\"trace\":0,\"custom.missing\":1}")
+          (data (i32.const 2048) "{\"test/allow\":0}"))
+    "#;
+
+    #[test]
+    fn modules_are_validated() {
+        let module = wat::parse_str(MODULE).unwrap();
+        let report = validate(&module);
+
+        assert!(matches!(report.abi_version, Some(AbiVersion::V1_1)));
+        assert_eq!(
+            report.entrypoints,
+            Some(BTreeMap::from([("test/allow".to_owned(), 0)]))
+        );
+        assert_eq!(report.builtins.as_ref().map(BTreeMap::len), Some(2));
+        assert_eq!(
+            report.unsupported_builtins,
+            BTreeSet::from(["custom.missing".to_owned()])
+        );
+        assert!(report.warnings.is_empty());
+        assert!(!report.is_valid());
+        assert!(report
+            .errors
+            .contains(&"could not find export \"eval\"".to_owned()));
+        assert!(report
+            .errors
+            .contains(&"unsupported builtin custom.missing".to_owned()));
+
+        let registry = BuiltinRegistry::<DefaultContext>::new().deny("trace");
+        let report = validate_with_builtins(&module, &registry);
+        assert_eq!(report.unsupported_builtins.len(), 2);
+    }
+
+    #[test]
+    fn invalid_modules_are_reported() {
+        let report = validate(b"\0asm\x01\0\0\0");
+        assert!(report.abi_version.is_none());
+        assert!(report.builtins.is_none());
+        assert!(report
+            .errors
+            .contains(&"missing global opa_wasm_abi_version".to_owned()));
+
+        let report = validate(b"not a module");
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("invalid WASM module"));
+    }
+}
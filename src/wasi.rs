@@ -0,0 +1,196 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for policy modules importing WASI functions, with the clock and
+//! the RNG of the evaluation context
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use wasmtime::{Linker, StoreContextMut};
+use wasmtime_wasi::{preview1::WasiP1Ctx, HostWallClock, WasiCtxBuilder};
+
+/// The data of stores instantiating modules which import WASI functions,
+/// with [`Runtime::new_with_wasi`](crate::Runtime::new_with_wasi).
+///
+/// The WASI context it holds is replaced when the module is loaded, so it
+/// can be created with `WasiCtxBuilder::new().build_p1()`.
+pub trait WasiState: Send + 'static {
+    /// Get the WASI context of the store
+    fn wasi_ctx(&mut self) -> &mut WasiP1Ctx;
+}
+
+impl WasiState for WasiP1Ctx {
+    fn wasi_ctx(&mut self) -> &mut WasiP1Ctx {
+        self
+    }
+}
+
+/// A wall clock reading the time from the evaluation context, falling back
+/// to the system clock outside of evaluations
+struct ContextClock<F> {
+    /// Get the time from the evaluation context, as a duration since the UNIX
+    /// epoch
+    now: F,
+}
+
+impl<F> HostWallClock for ContextClock<F>
+where
+    F: Fn() -> Option<Duration> + Send,
+{
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> Duration {
+        (self.now)().unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// A random number generator taken from the evaluation context the first
+/// time it is needed, so that a seeded context gives reproducible bytes
+struct ContextRng<F, R> {
+    /// Get a random number generator from the evaluation context
+    take: F,
+
+    /// The random number generator, once taken
+    rng: Option<R>,
+
+    /// The generator used when the context has none to give
+    fallback: rand::rngs::OsRng,
+}
+
+impl<F, R> ContextRng<F, R>
+where
+    F: FnMut() -> Option<R>,
+    R: rand::RngCore,
+{
+    /// Get the random number generator, taking it first if needed. The OS
+    /// one is used if none could be taken.
+    fn rng(&mut self) -> &mut dyn rand::RngCore {
+        if self.rng.is_none() {
+            self.rng = (self.take)();
+        }
+
+        match &mut self.rng {
+            Some(rng) => rng,
+            None => &mut self.fallback,
+        }
+    }
+}
+
+impl<F, R> rand::RngCore for ContextRng<F, R>
+where
+    F: FnMut() -> Option<R>,
+    R: rand::RngCore,
+{
+    fn next_u32(&mut self) -> u32 {
+        self.rng().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng().fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng().try_fill_bytes(dest)
+    }
+}
+
+impl<F, R> ContextRng<F, R> {
+    /// Create a generator which takes the one of the context on first use
+    fn new(take: F) -> Self {
+        Self {
+            take,
+            rng: None,
+            fallback: rand::rngs::OsRng,
+        }
+    }
+}
+
+/// Define the WASI imports in the linker, and replace the WASI context of the
+/// store with one reading the clock and the RNG through the given functions.
+///
+/// The context has no access to the filesystem, the environment, the network
+/// or the standard streams.
+pub(crate) fn link<T, R>(
+    linker: &mut Linker<T>,
+    mut store: StoreContextMut<'_, T>,
+    now: impl Fn() -> Option<Duration> + Send + 'static,
+    rng: impl FnMut() -> Option<R> + Clone + Send + 'static,
+) -> Result<()>
+where
+    T: WasiState,
+    R: rand::RngCore + Send + 'static,
+{
+    wasmtime_wasi::preview1::add_to_linker_async(linker, T::wasi_ctx)?;
+
+    let ctx = WasiCtxBuilder::new()
+        .wall_clock(ContextClock { now })
+        .secure_random(ContextRng::new(rng.clone()))
+        .insecure_random(ContextRng::new(rng))
+        .build_p1();
+    *store.data_mut().wasi_ctx() = ctx;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{RngCore, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn clock_and_rng_come_from_the_context() {
+        let clock = ContextClock {
+            now: || Some(Duration::from_secs(42)),
+        };
+        assert_eq!(clock.now(), Duration::from_secs(42));
+
+        // Outside of evaluations, the system clock is used
+        let clock = ContextClock { now: || None };
+        assert!(clock.now() > Duration::from_secs(42));
+
+        let seeded = || Some(rand::rngs::StdRng::seed_from_u64(1));
+        let mut a = ContextRng::new(seeded);
+        let mut b = ContextRng::new(seeded);
+        let first = a.next_u64();
+        assert_eq!(first, b.next_u64());
+        // The generator is kept, instead of being taken again on each call
+        assert_ne!(a.next_u64(), first);
+    }
+
+    #[test]
+    fn loading_is_send() {
+        fn assert_send<F: std::future::Future + Send>(_: F) {}
+
+        let engine = wasmtime::Engine::new(wasmtime::Config::new().async_support(true)).unwrap();
+        let module = wasmtime::Module::new(&engine, b"\0asm\x01\0\0\0").unwrap();
+        let mut store = wasmtime::Store::new(&engine, WasiCtxBuilder::new().build_p1());
+        assert_send(crate::Runtime::new_with_wasi(
+            &mut store,
+            &module,
+            crate::DefaultContext::default(),
+        ));
+    }
+}
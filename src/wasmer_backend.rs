@@ -0,0 +1,635 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend evaluating policies with Wasmer, for embedders standardized on
+//! it.
+//!
+//! Wasmer host functions are synchronous, so evaluations run on a blocking
+//! thread of the tokio runtime, where the host functions wait for the async
+//! builtins to complete.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Poll, Wake, Waker},
+};
+
+use anyhow::{Context, Result};
+use wasmer::{
+    imports, sys::vm::TrapCode, Engine, Function, FunctionEnv, FunctionEnvMut, Instance, Memory,
+    MemoryType, MemoryView, Module, Pages, RuntimeError, Store, Type, Value,
+};
+
+use crate::{
+    backend::{self, Backend, Data, Exports, Host},
+    builtins::BuiltinRegistry,
+    failure::Aborted,
+    AbiVersion, DefaultContext, EvaluationContext,
+};
+
+/// Read a nul-terminated string from the memory
+fn read_nul_str(view: &MemoryView<'_>, addr: i32) -> Result<Vec<u8>> {
+    /// How many bytes are read at once
+    const CHUNK: u64 = 256;
+
+    let mut offset = u64::try_from(addr).context("invalid address")?;
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 256];
+    loop {
+        let len = view.data_size().saturating_sub(offset).min(CHUNK);
+        anyhow::ensure!(len > 0, "unterminated string");
+        let chunk = &mut chunk[..usize::try_from(len)?];
+        view.read(offset, chunk)?;
+
+        if let Some(end) = chunk.iter().position(|&b| b == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return Ok(bytes);
+        }
+
+        bytes.extend_from_slice(chunk);
+        offset += len;
+    }
+}
+
+/// Turn an error of Wasmer into one
+/// [`EvaluationFailure`](crate::EvaluationFailure) can describe if it is a trap
+fn error(error: RuntimeError) -> anyhow::Error {
+    use wasmtime::Trap;
+
+    let trap = match error.clone().to_trap() {
+        Some(TrapCode::StackOverflow) => Trap::StackOverflow,
+        Some(TrapCode::HeapAccessOutOfBounds) => Trap::MemoryOutOfBounds,
+        Some(TrapCode::HeapMisaligned) => Trap::HeapMisaligned,
+        Some(TrapCode::TableAccessOutOfBounds) => Trap::TableOutOfBounds,
+        Some(TrapCode::IndirectCallToNull) => Trap::IndirectCallToNull,
+        Some(TrapCode::BadSignature) => Trap::BadSignature,
+        Some(TrapCode::IntegerOverflow) => Trap::IntegerOverflow,
+        Some(TrapCode::IntegerDivisionByZero) => Trap::IntegerDivisionByZero,
+        Some(TrapCode::BadConversionToInteger) => Trap::BadConversionToInteger,
+        Some(TrapCode::UnreachableCodeReached) => Trap::UnreachableCodeReached,
+        _ => return error.into(),
+    };
+    trap.into()
+}
+
+/// The state of the host functions
+struct Env<C> {
+    /// The memory shared with the module
+    memory: Memory,
+
+    /// The exported functions, set once the module is instantiated
+    exports: Option<Exports<Function>>,
+
+    /// The state the builtins need
+    host: Host<C>,
+
+    /// The error which made a host function fail, to report it instead of the
+    /// resulting trap
+    error: Option<anyhow::Error>,
+}
+
+impl<C: EvaluationContext> Env<C> {
+    /// Record the error of a host function, turning it into a trap
+    fn fail(&mut self, error: anyhow::Error) -> RuntimeError {
+        let trap = RuntimeError::new(error.to_string());
+        self.error = Some(error);
+        trap
+    }
+}
+
+impl<C: EvaluationContext> Backend for FunctionEnvMut<'_, Env<C>> {
+    type Context = C;
+    type Func = Function;
+
+    fn exports(&self) -> Result<&Exports<Function>> {
+        self.data()
+            .exports
+            .as_ref()
+            .context("the exports were never looked up")
+    }
+
+    fn host(&mut self) -> &mut Host<C> {
+        &mut self.data_mut().host
+    }
+
+    fn read_c_str(&mut self, addr: i32) -> Result<Vec<u8>> {
+        let (env, store) = self.data_and_store_mut();
+        read_nul_str(&env.memory.view(&store), addr)
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        let (env, store) = self.data_and_store_mut();
+        env.memory
+            .view(&store)
+            .write(u64::try_from(offset)?, bytes)?;
+        Ok(())
+    }
+
+    fn read_memory(&mut self) -> Result<Vec<u8>> {
+        let (env, store) = self.data_and_store_mut();
+        Ok(env.memory.view(&store).copy_to_vec()?)
+    }
+
+    fn memory_size(&mut self) -> usize {
+        let (env, store) = self.data_and_store_mut();
+        usize::try_from(env.memory.view(&store).data_size()).unwrap_or(usize::MAX)
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Result<()> {
+        let (env, mut store) = self.data_and_store_mut();
+        env.memory.grow(&mut store, Pages(pages))?;
+        Ok(())
+    }
+
+    /// Call the function, reporting the error of the host function which made
+    /// it fail if any
+    fn call(&mut self, func: &Function, params: &[i32]) -> Result<i32> {
+        let params: Vec<Value> = params.iter().copied().map(Value::I32).collect();
+        let (env, mut store) = self.data_and_store_mut();
+        let result = func.call(&mut store, &params);
+        match (result, env.error.take()) {
+            (Ok(results), _) => Ok(results.first().and_then(Value::i32).unwrap_or(0)),
+            (Err(_), Some(error)) => Err(error),
+            (Err(trap), None) => Err(error(trap)),
+        }
+    }
+
+    /// Call the function, the builtins it needs being called by the host
+    /// functions
+    async fn eval(&mut self, func: &Function, params: &[i32]) -> Result<i32> {
+        self.call(func, params)
+    }
+}
+
+/// Look the exports up in an instance, checking their signature
+fn lookup_exports(
+    store: &Store,
+    instance: &Instance,
+    version: AbiVersion,
+) -> Result<Exports<Function>> {
+    Exports::lookup(version, |name, params, results| {
+        let func = instance
+            .exports
+            .get_function(name)
+            .with_context(|| format!("could not find export {name}"))?;
+        let ty = func.ty(store);
+        let is_i32 = |types: &[Type], len: usize| {
+            types.len() == len && types.iter().all(|ty| *ty == Type::I32)
+        };
+        anyhow::ensure!(
+            is_i32(ty.params(), params) && is_i32(ty.results(), results),
+            "export {name} does not have the expected signature"
+        );
+        Ok(func.clone())
+    })
+}
+
+/// `opa_abort`, reporting that the policy aborted
+fn abort<C: EvaluationContext>(mut env: FunctionEnvMut<'_, Env<C>>, addr: i32) -> RuntimeError {
+    let message = env
+        .read_c_str(addr)
+        .map(|message| String::from_utf8_lossy(&message).into_owned())
+        .unwrap_or_default();
+    let env = env.data_mut();
+    env.host.context.abort(&message);
+    env.fail(Aborted(message).into())
+}
+
+/// `opa_println`, printing a message from the policy
+fn println<C: EvaluationContext>(mut env: FunctionEnvMut<'_, Env<C>>, addr: i32) {
+    let message = env
+        .read_c_str(addr)
+        .map(|message| String::from_utf8_lossy(&message).into_owned())
+        .unwrap_or_default();
+    env.data_mut().host.context.print(&message);
+}
+
+/// `opa_builtinN`, calling a builtin and returning the address of its result
+fn builtin<C: EvaluationContext>(
+    mut env: FunctionEnvMut<'_, Env<C>>,
+    id: i32,
+    args: &[i32],
+) -> Result<i32, RuntimeError> {
+    // Evaluations run on a blocking thread of the runtime, where waiting for
+    // the builtin is allowed
+    let result = match tokio::runtime::Handle::try_current() {
+        Ok(handle) => handle.block_on(env.call_builtin(id, args)),
+        Err(error) => Err(error.into()),
+    };
+    result.map_err(|error| env.data_mut().fail(error))
+}
+
+/// A waker which does nothing, for futures which never suspend
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Run a future which never suspends, as the calls into Wasmer block
+fn run<F: Future>(future: F) -> Result<F::Output> {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut context = std::task::Context::from_waker(&waker);
+    match pin!(future).poll(&mut context) {
+        Poll::Ready(output) => Ok(output),
+        Poll::Pending => anyhow::bail!("the evaluation was suspended"),
+    }
+}
+
+/// The store holding an instance, with the state of its host functions
+struct Sandbox<C> {
+    /// The store holding the instance
+    store: Store,
+
+    /// The state of the host functions, holding the evaluation context
+    env: FunctionEnv<Env<C>>,
+}
+
+impl<C> Sandbox<C> {
+    /// Lock the sandbox of a runtime. A panic while it was locked leaves it
+    /// as usable as an evaluation failing.
+    fn lock(sandbox: &Mutex<Self>) -> std::sync::MutexGuard<'_, Self> {
+        sandbox.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An instance of a policy, running on Wasmer, with builtins and entrypoints
+/// resolved, but with no data provided yet.
+///
+/// Unlike [`Runtime`](crate::Runtime), it owns its store, created from the
+/// given [`wasmer::Engine`], and its evaluations run on a blocking thread of
+/// the tokio runtime.
+pub struct WasmerRuntime<C = DefaultContext> {
+    /// The store and the state of the host functions, shared with the
+    /// blocking tasks evaluating the policy
+    sandbox: Arc<Mutex<Sandbox<C>>>,
+
+    /// What is known about the module
+    module: Arc<backend::Module>,
+}
+
+impl<C> std::fmt::Debug for WasmerRuntime<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmerRuntime")
+            .field("version", &self.module.version)
+            .field("entrypoints", &self.module.entrypoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmerRuntime<DefaultContext> {
+    /// Load a new WASM policy module, compiled with the given engine, with the
+    /// default evaluation context
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is not a valid OPA WASM compiled
+    /// policy, or if it needs builtins which are not supported.
+    pub fn new(engine: &Engine, module: &Module) -> Result<Self> {
+        Self::new_with_evaluation_context(engine, module, DefaultContext::default())
+    }
+}
+
+impl<C: EvaluationContext> WasmerRuntime<C> {
+    /// Load a new WASM policy module, compiled with the given engine, with the
+    /// given evaluation context
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is not a valid OPA WASM compiled
+    /// policy, or if it needs builtins which are not supported.
+    pub fn new_with_evaluation_context(
+        engine: &Engine,
+        module: &Module,
+        context: C,
+    ) -> Result<Self> {
+        Self::new_with_builtins(engine, module, context, &BuiltinRegistry::new())
+    }
+
+    /// Load a new WASM policy module, compiled with the given engine,
+    /// resolving its builtins from the given registry
+    ///
+    /// # Errors
+    ///
+    /// It will raise an error if the module is not a valid OPA WASM compiled
+    /// policy, or if it needs builtins which the registry does not provide.
+    pub fn new_with_builtins(
+        engine: &Engine,
+        module: &Module,
+        context: C,
+        registry: &BuiltinRegistry<C>,
+    ) -> Result<Self> {
+        let mut store = Store::new(engine.clone());
+        let memory = Memory::new(&mut store, MemoryType::new(2, None, false))?;
+
+        let env = FunctionEnv::new(
+            &mut store,
+            Env {
+                memory: memory.clone(),
+                exports: None,
+                host: Host::new(context),
+                error: None,
+            },
+        );
+        let imports = imports! {
+            "env" => {
+                "memory" => memory,
+                "opa_abort" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>, addr: i32| -> Result<(), RuntimeError> {
+                        Err(abort(env, addr))
+                    },
+                ),
+                "opa_println" => Function::new_typed_with_env(&mut store, &env, println::<C>),
+                "opa_builtin0" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>, id: i32, _ctx: i32| builtin(env, id, &[]),
+                ),
+                "opa_builtin1" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>, id: i32, _ctx: i32, a: i32| {
+                        builtin(env, id, &[a])
+                    },
+                ),
+                "opa_builtin2" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>, id: i32, _ctx: i32, a: i32, b: i32| {
+                        builtin(env, id, &[a, b])
+                    },
+                ),
+                "opa_builtin3" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>, id: i32, _ctx: i32, a: i32, b: i32, c: i32| {
+                        builtin(env, id, &[a, b, c])
+                    },
+                ),
+                "opa_builtin4" => Function::new_typed_with_env(
+                    &mut store,
+                    &env,
+                    |env: FunctionEnvMut<'_, Env<C>>,
+                     id: i32,
+                     _ctx: i32,
+                     a: i32,
+                     b: i32,
+                     c: i32,
+                     d: i32| { builtin(env, id, &[a, b, c, d]) },
+                ),
+            }
+        };
+
+        let instance = Instance::new(&mut store, module, &imports)?;
+
+        let global = |store: &mut Store, name: &str| {
+            instance
+                .exports
+                .get_global(name)
+                .with_context(|| format!("missing global {name}"))?
+                .get(store)
+                .i32()
+                .with_context(|| format!("{name} is not an i32"))
+        };
+        let version = AbiVersion::new(
+            global(&mut store, "opa_wasm_abi_version")?,
+            global(&mut store, "opa_wasm_abi_minor_version")?,
+        )?;
+        let exports = lookup_exports(&store, &instance, version)?;
+        env.as_mut(&mut store).exports = Some(exports);
+
+        let module = {
+            let mut backend = env.clone().into_mut(&mut store);
+            backend::Module::inspect(&mut backend, version, registry)?
+        };
+
+        Ok(Self {
+            sandbox: Arc::new(Mutex::new(Sandbox { store, env })),
+            module: Arc::new(module),
+        })
+    }
+
+    /// Set the revision of the bundle this module was loaded from. It is
+    /// passed to the evaluation context when an evaluation starts.
+    #[must_use]
+    pub fn with_revision(mut self, revision: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.module).revision = Some(revision.into());
+        self
+    }
+
+    /// Snapshot the memory once the data is loaded, and restore it before
+    /// each evaluation. See
+    /// [`Runtime::with_memory_snapshot`](crate::Runtime::with_memory_snapshot).
+    #[must_use]
+    pub fn with_memory_snapshot(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.module).memory_snapshot = enabled;
+        self
+    }
+
+    /// Fail evaluations when a builtin returns an error. See
+    /// [`Runtime::with_strict_builtin_errors`](crate::Runtime::with_strict_builtin_errors).
+    #[must_use]
+    pub fn with_strict_builtin_errors(self, enabled: bool) -> Self {
+        {
+            let mut sandbox = Sandbox::lock(&self.sandbox);
+            let Sandbox { store, env } = &mut *sandbox;
+            env.as_mut(store).host.strict_builtin_errors = enabled;
+        }
+        self
+    }
+
+    /// Instanciate the policy with an empty `data` object
+    ///
+    /// # Errors
+    ///
+    /// If it failed to load the empty data object in memory
+    pub fn without_data(self) -> Result<WasmerPolicy<C>> {
+        self.with_data(&serde_json::Value::Object(serde_json::Map::default()))
+    }
+
+    /// Instanciate the policy with the given `data` object
+    ///
+    /// # Errors
+    ///
+    /// If it failed to serialize and load the `data` object
+    pub fn with_data<V: serde::Serialize>(self, data: &V) -> Result<WasmerPolicy<C>> {
+        let data = {
+            let mut sandbox = Sandbox::lock(&self.sandbox);
+            let Sandbox { store, env } = &mut *sandbox;
+            let mut backend = env.clone().into_mut(store);
+            self.module.load_data(&mut backend, data)?
+        };
+        Ok(WasmerPolicy {
+            runtime: self,
+            data: Arc::new(data),
+        })
+    }
+
+    /// Get the list of entrypoints found in this module.
+    #[must_use]
+    pub fn entrypoints(&self) -> HashSet<&str> {
+        self.module.entrypoints.keys().map(String::as_str).collect()
+    }
+
+    /// Get the ABI version detected for this module
+    #[must_use]
+    pub fn abi_version(&self) -> AbiVersion {
+        self.module.version
+    }
+}
+
+/// An instance of a policy, running on Wasmer, ready to be executed
+pub struct WasmerPolicy<C = DefaultContext> {
+    /// The runtime this policy instance belongs to
+    runtime: WasmerRuntime<C>,
+
+    /// The data loaded in the instance
+    data: Arc<Data>,
+}
+
+impl<C> std::fmt::Debug for WasmerPolicy<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmerPolicy")
+            .field("runtime", &self.runtime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C> std::ops::Deref for WasmerPolicy<C> {
+    type Target = WasmerRuntime<C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.runtime
+    }
+}
+
+impl<C: EvaluationContext> WasmerPolicy<C> {
+    /// Evaluate a policy with the given entrypoint and input.
+    ///
+    /// The evaluation runs on a blocking thread of the current tokio runtime,
+    /// so it doesn't block the async tasks.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy evaluation failed, or if it was not
+    /// called from a tokio runtime.
+    pub async fn evaluate<V: serde::Serialize, R: for<'de> serde::Deserialize<'de>>(
+        &mut self,
+        entrypoint: &str,
+        input: &V,
+    ) -> Result<R> {
+        tokio::runtime::Handle::try_current()
+            .context("evaluations run on the blocking threads of a tokio runtime")?;
+
+        let input = serde_json::to_value(input)?;
+        let entrypoint = entrypoint.to_owned();
+        let sandbox = Arc::clone(&self.runtime.sandbox);
+        let module = Arc::clone(&self.runtime.module);
+        let data = Arc::clone(&self.data);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut sandbox = Sandbox::lock(&sandbox);
+            let Sandbox { store, env } = &mut *sandbox;
+            let mut backend = env.clone().into_mut(store);
+            run(module.evaluate(&mut backend, &data, &entrypoint, &input))?
+        })
+        .await??;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{self, StubPolicy};
+
+    #[test]
+    fn modules_are_checked() {
+        // A valid module, but without the ABI of OPA policies
+        let engine = Engine::default();
+        let module = Module::new(&engine, b"\0asm\x01\0\0\0").unwrap();
+        let error = WasmerRuntime::new(&engine, &module).unwrap_err();
+        assert_eq!(error.to_string(), "missing global opa_wasm_abi_version");
+    }
+
+    #[test]
+    fn strings_are_read_from_memory() {
+        let mut store = Store::default();
+        let memory = Memory::new(&mut store, MemoryType::new(1, None, false)).unwrap();
+        let view = memory.view(&store);
+        let long = "a".repeat(1000);
+        view.write(0, b"hello\0").unwrap();
+        view.write(6, long.as_bytes()).unwrap();
+
+        assert_eq!(read_nul_str(&view, 0).unwrap(), b"hello");
+        assert_eq!(read_nul_str(&view, 6).unwrap(), long.as_bytes());
+        assert!(read_nul_str(&view, 70_000).is_err());
+    }
+
+    #[test]
+    fn evaluation_is_send() {
+        fn assert_send<F: std::future::Future + Send>(_: F) {}
+
+        fn check(policy: &mut WasmerPolicy) {
+            assert_send(policy.evaluate::<_, serde_json::Value>("allow", &()));
+        }
+
+        let _ = check;
+    }
+
+    #[test]
+    fn evaluations_need_a_runtime() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, stub::failing_wasm()).unwrap();
+        let mut policy = WasmerRuntime::new(&engine, &module)
+            .unwrap()
+            .without_data()
+            .unwrap();
+        let error = run(policy.evaluate::<_, serde_json::Value>("trap", &()))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "evaluations run on the blocking threads of a tokio runtime"
+        );
+    }
+
+    impl StubPolicy for WasmerPolicy {
+        async fn load(wasm: &[u8], revision: &str, strict_builtin_errors: bool) -> Result<Self> {
+            let engine = Engine::default();
+            let module = Module::new(&engine, wasm)?;
+            WasmerRuntime::new(&engine, &module)?
+                .with_revision(revision)
+                .with_strict_builtin_errors(strict_builtin_errors)
+                .without_data()
+        }
+
+        async fn evaluate(&mut self, entrypoint: &str) -> Result<serde_json::Value> {
+            WasmerPolicy::evaluate(self, entrypoint, &()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn builtin_errors_are_undefined_unless_strict() {
+        stub::builtin_errors_are_undefined_unless_strict::<WasmerPolicy>().await;
+    }
+
+    #[tokio::test]
+    async fn failures_are_described() {
+        stub::failures_are_described::<WasmerPolicy>().await;
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use opa_wasm::{PolicyInput, PolicyOutput};
+use serde_json::json;
+
+#[derive(PolicyInput)]
+#[policy(entrypoint = "authz/allow", output = Decision, rename_all = "camelCase")]
+struct Request {
+    user_name: String,
+    #[policy(rename = "path")]
+    segments: Vec<String>,
+    #[policy(skip)]
+    #[allow(dead_code)]
+    cache_key: u64,
+}
+
+#[derive(Debug, PartialEq, PolicyOutput)]
+struct Decision {
+    allow: bool,
+    r#type: String,
+    reason: Option<String>,
+    #[policy(skip)]
+    cached: bool,
+}
+
+#[derive(PolicyInput)]
+#[policy(entrypoint = "example/raw")]
+struct Raw {}
+
+#[test]
+fn inputs_are_serialized() {
+    assert_eq!(Request::ENTRYPOINT, "authz/allow");
+    assert_eq!(Request::entrypoint().name(), "authz/allow");
+    assert_eq!(Raw::entrypoint().name(), "example/raw");
+
+    let request = Request {
+        user_name: "alice".to_owned(),
+        segments: vec!["users".to_owned(), "alice".to_owned()],
+        cache_key: 42,
+    };
+    assert_eq!(
+        serde_json::to_value(&request).unwrap(),
+        json!({"userName": "alice", "path": ["users", "alice"]}),
+    );
+}
+
+#[test]
+fn outputs_are_deserialized() {
+    let decision: Decision =
+        serde_json::from_value(json!({"allow": true, "type": "user", "extra": 1})).unwrap();
+    assert_eq!(
+        decision,
+        Decision {
+            allow: true,
+            r#type: "user".to_owned(),
+            reason: None,
+            cached: false,
+        },
+    );
+
+    let error = serde_json::from_value::<Decision>(json!({"type": "user"})).unwrap_err();
+    assert_eq!(error.to_string(), "missing field `allow`");
+}
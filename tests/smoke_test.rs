@@ -16,8 +16,10 @@ use std::path::Path;
 
 use anyhow::Result as AnyResult;
 use insta::assert_yaml_snapshot;
-use opa_wasm::{read_bundle, Runtime, TestContext};
-use wasmtime::{Config, Engine, Module, Store};
+use opa_wasm::{
+    read_bundle,
+    testing::{read_json, TestBundle},
+};
 
 macro_rules! integration_test {
     ($name:ident, $suite:expr) => {
@@ -38,36 +40,6 @@ macro_rules! integration_test {
     };
 }
 
-async fn eval_policy(
-    bundle: &str,
-    entrypoint: &str,
-    input: &serde_json::Value,
-) -> AnyResult<serde_json::Value> {
-    let module = read_bundle(bundle).await?;
-
-    // Configure the WASM runtime
-    let mut config = Config::new();
-    config.async_support(true);
-
-    let engine = Engine::new(&config)?;
-
-    let module = Module::new(&engine, module)?;
-
-    // Create a store which will hold the module instance
-    let mut store = Store::new(&engine, ());
-
-    let ctx = TestContext::default();
-
-    // Instantiate the module
-    let runtime = Runtime::new_with_evaluation_context(&mut store, &module, ctx).await?;
-
-    let policy = runtime.without_data(&mut store).await?;
-
-    // Evaluate the policy
-    let p: serde_json::Value = policy.evaluate(&mut store, entrypoint, &input).await?;
-    Ok(p)
-}
-
 fn bundle(name: &str) -> String {
     Path::new("tests/infra-fixtures")
         .join(name)
@@ -84,17 +56,14 @@ fn input(name: &str) -> String {
 
 async fn test_policy(bundle_name: &str, data: Option<&str>) -> AnyResult<serde_json::Value> {
     let input = if let Some(data) = data {
-        let input_bytes = tokio::fs::read(input(&format!("{}.json", data))).await?;
-        serde_json::from_slice(&input_bytes[..])?
+        read_json(input(&format!("{}.json", data))).await?
     } else {
         serde_json::Value::Object(serde_json::Map::default())
     };
-    eval_policy(
-        &bundle(&format!("{}.rego.tar.gz", bundle_name)),
-        "test",
-        &input,
-    )
-    .await
+    TestBundle::load(bundle(&format!("{}.rego.tar.gz", bundle_name)))
+        .await?
+        .evaluate("test", &input)
+        .await
 }
 
 #[tokio::test]
@@ -122,6 +91,15 @@ integration_test!(test_yaml, "test-yaml");
 integration_test!(test_urlquery, "test-urlquery");
 integration_test!(test_time, "test-time");
 
+#[tokio::test]
+async fn policy_snapshot() {
+    opa_wasm::assert_policy_snapshot!(
+        bundle("test-loader.rego.tar.gz"),
+        "test",
+        serde_json::json!({})
+    );
+}
+
 /*
 #[tokio::test]
 async fn test_uuid() {